@@ -0,0 +1,109 @@
+// clap.audio-ports-activation: the plugin's ports beyond the main ones are
+// switched off while nothing is connected to their JACK ports, so it can skip
+// work for, say, an unused sidechain, and back on once something is. We only
+// call set_active while the plugin is deactivated, before the first
+// activation and then inside the restart the main loop does for a change in
+// connections, which is rare enough for that. An inactive port still gets
+// buffers; nothing is connected to them.
+use clack_extensions::audio_ports_activation::PluginAudioPortsActivation;
+use clack_host::prelude::*;
+use jack::Client;
+
+use crate::layout::{Layout, PortLayout};
+use crate::MyHost;
+
+struct AuxPort {
+    is_input: bool,
+    index: u32,
+    name: String,
+    // full names of its JACK ports, one per channel
+    jack_names: Vec<String>,
+    active: bool,
+    // the plugin turned down a set_active for it, so we leave it be
+    refused: bool,
+}
+
+pub struct PortActivation {
+    ext: PluginAudioPortsActivation,
+    ports: Vec<AuxPort>,
+    // bits per sample we process the plugin in
+    sample_size: u32,
+}
+
+impl PortActivation {
+    // Before the plugin is first activated: nothing is connected to our
+    // ports yet, so every port beyond the main ones starts off. None if the
+    // plugin has no such ports or doesn't support turning them off.
+    pub fn new(instance: &mut PluginInstance<MyHost>, layout: &Layout, wide: bool) -> Option<Self> {
+        let ext = instance.plugin_handle().get_extension::<PluginAudioPortsActivation>()?;
+        let side = |ports: &[PortLayout], is_input| {
+            ports.iter().enumerate().skip(1).map(move |(i, port)| AuxPort {
+                is_input,
+                index: i as u32,
+                name: port.name.clone(),
+                jack_names: Vec::new(),
+                active: true,
+                refused: false,
+            })
+        };
+        let ports: Vec<AuxPort> = side(&layout.inputs, true).chain(side(&layout.outputs, false)).collect();
+        if ports.is_empty() {
+            return None;
+        }
+        let mut activation = PortActivation { ext, ports, sample_size: if wide { 64 } else { 32 } };
+        for i in 0..activation.ports.len() {
+            activation.set(instance, i, false);
+        }
+        Some(activation)
+    }
+
+    // Once our JACK ports exist: their full names, channel by channel in the
+    // plugin's port order for each side (an empty one for an input channel
+    // fed from a file)
+    pub fn watch(&mut self, layout: &Layout, in_names: &[String], out_names: &[String]) {
+        for port in &mut self.ports {
+            let (ports, names) = match port.is_input {
+                true => (&layout.inputs, in_names),
+                false => (&layout.outputs, out_names),
+            };
+            let first: usize = ports[..port.index as usize].iter().map(|p| p.channels).sum();
+            let channels = ports[port.index as usize].channels;
+            let names = names.iter().skip(first).take(channels);
+            port.jack_names = names.filter(|name| !name.is_empty()).cloned().collect();
+        }
+    }
+
+    // Whether any port's connections no longer match whether it's active
+    pub fn stale(&self, client: &Client) -> bool {
+        self.ports.iter().any(|port| !port.refused && connected(client, port) != port.active)
+    }
+
+    // While the plugin is deactivated: each port on if its JACK ports are
+    // connected, off if not
+    pub fn apply(&mut self, instance: &mut PluginInstance<MyHost>, client: &Client) {
+        for i in 0..self.ports.len() {
+            let connected = connected(client, &self.ports[i]);
+            if !self.ports[i].refused && connected != self.ports[i].active {
+                self.set(instance, i, connected);
+            }
+        }
+    }
+
+    fn set(&mut self, instance: &mut PluginInstance<MyHost>, i: usize, active: bool) {
+        let port = &mut self.ports[i];
+        let side = if port.is_input { "input" } else { "output" };
+        if self.ext.set_active(&mut instance.plugin_handle(), port.is_input, port.index, active, self.sample_size) {
+            port.active = active;
+            println!("Plugin {side} {:?} {}", port.name, if active { "on" } else { "off while unconnected" });
+        } else {
+            port.refused = true;
+            eprintln!("The plugin would not turn its {side} {:?} {}", port.name, if active { "on" } else { "off" });
+        }
+    }
+}
+
+// A port with no JACK ports is fed from an --input-file, so always in use
+fn connected(client: &Client, port: &AuxPort) -> bool {
+    let mut ports = port.jack_names.iter().filter_map(|name| client.port_by_name(name));
+    port.jack_names.is_empty() || ports.any(|port| port.connected_count().is_ok_and(|n| n > 0))
+}
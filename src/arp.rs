@@ -0,0 +1,174 @@
+// Host-side arpeggiator between the MIDI input and the plugin: held notes are
+// played one at a time, in steps synced to the JACK transport, so plain synth
+// plugins get basic pattern playback live.
+use clap::ValueEnum;
+use jack::{TransportState, TransportStatePosition};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ArpMode {
+    Up,
+    Down,
+    Random,
+}
+
+// One generated note event, `time` in frames from the start of the slice
+pub struct ArpNote {
+    pub time: u32,
+    pub on: bool,
+    pub channel: u16,
+    pub key: u16,
+    pub velocity: f64,
+}
+
+pub struct Arp {
+    mode: ArpMode,
+    sample_rate: f64,
+    // tempo to use when no timebase master is publishing BBT
+    fallback_bpm: f64,
+    bpm: f64,
+    steps_per_beat: f64,
+    // fraction of a step each note lasts
+    gate: f64,
+    // held keys (sorted) and their velocities, and the channel they came in on
+    held: Vec<(u16, f64)>,
+    channel: u16,
+    // position in steps at the start of the next slice, and the last step played
+    phase: f64,
+    last_step: i64,
+    count: usize,
+    rng: u32,
+    // the note we're sounding and the step position its gate ends at
+    sounding: Option<(u16, u16, f64)>,
+    out: Vec<ArpNote>,
+}
+
+impl Arp {
+    pub fn new(mode: ArpMode, sample_rate: f64, fallback_bpm: f64, steps_per_beat: u32, gate: f64) -> Self {
+        Arp {
+            mode,
+            sample_rate,
+            fallback_bpm,
+            bpm: fallback_bpm,
+            steps_per_beat: steps_per_beat.max(1) as f64,
+            gate: gate.clamp(0.01, 1.0),
+            // room for every MIDI key, so holding notes never allocates
+            held: Vec::with_capacity(128),
+            channel: 0,
+            phase: 0.0,
+            last_step: -1,
+            count: 0,
+            rng: 0x2545f491,
+            sounding: None,
+            out: Vec::with_capacity(64),
+        }
+    }
+
+    // Steps are counted in beats, so only their length in frames changes
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    // Take note on/off messages from the MIDI input; anything else is ignored
+    pub fn midi(&mut self, bytes: &[u8]) {
+        let &[status, key, velocity] = bytes else { return };
+        let key = key as u16;
+        let held = self.held.binary_search_by_key(&key, |&(k, _)| k);
+        match (status & 0xf0, held) {
+            (0x90, Err(at)) if velocity > 0 => {
+                self.held.insert(at, (key, velocity as f64 / 127.0));
+                self.channel = (status & 0x0f) as u16;
+            }
+            (0x90, Ok(_)) if velocity > 0 => {}
+            (0x80 | 0x90, Ok(at)) => {
+                self.held.remove(at);
+            }
+            _ => {}
+        }
+    }
+
+    // Let go of every held key; the note that's sounding finishes its gate
+    pub fn release(&mut self) {
+        self.held.clear();
+    }
+
+    // Follow the transport once per JACK block: its tempo always, its
+    // position while rolling; when stopped we free-run
+    pub fn sync(&mut self, transport: &TransportStatePosition) {
+        let rolling = matches!(transport.state, TransportState::Rolling);
+        let beats = match transport.pos.bbt() {
+            Some(bbt) if bbt.bpm > 0.0 => {
+                self.bpm = bbt.bpm;
+                rolling.then(|| bbt.beat.saturating_sub(1) as f64 + bbt.tick as f64 / bbt.ticks_per_beat)
+            }
+            _ => {
+                self.bpm = self.fallback_bpm;
+                rolling.then(|| transport.pos.frame() as f64 * self.bpm / (60.0 * self.sample_rate))
+            }
+        };
+        if let Some(beats) = beats {
+            let phase = beats * self.steps_per_beat;
+            // A relocate: play from the new position rather than waiting to
+            // catch up with the steps already played
+            if (phase - self.phase).abs() > 1.0 {
+                self.last_step = phase.ceil() as i64 - 1;
+            }
+            self.phase = phase;
+        }
+    }
+
+    // Generate the notes for the next `frames` frames
+    pub fn process(&mut self, frames: usize) {
+        self.out.clear();
+        let frames_per_step = self.sample_rate * 60.0 / (self.bpm * self.steps_per_beat);
+        let start = self.phase;
+        let end = start + frames as f64 / frames_per_step;
+        let time_of = |phase: f64| (((phase - start) * frames_per_step) as u32).min(frames as u32 - 1);
+
+        let mut step = start.ceil();
+        while step < end {
+            self.end_note_before(step, time_of);
+            if step as i64 > self.last_step {
+                self.last_step = step as i64;
+                self.trigger(time_of(step), step);
+            }
+            step += 1.0;
+        }
+        self.end_note_before(end, time_of);
+        self.phase = end;
+    }
+
+    pub fn events(&self) -> &[ArpNote] {
+        &self.out
+    }
+
+    fn end_note_before(&mut self, phase: f64, time_of: impl Fn(f64) -> u32) {
+        if let Some((channel, key, off)) = self.sounding {
+            if off <= phase {
+                self.out.push(ArpNote { time: time_of(off), on: false, channel, key, velocity: 0.0 });
+                self.sounding = None;
+            }
+        }
+    }
+
+    fn trigger(&mut self, time: u32, step: f64) {
+        let n = self.held.len();
+        if n == 0 {
+            return;
+        }
+        let i = match self.mode {
+            ArpMode::Up => self.count % n,
+            ArpMode::Down => n - 1 - self.count % n,
+            ArpMode::Random => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 17;
+                self.rng ^= self.rng << 5;
+                self.rng as usize % n
+            }
+        };
+        self.count += 1;
+        let (key, velocity) = self.held[i];
+        let channel = self.channel;
+        self.out.push(ArpNote { time, on: true, channel, key, velocity });
+        self.sounding = Some((channel, key, step + self.gate));
+    }
+}
@@ -0,0 +1,111 @@
+// `calibrate`: a test signal on the same JACK ports the host normally plays
+// the plugin through, with no plugin loaded, so the monitoring chain can be
+// level-set before there is anything unpredictable on it.
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use jack::{AudioOut, Client, ClientOptions, Control, Port, ProcessHandler, ProcessScope};
+
+use crate::shutdown;
+use crate::{connect_outputs, prefixed_port, CalibrateArgs};
+
+// RMS of the pink filter below when fed uniform white noise in -1..1
+// (measured over a few million samples)
+const PINK_RMS: f32 = 1.745;
+
+// How long JACK gets to drop our callback and ports on the way out
+const DEACTIVATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Signal {
+    Tone,
+    Pink,
+}
+
+enum Source {
+    Tone { phase: f64, step: f64, amplitude: f32 },
+    // Paul Kellet's refined pink filter over xorshift white noise
+    Pink { state: [f32; 7], rng: u32, gain: f32 },
+}
+
+impl Source {
+    fn next(&mut self) -> f32 {
+        match self {
+            Source::Tone { phase, step, amplitude } => {
+                let s = phase.sin() as f32 * *amplitude;
+                *phase = (*phase + *step) % TAU;
+                s
+            }
+            Source::Pink { state: b, rng, gain } => {
+                *rng ^= *rng << 13;
+                *rng ^= *rng >> 17;
+                *rng ^= *rng << 5;
+                let white = *rng as f32 / u32::MAX as f32 * 2.0 - 1.0;
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b[..6].iter().sum::<f32>() + b[6] + white * 0.5362;
+                b[6] = white * 0.115926;
+                pink * *gain
+            }
+        }
+    }
+}
+
+struct CalibrationHandler {
+    out_l: Port<AudioOut>,
+    out_r: Port<AudioOut>,
+    source: Source,
+}
+
+impl ProcessHandler for CalibrationHandler {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let out_l = self.out_l.as_mut_slice(ps);
+        let out_r = self.out_r.as_mut_slice(ps);
+        for (l, r) in out_l.iter_mut().zip(out_r.iter_mut()) {
+            // Same signal on both sides, so a level meter reads them alike
+            *l = self.source.next();
+            *r = *l;
+        }
+        Control::Continue
+    }
+}
+
+pub fn run(args: &CalibrateArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (client, _status) = Client::new("clap_to_jack", ClientOptions::NO_START_SERVER)?;
+    let sample_rate = client.sample_rate() as f64;
+
+    // dBFS as the peak of a sine; pink noise gets that sine's RMS
+    let amplitude = 10f32.powf(args.level / 20.0);
+    let source = match args.signal {
+        Signal::Tone => Source::Tone { phase: 0.0, step: TAU * args.frequency / sample_rate, amplitude },
+        Signal::Pink => Source::Pink {
+            state: [0.0; 7],
+            rng: 0x9e3779b9,
+            gain: amplitude / std::f32::consts::SQRT_2 / PINK_RMS,
+        },
+    };
+
+    let port_name = |name: &str| prefixed_port(args.port_prefix.as_deref(), name);
+    let out_l = client.register_port(&port_name("out_l"), AudioOut::default())?;
+    let out_r = client.register_port(&port_name("out_r"), AudioOut::default())?;
+    let out_names = [out_l.name()?, out_r.name()?];
+
+    let quit = shutdown::on_signal()?;
+    let active = client.activate_async((), CalibrationHandler { out_l, out_r, source })?;
+    match args.signal {
+        Signal::Tone => println!("Calibration: {} Hz sine at {} dBFS peak", args.frequency, args.level),
+        Signal::Pink => println!("Calibration: pink noise at {} dBFS RMS", args.level - 3.0),
+    }
+    connect_outputs(active.as_client(), &out_names, &args.connect_out);
+    println!("Ctrl+C to quit.");
+    while !quit.requested() {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    shutdown::with_deadline("JACK deactivation", DEACTIVATE_TIMEOUT, || active.deactivate())?;
+    Ok(())
+}
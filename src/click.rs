@@ -0,0 +1,84 @@
+// Metronome click derived from the JACK transport, so performers playing a
+// hosted instrument have a tempo reference.
+use std::f64::consts::TAU;
+
+use jack::{TransportBBT, TransportState, TransportStatePosition};
+
+// Length of one click
+const CLICK_SECONDS: f64 = 0.03;
+// Pitch of the first beat of a bar and of the other beats
+const ACCENT_HZ: f64 = 1760.0;
+const BEAT_HZ: f64 = 880.0;
+
+pub struct Click {
+    sample_rate: f64,
+    // tempo to use when no timebase master is publishing BBT
+    fallback_bpm: f64,
+    gain: f32,
+    // currently sounding click: samples left, phase increment, phase
+    remaining: usize,
+    step: f64,
+    phase: f64,
+}
+
+impl Click {
+    pub fn new(sample_rate: f64, fallback_bpm: f64, level_db: f32) -> Self {
+        Click {
+            sample_rate,
+            fallback_bpm,
+            gain: 10f32.powf(level_db / 20.0),
+            remaining: 0,
+            step: 0.0,
+            phase: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        // a click cut short rather than one pitched wrong
+        self.remaining = 0;
+    }
+
+    // Write this block's clicks into `out`, given the transport state at the block start
+    pub fn process(&mut self, transport: &TransportStatePosition, out: &mut [f32]) {
+        let rolling = matches!(transport.state, TransportState::Rolling);
+        let bbt = transport.pos.bbt();
+        let (bpm, beats_per_bar, beat, frac) = match &bbt {
+            Some(TransportBBT { bpm, sig_num, beat, tick, ticks_per_beat, .. }) if *bpm > 0.0 => {
+                (*bpm, (*sig_num as usize).max(1), beat.saturating_sub(1), *tick as f64 / ticks_per_beat)
+            }
+            // No BBT: count beats from the transport frame at the fallback tempo, in 4/4
+            _ => {
+                let beats = transport.pos.frame() as f64 * self.fallback_bpm / (60.0 * self.sample_rate);
+                (self.fallback_bpm, 4, beats.floor() as usize, beats.fract())
+            }
+        };
+        let frames_per_beat = self.sample_rate * 60.0 / bpm;
+
+        // First beat boundary at or after the start of this block
+        let (mut next, mut beat) = if frac == 0.0 {
+            (0.0, beat)
+        } else {
+            ((1.0 - frac) * frames_per_beat, beat + 1)
+        };
+
+        for (i, s) in out.iter_mut().enumerate() {
+            if rolling && (i as f64) >= next.floor() {
+                let hz = if beat % beats_per_bar == 0 { ACCENT_HZ } else { BEAT_HZ };
+                self.step = TAU * hz / self.sample_rate;
+                self.phase = 0.0;
+                self.remaining = (CLICK_SECONDS * self.sample_rate) as usize;
+                next += frames_per_beat;
+                beat += 1;
+            }
+            *s = 0.0;
+            if self.remaining > 0 {
+                // Linear decay over the click's length
+                let env = self.remaining as f64 / (CLICK_SECONDS * self.sample_rate);
+                *s = (self.phase.sin() * env) as f32 * self.gain;
+                self.phase += self.step;
+                self.remaining -= 1;
+            }
+        }
+    }
+}
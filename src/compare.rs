@@ -0,0 +1,288 @@
+// `compare`: two builds of the same plugin side by side, to help decide on an
+// upgrade. Both render the same test signal and notes offline. The report
+// covers how far their outputs differ (a null test), what changed in their
+// parameter lists, and whether the new build takes the old one's state.
+use std::ffi::CStr;
+
+use clack_extensions::params::PluginParams;
+use clack_extensions::state::PluginState;
+use clack_host::events::event_types::ParamValueEvent;
+use clack_host::events::io::{EventBuffer, InputEvents, OutputEvents};
+use clack_host::events::Pckn;
+use clack_host::prelude::*;
+use clack_host::utils::Cookie;
+
+use crate::midi::{self, NotePort};
+use crate::params::{self, Param};
+use crate::layout::Layout;
+use crate::{instantiate, process_ports, MyHost};
+
+// Residual peaks at or below this count as a null, as for the block-size test
+const TOLERANCE: f32 = 1e-6;
+
+// Test notes: one every half second, held for this much of it
+const NOTE_EVERY: f64 = 0.5;
+const NOTE_LENGTH: f64 = 0.4;
+
+pub struct CompareConfig {
+    pub sample_rate: f64,
+    pub block: u32,
+    pub frames: usize,
+}
+
+pub fn run(
+    old: &PluginBundle,
+    new: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &CompareConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "Comparing {}: {} frames at {} Hz in blocks of {}",
+        plugin_id.to_string_lossy(),
+        cfg.frames,
+        cfg.sample_rate,
+        cfg.block
+    );
+
+    println!("\nOutput");
+    let reference = render(old, plugin_id, host_info, cfg)?;
+    let upgraded = render(new, plugin_id, host_info, cfg)?;
+    if reference.len() != upgraded.len() {
+        println!(
+            "  the builds have {} and {} output channels; comparing those they share",
+            reference.len(),
+            upgraded.len()
+        );
+    }
+    let (peak, rms) = residual(&reference, &upgraded);
+    if peak <= TOLERANCE {
+        println!("  null: the outputs are the same");
+    } else {
+        println!("  residual: peak {:.1} dBFS, RMS {:.1} dBFS", db(peak), db(rms));
+        // A plugin that differs from itself can't null against anything
+        let (again, _) = residual(&reference, &render(old, plugin_id, host_info, cfg)?);
+        if again > TOLERANCE {
+            println!("  (the old build differs from itself by {:.1} dBFS peak, so some of that is noise)", db(again));
+        }
+    }
+
+    println!("\nParameters");
+    let mut old_instance = instantiate(old, plugin_id, host_info)?;
+    let mut new_instance = instantiate(new, plugin_id, host_info)?;
+    let old_params = params::list(&mut old_instance);
+    let new_params = params::list(&mut new_instance);
+    param_diff(&old_params, &new_params);
+
+    println!("\nState");
+    state_check(&mut old_instance, &mut new_instance, &old_params, &new_params);
+    Ok(())
+}
+
+// Render from a fresh instance: noise into every input, a run of notes
+// into instruments, the same every time. Returns every output channel,
+// port after port.
+fn render(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &CompareConfig,
+) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+    let mut instance = instantiate(bundle, plugin_id, host_info)?;
+    let layout = Layout::query(&mut instance);
+    let audio_cfg = PluginAudioConfiguration {
+        sample_rate: cfg.sample_rate,
+        min_frames_count: 1,
+        max_frames_count: cfg.block,
+    };
+    let mut proc = instance.activate(|_, _| (), audio_cfg)?.start_processing()?;
+
+    let block = cfg.block as usize;
+    let mut inputs = vec![vec![0.0; block]; layout.input_channels()];
+    let mut scratch = vec![vec![0.0; block]; layout.output_channels()];
+    let mut outputs: Vec<&mut [f32]> = scratch.iter_mut().map(Vec::as_mut_slice).collect();
+    let mut ports = [
+        AudioPorts::with_capacity(layout.input_channels(), layout.inputs.len()),
+        AudioPorts::with_capacity(layout.output_channels(), layout.outputs.len()),
+    ];
+    let mut rendered: Vec<Vec<f32>> = (0..layout.output_channels()).map(|_| Vec::with_capacity(cfg.frames)).collect();
+    let mut events = EventBuffer::with_capacity(8);
+    let mut out_events = EventBuffer::with_capacity(256);
+    let mut noise = 0x2545_f491_4f6c_dd1du64;
+    // at least a frame apart, whatever the sample rate
+    let every = ((NOTE_EVERY * cfg.sample_rate) as usize).max(1);
+    let length = (NOTE_LENGTH * cfg.sample_rate) as usize;
+
+    let mut pos = 0;
+    while pos < cfg.frames {
+        let n = block.min(cfg.frames - pos);
+        for s in inputs.iter_mut().flat_map(|input| &mut input[..n]) {
+            noise ^= noise << 13;
+            noise ^= noise >> 7;
+            noise ^= noise << 17;
+            // -12 dBFS peak
+            *s = ((noise >> 40) as f32 / (1u64 << 23) as f32 - 1.0) * 0.25;
+        }
+
+        // Every note that starts or ends in this block, in time order
+        events.clear();
+        let block_frames = pos..pos + n;
+        for k in pos.saturating_sub(length) / every..=(pos + n) / every {
+            let key = 48 + (k * 7 % 24) as u16;
+            let (on, off) = (k * every, k * every + length);
+            if block_frames.contains(&on) {
+                midi::note_on((on - pos) as u32, NotePort::default(), 0, key, 0.8, &mut events);
+            }
+            if block_frames.contains(&off) {
+                midi::note_off((off - pos) as u32, NotePort::default(), 0, key, 0.0, &mut events);
+            }
+        }
+
+        out_events.clear();
+        process_ports(
+            &mut proc,
+            pos as u64,
+            None,
+            &InputEvents::from_buffer(&events),
+            &mut out_events,
+            &layout,
+            &mut ports,
+            &mut inputs,
+            &mut outputs,
+            0..n,
+        )?;
+        for (channel, out) in rendered.iter_mut().zip(&outputs) {
+            channel.extend_from_slice(&out[..n]);
+        }
+        pos += n;
+    }
+
+    instance.deactivate(proc.stop_processing());
+    Ok(rendered)
+}
+
+// Peak and RMS of the difference between two renders, over the channels
+// both have
+fn residual(a: &[Vec<f32>], b: &[Vec<f32>]) -> (f32, f32) {
+    let (mut peak, mut sum, mut count) = (0.0f32, 0.0f64, 0usize);
+    for (a, b) in a.iter().zip(b) {
+        for (x, y) in a.iter().zip(b) {
+            let diff = (x - y).abs();
+            peak = peak.max(diff);
+            sum += (diff as f64).powi(2);
+            count += 1;
+        }
+    }
+    (peak, (sum / count.max(1) as f64).sqrt() as f32)
+}
+
+fn db(level: f32) -> f32 {
+    20.0 * level.max(1e-10).log10()
+}
+
+// Parameters matched by ID: those gone, those new, and those whose name,
+// range, default or steppedness changed
+fn param_diff(old: &[Param], new: &[Param]) {
+    let mut same = 0;
+    for p in old {
+        match new.iter().find(|q| q.id == p.id) {
+            None => println!("  - {} {:?}: removed", p.id.get(), p.name),
+            Some(q) => {
+                let mut changes = Vec::new();
+                if q.name != p.name {
+                    changes.push(format!("renamed to {:?}", q.name));
+                }
+                if (q.min, q.max) != (p.min, p.max) {
+                    changes.push(format!("range {}..{} -> {}..{}", p.min, p.max, q.min, q.max));
+                }
+                if q.default != p.default {
+                    changes.push(format!("default {} -> {}", p.default, q.default));
+                }
+                if q.stepped != p.stepped {
+                    changes.push(if q.stepped { "now stepped" } else { "no longer stepped" }.into());
+                }
+                if changes.is_empty() {
+                    same += 1;
+                } else {
+                    println!("  ~ {} {:?}: {}", p.id.get(), p.name, changes.join(", "));
+                }
+            }
+        }
+    }
+    for q in new.iter().filter(|q| !old.iter().any(|p| p.id == q.id)) {
+        println!("  + {} {:?}: added", q.id.get(), q.name);
+    }
+    println!("  {same} unchanged of {} old, {} new", old.len(), new.len());
+}
+
+// Move the parameters both builds have off their defaults on the old one,
+// save its state and load that into the new one, which should then read the
+// same values. At defaults on both sides, a new build that ignored the state
+// would look just like one that took it.
+fn state_check(
+    old: &mut PluginInstance<MyHost>,
+    new: &mut PluginInstance<MyHost>,
+    old_params: &[Param],
+    new_params: &[Param],
+) {
+    let mut old_handle = old.plugin_handle();
+    let mut new_handle = new.plugin_handle();
+    let (Some(old_state), Some(new_state)) =
+        (old_handle.get_extension::<PluginState>(), new_handle.get_extension::<PluginState>())
+    else {
+        println!("  not checked: both builds need clap.state");
+        return;
+    };
+    let shared: Vec<&Param> = old_params.iter().filter(|p| new_params.iter().any(|q| q.id == p.id)).collect();
+    if let Some(params) = old_handle.get_extension::<PluginParams>() {
+        let mut events = EventBuffer::with_capacity(shared.len());
+        for p in &shared {
+            events.push(&ParamValueEvent::new(0, p.id, Pckn::match_all(), off_default(p), Cookie::empty()));
+        }
+        let mut ignored = EventBuffer::new();
+        params.flush(&mut old_handle, &InputEvents::from_buffer(&events), &mut OutputEvents::from_buffer(&mut ignored));
+    }
+    let mut blob = Vec::new();
+    if let Err(e) = old_state.save(&mut old_handle, &mut blob) {
+        println!("  the old build failed to save its state: {e:?}");
+        return;
+    }
+    if let Err(e) = new_state.load(&mut new_handle, &mut blob.as_slice()) {
+        println!("  INCOMPATIBLE: the new build rejected the old build's state ({} bytes): {e:?}", blob.len());
+        return;
+    }
+
+    let (Some(old_values), Some(new_values)) =
+        (old_handle.get_extension::<PluginParams>(), new_handle.get_extension::<PluginParams>())
+    else {
+        println!("  the new build loads the old build's state");
+        return;
+    };
+    let (mut differing, mut moved) = (0, 0);
+    for p in shared {
+        let before = old_values.get_value(&mut old_handle, p.id);
+        let after = new_values.get_value(&mut new_handle, p.id);
+        if before != Some(p.default) {
+            moved += 1;
+        }
+        if before != after {
+            differing += 1;
+            let show = |v: Option<f64>| v.map_or("-".into(), |v| format!("{v:.4}"));
+            println!("  {} {:?}: {} before, {} after loading", p.id.get(), p.name, show(before), show(after));
+        }
+    }
+    match differing {
+        0 if moved == 0 => println!("  the new build loads the old build's state, but no parameter could be moved off its default to show it"),
+        0 => println!("  the new build loads the old build's state, with all {moved} parameter(s) moved off their defaults intact"),
+        n => println!("  the new build loads the old build's state, but {n} parameter(s) read differently"),
+    }
+}
+
+// A value well away from the parameter's default, on a step if it has them
+fn off_default(p: &Param) -> f64 {
+    let at = |fraction: f64| {
+        let value = p.min + (p.max - p.min) * fraction;
+        if p.stepped { value.round() } else { value }
+    };
+    [0.3, 0.7, 0.0, 1.0].into_iter().map(at).find(|v| *v != p.default).unwrap_or(p.default)
+}
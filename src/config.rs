@@ -0,0 +1,295 @@
+// Defaults for the command line from a TOML config file and the environment,
+// so long-running setups don't need a wall of flags. Every flag can be set in
+// three places, and the first one found wins: the command line, then the
+// environment, then the config file, then the built-in default.
+//
+// Config keys are flag names without the leading `--`. Top-level keys apply
+// to every command that has that flag; a [run], [soak], [diag]... section
+// applies to one command only and wins over the top level:
+//
+//     plugin-id = "in.lsp-plug.noise_generator_x1"
+//     connect-out = ["@default-sink"]
+//
+//     [soak]
+//     hours = 1.5
+//
+// Named profiles hold the same keys and sections, for one file to serve
+// several setups. `--profile live` (or JACK_MINIMAL_CLAP_PROFILE=live) puts
+// [profile.live] over the rest of the file, sections and all:
+//
+//     [profile.live]
+//     max-frames = 256
+//     connect-out = ["system:playback_1", "system:playback_2"]
+//
+//     [profile.live.soak]
+//     hours = 0.5
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, Command, CommandFactory, FromArgMatches};
+use toml::{Table, Value};
+
+pub const PRECEDENCE: &str = "\
+Every flag can also be set as JACK_MINIMAL_CLAP_<FLAG> in the environment \
+(e.g. JACK_MINIMAL_CLAP_SAMPLE_RATE=44100) or in the config file \
+(e.g. sample-rate = 44100, or under [soak] for just that command). \
+The command line wins over the environment, which wins over the config file; \
+in the file, the --profile chosen wins over the rest.";
+
+const ENV_PREFIX: &str = "JACK_MINIMAL_CLAP_";
+
+// Section name for the root command, which runs the plugin like `run` does
+const ROOT_SECTION: &str = "run";
+
+// Table holding the named profiles: [profile.live], [profile.studio]...
+const PROFILES: &str = "profile";
+
+// Parse the command line with config file and environment defaults applied
+pub fn parse<T: CommandFactory + FromArgMatches>() -> Result<T, Box<dyn std::error::Error>> {
+    let argv: Vec<OsString> = std::env::args_os().collect();
+    let mut table = match config_path(&argv) {
+        Some(path) => load(&path)?,
+        None => Table::new(),
+    };
+    let profiles = match table.remove(PROFILES) {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err(format!("config: {PROFILES} should hold tables like [{PROFILES}.live]").into()),
+        None => Table::new(),
+    };
+    validate(&T::command(), &table)?;
+    for (name, profile) in &profiles {
+        let profile = profile.as_table().ok_or_else(|| format!("config: {PROFILES}.{name} should be a table"))?;
+        validate(&T::command(), profile).map_err(|e| format!("{e} (in [{PROFILES}.{name}])"))?;
+    }
+    let profile = profile_name(&argv).map(|name| select(&profiles, &name)).transpose()?;
+    // Where to look for a default, first to last
+    let layers: Vec<&Table> = profile.into_iter().chain([&table]).collect();
+
+    let mut cmd = with_defaults(T::command(), &layers, ROOT_SECTION)
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("Config file to read defaults from (default: ~/.config/jack_minimal_clap/config.toml)"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("NAME")
+                .global(true)
+                .help("Profile in the config file to use, from its [profile.NAME] table"),
+        );
+    let names: Vec<String> = cmd.get_subcommands().map(|sub| sub.get_name().to_string()).collect();
+    for name in names {
+        cmd = cmd.mut_subcommand(&name, |sub| with_defaults(sub, &layers, &name));
+    }
+
+    let matches = cmd.get_matches_from(argv);
+    Ok(T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+// A flag's value from the command line, as `--flag VALUE` or `--flag=VALUE`.
+// Found by hand for the flags that say how to read the config, which has to
+// happen before clap can parse.
+fn flag_value(argv: &[OsString], flag: &str) -> Option<OsString> {
+    let mut args = argv.iter().skip(1).take_while(|a| *a != "--");
+    let long = format!("--{flag}");
+    while let Some(arg) = args.next() {
+        if *arg == *long {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.to_str().and_then(|a| a.strip_prefix(&long)).and_then(|a| a.strip_prefix('=')) {
+            return Some(value.into());
+        }
+    }
+    None
+}
+
+// --config, or $JACK_MINIMAL_CLAP_CONFIG, or the XDG default if it exists
+fn config_path(argv: &[OsString]) -> Option<PathBuf> {
+    if let Some(path) = flag_value(argv, "config") {
+        return Some(path.into());
+    }
+    if let Some(path) = std::env::var_os(format!("{ENV_PREFIX}CONFIG")) {
+        return Some(path.into());
+    }
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("jack_minimal_clap").join("config.toml")).filter(|path| path.exists())
+}
+
+// --profile, or $JACK_MINIMAL_CLAP_PROFILE
+fn profile_name(argv: &[OsString]) -> Option<String> {
+    flag_value(argv, "profile")
+        .or_else(|| std::env::var_os(format!("{ENV_PREFIX}PROFILE")))
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+fn select<'a>(profiles: &'a Table, name: &str) -> Result<&'a Table, String> {
+    match profiles.get(name).and_then(Value::as_table) {
+        Some(profile) => Ok(profile),
+        None if profiles.is_empty() => Err(format!("--profile {name}: the config file has no [{PROFILES}.NAME] tables")),
+        None => {
+            let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            Err(format!("--profile {name}: no such profile (the config has {})", names.join(", ")))
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<Table, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Can't read config {}: {e}", path.display()))?;
+    text.parse()
+        .map_err(|e| format!("Bad config {}: {e}", path.display()))
+}
+
+// Typos in the config should fail loudly, not be silently ignored
+fn validate(root: &Command, table: &Table) -> Result<(), String> {
+    let has_flag = |cmd: &Command, key: &str| cmd.get_arguments().any(|a| a.get_long() == Some(key));
+    for (key, value) in table {
+        match value {
+            Value::Table(section) => {
+                let cmd = if key == ROOT_SECTION { Some(root) } else { root.find_subcommand(key) };
+                let cmd = cmd.ok_or_else(|| format!("config: no command called [{key}]"))?;
+                if let Some(bad) = section.keys().find(|k| !has_flag(cmd, k)) {
+                    return Err(format!("config: [{key}] has no option {bad:?}"));
+                }
+            }
+            _ => {
+                let known = has_flag(root, key) || root.get_subcommands().any(|sub| has_flag(sub, key));
+                if !known {
+                    return Err(format!("config: no command has an option {key:?}"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Give each of the command's flags its environment variable and, when the
+// config has a value for it, a new default. Each layer of the config is
+// checked in turn, its command section before its top level.
+fn with_defaults(cmd: Command, layers: &[&Table], section: &str) -> Command {
+    cmd.mut_args(|arg| {
+        let Some(long) = arg.get_long().map(str::to_owned) else { return arg };
+        let arg = arg.env(format!("{ENV_PREFIX}{}", long.to_uppercase().replace('-', "_")));
+        let value = layers.iter().find_map(|table| {
+            table
+                .get(section)
+                .and_then(Value::as_table)
+                .and_then(|s| s.get(&long))
+                .or_else(|| table.get(&long).filter(|v| !v.is_table()))
+        });
+        match value {
+            Some(value) => arg.default_values(config_values(value)),
+            None => arg,
+        }
+    })
+}
+
+// TOML value as the strings clap would have got on the command line
+fn config_values(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(items) => items.iter().flat_map(config_values).collect(),
+        other => vec![other.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flags named so no JACK_MINIMAL_CLAP_ variable in the environment
+    // reaches them
+    fn command() -> Command {
+        let flag = |name: &'static str, default: &'static str| Arg::new(name).long(name).default_value(default);
+        Command::new("test")
+            .arg(flag("test-frames", "1"))
+            .subcommand(Command::new("soak").arg(flag("test-frames", "1")).arg(flag("test-hours", "8")))
+    }
+
+    fn table(text: &str) -> Table {
+        text.parse().unwrap()
+    }
+
+    // What each command's flag comes out as, given the layers and argv
+    fn value(layers: &[&Table], args: &[&str], sub: Option<&str>, flag: &str) -> String {
+        let mut cmd = with_defaults(command(), layers, ROOT_SECTION);
+        cmd = cmd.mut_subcommand("soak", |sub| with_defaults(sub, layers, "soak"));
+        let matches = cmd.try_get_matches_from(["test"].iter().chain(args)).unwrap();
+        let matches = match sub {
+            Some(sub) => matches.subcommand_matches(sub).unwrap().clone(),
+            None => matches,
+        };
+        matches.get_one::<String>(flag).unwrap().clone()
+    }
+
+    #[test]
+    fn built_in_defaults_without_config() {
+        assert_eq!(value(&[], &[], None, "test-frames"), "1");
+        assert_eq!(value(&[], &["soak"], Some("soak"), "test-hours"), "8");
+    }
+
+    #[test]
+    fn sections_win_over_the_top_level() {
+        let file = table("test-frames = 2\n[run]\ntest-frames = 3\n[soak]\ntest-hours = 1.5\n");
+        assert_eq!(value(&[&file], &[], None, "test-frames"), "3");
+        // soak has no section value for it, so the top level applies
+        assert_eq!(value(&[&file], &["soak"], Some("soak"), "test-frames"), "2");
+        assert_eq!(value(&[&file], &["soak"], Some("soak"), "test-hours"), "1.5");
+    }
+
+    #[test]
+    fn profile_wins_over_the_file() {
+        let file = table("test-frames = 2\n[run]\ntest-frames = 3\n[soak]\ntest-hours = 1.5\n");
+        let profile = table("test-frames = 4\n[soak]\ntest-hours = 0.5\n");
+        let layers = [&profile, &file];
+        // even the profile's top level beats the file's [run]
+        assert_eq!(value(&layers, &[], None, "test-frames"), "4");
+        assert_eq!(value(&layers, &["soak"], Some("soak"), "test-frames"), "4");
+        assert_eq!(value(&layers, &["soak"], Some("soak"), "test-hours"), "0.5");
+    }
+
+    #[test]
+    fn command_line_wins_over_config() {
+        let file = table("[run]\ntest-frames = 3\n");
+        assert_eq!(value(&[&file], &["--test-frames", "5"], None, "test-frames"), "5");
+    }
+
+    #[test]
+    fn arrays_are_several_values() {
+        assert_eq!(config_values(&table("a = [\"x\", 2, true]")["a"]), ["x", "2", "true"]);
+    }
+
+    #[test]
+    fn validate_rejects_unknown_keys() {
+        let cmd = command();
+        assert!(validate(&cmd, &table("test-hours = 1\n[run]\ntest-frames = 2\n")).is_ok());
+        assert!(validate(&cmd, &table("test-frame = 2\n")).is_err());
+        assert!(validate(&cmd, &table("[run]\ntest-hours = 1\n")).is_err());
+        assert!(validate(&cmd, &table("[diag]\ntest-frames = 1\n")).is_err());
+    }
+
+    #[test]
+    fn flag_value_forms() {
+        let argv = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(flag_value(&argv(&["x", "--profile=live"]), "profile"), Some("live".into()));
+        assert_eq!(flag_value(&argv(&["x", "soak", "--profile", "live"]), "profile"), Some("live".into()));
+        assert_eq!(flag_value(&argv(&["x", "--profiles=live"]), "profile"), None);
+        assert_eq!(flag_value(&argv(&["x", "--", "--profile", "live"]), "profile"), None);
+        // argv[0] is the program, however it's named
+        assert_eq!(flag_value(&argv(&["--profile=live"]), "profile"), None);
+    }
+
+    #[test]
+    fn select_names_the_profiles() {
+        let profiles = table("[live]\ntest-frames = 4\n[studio]\n");
+        assert!(select(&profiles, "live").is_ok());
+        let err = select(&profiles, "stage").unwrap_err();
+        assert!(err.contains("live, studio"), "{err}");
+        assert!(select(&Table::new(), "live").unwrap_err().contains("no [profile.NAME]"));
+    }
+}
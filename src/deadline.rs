@@ -0,0 +1,44 @@
+// Per-block deadline margin: how much of each JACK period was left after the
+// plugin returned. Printed as a histogram on exit, to pick the smallest safe
+// buffer size empirically.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// 10% wide buckets of remaining time, plus one for overruns
+const BUCKETS: usize = 10;
+
+#[derive(Default)]
+pub struct DeadlineStats {
+    // [0] = overrun, [1 + i] = i*10%..(i+1)*10% of the period left
+    counts: [AtomicU64; BUCKETS + 1],
+}
+
+impl DeadlineStats {
+    // Called from the audio thread with the fraction of the period still left
+    pub fn record(&self, margin: f32) {
+        let bucket = if margin < 0.0 {
+            0
+        } else {
+            1 + ((margin * BUCKETS as f32) as usize).min(BUCKETS - 1)
+        };
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn print(&self) {
+        let counts: Vec<u64> = self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return;
+        }
+        let widest = counts.iter().copied().max().unwrap_or(1).max(1);
+        println!("Deadline margin after the plugin returned ({total} blocks):");
+        for (i, &count) in counts.iter().enumerate().rev() {
+            let label = if i == 0 {
+                "overrun".to_string()
+            } else {
+                format!("{}-{}%", (i - 1) * 10, i * 10)
+            };
+            let bar = "#".repeat((count * 40 / widest) as usize);
+            println!("  {label:>8} {bar:<40} {count}");
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// `diag`: gather everything a bug report against the host needs (JACK server,
+// plugin descriptor, extension matrix, environment) into one text or JSON blob.
+use std::path::Path;
+
+use clack_extensions::{
+    audio_ports::PluginAudioPorts, gui::PluginGui, latency::PluginLatency,
+    note_ports::PluginNotePorts, params::PluginParams, render::PluginRender,
+    state::PluginState, tail::PluginTail, timer::PluginTimer,
+};
+use clack_host::prelude::*;
+use jack::{Client, ClientOptions, PortFlags};
+
+use crate::{host_info, instantiate, unknown_plugin_id};
+
+// Environment variables that commonly change how the host or plugins behave
+const ENV_VARS: &[&str] = &["CLAP_PATH", "JACK_DEFAULT_SERVER", "PIPEWIRE_LATENCY", "LC_ALL", "LANG"];
+
+// One report section: a title and ordered key/value lines
+type Section = (&'static str, Vec<(String, String)>);
+
+pub fn run(bundle_path: &Path, plugin_id: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let sections = vec![
+        host_section(),
+        jack_section(),
+        plugin_section(bundle_path, plugin_id),
+    ];
+    if json {
+        println!("{}", to_json(&sections));
+    } else {
+        for (title, lines) in &sections {
+            println!("[{title}]");
+            for (key, value) in lines {
+                println!("{key}: {value}");
+            }
+            println!();
+        }
+    }
+    Ok(())
+}
+
+fn host_section() -> Section {
+    let mut lines = vec![
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("os".to_string(), format!("{} {}", std::env::consts::OS, std::env::consts::ARCH)),
+    ];
+    for var in ENV_VARS {
+        let value = std::env::var(var).unwrap_or_else(|_| "(unset)".into());
+        lines.push((format!("env.{var}"), value));
+    }
+    ("host", lines)
+}
+
+fn jack_section() -> Section {
+    let client = match Client::new("clap_to_jack_diag", ClientOptions::NO_START_SERVER) {
+        Ok((client, _status)) => client,
+        Err(e) => return ("jack", vec![("status".into(), format!("unavailable: {e}"))]),
+    };
+    let physical = |flags: PortFlags| client.ports(None, None, PortFlags::IS_PHYSICAL | flags).join(", ");
+    ("jack", vec![
+        ("status".into(), "running".into()),
+        ("sample_rate".into(), client.sample_rate().to_string()),
+        ("buffer_size".into(), client.buffer_size().to_string()),
+        ("cpu_load".into(), format!("{:.1}%", client.cpu_load())),
+        ("physical_outputs".into(), physical(PortFlags::IS_OUTPUT)),
+        ("physical_inputs".into(), physical(PortFlags::IS_INPUT)),
+    ])
+}
+
+fn plugin_section(bundle_path: &Path, plugin_id: &str) -> Section {
+    let mut lines = vec![("bundle".to_string(), bundle_path.display().to_string())];
+    if let Err(e) = describe_plugin(bundle_path, plugin_id, &mut lines) {
+        lines.push(("error".into(), e.to_string()));
+    }
+    ("plugin", lines)
+}
+
+fn describe_plugin(
+    bundle_path: &Path,
+    plugin_id: &str,
+    lines: &mut Vec<(String, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bundle = unsafe { PluginBundle::load(bundle_path) }
+        .map_err(|e| format!("failed to load bundle: {e:?}"))?;
+    let factory = bundle.get_plugin_factory().ok_or("bundle has no plugin factory")?;
+    let desc = factory
+        .plugin_descriptors()
+        .find(|d| d.id().is_some_and(|id| id.to_bytes() == plugin_id.as_bytes()))
+        .ok_or_else(|| unknown_plugin_id(plugin_id, factory.plugin_descriptors().filter_map(|d| d.id())))?;
+
+    let text = |s: Option<&std::ffi::CStr>| s.map_or("-".into(), |s| s.to_string_lossy().into_owned());
+    lines.push(("id".into(), plugin_id.into()));
+    lines.push(("name".into(), text(desc.name())));
+    lines.push(("vendor".into(), text(desc.vendor())));
+    lines.push(("version".into(), text(desc.version())));
+    lines.push(("url".into(), text(desc.url())));
+    lines.push(("description".into(), text(desc.description())));
+    let features: Vec<_> = desc.features().map(|f| f.to_string_lossy().into_owned()).collect();
+    lines.push(("features".into(), features.join(", ")));
+
+    // Which extensions does the plugin actually expose?
+    let mut instance = instantiate(&bundle, desc.id().expect("matched on id"), &host_info()?)?;
+    let handle = instance.plugin_handle();
+    let matrix = [
+        ("clap.audio-ports", handle.get_extension::<PluginAudioPorts>().is_some()),
+        ("clap.note-ports", handle.get_extension::<PluginNotePorts>().is_some()),
+        ("clap.params", handle.get_extension::<PluginParams>().is_some()),
+        ("clap.state", handle.get_extension::<PluginState>().is_some()),
+        ("clap.latency", handle.get_extension::<PluginLatency>().is_some()),
+        ("clap.tail", handle.get_extension::<PluginTail>().is_some()),
+        ("clap.gui", handle.get_extension::<PluginGui>().is_some()),
+        ("clap.render", handle.get_extension::<PluginRender>().is_some()),
+        ("clap.timer-support", handle.get_extension::<PluginTimer>().is_some()),
+    ];
+    for (ext, supported) in matrix {
+        lines.push((format!("ext.{ext}"), if supported { "yes" } else { "no" }.into()));
+    }
+    Ok(())
+}
+
+fn to_json(sections: &[Section]) -> String {
+    let body: Vec<String> = sections
+        .iter()
+        .map(|(title, lines)| {
+            let fields: Vec<String> = lines
+                .iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+                .collect();
+            format!("{}:{{{}}}", json_string(title), fields.join(","))
+        })
+        .collect();
+    format!("{{{}}}", body.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,105 @@
+// Whether the plugin's settings have changed since they were last loaded or
+// saved, so a preset switch or exit doesn't lose them unannounced. The plugin
+// tells us with clap.state's mark_dirty; parameters it moves itself (from
+// its GUI, say) come out of process() as events; those the console, OSC and
+// --map'd controllers move go in as events. Each bumps a count, and what was
+// last saved or loaded remembers the count it saw.
+//
+// With --recovery-file, changes are also saved there every so often, for
+// --load-state to bring back after a crash. A clean exit that saves them to
+// --save-state removes it again.
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use clack_host::prelude::*;
+
+use crate::state::{self, Identity};
+use crate::MyHost;
+
+// Longest a change goes without reaching the recovery file
+const RECOVERY_EVERY: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Edits {
+    count: AtomicU64,
+    clean: AtomicU64,
+}
+
+impl Edits {
+    // Safe on the audio thread
+    pub fn mark(&self) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dirty(&self) -> bool {
+        self.count.load(Ordering::Relaxed) != self.clean.load(Ordering::Relaxed)
+    }
+
+    // The settings were just saved, or replaced by a load
+    pub fn saved(&self) {
+        self.clean.store(self.count.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+pub struct Recovery {
+    path: PathBuf,
+    // the edit count the file was last written at
+    written: u64,
+    next: Instant,
+}
+
+impl Recovery {
+    pub fn new(path: PathBuf) -> Self {
+        if path.exists() {
+            println!(
+                "{} holds unsaved changes from an earlier run; --load-state it to get them back",
+                path.display()
+            );
+        }
+        Recovery { path, written: 0, next: Instant::now() }
+    }
+
+    // From the main loop: save unsaved changes that aren't in the file yet
+    pub fn poll(&mut self, instance: &mut PluginInstance<MyHost>, edits: &Edits, plugin: &Identity) {
+        let count = edits.count.load(Ordering::Relaxed);
+        if edits.dirty() && count != self.written && Instant::now() >= self.next {
+            self.next = Instant::now() + RECOVERY_EVERY;
+            self.save(instance, count, plugin);
+        }
+    }
+
+    // On exit. `kept` if --save-state has them, when the file can go.
+    pub fn finish(mut self, instance: &mut PluginInstance<MyHost>, edits: &Edits, plugin: &Identity, kept: bool) {
+        if kept {
+            if let Err(e) = std::fs::remove_file(&self.path).or_else(not_found) {
+                eprintln!("--recovery-file: can't remove {}: {e}", self.path.display());
+            }
+        } else if edits.dirty() {
+            let count = edits.count.load(Ordering::Relaxed);
+            if self.save(instance, count, plugin) {
+                println!("Unsaved changes are in {}", self.path.display());
+            }
+        }
+    }
+
+    fn save(&mut self, instance: &mut PluginInstance<MyHost>, count: u64, plugin: &Identity) -> bool {
+        match state::save(instance, &self.path, plugin) {
+            Ok(()) => {
+                self.written = count;
+                true
+            }
+            Err(e) => {
+                eprintln!("--recovery-file: {e}");
+                false
+            }
+        }
+    }
+}
+
+fn not_found(e: std::io::Error) -> std::io::Result<()> {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
@@ -0,0 +1,83 @@
+// Backpressure: every queue between the control side (console, OSC, MIDI
+// devices) and the audio thread is bounded, and whatever finds one full is
+// dropped and counted here, by what it was, rather than growing the queue or
+// blocking the audio thread. The main loop reports new drops as they happen
+// and `status` shows the totals. Note-offs get room kept back for them in
+// each queue, so a flood of other events can't leave notes hanging.
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    // OSC messages that found the command queue full
+    Command,
+    Param,
+    Note,
+    // note-offs, all notes off and all sound off
+    NoteOff,
+    // other MIDI from the ports or the raw device
+    Midi,
+}
+
+const KINDS: [(Kind, &str); 5] = [
+    (Kind::Command, "OSC command"),
+    (Kind::Param, "parameter change"),
+    (Kind::Note, "note-on"),
+    (Kind::NoteOff, "note-off"),
+    (Kind::Midi, "other MIDI message"),
+];
+
+#[derive(Default)]
+pub struct Dropped([AtomicU64; KINDS.len()]);
+
+impl Dropped {
+    pub fn count(&self, kind: Kind) {
+        self.0[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The counts so far, in KINDS order
+    pub fn counts(&self) -> [u64; KINDS.len()] {
+        std::array::from_fn(|i| self.0[i].load(Ordering::Relaxed))
+    }
+}
+
+// "3 note-ons, 1 note-off" for what's non-zero in the counts, or None if
+// nothing is
+pub fn describe(counts: &[u64; KINDS.len()]) -> Option<String> {
+    let parts: Vec<String> = KINDS
+        .iter()
+        .zip(counts)
+        .filter(|(_, n)| **n > 0)
+        .map(|((_, name), n)| format!("{n} {name}{}", if *n == 1 { "" } else { "s" }))
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+// Which kind a short MIDI message is, when it has to be dropped
+pub fn kind_of(bytes: &[u8]) -> Kind {
+    match bytes {
+        [status, _, 0] if status & 0xf0 == 0x90 => Kind::NoteOff,
+        [status, ..] if status & 0xf0 == 0x80 => Kind::NoteOff,
+        [status, 120 | 123, _] if status & 0xf0 == 0xb0 => Kind::NoteOff,
+        [status, ..] if status & 0xf0 == 0x90 => Kind::Note,
+        _ => Kind::Midi,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_by_kind() {
+        let dropped = Dropped::default();
+        assert_eq!(describe(&dropped.counts()), None);
+        let messages =
+            [&[0x90, 60, 100][..], &[0x91, 60, 100], &[0x80, 60, 0], &[0x90, 61, 0], &[0xb0, 123, 0], &[0xb0, 7, 1]];
+        for bytes in messages {
+            dropped.count(kind_of(bytes));
+        }
+        dropped.count(Kind::Command);
+        let counted = describe(&dropped.counts()).unwrap();
+        assert_eq!(counted, "1 OSC command, 2 note-ons, 3 note-offs, 1 other MIDI message");
+    }
+}
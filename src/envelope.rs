@@ -0,0 +1,127 @@
+// --envelope-follow: the level of one of our audio inputs, with its own
+// attack and release, as a control source. It can drive a parameter of the
+// hosted plugin (--envelope-param), for ducking or audio-reactive sounds
+// with plugins that have no sidechain, and go out over OSC (--envelope-osc)
+// for anything else to react to.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use clack_host::utils::ClapId;
+
+// How far the level has to move, as a share of the parameter's range, before
+// the parameter gets an event for it
+const PARAM_STEP: f64 = 0.001;
+
+// The level as f32 bits, 0 for silence to 1 at full scale, for the main thread
+#[derive(Default)]
+pub struct Level(AtomicU32);
+
+impl Level {
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+// A parameter driven over its range by the level
+#[derive(Clone, Copy)]
+pub struct Target {
+    pub id: ClapId,
+    pub min: f64,
+    pub max: f64,
+}
+
+pub struct Follower {
+    // which of the plugin's input channels it follows
+    channel: usize,
+    attack_seconds: f64,
+    release_seconds: f64,
+    attack: f32,
+    release: f32,
+    level: f32,
+    shared: Arc<Level>,
+    target: Option<Target>,
+    // the value last sent to the target
+    sent: Option<f64>,
+}
+
+impl Follower {
+    pub fn new(
+        channel: usize,
+        sample_rate: f64,
+        attack_seconds: f64,
+        release_seconds: f64,
+        shared: Arc<Level>,
+        target: Option<Target>,
+    ) -> Self {
+        let mut follower = Follower {
+            channel,
+            attack_seconds,
+            release_seconds,
+            attack: 0.0,
+            release: 0.0,
+            level: 0.0,
+            shared,
+            target,
+            sent: None,
+        };
+        follower.set_sample_rate(sample_rate);
+        follower
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        let coeff = |seconds: f64| (1.0 - (-1.0 / (seconds.max(1e-4) * sample_rate)).exp()) as f32;
+        self.attack = coeff(self.attack_seconds);
+        self.release = coeff(self.release_seconds);
+    }
+
+    // One block of the plugin's input channels
+    pub fn process(&mut self, inputs: &[Vec<f32>]) {
+        let Some(input) = inputs.get(self.channel) else { return };
+        for &sample in input {
+            let x = sample.abs().min(1.0);
+            let coeff = if x > self.level { self.attack } else { self.release };
+            self.level += (x - self.level) * coeff;
+        }
+        self.shared.0.store(self.level.to_bits(), Ordering::Relaxed);
+    }
+
+    // The target's new value, if the level has moved it far enough to send
+    pub fn param_change(&mut self) -> Option<(ClapId, f64)> {
+        let target = self.target?;
+        let value = target.min + self.level as f64 * (target.max - target.min);
+        if self.sent.is_some_and(|sent| (value - sent).abs() < PARAM_STEP * (target.max - target.min).abs()) {
+            return None;
+        }
+        self.sent = Some(value);
+        Some((target.id, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    #[test]
+    fn rises_with_the_attack_and_falls_with_the_release() {
+        let shared = Arc::new(Level::default());
+        let target = Target { id: ClapId::new(3), min: 100.0, max: 200.0 };
+        let mut follower = Follower::new(1, RATE, 0.001, 0.1, shared.clone(), Some(target));
+        let block = |value: f32, frames: usize| vec![vec![0.0; frames], vec![value; frames]];
+
+        // 10 ms of full scale is ten attack time constants
+        follower.process(&block(-1.0, 480));
+        assert!(shared.get() > 0.99);
+        let (_, value) = follower.param_change().unwrap();
+        assert!(value > 199.0);
+        assert!(follower.param_change().is_none());
+
+        // 10 ms of silence is a tenth of the release's
+        follower.process(&block(0.0, 480));
+        assert!((shared.get() - (-0.1f32).exp()).abs() < 0.01);
+        follower.process(&block(0.0, 48000));
+        assert!(shared.get() < 0.001);
+        assert!(follower.param_change().unwrap().1 < 100.1);
+    }
+}
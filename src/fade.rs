@@ -0,0 +1,82 @@
+// `fade in|out SECONDS`: the whole output ramped down to silence or back up
+// to full level, for --at schedules and for ending a set by hand. It is the
+// last gain on the way out, after mute and solo, and stays where it ends: a
+// faded-out host keeps running, silent, until something fades it back in.
+pub struct Fade {
+    gain: f32,
+    target: f32,
+    // gain change per frame until it gets there
+    step: f32,
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Fade { gain: 1.0, target: 1.0, step: 0.0 }
+    }
+}
+
+impl Fade {
+    // From wherever the gain is now, so a fade in during a fade out turns back
+    pub fn start(&mut self, target: f32, seconds: f32, sample_rate: f64) {
+        let frames = (seconds as f64 * sample_rate).max(1.0);
+        self.target = target.clamp(0.0, 1.0);
+        self.step = ((self.target - self.gain).abs() as f64 / frames) as f32;
+    }
+
+    // The same `frames` of the ramp on each output, then advance() once
+    pub fn apply(&self, buf: &mut [f32]) {
+        let mut gain = self.gain;
+        match gain == self.target {
+            true if gain == 1.0 => {}
+            true => buf.iter_mut().for_each(|s| *s *= gain),
+            false => {
+                for sample in buf.iter_mut() {
+                    gain = self.toward(gain, 1);
+                    *sample *= gain;
+                }
+            }
+        }
+    }
+
+    pub fn advance(&mut self, frames: usize) {
+        self.gain = self.toward(self.gain, frames);
+    }
+
+    fn toward(&self, gain: f32, frames: usize) -> f32 {
+        let step = self.step * frames as f32;
+        match self.target > gain {
+            true => (gain + step).min(self.target),
+            false => (gain - step).max(self.target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ramps_every_output_alike_and_stays_down() {
+        let mut fade = Fade::default();
+        fade.start(0.0, 1.0, 100.0);
+        let (mut left, mut right) = (vec![1.0; 50], vec![-1.0; 50]);
+        fade.apply(&mut left);
+        fade.apply(&mut right);
+        fade.advance(50);
+        assert!((left[49] - 0.5).abs() < 1e-4);
+        assert_eq!(left[49], -right[49]);
+
+        // turning back halfway takes as long again to get up from there
+        fade.start(1.0, 1.0, 100.0);
+        let mut block = vec![1.0; 100];
+        fade.apply(&mut block);
+        fade.advance(100);
+        assert!((block[49] - 0.75).abs() < 1e-4 && (block[99] - 1.0).abs() < 1e-4);
+
+        fade.start(0.0, 0.0, 100.0);
+        fade.advance(1);
+        let mut silent = vec![1.0; 10];
+        fade.apply(&mut silent);
+        assert!(silent.iter().all(|s| *s == 0.0));
+    }
+}
@@ -0,0 +1,304 @@
+// Loudness gate for streaming installations: when the K-weighted (BS.1770)
+// loudness of the output stays below a threshold for a while, fade to
+// silence; fade back in as soon as there is signal again. Stops a stuck
+// generator from sending noise-floor hiss to a broadcast chain all night.
+//
+// The same measurement also levels preset switches: the loudness just before
+// one becomes the target for what follows, and the compensating gain that
+// takes then fades back to unity.
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Time constant of the loudness measurement, about the BS.1770 momentary window
+const MEASURE_SECONDS: f64 = 0.4;
+
+// How fast gain matching hears a louder preset come in, so it isn't blasted
+// out for the length of the measurement window first
+const MATCH_ATTACK_SECONDS: f64 = 0.01;
+
+// Most gain matching will take off or add, in dB
+const MATCH_MAX_CUT: f64 = 24.0;
+const MATCH_MAX_BOOST: f64 = 12.0;
+
+// Below this (mean square, about -70 LUFS) there's nothing to match: a
+// preset that starts silent isn't boosted, nor one that follows silence cut
+const MATCH_SILENCE: f64 = 1e-7;
+
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    // RBJ cookbook coefficients, normalised by a0
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+        }
+    }
+
+    fn high_shelf(sample_rate: f64, f0: f64, q: f64, gain_db: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * f0 / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let sqrt_a = a.sqrt();
+        Biquad::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + 2.0 * sqrt_a * alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - 2.0 * sqrt_a * alpha),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + 2.0 * sqrt_a * alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - 2.0 * sqrt_a * alpha,
+            ],
+        )
+    }
+
+    fn high_pass(sample_rate: f64, f0: f64, q: f64) -> Self {
+        let w0 = 2.0 * PI * f0 / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    // Transposed direct form II
+    fn run(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// BS.1770 K-weighting: a +4 dB high shelf followed by the RLB high-pass
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    [
+        Biquad::high_shelf(sample_rate, 1681.974450955533, 0.7071752369554196, 4.0),
+        Biquad::high_pass(sample_rate, 38.13547087602444, 0.5003270373238773),
+    ]
+}
+
+pub struct LoudnessGate {
+    filters: [[Biquad; 2]; 2],
+    // smoothed K-weighted mean square, summed over channels
+    mean_square: f64,
+    smoothing: f64,
+    threshold: f64,
+    quiet_frames: u64,
+    timeout_frames: u64,
+    gain: f32,
+    fade_step: f32,
+    // kept to retune for a new sample rate
+    timeout_seconds: f64,
+    fade_seconds: f64,
+}
+
+impl LoudnessGate {
+    pub fn new(sample_rate: f64, threshold_lufs: f64, timeout_seconds: f64, fade_seconds: f64) -> Self {
+        let k = k_weighting(sample_rate);
+        let mut gate = LoudnessGate {
+            filters: [k, k],
+            mean_square: 0.0,
+            smoothing: 0.0,
+            // LUFS = -0.691 + 10 log10(mean square)
+            threshold: 10f64.powf((threshold_lufs + 0.691) / 10.0),
+            quiet_frames: 0,
+            timeout_frames: 0,
+            gain: 1.0,
+            fade_step: 0.0,
+            timeout_seconds,
+            fade_seconds,
+        };
+        gate.set_sample_rate(sample_rate);
+        gate
+    }
+
+    // For output at another rate. The filters start again from silence,
+    // but the measured loudness and how long it's been quiet carry over.
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        let k = k_weighting(sample_rate);
+        self.filters = [k, k];
+        self.smoothing = 1.0 - (-1.0 / (MEASURE_SECONDS * sample_rate)).exp();
+        self.timeout_frames = (self.timeout_seconds * sample_rate) as u64;
+        self.fade_step = (1.0 / (self.fade_seconds.max(0.001) * sample_rate)) as f32;
+    }
+
+    pub fn process(&mut self, l: &mut [f32], r: &mut [f32]) {
+        for (l, r) in l.iter_mut().zip(r.iter_mut()) {
+            let [fl, fr] = &mut self.filters;
+            let kl = fl.iter_mut().fold(*l as f64, |x, f| f.run(x));
+            let kr = fr.iter_mut().fold(*r as f64, |x, f| f.run(x));
+            self.mean_square += (kl * kl + kr * kr - self.mean_square) * self.smoothing;
+
+            if self.mean_square < self.threshold {
+                self.quiet_frames += 1;
+            } else {
+                self.quiet_frames = 0;
+            }
+            if self.quiet_frames >= self.timeout_frames {
+                self.gain = (self.gain - self.fade_step).max(0.0);
+            } else {
+                self.gain = (self.gain + self.fade_step).min(1.0);
+            }
+
+            *l *= self.gain;
+            *r *= self.gain;
+        }
+    }
+}
+
+// --preset-gain-match: holds the output at the loudness it had before a
+// preset switch, then lets go over `decay_seconds`
+pub struct GainMatch {
+    filters: [[Biquad; 2]; 2],
+    mean_square: f64,
+    attack: f64,
+    release: f64,
+    // set by the main thread once it has loaded a preset
+    switched: Arc<AtomicBool>,
+    // loudness before the last switch, and how much of the difference to
+    // it we still make up (1 just after, 0 once decayed)
+    reference: f64,
+    hold: f64,
+    decay_step: f64,
+    decay_seconds: f64,
+}
+
+impl GainMatch {
+    pub fn new(sample_rate: f64, decay_seconds: f64, switched: Arc<AtomicBool>) -> Self {
+        let k = k_weighting(sample_rate);
+        let mut gain_match = GainMatch {
+            filters: [k, k],
+            mean_square: 0.0,
+            attack: 0.0,
+            release: 0.0,
+            switched,
+            reference: 0.0,
+            hold: 0.0,
+            decay_step: 0.0,
+            decay_seconds,
+        };
+        gain_match.set_sample_rate(sample_rate);
+        gain_match
+    }
+
+    // As LoudnessGate::set_sample_rate
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        let k = k_weighting(sample_rate);
+        self.filters = [k, k];
+        self.attack = 1.0 - (-1.0 / (MATCH_ATTACK_SECONDS * sample_rate)).exp();
+        self.release = 1.0 - (-1.0 / (MEASURE_SECONDS * sample_rate)).exp();
+        self.decay_step = 1.0 / (self.decay_seconds.max(0.001) * sample_rate);
+    }
+
+    pub fn process(&mut self, l: &mut [f32], r: &mut [f32]) {
+        if self.switched.swap(false, Ordering::Relaxed) {
+            self.reference = self.mean_square;
+            self.hold = 1.0;
+        }
+        for (l, r) in l.iter_mut().zip(r.iter_mut()) {
+            let [fl, fr] = &mut self.filters;
+            let kl = fl.iter_mut().fold(*l as f64, |x, f| f.run(x));
+            let kr = fr.iter_mut().fold(*r as f64, |x, f| f.run(x));
+            let power = kl * kl + kr * kr;
+            let smoothing = if power > self.mean_square { self.attack } else { self.release };
+            self.mean_square += (power - self.mean_square) * smoothing;
+
+            if self.hold <= 0.0 {
+                continue;
+            }
+            let db = match self.reference > MATCH_SILENCE && self.mean_square > MATCH_SILENCE {
+                true => (10.0 * (self.reference / self.mean_square).log10()).clamp(-MATCH_MAX_CUT, MATCH_MAX_BOOST),
+                false => 0.0,
+            };
+            let gain = 10f64.powf(db * self.hold / 20.0) as f32;
+            *l *= gain;
+            *r *= gain;
+            self.hold = (self.hold - self.decay_step).max(0.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    fn sine(amplitude: f32, seconds: f64) -> Vec<f32> {
+        let frames = (seconds * RATE) as usize;
+        (0..frames).map(|n| amplitude * (2.0 * PI * 1000.0 * n as f64 / RATE).sin() as f32).collect()
+    }
+
+    fn run(process: impl FnOnce(&mut [f32], &mut [f32]), input: &[f32]) -> Vec<f32> {
+        let (mut l, mut r) = (input.to_vec(), input.to_vec());
+        process(&mut l, &mut r);
+        l
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, x| peak.max(x.abs()))
+    }
+
+    #[test]
+    fn gate_fades_out_after_the_timeout_and_back_in() {
+        let mut gate = LoudnessGate::new(RATE, -50.0, 1.0, 0.1);
+        let out = run(|l, r| gate.process(l, r), &sine(0.5, 1.0));
+        assert_eq!(gate.gain, 1.0);
+        assert_eq!(out, sine(0.5, 1.0));
+
+        // the measurement takes a few seconds to fall below -50 LUFS, then
+        // the timeout runs
+        run(|l, r| gate.process(l, r), &sine(0.0, 1.0));
+        assert_eq!(gate.gain, 1.0);
+        run(|l, r| gate.process(l, r), &sine(0.0, 9.0));
+        assert_eq!(gate.gain, 0.0);
+        let out = run(|l, r| gate.process(l, r), &sine(1e-4, 1.0));
+        assert_eq!(peak(&out), 0.0);
+
+        run(|l, r| gate.process(l, r), &sine(0.5, 0.2));
+        assert_eq!(gate.gain, 1.0);
+    }
+
+    #[test]
+    fn gate_retuned_for_a_new_rate_times_out_in_seconds() {
+        let mut gate = LoudnessGate::new(RATE, -50.0, 1.0, 0.1);
+        gate.set_sample_rate(2.0 * RATE);
+        assert_eq!(gate.timeout_frames, 2 * RATE as u64);
+        let fresh = LoudnessGate::new(2.0 * RATE, -50.0, 1.0, 0.1);
+        assert_eq!((gate.smoothing, gate.fade_step), (fresh.smoothing, fresh.fade_step));
+    }
+
+    #[test]
+    fn gain_match_holds_the_old_level() {
+        let switched = Arc::new(AtomicBool::new(false));
+        let mut matcher = GainMatch::new(RATE, 1.0, switched.clone());
+        run(|l, r| matcher.process(l, r), &sine(0.1, 1.0));
+        switched.store(true, Ordering::Relaxed);
+        let out = run(|l, r| matcher.process(l, r), &sine(0.4, 0.2));
+        assert!(peak(&out[out.len() - 480..]) < 0.2);
+        // once the hold has decayed, the new level comes through
+        let out = run(|l, r| matcher.process(l, r), &sine(0.4, 1.0));
+        assert!((peak(&out[out.len() - 480..]) - 0.4).abs() < 0.01);
+    }
+
+    #[test]
+    fn gain_match_leaves_a_switch_from_silence_alone() {
+        let switched = Arc::new(AtomicBool::new(false));
+        let mut matcher = GainMatch::new(RATE, 1.0, switched.clone());
+        run(|l, r| matcher.process(l, r), &sine(0.0, 1.0));
+        switched.store(true, Ordering::Relaxed);
+        assert_eq!(run(|l, r| matcher.process(l, r), &sine(0.4, 0.5)), sine(0.4, 0.5));
+    }
+}
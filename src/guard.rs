@@ -0,0 +1,192 @@
+// Resource guardrails for long unattended runs: memory, run time, xrun rate,
+// and output health (prolonged silence or a frozen, repeating buffer).
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::ValueEnum;
+
+// What to do when a limit is exceeded
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum GuardAction {
+    /// Just report it
+    Log,
+    /// Stop calling the plugin and output silence
+    Bypass,
+    /// Shut down cleanly with a non-zero exit status
+    Exit,
+}
+
+pub struct Limits {
+    pub max_rss_mb: Option<u64>,
+    pub max_run_time: Option<Duration>,
+    pub max_xruns_per_min: Option<u64>,
+    pub max_silence: Option<Duration>,
+    pub max_stuck: Option<Duration>,
+}
+
+pub enum Breach {
+    Rss(u64),
+    RunTime(Duration),
+    Xruns(u64),
+    Silence(Duration),
+    Stuck(Duration),
+}
+
+impl Breach {
+    // Short name for the limit, for --osc-alert
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Breach::Rss(_) => "rss",
+            Breach::RunTime(_) => "run_time",
+            Breach::Xruns(_) => "xruns",
+            Breach::Silence(_) => "silence",
+            Breach::Stuck(_) => "stuck",
+        }
+    }
+}
+
+impl fmt::Display for Breach {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Breach::Rss(mb) => write!(f, "resident memory {mb} MB over limit"),
+            Breach::RunTime(t) => write!(f, "run time {}s over limit", t.as_secs()),
+            Breach::Xruns(n) => write!(f, "{n} xruns in the last minute, over limit"),
+            Breach::Silence(t) => write!(f, "plugin output silent for {}s", t.as_secs()),
+            Breach::Stuck(t) => write!(f, "plugin output repeating the same block for {}s", t.as_secs()),
+        }
+    }
+}
+
+// Output health counters, written by the audio thread
+#[derive(Default)]
+pub struct OutputHealth {
+    // consecutive frames of digital silence
+    silent_frames: AtomicU64,
+    // consecutive frames of (non-silent) blocks identical to the one before
+    stuck_frames: AtomicU64,
+}
+
+// Audio-thread side of OutputHealth: looks at each block of plugin output
+pub struct HealthMonitor {
+    health: Arc<OutputHealth>,
+    prev: [Vec<f32>; 2],
+}
+
+impl HealthMonitor {
+    // `frames`: the longest block we'll see, so observing never allocates
+    pub fn new(health: Arc<OutputHealth>, frames: usize) -> Self {
+        HealthMonitor { health, prev: [Vec::with_capacity(frames), Vec::with_capacity(frames)] }
+    }
+
+    // `expect_signal` is false while an effect's input is silent, when
+    // silence from the plugin is the right answer rather than a fault
+    pub fn observe(&mut self, expect_signal: bool, l: &[f32], r: &[f32]) {
+        let n = l.len() as u64;
+        let zero = l.iter().chain(r).all(|&s| s == 0.0);
+        let silent = expect_signal && zero;
+        let stuck = !zero && self.prev[0].as_slice() == l && self.prev[1].as_slice() == r;
+        for (counter, hit) in [(&self.health.silent_frames, silent), (&self.health.stuck_frames, stuck)] {
+            if hit {
+                counter.fetch_add(n, Ordering::Relaxed);
+            } else {
+                counter.store(0, Ordering::Relaxed);
+            }
+        }
+        for (prev, block) in self.prev.iter_mut().zip([l, r]) {
+            prev.clear();
+            prev.extend_from_slice(block);
+        }
+    }
+}
+
+pub struct Guard {
+    limits: Limits,
+    started: Instant,
+    // total xrun count, bumped by the JACK notification handler
+    xruns: Arc<AtomicU64>,
+    // (when, total xruns) samples covering the last minute
+    window: VecDeque<(Instant, u64)>,
+    health: Arc<OutputHealth>,
+    sample_rate: f64,
+    // which limits are currently exceeded, so each breach is reported once
+    tripped: [bool; 5],
+}
+
+impl Guard {
+    pub fn new(limits: Limits, xruns: Arc<AtomicU64>, health: Arc<OutputHealth>, sample_rate: f64) -> Self {
+        Guard {
+            limits,
+            started: Instant::now(),
+            xruns,
+            window: VecDeque::new(),
+            health,
+            sample_rate,
+            tripped: [false; 5],
+        }
+    }
+
+    // JACK's rate, which the output health counts frames at
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn frames_to_duration(&self, frames: &AtomicU64) -> Duration {
+        Duration::from_secs_f64(frames.load(Ordering::Relaxed) as f64 / self.sample_rate)
+    }
+
+    // Sample current usage; returns limits that have newly been exceeded.
+    // Meant to be called periodically (every second, or few with --low-power) from the main thread.
+    pub fn check(&mut self) -> Vec<Breach> {
+        let now = Instant::now();
+        let total = self.xruns.load(Ordering::Relaxed);
+        self.window.push_back((now, total));
+        while let Some(&(t, _)) = self.window.front() {
+            if now.duration_since(t) <= Duration::from_secs(60) { break; }
+            self.window.pop_front();
+        }
+        let recent_xruns = total - self.window.front().map_or(total, |&(_, n)| n);
+
+        let rss = self.limits.max_rss_mb.and_then(|max| {
+            let mb = rss_mb()?;
+            (mb > max).then_some(Breach::Rss(mb))
+        });
+        let run_time = self.limits.max_run_time.and_then(|max| {
+            let t = now.duration_since(self.started);
+            (t > max).then_some(Breach::RunTime(t))
+        });
+        let xruns = self.limits.max_xruns_per_min
+            .and_then(|max| (recent_xruns > max).then_some(Breach::Xruns(recent_xruns)));
+        let silence = self.limits.max_silence.and_then(|max| {
+            let t = self.frames_to_duration(&self.health.silent_frames);
+            (t > max).then_some(Breach::Silence(t))
+        });
+        let stuck = self.limits.max_stuck.and_then(|max| {
+            let t = self.frames_to_duration(&self.health.stuck_frames);
+            (t > max).then_some(Breach::Stuck(t))
+        });
+
+        let mut breaches = Vec::new();
+        for (tripped, breach) in self.tripped.iter_mut().zip([rss, run_time, xruns, silence, stuck]) {
+            match breach {
+                Some(b) if !*tripped => {
+                    *tripped = true;
+                    breaches.push(b);
+                }
+                Some(_) => {}
+                None => *tripped = false,
+            }
+        }
+        breaches
+    }
+}
+
+// Current resident set size of this process in MB, from /proc
+fn rss_mb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb / 1024)
+}
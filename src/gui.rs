@@ -0,0 +1,218 @@
+// --gui: the plugin's own editor through clap.gui. Embedded in an X11 window
+// of ours where the plugin supports that, otherwise in a floating window the
+// plugin makes itself. Either way it runs on the main thread, between
+// console commands, while JACK keeps processing. `gui show|hide|resize WxH|
+// scale F` from the console or OSC drive it from afar, say during a set.
+use std::ffi::CString;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use clack_extensions::gui::{GuiApiType, GuiConfiguration, GuiSize, PluginGui, Window};
+use clack_host::prelude::*;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    AtomEnum, ConfigureWindowAux, ConnectionExt as _, CreateWindowAux, EventMask, PropMode, WindowClass,
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+
+use crate::MyHost;
+
+// What the plugin asked of its window, from whatever thread it was on
+#[derive(Default)]
+pub struct Requests {
+    resize: Mutex<Option<GuiSize>>,
+    closed: AtomicBool,
+}
+
+impl Requests {
+    pub fn resize(&self, size: GuiSize) {
+        *self.resize.lock().unwrap() = Some(size);
+    }
+
+    pub fn closed(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Embedded {
+    conn: RustConnection,
+    window: u32,
+    wm_delete: u32,
+    size: (u32, u32),
+}
+
+pub struct Gui {
+    gui: PluginGui,
+    // None when the plugin made its own floating window
+    embedded: Option<Embedded>,
+}
+
+pub fn open(instance: &mut PluginInstance<MyHost>, title: &str) -> Result<Gui, String> {
+    let mut handle = instance.plugin_handle();
+    let gui = handle.get_extension::<PluginGui>().ok_or("plugin has no GUI (clap.gui)")?;
+    let embedded = GuiConfiguration { api_type: GuiApiType::X11, is_floating: false };
+    let floating = GuiConfiguration { api_type: GuiApiType::X11, is_floating: true };
+
+    if gui.is_api_supported(&mut handle, embedded) {
+        gui.create(&mut handle, embedded).map_err(|e| format!("plugin could not create its GUI: {e:?}"))?;
+        let size = gui.get_size(&mut handle).unwrap_or(GuiSize { width: 640, height: 480 });
+        let window = match Embedded::new(title, size) {
+            Ok(window) => window,
+            Err(e) => {
+                gui.destroy(&mut handle);
+                return Err(e);
+            }
+        };
+        // SAFETY: the window stays alive until close(), which destroys the GUI first
+        let attached = unsafe { gui.set_parent(&mut handle, Window::from_x11_handle(window.window as _)) };
+        if let Err(e) = attached {
+            gui.destroy(&mut handle);
+            return Err(format!("plugin could not attach to our window: {e:?}"));
+        }
+        let _ = gui.show(&mut handle);
+        return Ok(Gui { gui, embedded: Some(window) });
+    }
+    if gui.is_api_supported(&mut handle, floating) {
+        gui.create(&mut handle, floating).map_err(|e| format!("plugin could not create its GUI: {e:?}"))?;
+        if let Ok(title) = CString::new(title) {
+            gui.suggest_title(&mut handle, &title);
+        }
+        let _ = gui.show(&mut handle);
+        return Ok(Gui { gui, embedded: None });
+    }
+    Err("plugin has no X11 GUI".into())
+}
+
+impl Gui {
+    // Handle window events and the plugin's requests. False once the window
+    // has been closed, after which the GUI is gone.
+    pub fn poll(&mut self, instance: &mut PluginInstance<MyHost>) -> bool {
+        let (resize, closed) = instance.access_shared_handler(|host| {
+            (host.gui.resize.lock().unwrap().take(), host.gui.closed.swap(false, Ordering::Relaxed))
+        });
+        let mut handle = instance.plugin_handle();
+        let mut close = closed;
+
+        if let Some(window) = &mut self.embedded {
+            if let Some(size) = resize {
+                window.resize(size);
+            }
+            for event in window.events() {
+                match event {
+                    Event::ClientMessage(e) if e.data.as_data32()[0] == window.wm_delete => close = true,
+                    // Dragged by the user: let the plugin snap it to a size it can do
+                    Event::ConfigureNotify(e) => {
+                        let size = (e.width as u32, e.height as u32);
+                        if size != window.size && self.gui.can_resize(&mut handle) {
+                            let wanted = GuiSize { width: size.0, height: size.1 };
+                            let size = self.gui.adjust_size(&mut handle, wanted).unwrap_or(wanted);
+                            if self.gui.set_size(&mut handle, size).is_ok() {
+                                window.resize(size);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if close {
+            self.gui.destroy(&mut handle);
+            println!("GUI closed; the plugin keeps running");
+        }
+        !close
+    }
+
+    pub fn close(self, instance: &mut PluginInstance<MyHost>) {
+        self.gui.destroy(&mut instance.plugin_handle());
+    }
+
+    pub fn set_visible(&mut self, instance: &mut PluginInstance<MyHost>, visible: bool) -> Result<(), String> {
+        let mut handle = instance.plugin_handle();
+        let shown = match visible {
+            true => self.gui.show(&mut handle),
+            false => self.gui.hide(&mut handle),
+        };
+        shown.map_err(|e| format!("the plugin would not {} its GUI: {e:?}", if visible { "show" } else { "hide" }))?;
+        if let Some(window) = &self.embedded {
+            window.map(visible);
+        }
+        Ok(())
+    }
+
+    // The nearest size to this the plugin can do; returns that size
+    pub fn resize(&mut self, instance: &mut PluginInstance<MyHost>, wanted: GuiSize) -> Result<GuiSize, String> {
+        let mut handle = instance.plugin_handle();
+        if !self.gui.can_resize(&mut handle) {
+            return Err("the plugin's GUI has a fixed size".into());
+        }
+        let size = self.gui.adjust_size(&mut handle, wanted).unwrap_or(wanted);
+        self.gui.set_size(&mut handle, size).map_err(|e| format!("the plugin would not resize its GUI: {e:?}"))?;
+        if let Some(window) = &mut self.embedded {
+            window.resize(size);
+        }
+        Ok(size)
+    }
+
+    // Scale for a HiDPI screen; the plugin may work it out for itself instead
+    pub fn scale(&mut self, instance: &mut PluginInstance<MyHost>, scale: f64) -> Result<(), String> {
+        let mut handle = instance.plugin_handle();
+        self.gui.set_scale(&mut handle, scale).map_err(|e| format!("the plugin would not scale its GUI: {e:?}"))
+    }
+}
+
+impl Embedded {
+    fn new(title: &str, size: GuiSize) -> Result<Self, String> {
+        let x11 = |e: &dyn std::fmt::Display| format!("X11: {e}");
+        let (conn, screen) = x11rb::connect(None).map_err(|e| x11(&e))?;
+        let root = &conn.setup().roots[screen];
+        let (root_window, black) = (root.root, root.black_pixel);
+        let window = conn.generate_id().map_err(|e| x11(&e))?;
+        let aux = CreateWindowAux::new().background_pixel(black).event_mask(EventMask::STRUCTURE_NOTIFY);
+        let (width, height) = (size.width.max(1) as u16, size.height.max(1) as u16);
+        conn.create_window(x11rb::COPY_DEPTH_FROM_PARENT, window, root_window, 0, 0, width, height, 0,
+            WindowClass::INPUT_OUTPUT, 0, &aux)
+            .map_err(|e| x11(&e))?;
+
+        // Ask for a message instead of being killed when the user closes it
+        let atom = |name: &[u8]| -> Result<u32, String> {
+            Ok(conn.intern_atom(false, name).map_err(|e| x11(&e))?.reply().map_err(|e| x11(&e))?.atom)
+        };
+        let (wm_protocols, wm_delete) = (atom(b"WM_PROTOCOLS")?, atom(b"WM_DELETE_WINDOW")?);
+        conn.change_property32(PropMode::REPLACE, window, wm_protocols, AtomEnum::ATOM, &[wm_delete])
+            .map_err(|e| x11(&e))?;
+        conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_NAME, AtomEnum::STRING, title.as_bytes())
+            .map_err(|e| x11(&e))?;
+        conn.map_window(window).map_err(|e| x11(&e))?;
+        conn.flush().map_err(|e| x11(&e))?;
+        Ok(Embedded { conn, window, wm_delete, size: (size.width, size.height) })
+    }
+
+    fn resize(&mut self, size: GuiSize) {
+        self.size = (size.width, size.height);
+        let aux = ConfigureWindowAux::new().width(size.width).height(size.height);
+        let _ = self.conn.configure_window(self.window, &aux);
+        let _ = self.conn.flush();
+    }
+
+    fn map(&self, visible: bool) {
+        let _ = match visible {
+            true => self.conn.map_window(self.window),
+            false => self.conn.unmap_window(self.window),
+        };
+        let _ = self.conn.flush();
+    }
+
+    fn events(&self) -> Vec<Event> {
+        std::iter::from_fn(|| self.conn.poll_for_event().ok().flatten()).collect()
+    }
+}
+
+impl Drop for Embedded {
+    fn drop(&mut self) {
+        let _ = self.conn.destroy_window(self.window);
+        let _ = self.conn.flush();
+    }
+}
@@ -0,0 +1,86 @@
+// --input-file PORT=FILE: feed one of the plugin's audio inputs from a WAV
+// file, looped, instead of from JACK, e.g. a sidechain to test a ducker or
+// vocoder headless while the main input stays live, or the other way round.
+// The file is read into memory up front, so playing it never touches the disk.
+use std::path::Path;
+
+use crate::layout::PortLayout;
+
+pub struct InputFile {
+    // the port's first channel among all the plugin's input channels
+    first: usize,
+    // one buffer per channel of the port
+    channels: Vec<Vec<f32>>,
+    pos: usize,
+}
+
+impl InputFile {
+    // For input port `port` of `ports`, at JACK's `sample_rate`. A mono file
+    // feeds every channel of the port; otherwise the channel counts must match.
+    pub fn open(path: &Path, ports: &[PortLayout], port: usize, sample_rate: f64) -> Result<Self, String> {
+        let what = || format!("--input-file {}", path.display());
+        let mut wav = hound::WavReader::open(path).map_err(|e| format!("{}: {e}", what()))?;
+        let spec = wav.spec();
+        if spec.sample_rate as f64 != sample_rate {
+            return Err(format!("{}: recorded at {} Hz, but JACK runs at {sample_rate} Hz", what(), spec.sample_rate));
+        }
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => wav.samples::<f32>().collect::<Result<_, _>>(),
+            hound::SampleFormat::Int => {
+                let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+                wav.samples::<i32>().map(|s| s.map(|s| s as f32 * scale)).collect::<Result<_, _>>()
+            }
+        }
+        .map_err(|e| format!("{}: {e}", what()))?;
+        let wanted = ports[port].channels;
+        let file_channels = spec.channels as usize;
+        if file_channels != 1 && file_channels != wanted {
+            return Err(format!("{}: {file_channels} channels for a port with {wanted}", what()));
+        }
+        if samples.len() < file_channels {
+            return Err(format!("{}: no audio in it", what()));
+        }
+        let channels = (0..wanted)
+            .map(|c| samples.iter().skip(c % file_channels).step_by(file_channels).copied().collect())
+            .collect();
+        let first = ports[..port].iter().map(|p| p.channels).sum();
+        Ok(InputFile { first, channels, pos: 0 })
+    }
+
+    // The next `frames` of the file into its port's channels of `inputs`,
+    // round again from the start at the end
+    pub fn play(&mut self, inputs: &mut [Vec<f32>], frames: usize) {
+        let len = self.channels[0].len();
+        for (channel, buf) in self.channels.iter().zip(&mut inputs[self.first..]) {
+            let mut pos = self.pos;
+            for out in &mut buf[..frames] {
+                *out = channel[pos];
+                pos = if pos + 1 == len { 0 } else { pos + 1 };
+            }
+        }
+        self.pos = (self.pos + frames) % len;
+    }
+
+    // Which of the plugin's input channels this feeds
+    pub fn feeds(&self, channel: usize) -> bool {
+        (self.first..self.first + self.channels.len()).contains(&channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loops_into_its_own_channels_only() {
+        let mut file = InputFile { first: 2, channels: vec![vec![1.0, 2.0, 3.0], vec![-1.0, -2.0, -3.0]], pos: 0 };
+        let mut inputs = vec![vec![9.0; 4]; 4];
+        file.play(&mut inputs, 4);
+        assert_eq!(inputs[1], [9.0; 4]);
+        assert_eq!(inputs[2], [1.0, 2.0, 3.0, 1.0]);
+        assert_eq!(inputs[3], [-1.0, -2.0, -3.0, -1.0]);
+        file.play(&mut inputs, 2);
+        assert_eq!(inputs[2][..2], [2.0, 3.0]);
+        assert!(file.feeds(3) && !file.feeds(1) && !file.feeds(4));
+    }
+}
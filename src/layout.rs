@@ -0,0 +1,193 @@
+// The plugin's audio ports as clap.audio-ports describes them, and the JACK
+// ports we make for them: one per channel. The main ports keep the familiar
+// in_l/in_r and out_l/out_r; the rest are named after the plugin's own ports
+// (e.g. out_aux_2_l, in_sidechain_l). Ports with more than two channels are
+// named after the speakers clap.surround says they feed (out_c, out_lfe...),
+// or numbered if the plugin doesn't say.
+use clack_extensions::audio_ports::{AudioPortFlags, AudioPortInfoBuffer, PluginAudioPorts};
+use clack_extensions::surround::PluginSurround;
+use clack_host::prelude::*;
+
+use crate::MyHost;
+
+// The CLAP surround channel positions, in the order of their IDs
+const POSITIONS: [&str; 18] = [
+    "l", "r", "c", "lfe", "bl", "br", "flc", "frc", "bc", "sl", "sr", "tc", "tfl", "tfc", "tfr", "tbl", "tbc", "tbr",
+];
+
+pub struct PortLayout {
+    pub name: String,
+    pub channels: usize,
+    // the surround position of each channel; empty if unknown
+    pub positions: Vec<u8>,
+    // whether the port takes 64-bit buffers, and would rather have them
+    pub supports_f64: bool,
+    pub prefers_f64: bool,
+}
+
+pub struct Layout {
+    pub inputs: Vec<PortLayout>,
+    pub outputs: Vec<PortLayout>,
+}
+
+impl Layout {
+    // Without clap.audio-ports we assume stereo in and out, as hosts do
+    pub fn query(instance: &mut PluginInstance<MyHost>) -> Layout {
+        let mut handle = instance.plugin_handle();
+        let Some(ports) = handle.get_extension::<PluginAudioPorts>() else {
+            let stereo = |name: &str| {
+                vec![PortLayout {
+                    name: name.into(),
+                    channels: 2,
+                    positions: Vec::new(),
+                    supports_f64: false,
+                    prefers_f64: false,
+                }]
+            };
+            return Layout { inputs: stereo("main"), outputs: stereo("main") };
+        };
+        let surround = handle.get_extension::<PluginSurround>();
+        let mut buffer = AudioPortInfoBuffer::new();
+        let mut side = |is_input| {
+            (0..ports.count(&mut handle, is_input))
+                .filter_map(|i| {
+                    let info = ports.get(&mut handle, i, is_input, &mut buffer)?;
+                    let name = String::from_utf8_lossy(info.name).into_owned();
+                    let channels = info.channel_count as usize;
+                    let mut positions = match surround {
+                        Some(surround) if channels > 2 => {
+                            let mut map = [0u8; POSITIONS.len()];
+                            surround.get_channel_map(&mut handle, is_input, i, &mut map).to_vec()
+                        }
+                        _ => Vec::new(),
+                    };
+                    // no use for naming unless it covers every channel
+                    if positions.len() != channels {
+                        positions.clear();
+                    }
+                    let supports_f64 = info.flags.contains(AudioPortFlags::SUPPORTS_64BITS);
+                    let prefers_f64 = supports_f64 && info.flags.contains(AudioPortFlags::PREFERS_64BITS);
+                    Some(PortLayout { name, channels, positions, supports_f64, prefers_f64 })
+                })
+                .collect()
+        };
+        let inputs = side(true);
+        let outputs = side(false);
+        Layout { inputs, outputs }
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.inputs.iter().map(|p| p.channels).sum()
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.outputs.iter().map(|p| p.channels).sum()
+    }
+
+    // We use 64-bit buffers on every port or none, which also satisfies
+    // plugins that want the same sample size throughout
+    pub fn supports_f64(&self) -> bool {
+        self.inputs.iter().chain(&self.outputs).all(|p| p.supports_f64)
+    }
+
+    pub fn prefers_f64(&self) -> bool {
+        self.inputs.iter().chain(&self.outputs).any(|p| p.prefers_f64)
+    }
+}
+
+// JACK port names for every channel of `ports`, in order, under `prefix`
+// ("in" or "out")
+pub fn jack_names(ports: &[PortLayout], prefix: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for (i, port) in ports.iter().enumerate() {
+        let base = port_base(port, i, prefix);
+        match port.channels {
+            1 => names.push(base),
+            2 => names.extend([format!("{base}_l"), format!("{base}_r")]),
+            _ if !port.positions.is_empty() => {
+                names.extend(port.positions.iter().enumerate().map(|(c, p)| match POSITIONS.get(*p as usize) {
+                    Some(position) => format!("{base}_{position}"),
+                    None => format!("{base}_{}", c + 1),
+                }));
+            }
+            n => names.extend((1..=n).map(|c| format!("{base}_{c}"))),
+        }
+    }
+    names
+}
+
+// What the JACK ports for the `index`th of the ports are named after, before
+// the channel: `prefix` itself for the main port
+pub fn port_base(port: &PortLayout, index: usize, prefix: &str) -> String {
+    match index {
+        0 => prefix.to_string(),
+        _ => format!("{prefix}_{}", port_name(&port.name, index)),
+    }
+}
+
+// Lower case with anything odd as `_`; the port's index if nothing is left
+pub fn port_name(name: &str, index: usize) -> String {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect();
+    if name.chars().all(|c| c == '_') { index.to_string() } else { name }
+}
+
+// Split per-channel buffers, port after port, into one slice per port
+pub fn split<'a, T>(channels: &'a mut [T], ports: &'a [PortLayout]) -> impl Iterator<Item = &'a mut [T]> {
+    let mut rest = channels;
+    ports.iter().map(move |port| {
+        let (this, tail) = std::mem::take(&mut rest).split_at_mut(port.channels);
+        rest = tail;
+        this
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(name: &str, channels: usize, positions: &[u8]) -> PortLayout {
+        PortLayout {
+            name: name.into(),
+            channels,
+            positions: positions.to_vec(),
+            supports_f64: false,
+            prefers_f64: false,
+        }
+    }
+
+    #[test]
+    fn jack_names_per_channel() {
+        let ports = [port("Main", 2, &[]), port("Sidechain", 2, &[]), port("Aux 2", 1, &[]), port("", 3, &[])];
+        assert_eq!(
+            jack_names(&ports, "in"),
+            ["in_l", "in_r", "in_sidechain_l", "in_sidechain_r", "in_aux_2", "in_3_1", "in_3_2", "in_3_3"]
+        );
+    }
+
+    #[test]
+    fn jack_names_surround_positions() {
+        // 5.1, then a port with a channel past the known positions
+        let ports = [port("main", 6, &[0, 1, 2, 3, 9, 10]), port("Height", 3, &[12, 14, 40])];
+        let names = jack_names(&ports, "out");
+        assert_eq!(&names[..6], ["out_l", "out_r", "out_c", "out_lfe", "out_sl", "out_sr"]);
+        assert_eq!(&names[6..], ["out_height_tfl", "out_height_tfr", "out_height_3"]);
+    }
+
+    #[test]
+    fn port_names_are_tidied() {
+        assert_eq!(port_name(" Side-Chain In ", 1), "side_chain_in");
+        assert_eq!(port_name("--", 4), "4");
+    }
+
+    #[test]
+    fn split_by_port() {
+        let ports = [port("main", 2, &[]), port("mono", 1, &[]), port("quad", 4, &[])];
+        let mut channels: Vec<Vec<f32>> = (0..7).map(|c| vec![c as f32]).collect();
+        let split: Vec<Vec<f32>> = split(&mut channels, &ports).map(|port| port.iter().map(|c| c[0]).collect()).collect();
+        assert_eq!(split, [vec![0.0, 1.0], vec![2.0], vec![3.0, 4.0, 5.0, 6.0]]);
+    }
+}
@@ -0,0 +1,55 @@
+// Time-stamped log of host lifecycle events, one logfmt-style line per event
+// on stderr, so a glitchy session can be reconstructed afterwards.
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+pub enum Event<'a> {
+    HostStarted,
+    BundleLoaded(&'a Path),
+    Instantiated(&'a str),
+    Activated { sample_rate: f64, min_frames: u32, max_frames: u32 },
+    ProcessingStarted,
+    JackActivated(&'a str),
+    Xrun,
+    RestartRequested,
+    StateLoaded(&'a Path),
+    StateSaved(&'a Path),
+    Faulted,
+    GuardTripped(&'a str),
+    Interrupted,
+    Shutdown,
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::HostStarted => write!(f, "event=host_started"),
+            Event::BundleLoaded(path) => write!(f, "event=bundle_loaded path={:?}", path.display().to_string()),
+            Event::Instantiated(id) => write!(f, "event=instantiated plugin={id:?}"),
+            Event::Activated { sample_rate, min_frames, max_frames } => write!(
+                f,
+                "event=activated sample_rate={sample_rate} min_frames={min_frames} max_frames={max_frames}"
+            ),
+            Event::ProcessingStarted => write!(f, "event=processing_started"),
+            Event::JackActivated(client) => write!(f, "event=jack_activated client={client:?}"),
+            Event::Xrun => write!(f, "event=xrun"),
+            Event::RestartRequested => write!(f, "event=restart_requested"),
+            Event::StateLoaded(path) => write!(f, "event=state_loaded path={:?}", path.display().to_string()),
+            Event::StateSaved(path) => write!(f, "event=state_saved path={:?}", path.display().to_string()),
+            Event::Faulted => write!(f, "event=faulted"),
+            Event::GuardTripped(reason) => write!(f, "event=guard_tripped reason={reason:?}"),
+            Event::Interrupted => write!(f, "event=interrupted"),
+            Event::Shutdown => write!(f, "event=shutdown"),
+        }
+    }
+}
+
+// Record an event, timestamped in seconds since the first event (monotonic clock)
+pub fn log(event: Event) {
+    let t = START.get_or_init(Instant::now).elapsed().as_secs_f64();
+    eprintln!("lifecycle t={t:.6} {event}");
+}
@@ -0,0 +1,88 @@
+// `list` and `scan`: print every plugin descriptor in a bundle, or in every
+// bundle on the standard CLAP search paths, so users can find the ID to pass
+// to --plugin-id without reading plugin docs.
+use std::path::{Path, PathBuf};
+
+use clack_host::prelude::*;
+
+// System-wide CLAP install locations on Linux; ~/.clap and $CLAP_PATH come first
+const SYSTEM_PATHS: &[&str] = &["/usr/lib/clap", "/usr/local/lib/clap"];
+
+pub fn run(bundle_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let count = print_bundle(bundle_path)?;
+    println!("{count} plugin(s) in {}", bundle_path.display());
+    Ok(())
+}
+
+pub fn scan() -> Result<(), Box<dyn std::error::Error>> {
+    let mut bundles = Vec::new();
+    for dir in search_paths() {
+        find_bundles(&dir, &mut bundles);
+    }
+
+    let mut plugins = 0;
+    let mut failed = 0;
+    for path in &bundles {
+        println!("== {}", path.display());
+        match print_bundle(path) {
+            Ok(count) => plugins += count,
+            Err(e) => {
+                // One broken bundle shouldn't hide the rest
+                eprintln!("  skipped: {e}");
+                failed += 1;
+            }
+        }
+        println!();
+    }
+    println!("{plugins} plugin(s) in {} bundle(s), {failed} bundle(s) failed to load", bundles.len());
+    Ok(())
+}
+
+fn search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        paths.push(Path::new(&home).join(".clap"));
+    }
+    if let Some(clap_path) = std::env::var_os("CLAP_PATH") {
+        paths.extend(std::env::split_paths(&clap_path));
+    }
+    paths.extend(SYSTEM_PATHS.iter().map(PathBuf::from));
+    paths
+}
+
+// Collect *.clap under `dir`, recursively. Missing directories are normal
+// (most systems only have one or two of the standard paths).
+fn find_bundles(dir: &Path, bundles: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else { return };
+    let mut entries: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        if path.extension().is_some_and(|ext| ext == "clap") {
+            if !bundles.contains(&path) {
+                bundles.push(path);
+            }
+        } else if path.is_dir() {
+            find_bundles(&path, bundles);
+        }
+    }
+}
+
+// Print the bundle's descriptors and return how many there were
+fn print_bundle(bundle_path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let bundle = unsafe { PluginBundle::load(bundle_path) }
+        .map_err(|e| format!("Failed to load bundle: {e:?}"))?;
+    let factory = bundle.get_plugin_factory().ok_or("Bundle has no plugin factory")?;
+
+    let text = |s: Option<&std::ffi::CStr>| s.map_or("-".into(), |s| s.to_string_lossy().into_owned());
+    let mut count = 0;
+    for desc in factory.plugin_descriptors() {
+        let features: Vec<_> = desc.features().map(|f| f.to_string_lossy().into_owned()).collect();
+        println!("{}", text(desc.id()));
+        println!("  name:     {}", text(desc.name()));
+        println!("  vendor:   {}", text(desc.vendor()));
+        println!("  version:  {}", text(desc.version()));
+        println!("  features: {}", features.join(", "));
+        count += 1;
+    }
+    Ok(count)
+}
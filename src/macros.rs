@@ -0,0 +1,186 @@
+// --macro NAME=COMMANDS: a named run of console commands, separated by `;`,
+// with `wait SECONDS` between them where they shouldn't all happen at once:
+//
+//     --macro "scene2=preset Morning; set Mix 30; wait 2; set Gain -6"
+//
+// Started with `macro scene2` from the console, /macro scene2 over OSC or a
+// --macro-trigger from MIDI. The main loop runs the commands as they come
+// due, as if typed at the console: the operator set the macros up, so even
+// one started over OSC may name files. A macro started while it's still
+// running starts again from the top.
+use std::time::{Duration, Instant};
+
+use crate::presets::MidiSource;
+
+#[derive(Clone, Debug)]
+enum Step {
+    Command(String),
+    Wait(Duration),
+}
+
+#[derive(Clone, Debug)]
+pub struct Macro {
+    name: String,
+    steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+pub fn parse_macro(s: &str) -> Result<Macro, String> {
+    let (name, commands) = s.split_once('=').ok_or("expected NAME=COMMAND; COMMAND; ...")?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) || name.starts_with('@') {
+        return Err(format!("bad macro name {name:?}: expected one word, not starting with @"));
+    }
+    parse_steps(name, commands, false)
+}
+
+// An --at entry's commands, as a macro called @TIME. Those may run a --macro,
+// which can't run another in turn, but not each other.
+pub fn scheduled(time: &str, commands: &str) -> Result<Macro, String> {
+    parse_steps(&format!("@{time}"), commands, true)
+}
+
+fn parse_steps(name: &str, commands: &str, nested: bool) -> Result<Macro, String> {
+    let mut steps = Vec::new();
+    for command in commands.split(';').map(str::trim).filter(|c| !c.is_empty()) {
+        let mut words = command.split_whitespace();
+        steps.push(match (words.next(), words.next(), words.next()) {
+            (Some("wait"), Some(seconds), None) => {
+                let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                let seconds: f64 = seconds
+                    .parse()
+                    .ok()
+                    .filter(|s: &f64| *s >= 0.0 && s.is_finite())
+                    .ok_or_else(|| format!("macro {name}: bad wait {command:?}: expected wait SECONDS"))?;
+                Step::Wait(Duration::from_secs_f64(seconds))
+            }
+            // one macro running another could go round for ever
+            (Some("macro"), Some(other), None) if nested && !other.starts_with('@') => {
+                Step::Command(command.to_string())
+            }
+            (Some("macro"), ..) => return Err(format!("macro {name}: macros can't run other macros")),
+            _ => Step::Command(command.to_string()),
+        });
+    }
+    if steps.is_empty() {
+        return Err(format!("macro {name}: no commands"));
+    }
+    Ok(Macro { name: name.to_string(), steps })
+}
+
+// --macro-trigger noteN=NAME or ccN=NAME
+#[derive(Clone, Debug)]
+pub struct MacroTrigger {
+    pub source: MidiSource,
+    pub name: String,
+}
+
+pub fn parse_macro_trigger(s: &str) -> Result<MacroTrigger, String> {
+    let (source, name) = s.split_once('=').ok_or("expected noteN=MACRO or ccN=MACRO")?;
+    Ok(MacroTrigger { source: MidiSource::parse(source)?, name: name.to_string() })
+}
+
+struct Running {
+    index: usize,
+    // the next step to take, once `due`
+    next: usize,
+    due: Instant,
+}
+
+#[derive(Default)]
+pub struct Macros {
+    macros: Vec<Macro>,
+    running: Vec<Running>,
+}
+
+impl Macros {
+    pub fn new(macros: Vec<Macro>) -> Result<Self, String> {
+        for (i, m) in macros.iter().enumerate() {
+            if macros[..i].iter().any(|other| other.name == m.name) {
+                return Err(format!("macro {}: defined twice", m.name));
+            }
+        }
+        Ok(Macros { macros, running: Vec::new() })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macros.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.macros.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.macros.iter().position(|m| m.name == name)
+    }
+
+    pub fn start(&mut self, index: usize, now: Instant) {
+        self.running.retain(|r| r.index != index);
+        self.running.push(Running { index, next: 0, due: now });
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.running.iter().map(|r| r.due).min()
+    }
+
+    // The commands that have come due, each running macro taken as far as
+    // its next wait; those come due that long from now
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        let mut due = Vec::new();
+        for running in self.running.iter_mut().filter(|r| r.due <= now) {
+            let steps = &self.macros[running.index].steps;
+            while let Some(step) = steps.get(running.next) {
+                running.next += 1;
+                match step {
+                    Step::Command(command) => due.push(command.clone()),
+                    Step::Wait(wait) => {
+                        running.due = now + *wait;
+                        break;
+                    }
+                }
+            }
+        }
+        let macros = &self.macros;
+        self.running.retain(|r| r.next < macros[r.index].steps.len());
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_the_commands_between_waits() {
+        let scene = parse_macro("scene2 = preset Morning; set Mix 30;; wait 2s; set Gain -6; wait 1").unwrap();
+        let mut macros = Macros::new(vec![scene]).unwrap();
+        let start = Instant::now();
+        macros.start(macros.index("scene2").unwrap(), start);
+        assert_eq!(macros.take_due(start), ["preset Morning", "set Mix 30"]);
+        assert_eq!(macros.next_due(), Some(start + Duration::from_secs(2)));
+        assert!(macros.take_due(start + Duration::from_secs(1)).is_empty());
+        assert_eq!(macros.take_due(start + Duration::from_secs(2)), ["set Gain -6"]);
+        // a wait with nothing after it ends the macro there
+        assert_eq!(macros.take_due(start + Duration::from_secs(3)), Vec::<String>::new());
+        assert_eq!(macros.next_due(), None);
+    }
+
+    #[test]
+    fn rejects_bad_macros() {
+        for bad in ["scene2", "=set Mix 1", "two words=set Mix 1", "a=wait soon", "a=wait -1", "a=macro b", "a= ; "] {
+            assert!(parse_macro(bad).is_err(), "{bad}");
+        }
+        // @NAME is kept for --at
+        assert!(parse_macro("@a=play").is_err());
+        assert!(scheduled("22:00", "macro closing; wait 30; stop").is_ok());
+        assert!(scheduled("22:00", "macro @09:00").is_err());
+        let twice = vec![parse_macro("a=play").unwrap(), parse_macro("a=stop").unwrap()];
+        assert!(Macros::new(twice).is_err());
+    }
+}
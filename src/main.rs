@@ -1,51 +1,1103 @@
-use std::{path::PathBuf};
-use clap::Parser;
+use std::ffi::{CStr, CString};
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use clap::{Parser, Subcommand, ValueEnum};
 
+use clack_extensions::gui::{GuiSize, HostGui, HostGuiImplShared};
+use clack_extensions::latency::{HostLatency, HostLatencyImpl, PluginLatency};
+use clack_extensions::note_ports::{
+    HostNotePorts, HostNotePortsImpl, NoteDialects, NotePortInfoBuffer, NotePortRescanFlags, PluginNotePorts,
+};
+use clack_extensions::params::{
+    HostParams, HostParamsImplMainThread, HostParamsImplShared, ParamClearFlags, ParamRescanFlags,
+};
+use clack_extensions::preset_discovery::Location;
+use clack_extensions::preset_load::{HostPresetLoad, HostPresetLoadImpl};
+use clack_extensions::state::{HostState, HostStateImpl};
+use clack_extensions::surround::{HostSurround, HostSurroundImpl};
+use clack_extensions::tail::{PluginTail, TailLength};
+use clack_extensions::timer::{HostTimer, HostTimerImpl, TimerId};
 use clack_host::prelude::*;
+use clack_host::events::event_types::{ParamValueEvent, TransportEvent};
 use clack_host::events::io::{InputEvents, OutputEvents, EventBuffer};
-use clack_host::prelude::UnknownEvent;
+use clack_host::events::Pckn;
+use clack_host::process::StartedPluginAudioProcessor;
+use clack_host::utils::{ClapId, Cookie};
 
-use jack::{Client, ClientOptions, Control, ProcessHandler, ProcessScope, AudioOut, Port};
+use jack::{Client, ClientOptions, Control, LatencyType, NotificationHandler, ProcessHandler, ProcessScope, AudioIn, AudioOut, MidiIn, MidiOut, Port, PortFlags, Transport};
+
+mod activation;
+mod arp;
+mod calibrate;
+mod click;
+mod compare;
+mod config;
+mod deadline;
+mod diag;
+mod dirty;
+mod dropped;
+mod envelope;
+mod fade;
+mod gate;
+mod guard;
+mod gui;
+mod inputfile;
+mod layout;
+mod lifecycle;
+mod limiter;
+mod macros;
+mod mapfile;
+mod list;
+mod midi;
+mod mute;
+mod offline;
+mod osc;
+mod params;
+mod presets;
+mod rawmidi;
+mod record;
+mod repl;
+mod resample;
+mod restart;
+mod retro;
+mod scale;
+mod schedule;
+mod shutdown;
+mod sinks;
+mod slots;
+mod soak;
+mod state;
+mod stereo;
+mod timer;
+mod transport;
+
+use activation::PortActivation;
+use arp::{Arp, ArpMode};
+use click::Click;
+use deadline::DeadlineStats;
+use dirty::{Edits, Recovery};
+use dropped::{Dropped, Kind};
+use envelope::Follower;
+use fade::Fade;
+use gate::{GainMatch, LoudnessGate};
+use guard::{Guard, GuardAction, HealthMonitor, Limits, OutputHealth};
+use inputfile::InputFile;
+use layout::Layout;
+use lifecycle::Event;
+use limiter::{TruePeakLimiter, TruePeakStats};
+use macros::{Macro, MacroTrigger, Macros};
+use midi::{CcMap, Curve, MapTarget, MidiMaps, NotePort, Tuning};
+use mute::{OutputGains, Switch, Switches};
+use presets::{Bank, MidiSource, Step, Trigger};
+use rawmidi::MidiBackend;
+use record::{RecordSync, Recorder};
+use repl::{Change, MapProfiles, Repl};
+use resample::{Rates, Resampler, SrPolicy};
+use retro::RetroBuffer;
+use scale::Scale;
+use schedule::{At, Schedule};
+use sinks::{SinkSpec, Sinks};
+use slots::{SlotNotes, Slots};
+use stereo::{Correlation, CorrelationMeter, StereoStage};
+use timer::Timers;
+use transport::TransportSync;
+
+// Plugin hosted when no --plugin-id is given: a generator that needs no MIDI
+const DEFAULT_PLUGIN_ID: &str = "in.lsp-plug.noise_generator_x1";
+
+// Longest the main loop waits before servicing plugin callbacks and the GUI
+const MAIN_THREAD_POLL: Duration = Duration::from_millis(10);
+
+// How often the guardrails look at the process and its output
+const GUARD_CHECK: Duration = Duration::from_secs(1);
+
+// How often --envelope-osc sends the level
+const ENVELOPE_OSC_EVERY: Duration = Duration::from_millis(33);
+
+// Commands handled per pass before the main loop gets on with its checks, so
+// a flood of them (OSC, a pasted script) can't hold those up
+const COMMANDS_PER_POLL: usize = 32;
+
+// --low-power: the same, less often. Plugin timers still fire when due and
+// commands are still handled when they arrive.
+const LOW_POWER_POLL: Duration = Duration::from_millis(100);
+const LOW_POWER_GUARD_CHECK: Duration = Duration::from_secs(5);
+
+// JACK can't tell us the largest period it might switch to, so unless told
+// otherwise we allow for PipeWire's default maximum quantum.
+const DEFAULT_MAX_FRAMES: u32 = 8192;
+
+// Room for the longest period JACK and PipeWire run at, in every buffer the
+// audio thread uses, so process() never allocates
+const MAX_PERIOD: usize = 8192;
+
+// Events we send the plugin in one slice. Past this (less the most a single
+// MIDI message or change turns into) the rest of a burst is dropped and
+// counted rather than growing the buffer on the audio thread.
+const MAX_EVENTS: usize = 1024;
+const EVENTS_PER_MESSAGE: usize = midi::MAX_EVENTS_PER_MESSAGE;
+// Of those, kept back for note-offs, so a flood of anything else can't leave
+// notes hanging; likewise of the MIDI messages staged for one slice
+const NOTE_OFF_EVENTS: usize = 64;
+const NOTE_OFF_STAGED: usize = 64;
+
+// Commands from the console and OSC waiting for the main loop
+const COMMAND_QUEUE_LEN: usize = 1024;
 
 #[derive(Parser, Debug)]
-#[command(version, about = "CLAP -> JACK: run LSP Noise Generator through JACK")]
+#[command(version, about = "CLAP -> JACK: run a CLAP plugin through JACK")]
+#[command(after_help = config::PRECEDENCE)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Without a subcommand we run, so `PLUGIN [flags]` keeps working
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+// Hosting a plugin through JACK: the root command and `run`
+#[derive(clap::Args, Debug)]
+struct RunArgs {
     /// Path to a .clap bundle (e.g. /usr/lib/clap/lsp-plugins.clap)
+    #[arg(required = true)]
+    plugin: Option<PathBuf>,
+
+    /// ID of the plugin to host from the bundle
+    #[arg(long, default_value = DEFAULT_PLUGIN_ID)]
+    plugin_id: String,
+
+    /// Drive a parameter from a MIDI controller, e.g. `cc74=param:cutoff` (by name
+    /// or ID), scaled onto the parameter's range, or mute or solo an output
+    /// from 64 up, e.g. `cc20=mute:out_sidechain_l` or `cc21=solo:3`. A
+    /// parameter can have a --cc-curve of its own, e.g.
+    /// `cc74=param:cutoff@exponential`. May be repeated.
+    #[arg(long, value_parser = parse_map)]
+    map: Vec<(u8, MapTarget)>,
+
+    /// Keys that cut each other off, e.g. `42,44,46` for closed, pedal and
+    /// open hi-hat: a note-on chokes the group's other notes first. May be
+    /// repeated for more groups.
+    #[arg(long, value_parser = parse_choke)]
+    choke: Vec<Vec<u8>>,
+
+    /// Read more --map and --choke settings from a CSV file of CC,TARGET
+    /// lines (e.g. `74,cutoff` or `cc20,mute:out_r`) and choke,KEYS lines;
+    /// a header line is skipped. May be repeated.
+    #[arg(long, value_name = "FILE")]
+    map_file: Vec<PathBuf>,
+
+    /// A named MIDI map to switch to while playing, as NAME=FILE in the
+    /// --map-file format, applied over the map from the flags above (which is
+    /// the one called `default`). May be repeated.
+    #[arg(long, value_parser = parse_map_profile)]
+    map_profile: Vec<(String, PathBuf)>,
+
+    /// Controller that picks the --map-profile, e.g. cc85: its range is
+    /// split evenly between the maps, `default` first
+    #[arg(long, value_parser = parse_controller)]
+    map_switch: Option<u8>,
+
+    /// Glide --map'd parameters to each new controller value over about this
+    /// many milliseconds, instead of in 7-bit steps
+    #[arg(long, default_value_t = 0.0)]
+    cc_smoothing: f64,
+
+    /// Shape of the --cc-smoothing glide: exponential eases in with
+    /// --cc-smoothing as its time constant, the rest take that long to get
+    /// there, in a straight line, an S or four stairs
+    #[arg(long, value_enum, default_value_t = Curve::Exponential)]
+    cc_curve: Curve,
+
+    /// Pitch of A4 in Hz, for playing with ensembles that don't tune to 440;
+    /// sent to the plugin as note-expression tuning on every note
+    #[arg(long, default_value_t = 440.0)]
+    tuning: f64,
+
+    /// Transpose every note by this many semitones (fractions allowed), the
+    /// same way as --tuning
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    master_transpose: f64,
+
+    /// Keep the last this-many seconds of output in memory, for the console's
+    /// `dump` command to save as WAV after the fact
+    #[arg(long)]
+    retro: Option<f64>,
+
+    /// Where `dump` and `record` save files given by name alone, and the
+    /// only place OSC /dump and /record can write to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    dump_dir: PathBuf,
+
+    /// Send the output somewhere besides JACK as well, as [NAME=]file:PATH
+    /// (a WAV of the whole run) or [NAME=]rtp:HOST:PORT (an L16 stream); `sink
+    /// NAME on|off` switches each, and JACK's as `jack`. May be repeated.
+    #[arg(long, value_name = "SINK", value_parser = sinks::parse_sink)]
+    sink: Vec<SinkSpec>,
+
+    /// Hold a `record` back until the next bar line of the JACK transport,
+    /// for loop-ready takes (needs a timebase master)
+    #[arg(long, value_enum)]
+    record_sync: Option<RecordSync>,
+
+    /// Open the plugin's own editor window (`gui show` opens it later)
+    #[arg(long)]
+    gui: bool,
+
+    /// Accept OSC control messages on this UDP port (see `help` on the console
+    /// for the commands; /param/NAME, /bypass, /note_on, /transport/start...)
+    #[arg(long)]
+    osc_port: Option<u16>,
+
+    /// Where MIDI comes from besides the JACK MIDI ports: `jack` for just
+    /// those, or `alsa-raw:hw:CARD,DEVICE` (as `amidi -l` lists them) to also
+    /// read an ALSA raw MIDI device straight into the first note input
+    #[arg(long, value_parser = rawmidi::parse, default_value = "jack")]
+    midi_backend: MidiBackend,
+
+    /// Address to take OSC on; anyone who can reach it can control the host,
+    /// so use 0.0.0.0 for other machines only on a network you trust
+    #[arg(long, default_value = "127.0.0.1")]
+    osc_bind: std::net::IpAddr,
+
+    /// Also send guardrail alerts as OSC, /alert <kind> <message>, to this
+    /// HOST:PORT (e.g. 127.0.0.1:9001)
+    #[arg(long)]
+    osc_alert: Option<std::net::SocketAddr>,
+
+    /// Set a parameter before the first block, as NAME=VALUE or ID=VALUE. The
+    /// value may be a number or the plugin's own text for it. May be repeated.
+    #[arg(long, value_parser = parse_param)]
+    param: Vec<(String, String)>,
+
+    /// Environment variable to set for the plugin, as KEY=VALUE. May be repeated.
+    #[arg(long, value_parser = parse_env_var)]
+    plugin_env: Vec<(String, String)>,
+
+    /// Working directory to switch to before loading the plugin
+    #[arg(long)]
+    plugin_cwd: Option<PathBuf>,
+
+    /// Locale for the plugin (sets LC_ALL), e.g. `C` or `de_DE.UTF-8`
+    #[arg(long)]
+    plugin_locale: Option<String>,
+
+    /// Don't start JACK; instead render offline while sweeping the block size
+    /// from 1 to --max-block and compare against a fixed-block reference render
+    #[arg(long)]
+    block_size_test: bool,
+
+    /// Sample rate used for offline renders
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Largest block size used for offline renders (also the reference block size)
+    #[arg(long, default_value_t = 512)]
+    max_block: u32,
+
+    /// Length of offline renders, in seconds
+    #[arg(long, default_value_t = 5.0)]
+    seconds: f64,
+
+    /// Stereo width of the output: 0 = mono, 1 = as the plugin made it, 2 = extra wide
+    #[arg(long, default_value_t = 1.0)]
+    width: f32,
+
+    /// Output balance from -1 (left) through 0 (centre) to 1 (right)
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    balance: f32,
+
+    /// Flip the polarity of the left, right or both output channels
+    #[arg(long, value_enum, ignore_case = true)]
+    invert_polarity: Option<Polarity>,
+
+    /// Prefix for our JACK port names, e.g. `bass` gives `clap_to_jack:bass:out_l`
+    #[arg(long)]
+    port_prefix: Option<String>,
+
+    /// Connect our outputs (left, right) to these JACK ports once running, and
+    /// again whenever they appear later. May be repeated; `@default-sink`
+    /// expands to the system's playback ports.
+    #[arg(long)]
+    connect_out: Vec<String>,
+
+    /// Feed one of the plugin's audio inputs from a WAV file, looped, instead
+    /// of from JACK, as PORT=FILE with the plugin's name for the port (e.g.
+    /// `sidechain=kick.wav`); the others stay live. May be repeated.
+    #[arg(long, value_parser = parse_input_file)]
+    input_file: Vec<(String, PathBuf)>,
+
+    /// Follow the level of one of our audio inputs (e.g. in_sidechain_l), as
+    /// a control source for --envelope-param and --envelope-osc
+    #[arg(long, value_name = "PORT")]
+    envelope_follow: Option<String>,
+
+    /// Attack of the --envelope-follow level, in milliseconds
+    #[arg(long, default_value_t = 10.0)]
+    envelope_attack: f64,
+
+    /// Release of the --envelope-follow level, in milliseconds
+    #[arg(long, default_value_t = 200.0)]
+    envelope_release: f64,
+
+    /// Sweep this parameter (by name or ID) over its range with the
+    /// --envelope-follow level, from its minimum in silence to its maximum
+    /// at full scale
+    #[arg(long, requires = "envelope_follow")]
+    envelope_param: Option<String>,
+
+    /// Send the --envelope-follow level as /envelope <0..1> to this OSC
+    /// HOST:PORT, about 30 times a second
+    #[arg(long, requires = "envelope_follow")]
+    envelope_osc: Option<std::net::SocketAddr>,
+
+    /// Extra latency in frames to report on one of our audio ports, e.g.
+    /// `out_l=64` when that output goes through an outboard loop, or
+    /// `in_sidechain_l=32` for an input fed through one. Takes our own name
+    /// for a port or its full JACK name. May be repeated.
+    #[arg(long, value_parser = parse_port_latency)]
+    port_latency: Vec<(String, u32)>,
+
+    /// Fade the output out when its loudness stays below this many LUFS
+    /// for --gate-timeout seconds, and back in when signal returns
+    #[arg(long, allow_negative_numbers = true)]
+    loudness_gate: Option<f64>,
+
+    /// Seconds below the --loudness-gate threshold before fading out
+    #[arg(long, default_value_t = 10.0)]
+    gate_timeout: f64,
+
+    /// Length of the gate's fades, in seconds
+    #[arg(long, default_value_t = 1.0)]
+    gate_fade: f64,
+
+    /// Keep out_l/out_r under this true-peak ceiling in dBTP (e.g. -1), with
+    /// 4x oversampled detection; adds 1.5 ms of latency
+    #[arg(long, allow_negative_numbers = true)]
+    true_peak_limit: Option<f64>,
+
+    /// How quickly the limiter lets go after a peak, in seconds
+    #[arg(long, default_value_t = 0.1)]
+    true_peak_release: f64,
+
+    /// Add a metronome click that follows the JACK transport
+    #[arg(long)]
+    click: bool,
+
+    /// Click tempo to use when no JACK timebase master publishes one
+    #[arg(long, default_value_t = 120.0)]
+    click_bpm: f64,
+
+    /// Click level in dBFS
+    #[arg(long, default_value_t = -12.0, allow_negative_numbers = true)]
+    click_level: f32,
+
+    /// Put the click on its own `click` port instead of mixing it into the outputs
+    #[arg(long)]
+    click_port: bool,
+
+    /// Move incoming MIDI notes to the nearest tone of a scale, as ROOT:SCALE
+    /// (e.g. C:minor; also major, dorian, pentatonic, blues...)
+    #[arg(long, value_parser = scale::parse)]
+    scale: Option<Scale>,
+
+    /// Arpeggiate notes held on the MIDI input instead of playing them directly
+    #[arg(long, value_enum)]
+    arp: Option<ArpMode>,
+
+    /// Reset the plugin when the JACK transport jumps, so delays and
+    /// sequencers start clean at the new position
+    #[arg(long)]
+    reset_on_locate: bool,
+
+    /// Arpeggiator steps per beat (4 = sixteenths)
+    #[arg(long, default_value_t = 4)]
+    arp_rate: u32,
+
+    /// Arpeggiator note length as a fraction of a step (0..1)
+    #[arg(long, default_value_t = 0.5)]
+    arp_gate: f64,
+
+    /// Arpeggiator tempo to use when no JACK timebase master publishes one
+    #[arg(long, default_value_t = 120.0)]
+    arp_bpm: f64,
+
+    /// Guardrail: resident memory limit in MB
+    #[arg(long)]
+    max_rss: Option<u64>,
+
+    /// Guardrail: maximum run time in seconds
+    #[arg(long)]
+    max_run_time: Option<u64>,
+
+    /// Guardrail: maximum number of JACK xruns within any minute
+    #[arg(long)]
+    max_xruns_per_min: Option<u64>,
+
+    /// Guardrail: longest stretch of digital silence from the plugin, in seconds
+    #[arg(long)]
+    max_silence: Option<u64>,
+
+    /// Guardrail: longest time the plugin may repeat an identical output block, in seconds
+    #[arg(long)]
+    max_stuck: Option<u64>,
+
+    /// What to do when a guardrail limit is exceeded
+    #[arg(long, value_enum, default_value_t = GuardAction::Log)]
+    guard_action: GuardAction,
+
+    /// Largest block the plugin is activated for; JACK periods beyond this are
+    /// processed in slices (default: the larger of the JACK buffer and 8192)
+    #[arg(long)]
+    max_frames: Option<u32>,
+
+    /// What to do when JACK's sample rate changes while we run: reactivate
+    /// the plugin at the new rate, or keep it at its own and resample around
+    /// it, for a few frames more latency
+    #[arg(long, value_enum, default_value_t = SrPolicy::Reactivate)]
+    sr_policy: SrPolicy,
+
+    /// Process in 64-bit floating point if the plugin can, converting to and
+    /// from JACK's 32-bit ports
+    #[arg(long)]
+    prefer_f64: bool,
+
+    /// Start from a factory preset: a preset file, FILE#KEY for one preset in
+    /// a file, or plugin:KEY for one built into the plugin (see `presets`)
+    #[arg(long, value_name = "LOCATION")]
+    preset: Option<String>,
+
+    /// A directory of preset files to step through while running, with
+    /// `preset next/prev/random` (or OSC /preset) and --preset-trigger
+    #[arg(long, value_name = "DIR")]
+    preset_bank: Option<PathBuf>,
+
+    /// Only step through the bank's presets in a subdirectory of this name
+    #[arg(long)]
+    preset_tag: Option<String>,
+
+    /// Step through the bank from MIDI, as noteN=STEP or ccN=STEP where STEP
+    /// is next, prev or random (e.g. note36=next, cc80=random)
+    #[arg(long, value_parser = presets::parse_trigger)]
+    preset_trigger: Vec<Trigger>,
+
+    /// A named run of live commands, separated by `;`, with `wait SECONDS`
+    /// between any that shouldn't happen at once, e.g.
+    /// "scene2=preset Morning; set Mix 30; wait 2; set Gain -6". Run it with
+    /// `macro scene2`, OSC /macro scene2 or a --macro-trigger. May be repeated.
+    #[arg(long = "macro", value_name = "NAME=COMMANDS", value_parser = macros::parse_macro)]
+    macros: Vec<Macro>,
+
+    /// Run a --macro from MIDI, as noteN=NAME or ccN=NAME (e.g. note48=scene2)
+    #[arg(long, value_parser = macros::parse_macro_trigger)]
+    macro_trigger: Vec<MacroTrigger>,
+
+    /// Live commands to run every day at a local time, as for --macro, e.g.
+    /// "22:00=fade out 30; wait 30; stop". May be repeated.
+    #[arg(long, value_name = "HH:MM=COMMANDS", value_parser = schedule::parse_at)]
+    at: Vec<At>,
+
+    /// Keep out_l/out_r at the loudness they had before each preset switch,
+    /// easing back to the new preset's own level over --gain-match-decay
+    #[arg(long)]
+    preset_gain_match: bool,
+
+    /// Seconds --preset-gain-match takes to hand back to the new preset's level
+    #[arg(long, default_value_t = 5.0)]
+    gain_match_decay: f64,
+
+    /// Restore the plugin's state (from a previous --save-state) before starting
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    /// Save the plugin's state to this file on exit
+    #[arg(long, value_name = "FILE")]
+    save_state: Option<PathBuf>,
+
+    /// While there are unsaved changes, save the plugin's state here too
+    /// every few seconds, for --load-state after a crash; removed once
+    /// --save-state has them
+    #[arg(long, value_name = "FILE")]
+    recovery_file: Option<PathBuf>,
+
+    /// A --save-state file to recall by playing one of the --slot-notes or
+    /// with `slot N`. May be repeated; the first is slot 1.
+    #[arg(long, value_name = "FILE")]
+    slot: Vec<PathBuf>,
+
+    /// The notes that recall --slot states, the lowest slot 1, as LOW..HIGH by
+    /// number or name (C-1 is 0, middle C C4); the plugin doesn't get them
+    #[arg(long, value_name = "LOW..HIGH", default_value = "C-1..B-1", value_parser = slots::parse_notes)]
+    slot_notes: SlotNotes,
+
+    /// How long the output takes to dip out before a --slot loads and to
+    /// come back in after, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 20.0)]
+    slot_fade: f64,
+
+    /// Longest to let the plugin's tail ring out on exit, in seconds
+    #[arg(long, default_value_t = 10.0)]
+    max_tail: f64,
+
+    /// Seconds to wait for each shutdown step before forcing the process to exit
+    #[arg(long, default_value_t = 5)]
+    shutdown_timeout: u64,
+
+    /// Wake the main thread less often (plugin callbacks, GUI, guardrails),
+    /// for small machines running headless
+    #[arg(long)]
+    low_power: bool,
+}
+
+// The bundle and plugin a subcommand works on
+#[derive(clap::Args, Debug)]
+struct PluginArgs {
+    /// Path to a .clap bundle
+    plugin: PathBuf,
+
+    /// ID of the plugin to use from the bundle
+    #[arg(long, default_value = DEFAULT_PLUGIN_ID)]
+    plugin_id: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the plugin through JACK (what happens without a subcommand)
+    Run(RunArgs),
+
+    /// List the plugins in a bundle with their IDs, names, vendors and features
+    List(ListArgs),
+
+    /// Print every parameter's ID, name, range, default and current value
+    Params(PluginArgs),
+
+    /// Find and list plugins in ~/.clap, $CLAP_PATH, /usr/lib/clap and /usr/local/lib/clap
+    Scan,
+
+    /// List the preset discovery providers in a bundle
+    Presets(ListArgs),
+
+    /// Print JACK, plugin and host details in one blob for bug reports
+    Diag(DiagArgs),
+
+    /// Play a test tone or pink noise on our output ports, without the plugin,
+    /// to level-set the monitoring chain
+    Calibrate(CalibrateArgs),
+
+    /// Run the plugin offline for hours with random parameter changes, state
+    /// round-trips and start/stop toggles, reporting any errors
+    Soak(SoakArgs),
+
+    /// Render the same input through two builds of a plugin and report how
+    /// their output, parameters and state differ, e.g. before upgrading
+    Compare(CompareArgs),
+
+    /// Render the plugin offline, without JACK, into a WAV file
+    Render(RenderArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ListArgs {
+    /// Path to a .clap bundle
     plugin: PathBuf,
 }
 
+#[derive(clap::Args, Debug)]
+struct DiagArgs {
+    #[command(flatten)]
+    plugin: PluginArgs,
+
+    /// Output JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CalibrateArgs {
+    /// What to play
+    #[arg(long, value_enum, ignore_case = true, default_value_t = calibrate::Signal::Tone)]
+    signal: calibrate::Signal,
+
+    /// Level in dBFS (sine peak; pink noise at the same RMS as that sine)
+    #[arg(long, default_value_t = -18.0, allow_negative_numbers = true)]
+    level: f32,
+
+    /// Tone frequency in Hz
+    #[arg(long, default_value_t = 1000.0)]
+    frequency: f64,
+
+    /// Prefix for our JACK port names, as for the run mode
+    #[arg(long)]
+    port_prefix: Option<String>,
+
+    /// Connect our outputs to these JACK ports, as for the run mode
+    #[arg(long)]
+    connect_out: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SoakArgs {
+    #[command(flatten)]
+    plugin: PluginArgs,
+
+    /// How long to run for
+    #[arg(long, default_value_t = 8.0)]
+    hours: f64,
+
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Block size to process with
+    #[arg(long, default_value_t = 512)]
+    block: u32,
+
+    /// Seconds between state save/load round-trips
+    #[arg(long, default_value_t = 30)]
+    state_every: u64,
+
+    /// Seconds between stop/start_processing toggles
+    #[arg(long, default_value_t = 60)]
+    toggle_every: u64,
+
+    /// Seed for the random events (default: taken from the clock)
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompareArgs {
+    /// The .clap bundle in use now
+    old: PathBuf,
+
+    /// The .clap bundle to compare with it
+    new: PathBuf,
+
+    /// ID of the plugin to compare, which both bundles must have
+    #[arg(long, default_value = DEFAULT_PLUGIN_ID)]
+    plugin_id: String,
+
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Block size to process with
+    #[arg(long, default_value_t = 512)]
+    block: u32,
+
+    /// Seconds of audio to render through each
+    #[arg(long, default_value_t = 10.0)]
+    seconds: f64,
+}
+
+#[derive(clap::Args, Debug)]
+struct RenderArgs {
+    #[command(flatten)]
+    plugin: PluginArgs,
+
+    /// WAV file to write: the plugin's main output, a channel for each of
+    /// its channels, 32-bit float. With --batch, the directory to write into.
+    #[arg(long, value_name = "FILE")]
+    out: PathBuf,
+
+    /// Render every preset file under DIR, as --preset-bank finds them, each
+    /// one to a WAV named after it under --out
+    #[arg(long, value_name = "DIR")]
+    batch: Option<PathBuf>,
+
+    /// How many --batch renders to run at once, each with a plugin instance
+    /// of its own (default: one per CPU)
+    #[arg(long, requires = "batch")]
+    jobs: Option<usize>,
+
+    /// Also write each output bus to its own WAV beside --out, named after
+    /// its JACK ports (song_out.wav, song_out_kick.wav...), and make --out
+    /// the mixdown of every bus onto the main one's channels
+    #[arg(long)]
+    stems: bool,
+
+    /// Length to render, in seconds
+    #[arg(long, default_value_t = 10.0)]
+    seconds: f64,
+
+    #[arg(long, default_value_t = 48000)]
+    sample_rate: u32,
+
+    /// Block size to process with
+    #[arg(long, default_value_t = 512)]
+    block: u32,
+
+    /// Restore the plugin's state (from a --save-state) before rendering
+    #[arg(long, value_name = "FILE")]
+    load_state: Option<PathBuf>,
+
+    /// Set a parameter before rendering, as NAME=VALUE or ID=VALUE. May be repeated.
+    #[arg(long, value_parser = parse_param)]
+    param: Vec<(String, String)>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Polarity {
+    L,
+    R,
+    All,
+}
+
+impl Polarity {
+    // Which of the [left, right] outputs get inverted
+    fn channels(self) -> [bool; 2] {
+        match self {
+            Polarity::L => [true, false],
+            Polarity::R => [false, true],
+            Polarity::All => [true, true],
+        }
+    }
+}
+
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s.split_once('=').ok_or("expected KEY=VALUE")?;
+    if key.is_empty() {
+        return Err("empty variable name".into());
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_param(s: &str) -> Result<(String, String), String> {
+    let (param, value) = s.split_once('=').ok_or("expected NAME=VALUE")?;
+    Ok((param.trim().to_string(), value.trim().to_string()))
+}
+
+fn parse_map(s: &str) -> Result<(u8, MapTarget), String> {
+    let usage = "expected ccN=param:NAME, ccN=mute:OUTPUT or ccN=solo:OUTPUT";
+    let (cc, target) = s.split_once('=').ok_or(usage)?;
+    let cc = cc
+        .strip_prefix("cc")
+        .and_then(|n| n.parse().ok())
+        .filter(|n| *n < 120)
+        .ok_or_else(|| format!("bad controller {cc:?}: expected cc0 to cc119"))?;
+    let target = match target.split_once(':').ok_or(usage)? {
+        ("param", param) => {
+            let curve = param.rsplit_once('@').map(|(name, curve)| (name, Curve::from_str(curve, true)));
+            match curve {
+                Some((name, Ok(curve))) => MapTarget::Param(name.to_string(), Some(curve)),
+                Some((_, Err(_))) | None => MapTarget::Param(param.to_string(), None),
+            }
+        }
+        ("mute", output) => MapTarget::Switch(Switch::Mute, output.to_string()),
+        ("solo", output) => MapTarget::Switch(Switch::Solo, output.to_string()),
+        _ => return Err(usage.into()),
+    };
+    Ok((cc, target))
+}
+
+// The --map, --choke and --map-file settings, resolved against the plugin's
+// parameters and our outputs
+fn build_cc_map(
+    instance: &mut PluginInstance<MyHost>,
+    map: &mapfile::MapFile,
+    switches: Arc<Switches>,
+    outputs: &[String],
+    curve: Curve,
+) -> Result<CcMap, String> {
+    let mut cc_map = CcMap::new(switches);
+    for (cc, target) in &map.map {
+        match target {
+            MapTarget::Param(param, own_curve) => {
+                let param = params::lookup(instance, param).map_err(|e| format!("--map: {e}"))?;
+                cc_map.insert(*cc, &param, own_curve.unwrap_or(curve));
+            }
+            MapTarget::Switch(switch, output) => {
+                let output = mute::find(outputs, output).map_err(|e| format!("--map: {e}"))?;
+                cc_map.insert_switch(*cc, *switch, output);
+            }
+        }
+    }
+    for keys in &map.chokes {
+        cc_map.insert_choke(keys);
+    }
+    Ok(cc_map)
+}
+
+fn parse_map_profile(s: &str) -> Result<(String, PathBuf), String> {
+    let (name, path) = s.split_once('=').ok_or("expected NAME=FILE")?;
+    match name.trim() {
+        "" | "default" => Err(format!("{name:?} can't name a map profile")),
+        name => Ok((name.to_string(), PathBuf::from(path))),
+    }
+}
+
+fn parse_input_file(s: &str) -> Result<(String, PathBuf), String> {
+    let (port, path) = s.split_once('=').ok_or("expected PORT=FILE")?;
+    Ok((port.trim().to_string(), PathBuf::from(path)))
+}
+
+fn parse_controller(s: &str) -> Result<u8, String> {
+    s.strip_prefix("cc")
+        .unwrap_or(s)
+        .parse()
+        .ok()
+        .filter(|n| *n < 120)
+        .ok_or_else(|| format!("bad controller {s:?}: expected cc0 to cc119"))
+}
+
+fn parse_choke(s: &str) -> Result<Vec<u8>, String> {
+    let keys = s
+        .split(',')
+        .map(|key| key.trim().parse().ok().filter(|k| *k < 128).ok_or_else(|| format!("bad key {key:?}: expected 0 to 127")))
+        .collect::<Result<Vec<u8>, String>>()?;
+    match keys.len() {
+        0 | 1 => Err("a choke group needs at least two keys, e.g. 42,46".into()),
+        n if n > midi::MAX_CHOKE_KEYS => Err(format!("a choke group has at most {} keys", midi::MAX_CHOKE_KEYS)),
+        _ => Ok(keys),
+    }
+}
+
+fn parse_port_latency(s: &str) -> Result<(String, u32), String> {
+    let (port, frames) = s.split_once('=').ok_or("expected PORT=FRAMES")?;
+    let frames = frames.parse().map_err(|e| format!("bad frame count {frames:?}: {e}"))?;
+    Ok((port.to_string(), frames))
+}
+
 /* ------- minimal clack host scaffolding ------- */
-struct MyHostShared;
+#[derive(Default)]
+struct MyHostShared {
+    gui: gui::Requests,
+    // picked up by the main loop, which does the restart
+    restart: AtomicBool,
+    // likewise for on_main_thread
+    callback: AtomicBool,
+}
 impl<'a> SharedHandler<'a> for MyHostShared {
-    fn request_restart(&self) {}
+    // May be called from the audio thread, so only flag it here
+    fn request_restart(&self) {
+        self.restart.store(true, Ordering::Relaxed);
+    }
     fn request_process(&self) {}
-    fn request_callback(&self) {}
+    fn request_callback(&self) {
+        self.callback.store(true, Ordering::Relaxed);
+    }
+}
+// We process continuously, so pending parameter changes get flushed by the
+// next process() call anyway.
+impl HostParamsImplShared for MyHostShared {
+    fn request_flush(&self) {}
+}
+// Window requests are picked up by the main loop's next GUI poll
+impl HostGuiImplShared for MyHostShared {
+    fn resize_hints_changed(&self) {}
+    fn request_resize(&self, new_size: GuiSize) -> Result<(), HostError> {
+        self.gui.resize(new_size);
+        Ok(())
+    }
+    // The window is shown for as long as it's open
+    fn request_show(&self) -> Result<(), HostError> {
+        Ok(())
+    }
+    fn request_hide(&self) -> Result<(), HostError> {
+        Ok(())
+    }
+    fn closed(&self, _was_destroyed: bool) {
+        self.gui.closed();
+    }
+}
+#[derive(Default)]
+struct MyHostMainThread {
+    timers: Timers,
+    // set when the plugin says its latency changed; the main loop re-reports it
+    latency_changed: bool,
+    // likewise for clap.state's mark_dirty, which the main loop counts as an edit
+    marked_dirty: bool,
+}
+impl<'a> MainThreadHandler<'a> for MyHostMainThread {}
+// Nothing caches parameter info yet: `params` queries it fresh every time.
+impl HostParamsImplMainThread for MyHostMainThread {
+    fn rescan(&mut self, _flags: ParamRescanFlags) {}
+    fn clear(&mut self, _param_id: ClapId, _flags: ParamClearFlags) {}
+}
+impl HostStateImpl for MyHostMainThread {
+    fn mark_dirty(&mut self) {
+        self.marked_dirty = true;
+    }
+}
+impl HostLatencyImpl for MyHostMainThread {
+    fn changed(&mut self) {
+        self.latency_changed = true;
+    }
+}
+impl HostNotePortsImpl for MyHostMainThread {
+    fn supported_dialects(&self) -> NoteDialects {
+        NoteDialects::CLAP | NoteDialects::MIDI
+    }
+    // Our JACK MIDI ports are made once, at startup
+    fn rescan(&mut self, _flags: NotePortRescanFlags) {}
+}
+impl HostSurroundImpl for MyHostMainThread {
+    // As with note ports, our JACK ports are made once
+    fn changed(&mut self) {
+        eprintln!("The plugin changed its channel layout; restart to get JACK ports that match");
+    }
+}
+impl HostTimerImpl for MyHostMainThread {
+    fn register_timer(&mut self, period_ms: u32) -> Result<TimerId, HostError> {
+        Ok(self.timers.register(period_ms))
+    }
+    fn unregister_timer(&mut self, timer_id: TimerId) -> Result<(), HostError> {
+        if self.timers.unregister(timer_id) {
+            Ok(())
+        } else {
+            Err(HostError::Message("no such timer"))
+        }
+    }
+}
+// How a --preset request turned out
+impl HostPresetLoadImpl for MyHostMainThread {
+    fn on_error(&mut self, _location: Location, load_key: Option<&CStr>, os_error: i32, message: Option<&CStr>) {
+        let message = message.map_or("no details".into(), CStr::to_string_lossy);
+        let key = load_key.map_or("".into(), |key| format!(" ({})", key.to_string_lossy()));
+        eprintln!("--preset{key}: {message} (OS error {os_error})");
+    }
+    fn loaded(&mut self, _location: Location, load_key: Option<&CStr>) {
+        let key = load_key.map_or("".into(), |key| format!(" {}", key.to_string_lossy()));
+        println!("Loaded preset{key}");
+    }
 }
 struct MyHost;
 impl HostHandlers for MyHost {
     type Shared<'a> = MyHostShared;
-    type MainThread<'a> = ();
+    type MainThread<'a> = MyHostMainThread;
     type AudioProcessor<'a> = ();
+
+    fn declare_extensions(builder: &mut HostExtensions<Self>, _shared: &Self::Shared<'_>) {
+        builder.register::<HostParams>();
+        builder.register::<HostState>();
+        builder.register::<HostPresetLoad>();
+        builder.register::<HostGui>();
+        builder.register::<HostTimer>();
+        builder.register::<HostLatency>();
+        builder.register::<HostNotePorts>();
+        builder.register::<HostSurround>();
+    }
 }
 /* --------------------------------------------- */
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    let args: Args = config::parse()?;
+    let args = match args.command {
+        None => args.run,
+        Some(Command::Run(run)) => run,
+        Some(Command::List(ListArgs { plugin })) => return list::run(&plugin),
+        Some(Command::Scan) => return list::scan(),
+        Some(Command::Presets(ListArgs { plugin })) => return presets::run(&plugin),
+        Some(Command::Params(PluginArgs { plugin, plugin_id })) => {
+            let (bundle, plugin_id) = load_plugin(&plugin, &plugin_id)?;
+            return params::run(&bundle, &plugin_id, &host_info()?);
+        }
+        Some(Command::Diag(DiagArgs { plugin, json })) => {
+            return diag::run(&plugin.plugin, &plugin.plugin_id, json);
+        }
+        Some(Command::Calibrate(cal)) => return calibrate::run(&cal),
+        Some(Command::Soak(SoakArgs { plugin, hours, sample_rate, block, state_every, toggle_every, seed })) => {
+            let (bundle, plugin_id) = load_plugin(&plugin.plugin, &plugin.plugin_id)?;
+            let seed = seed.unwrap_or_else(|| {
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(1, |d| d.as_nanos() as u64)
+            });
+            let cfg = soak::SoakConfig {
+                sample_rate: sample_rate as f64,
+                block: block.max(1),
+                duration: Duration::from_secs_f64(hours * 3600.0),
+                state_every: Duration::from_secs(state_every),
+                toggle_every: Duration::from_secs(toggle_every),
+                seed,
+            };
+            return soak::run(&bundle, &plugin_id, &host_info()?, &cfg);
+        }
+        Some(Command::Compare(CompareArgs { old, new, plugin_id, sample_rate, block, seconds })) => {
+            let (new, _) = load_plugin(&new, &plugin_id)?;
+            let (old, plugin_id) = load_plugin(&old, &plugin_id)?;
+            let cfg = compare::CompareConfig {
+                sample_rate: sample_rate as f64,
+                block: block.max(1),
+                frames: (seconds * sample_rate as f64) as usize,
+            };
+            return compare::run(&old, &new, &plugin_id, &host_info()?, &cfg);
+        }
+        Some(Command::Render(RenderArgs {
+            plugin,
+            out,
+            batch,
+            jobs,
+            stems,
+            seconds,
+            sample_rate,
+            block,
+            load_state,
+            param,
+        })) => {
+            let cfg = offline::OfflineConfig {
+                sample_rate: sample_rate as f64,
+                max_block: block.max(1),
+                frames: (seconds * sample_rate as f64) as usize,
+            };
+            let setup = offline::Setup { load_state: load_state.as_deref(), params: &param, ..Default::default() };
+            if let Some(dir) = batch {
+                let bank = Bank::scan(&dir, None).map_err(|e| format!("--batch: {e}"))?;
+                let jobs = jobs.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+                std::fs::create_dir_all(&out).map_err(|e| format!("--out {}: {e}", out.display()))?;
+                return offline::batch(
+                    &plugin.plugin,
+                    &plugin.plugin_id,
+                    &cfg,
+                    &setup,
+                    &dir,
+                    bank.presets(),
+                    &out,
+                    stems,
+                    jobs,
+                );
+            }
+            let (bundle, plugin_id) = load_plugin(&plugin.plugin, &plugin.plugin_id)?;
+            return offline::bounce(&bundle, &plugin_id, &host_info()?, &cfg, &setup, &out, stems);
+        }
+    };
+    lifecycle::log(Event::HostStarted);
+
+    // Absolute, so it still resolves after --plugin-cwd
+    let plugin = args.plugin.as_ref().expect("clap requires a plugin to run");
+    let plugin_path = std::path::absolute(plugin)?;
 
-    let plugin_path = args.plugin;
+    // Plugins may read these while loading (license files, resource paths,
+    // debug switches), so set them up before touching the bundle.
+    for (key, value) in &args.plugin_env {
+        // SAFETY: no other threads exist yet
+        unsafe { std::env::set_var(key, value) };
+    }
+    if let Some(locale) = &args.plugin_locale {
+        // SAFETY: as above
+        unsafe { std::env::set_var("LC_ALL", locale) };
+    }
+    if let Some(dir) = &args.plugin_cwd {
+        std::env::set_current_dir(dir)
+            .map_err(|e| format!("Can't change to {}: {e}", dir.display()))?;
+    }
 
     println!("Loading bundle: {}", plugin_path.display());
 
     // Load bundle (FFI boundary)
     let bundle = unsafe { PluginBundle::load(&plugin_path) }
         .map_err(|e| format!("Failed to load bundle: {e:?}"))?;
+    lifecycle::log(Event::BundleLoaded(&plugin_path));
 
     let factory = bundle
         .get_plugin_factory()
         .ok_or("Bundle has no plugin factory")?;
 
-    // Choose a generator that needs no MIDI
-    let target_id = "in.lsp-plug.noise_generator_x1";
+    let target_id = args.plugin_id.as_str();
     let mut target_desc = None;
     for d in factory.plugin_descriptors() {
         if let Some(id) = d.id() {
@@ -56,28 +1108,136 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
     let Some(desc) = target_desc else {
-        eprintln!("Could not find {target_id} in this bundle.");
+        eprintln!("{}", unknown_plugin_id(target_id, factory.plugin_descriptors().filter_map(|d| d.id())));
         std::process::exit(3);
     };
+    let plugin_id = desc.id().expect("descriptor must have id");
 
-    println!("Instantiating {target_id}…");
+    let host_info = host_info()?;
 
-    // Host identity (name, vendor, url, version)
-    let host_info = HostInfo::new(
-        "jack_minimal_clap",
-        "Giles",
-        "https://example.invalid",
-        "0.1.0",
-    )?;
+    if args.block_size_test {
+        let cfg = offline::OfflineConfig {
+            sample_rate: args.sample_rate as f64,
+            max_block: args.max_block.max(1),
+            frames: (args.seconds * args.sample_rate as f64) as usize,
+        };
+        // 1 for a plugin that fails the test, 2 if it couldn't be tested
+        match offline::block_size_test(&bundle, plugin_id, &host_info, &cfg)? {
+            offline::BlockSizeOutcome::Independent => return Ok(()),
+            offline::BlockSizeOutcome::DependsOnBlockSize => std::process::exit(1),
+            offline::BlockSizeOutcome::NotDeterministic => std::process::exit(2),
+        }
+    }
+
+    println!("Instantiating {target_id}…");
 
-    // Create instance
-    let mut instance = PluginInstance::<MyHost>::new(
-        |_| MyHostShared,
-        |_| (),
-        &bundle,
-        desc.id().expect("descriptor must have id"),
-        &host_info,
-    )?;
+    // Create instance, with a preset first so a saved state and --param can
+    // adjust it
+    let fresh = || -> Result<PluginInstance<MyHost>, Box<dyn std::error::Error>> {
+        let mut instance = instantiate(&bundle, plugin_id, &host_info)?;
+        lifecycle::log(Event::Instantiated(target_id));
+        if let Some(location) = &args.preset {
+            presets::load(&mut instance, location).map_err(|e| format!("--preset: {e}"))?;
+        }
+        Ok(instance)
+    };
+    let mut instance = fresh()?;
+    let mut save_state = args.save_state.clone();
+    let identity = state::Identity::of(&bundle, plugin_id);
+    if let Some(path) = &args.load_state {
+        if !load_state_or_defaults(&mut instance, path, &identity, &fresh)? && save_state.as_deref() == Some(path.as_path()) {
+            eprintln!("--save-state: not saving over {} this time", path.display());
+            save_state = None;
+        }
+    }
+    if args.preset_bank.is_none() && (args.preset_tag.is_some() || !args.preset_trigger.is_empty()) {
+        return Err("--preset-tag and --preset-trigger need a --preset-bank".into());
+    }
+    let bank = match &args.preset_bank {
+        Some(dir) => Some(Bank::scan(dir, args.preset_tag.as_deref()).map_err(|e| format!("--preset-bank: {e}"))?),
+        None => None,
+    };
+    if let Some(bank) = &bank {
+        println!("Preset bank: {} preset(s)", bank.len());
+    }
+    let defined = args.macros.iter().cloned().chain(args.at.iter().cloned().map(At::into_macro));
+    let macros = Macros::new(defined.collect())?;
+    let mut schedule = Schedule::new(&args.at, &macros);
+    if args.slot.len() > args.slot_notes.len() {
+        return Err(format!("--slot: {} slots but only {} --slot-notes", args.slot.len(), args.slot_notes.len()).into());
+    }
+    let mut macro_triggers = Vec::new();
+    for trigger in &args.macro_trigger {
+        let index = macros.index(&trigger.name);
+        let index = index.ok_or_else(|| format!("--macro-trigger: no --macro called {:?}", trigger.name))?;
+        macro_triggers.push((trigger.source, index));
+    }
+    let layout = Layout::query(&mut instance);
+    // The output stages below work on out_l/out_r: the front pair of a
+    // surround main output, or a mono one on both
+    let main_channels = match layout.outputs.first() {
+        Some(main) if main.channels > 0 => main.channels,
+        _ => return Err("the plugin has no audio output".into()),
+    };
+    // Our JACK outputs in order: out_l/out_r, then one per further channel
+    let output_names: Vec<String> = ["out_l", "out_r"]
+        .map(String::from)
+        .into_iter()
+        .chain(layout::jack_names(&layout.outputs, "out").drain(main_channels.min(2)..))
+        .collect();
+    let wide = match (args.prefer_f64, layout.supports_f64()) {
+        (true, true) => {
+            println!("Processing in 64-bit floating point");
+            true
+        }
+        (true, false) => {
+            eprintln!("--prefer-f64: the plugin can't take 64-bit audio on all its ports; processing in 32-bit");
+            false
+        }
+        (false, _) => {
+            if layout.prefers_f64() {
+                println!("The plugin prefers 64-bit audio; --prefer-f64 would give it that");
+            }
+            false
+        }
+    };
+    let mut note_ins = note_ports(&mut instance, true);
+    let note_outs = note_ports(&mut instance, false);
+    let has_notes = !note_ins.is_empty();
+    let mut pending: Vec<Change> = params::resolve(&mut instance, &args.param)
+        .map_err(|e| format!("--param: {e}"))?
+        .into_iter()
+        .map(|(id, value)| Change::Param(id, value))
+        .collect();
+    pending.reserve(repl::QUEUE_LEN);
+    let switches = Arc::new(Switches::default());
+    let mut map = mapfile::MapFile { map: args.map.clone(), chokes: args.choke.clone() };
+    for path in &args.map_file {
+        let file = mapfile::load(path).map_err(|e| format!("--map-file: {e}"))?;
+        map.map.extend(file.map);
+        map.chokes.extend(file.chokes);
+    }
+    // The default map, then each --map-profile over it
+    let mut profiles = MapProfiles { names: vec!["default".to_string()], feedback: Vec::new() };
+    let mut cc_maps = vec![build_cc_map(&mut instance, &map, switches.clone(), &output_names, args.cc_curve)?];
+    for (name, path) in &args.map_profile {
+        let file = mapfile::load(path).map_err(|e| format!("--map-profile {name}: {e}"))?;
+        let profile = mapfile::MapFile {
+            map: map.map.iter().cloned().chain(file.map).collect(),
+            chokes: map.chokes.iter().cloned().chain(file.chokes).collect(),
+        };
+        cc_maps.push(build_cc_map(&mut instance, &profile, switches.clone(), &output_names, args.cc_curve)?);
+        profiles.names.push(name.clone());
+    }
+    if cc_maps.len() > 128 {
+        return Err("--map-profile: at most 127 profiles, one per --map-switch value".into());
+    }
+    profiles.feedback = cc_maps.iter().map(CcMap::param_targets).collect();
+    let map_switched = Arc::new(AtomicU8::new(0));
+    let mut maps = MidiMaps::new(cc_maps, args.map_switch, map_switched.clone());
+    if !args.tuning.is_finite() || args.tuning <= 0.0 {
+        return Err(format!("--tuning: {} is not a pitch in Hz", args.tuning).into());
+    }
 
     // Open JACK first to use its real SR / block size
     let (jack_client, _status) = Client::new("clap_to_jack", ClientOptions::NO_START_SERVER)
@@ -85,105 +1245,1627 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let sample_rate = jack_client.sample_rate() as f64;
     let frames      = jack_client.buffer_size() as u32;
     println!("JACK: sr={sample_rate}, buffer={frames}");
+    maps.set_smoothing(sample_rate, args.cc_smoothing / 1000.0);
 
-    // Activate plugin with JACK params
-    let audio_cfg = PluginAudioConfiguration {
+    // Activate plugin with JACK params. The period can change under us
+    // (PipeWire quantum changes), so activate for a range rather than one size.
+    let max_frames = args.max_frames.unwrap_or(frames.max(DEFAULT_MAX_FRAMES)).max(1);
+    let mut audio_cfg = PluginAudioConfiguration {
         sample_rate,
-        min_frames_count: frames,
-        max_frames_count: frames,
+        min_frames_count: 1,
+        max_frames_count: max_frames,
     };
+    let mut activation = PortActivation::new(&mut instance, &layout, wide);
     let audio_proc_stopped = instance.activate(|_, _| (), audio_cfg)?;
+    lifecycle::log(Event::Activated { sample_rate, min_frames: 1, max_frames });
     let audio_proc_started = audio_proc_stopped.start_processing()?;
+    lifecycle::log(Event::ProcessingStarted);
+    // Only meaningful once activated; reported to JACK from the latency callback
+    let latency = Arc::new(AtomicU32::new(plugin_latency(&mut instance)));
+    if latency.load(Ordering::Relaxed) > 0 {
+        println!("Plugin latency: {} frames", latency.load(Ordering::Relaxed));
+    }
 
     // Register JACK outs
-    let out_l = jack_client.register_port("out_l", AudioOut::default()).expect("jack L");
-    let out_r = jack_client.register_port("out_r", AudioOut::default()).expect("jack R");
+    let port_name = |name: &str| prefixed_port(args.port_prefix.as_deref(), name);
+    let out_l = jack_client.register_port(&port_name("out_l"), AudioOut::default()).expect("jack L");
+    let out_r = jack_client.register_port(&port_name("out_r"), AudioOut::default()).expect("jack R");
+    let out_names = [out_l.name()?, out_r.name()?];
+    // Any further outputs (the rest of a surround main, aux buses, multi-out
+    // instruments) get ports of their own
+    let mut aux_out = Vec::new();
+    let mut aux_names = Vec::new();
+    for name in &output_names[2..] {
+        let port = jack_client.register_port(&port_name(name), AudioOut::default())?;
+        aux_names.push(port.name()?);
+        aux_out.push(port);
+    }
+    if !aux_names.is_empty() {
+        println!("Also playing out of {}", aux_names.join(", "));
+    }
+    // Inputs only for plugins that take audio, so effects can process live
+    // sound: the main pair plus e.g. sidechains, unless an --input-file
+    // feeds the port instead
+    let mut input_files = Vec::new();
+    for (name, path) in &args.input_file {
+        let Some(port) = layout.inputs.iter().position(|p| p.name == *name) else {
+            let names: Vec<&str> = layout.inputs.iter().map(|p| p.name.as_str()).collect();
+            return Err(format!("--input-file: the plugin has no input {name:?} (it has {})", names.join(", ")).into());
+        };
+        input_files.push(InputFile::open(path, &layout.inputs, port, sample_rate)?);
+        println!("Looping {} into the plugin's {name} input", path.display());
+    }
+    let mut ins = Vec::new();
+    let mut in_names = Vec::new();
+    // one per input channel, empty for those fed from a file
+    let mut channel_names = Vec::new();
+    for (channel, name) in layout::jack_names(&layout.inputs, "in").into_iter().enumerate() {
+        if input_files.iter().any(|file| file.feeds(channel)) {
+            channel_names.push(String::new());
+            continue;
+        }
+        let port = jack_client.register_port(&port_name(&name), AudioIn::default())?;
+        in_names.push(port.name()?);
+        channel_names.push(port.name()?);
+        ins.push((channel, port));
+    }
+    if !in_names.is_empty() {
+        println!("Feed audio into {}", in_names.join(" / "));
+    }
+    let envelope = Arc::new(envelope::Level::default());
+    let follower = match &args.envelope_follow {
+        None => None,
+        Some(port) => {
+            let names = layout::jack_names(&layout.inputs, "in");
+            let Some(channel) = names.iter().position(|name| name == port) else {
+                return Err(format!("--envelope-follow: no input {port:?} (we have {})", names.join(", ")).into());
+            };
+            let target = match &args.envelope_param {
+                Some(param) => {
+                    let param = params::lookup(&mut instance, param).map_err(|e| format!("--envelope-param: {e}"))?;
+                    Some(envelope::Target { id: param.id, min: param.min, max: param.max })
+                }
+                None => None,
+            };
+            let (attack, release) = (args.envelope_attack / 1000.0, args.envelope_release / 1000.0);
+            Some(Follower::new(channel, sample_rate, attack, release, envelope.clone(), target))
+        }
+    };
+    if let Some(activation) = &mut activation {
+        // the plugin's output channels in order, as they're rendered
+        let channels: Vec<String> = out_names[..main_channels.min(2)].iter().chain(&aux_names).cloned().collect();
+        activation.watch(&layout, &channel_names, &channels);
+    }
+    // A MIDI in for each of the plugin's note inputs (multi-timbral plugins
+    // have several), or just the one if only its parameters are mapped to
+    // controllers or presets to triggers; a MIDI out for each note output
+    let mut midi_ins = Vec::new();
+    let mut midi_names = Vec::new();
+    let mapped = !(map.map.is_empty() && map.chokes.is_empty() && args.map_profile.is_empty());
+    // what each queue into the audio thread had to drop
+    let dropped = Arc::new(Dropped::default());
+    let raw_midi = match args.midi_backend {
+        MidiBackend::Jack => None,
+        MidiBackend::AlsaRaw { card, device } => Some(rawmidi::spawn(card, device, dropped.clone())?),
+    };
+    let triggered = !(args.preset_trigger.is_empty() && macro_triggers.is_empty() && args.slot.is_empty());
+    if note_ins.is_empty() && (mapped || triggered || raw_midi.is_some()) {
+        note_ins.push((String::new(), NotePort::default()));
+    }
+    for (i, (name, note_port)) in note_ins.iter().enumerate() {
+        let port = jack_client.register_port(&port_name(&midi_port_name("midi_in", name, i)), MidiIn::default())?;
+        midi_names.push(port.name()?);
+        midi_ins.push((port, *note_port));
+    }
+    if !midi_names.is_empty() {
+        println!("Play notes into {}", midi_names.join(", "));
+    }
+    let mut midi_outs = Vec::new();
+    for (i, (name, _)) in note_outs.iter().enumerate() {
+        let port = jack_client.register_port(&port_name(&midi_port_name("midi_out", name, i)), MidiOut::default())?;
+        println!("The plugin's notes come out of {}", port.name()?);
+        midi_outs.push(port);
+    }
+    if args.arp.is_some() && !has_notes {
+        eprintln!("--arp: plugin takes no notes, so there is nothing to arpeggiate");
+    }
+    // Where a controller hears the values of what it controls after a switch
+    let map_feedback = match args.map_profile.is_empty() {
+        true => None,
+        false => {
+            let port = jack_client.register_port(&port_name("map_feedback"), MidiOut::default())?;
+            println!("Controller feedback for map switches comes out of {}", port.name()?);
+            Some(port)
+        }
+    };
+    let click_out = if args.click && args.click_port {
+        Some(jack_client.register_port(&port_name("click"), AudioOut::default())?)
+    } else {
+        None
+    };
+
+    // Per-port latency offsets, keyed by full port name for the latency callback
+    let audio_names: Vec<&String> = out_names.iter().chain(&aux_names).chain(&in_names).collect();
+    let mut latency_offsets: Vec<(String, u32)> = Vec::new();
+    for (port, frames) in &args.port_latency {
+        // a full name is client:port, where port is what we registered
+        let ours = |full: &&&String| **full == port || full.split_once(':').is_some_and(|(_, short)| short == port_name(port));
+        let Some(full) = audio_names.iter().find(ours) else {
+            let names: Vec<&str> = audio_names.iter().map(|n| n.as_str()).collect();
+            return Err(format!("--port-latency: no port named {port:?} (we have {})", names.join(", ")).into());
+        };
+        match latency_offsets.iter_mut().find(|(n, _)| n == *full) {
+            Some((_, total)) => *total += frames,
+            None => latency_offsets.push(((*full).clone(), *frames)),
+        }
+    }
+
+    let true_peaks = Arc::new(TruePeakStats::default());
+    let correlation = Arc::new(Correlation::default());
+    let limiter = args.true_peak_limit
+        .map(|ceiling| TruePeakLimiter::new(sample_rate, ceiling, args.true_peak_release, true_peaks.clone()));
+    // The limiter delays out_l/out_r the way a --port-latency offset would
+    if let Some(limiter) = &limiter {
+        for name in &out_names {
+            match latency_offsets.iter_mut().find(|(n, _)| n == name) {
+                Some((_, frames)) => *frames += limiter.latency(),
+                None => latency_offsets.push((name.clone(), limiter.latency())),
+            }
+        }
+    }
+
+    // Console commands are handled here on the main thread and reach the
+    // audio thread through this queue
+    let (changes_tx, changes) = repl::queue();
 
     // Move processor into handler
+    let bypass = Arc::new(AtomicBool::new(false));
+    let jack_out = Arc::new(AtomicBool::new(true));
+    let faulted = Arc::new(AtomicBool::new(false));
+    let health = Arc::new(OutputHealth::default());
+    let deadlines = Arc::new(DeadlineStats::default());
+    // Always some recent output, for `record` to take from; --retro keeps more for `dump`
+    let retro = Arc::new(RetroBuffer::new(sample_rate, args.retro.unwrap_or(0.0)));
+    let tail = Arc::new(AtomicU64::new(shutdown::TAIL_UNKNOWN));
+    let (mut restarter, restart_audio) = restart::channel();
+    // What JACK is running at now, to compare with what the plugin was activated for
+    let period = Arc::new(AtomicU32::new(frames));
+    let rate = Arc::new(AtomicU32::new(sample_rate as u32));
+    // what the audio thread resamples between, under --sr-policy resample
+    let rates = Arc::new(Rates::default());
+    rates.plugin.store(sample_rate as u32, Ordering::Relaxed);
+    let ports_changed = Arc::new(AtomicBool::new(false));
+    // set to begin with, for anything connected before we were watching
+    let connections_changed = Arc::new(AtomicBool::new(true));
+    let preset_step = Arc::new(AtomicU8::new(0));
+    let macro_fired = Arc::new(AtomicU32::new(0));
+    let slot_fired = Arc::new(AtomicU32::new(0));
+    let edits = Arc::new(Edits::default());
+    let mut recovery = args.recovery_file.clone().map(Recovery::new);
+    let note_ports = note_ins.iter().map(|(_, port)| port.index as usize + 1).max().unwrap_or(1);
+    let tuning = Tuning::new(args.tuning, args.master_transpose, note_ports);
+    let preset_switched = args.preset_gain_match.then(|| Arc::new(AtomicBool::new(false)));
     let handler = JackHandler {
-        proc: audio_proc_started,
+        proc: Some(audio_proc_started),
+        restart: restart_audio,
+        max_frames,
+        steady_time: 0,
+        period: period.clone(),
         out_l,
         out_r,
-        in_l: Vec::new(),
-        in_r: Vec::new(),
-        scratch_l: Vec::new(),
-        scratch_r: Vec::new(),
+        aux_out,
+        main_channels,
+        ins,
+        input_files,
+        follower,
+        note_port: midi_ins.first().map_or(NotePort::default(), |(_, port)| *port),
+        midi_ins,
+        midi_outs,
+        staged: Vec::with_capacity(1024),
+        raw_midi,
+        midi_out_queue: Vec::with_capacity(1024),
+        maps,
+        map_feedback,
+        feedback_queue: Vec::with_capacity(repl::QUEUE_LEN),
+        tuning,
+        triggers: Triggers {
+            presets: args.preset_trigger.clone(),
+            preset_step: preset_step.clone(),
+            macros: macro_triggers,
+            macro_fired: macro_fired.clone(),
+            slot_notes: args.slot_notes,
+            slots: args.slot.len(),
+            slot_fired: slot_fired.clone(),
+        },
+        edits: edits.clone(),
+        scale: args.scale,
+        arp: args.arp.map(|mode| Arp::new(mode, sample_rate, args.arp_bpm, args.arp_rate, args.arp_gate)),
+        // sized for MAX_EVENTS of the largest events we send
+        events: EventBuffer::with_capacity(MAX_EVENTS * 4),
+        dropped: dropped.clone(),
+        out_events: EventBuffer::with_capacity(1024),
+        pending,
+        changes,
+        // not vec![]: cloning a Vec doesn't keep its capacity
+        inputs: (0..layout.input_channels()).map(|_| Vec::with_capacity(MAX_PERIOD)).collect(),
+        out_slices: Vec::with_capacity(layout.output_channels()),
+        resampler: (args.sr_policy == SrPolicy::Resample)
+            .then(|| Resampler::new(layout.input_channels(), layout.output_channels(), MAX_PERIOD)),
+        rates: rates.clone(),
+        wide: wide.then(|| Wide {
+            inputs: vec![vec![0.0; MAX_PERIOD]; layout.input_channels()],
+            outputs: vec![vec![0.0; MAX_PERIOD]; layout.output_channels()],
+        }),
+        ports: [
+            AudioPorts::with_capacity(layout.input_channels(), layout.inputs.len()),
+            AudioPorts::with_capacity(layout.output_channels(), layout.outputs.len()),
+        ],
+        layout,
+        stereo: StereoStage::new(args.width, args.balance),
+        invert: args.invert_polarity.map_or([false, false], Polarity::channels),
+        gate: args.loudness_gate
+            .map(|lufs| LoudnessGate::new(sample_rate, lufs, args.gate_timeout, args.gate_fade)),
+        gain_match: preset_switched.clone().map(|switched| GainMatch::new(sample_rate, args.gain_match_decay, switched)),
+        limiter,
+        correlation: CorrelationMeter::new(sample_rate, correlation.clone()),
+        mutes: OutputGains::new(switches.clone(), output_names.len(), sample_rate),
+        fade: Fade::default(),
+        dip: Fade::default(),
+        bypass: bypass.clone(),
+        jack_out: jack_out.clone(),
+        faulted: faulted.clone(),
+        health: HealthMonitor::new(health.clone(), MAX_PERIOD),
+        deadlines: deadlines.clone(),
+        retro: retro.clone(),
+        mark_bars: args.record_sync == Some(RecordSync::Bar),
+        tail: tail.clone(),
+        click: args.click.then(|| Click::new(sample_rate, args.click_bpm, args.click_level)),
+        click_out,
+        click_buf: Vec::with_capacity(MAX_PERIOD),
+        transport: jack_client.transport(),
+        transport_sync: TransportSync::new(sample_rate),
+        reset_on_locate: args.reset_on_locate,
+        output_rate: sample_rate,
+    };
+    let xruns = Arc::new(AtomicU64::new(0));
+    let notifications = JackNotifications {
+        xruns: xruns.clone(),
+        latency_offsets,
+        plugin_latency: latency.clone(),
+        in_names,
+        out_names: out_names.iter().chain(&aux_names).cloned().collect(),
+        rate: rate.clone(),
+        ports_changed: ports_changed.clone(),
+        connections_changed: connections_changed.clone(),
+    };
+    let active = jack_client.activate_async(notifications, handler).expect("activate JACK failed");
+    lifecycle::log(Event::JackActivated(active.as_client().name()));
+
+    connect_outputs(active.as_client(), &out_names, &args.connect_out);
+
+    // Commands come from stdin and, optionally, OSC; both are handled here
+    let mut repl = Repl::new(changes_tx, bypass.clone(), active.as_client().transport(), correlation, dropped.clone());
+    if args.retro.is_some() {
+        repl.enable_dump(retro.clone(), args.dump_dir.clone());
+    }
+    repl.enable_sinks(Sinks::open(&args.sink, retro.clone(), jack_out)?);
+    repl.enable_record(Recorder::new(retro, args.record_sync), args.dump_dir.clone());
+    if let Some(bank) = bank {
+        repl.enable_bank(bank);
+    }
+    repl.enable_macros(macros);
+    if !args.slot.is_empty() {
+        let fade = Duration::from_secs_f64(args.slot_fade.max(0.0) / 1000.0);
+        repl.enable_slots(Slots::spawn(args.slot.clone(), identity.clone(), fade));
+    }
+    repl.enable_switches(switches, output_names);
+    repl.track_edits(edits.clone());
+    if !args.map_profile.is_empty() {
+        repl.enable_maps(profiles);
+    }
+    if let Some(switched) = preset_switched {
+        repl.enable_gain_match(switched);
+    }
+    let (commands_tx, commands) = mpsc::sync_channel(COMMAND_QUEUE_LEN);
+    if let Some(port) = args.osc_port {
+        osc::spawn(args.osc_bind, port, commands_tx.clone(), dropped.clone())?;
+        println!("Listening for OSC on {}:{port} (UDP)", args.osc_bind);
+    }
+    // The last sender, so without OSC the channel disconnects when stdin closes
+    repl::spawn_reader(commands_tx);
+    let mut commands = Some(commands);
+    // SIGINT/SIGTERM ask the main loop to shut down as a guardrail exit would
+    let quit = shutdown::on_signal()?;
+    println!("Type `help` for live commands, Ctrl+C to quit.");
+
+    let limits = Limits {
+        max_rss_mb: args.max_rss,
+        max_run_time: args.max_run_time.map(Duration::from_secs),
+        max_xruns_per_min: args.max_xruns_per_min,
+        max_silence: args.max_silence.map(Duration::from_secs),
+        max_stuck: args.max_stuck.map(Duration::from_secs),
     };
-    let _active = jack_client.activate_async((), handler).expect("activate JACK failed");
+    // Without a GUI the plugin still runs, so a failure here isn't fatal
+    let mut gui = None;
+    if args.gui {
+        match gui::open(&mut instance, target_id) {
+            Ok(opened) => gui = Some(opened),
+            Err(e) => eprintln!("--gui: {e}"),
+        }
+    }
+    repl.enable_gui(target_id, gui);
 
-    println!("Running. Connect to playback, e.g.:");
-    println!("  jack_connect \"clap_to_jack:out_l\" \"USB Audio Analog Stereo:playback_FL\"");
-    println!("  jack_connect \"clap_to_jack:out_r\" \"USB Audio Analog Stereo:playback_FR\"");
-    println!("Ctrl+C to quit.");
-    loop { std::thread::park(); }
+    let (poll, check_every) = match args.low_power {
+        true => (LOW_POWER_POLL, LOW_POWER_GUARD_CHECK),
+        false => (MAIN_THREAD_POLL, GUARD_CHECK),
+    };
+    let mut fault_reported = false;
+    let mut dropped_reported = dropped.counts();
+    let mut guard = Guard::new(limits, xruns, health, sample_rate);
+    let alerts = args.osc_alert.map(osc::Outgoing::new).transpose()?;
+    let envelope_osc = args.envelope_osc.map(osc::Outgoing::new).transpose()?;
+    let mut next_envelope = Instant::now();
+    let mut next_check = Instant::now() + check_every;
+    // None when interrupted, else the guardrail that tripped
+    let tripped = 'run: loop {
+        if quit.requested() {
+            break 'run None;
+        }
+        // Serve commands until the next guard check, plugin timer or poll
+        // for plugin callbacks is due
+        let mut until = next_check.min(Instant::now() + poll);
+        if let Some(due) = instance.access_handler(|host| host.timers.next_due()) {
+            until = until.min(due);
+        }
+        if envelope_osc.is_some() {
+            until = until.min(next_envelope);
+        }
+        if let Some(due) = repl.next_macro_due() {
+            until = until.min(due);
+        }
+        if let Some(due) = schedule.next_due() {
+            until = until.min(due);
+        }
+        if let Some(due) = repl.next_slot_due() {
+            until = until.min(due);
+        }
+        let wait = until.saturating_duration_since(Instant::now());
+        match commands.as_ref().map(|commands| commands.recv_timeout(wait)) {
+            Some(Ok((source, line))) => {
+                repl.handle(&mut instance, source, &line);
+                for (source, line) in commands.iter().flat_map(|c| c.try_iter()).take(COMMANDS_PER_POLL - 1) {
+                    repl.handle(&mut instance, source, &line);
+                }
+            }
+            Some(Err(RecvTimeoutError::Timeout)) => {}
+            Some(Err(RecvTimeoutError::Disconnected)) => commands = None,
+            None => std::thread::sleep(wait),
+        }
+        repl.poll_recording();
+        repl.poll_sinks();
+        if let Some(out) = envelope_osc.as_ref().filter(|_| Instant::now() >= next_envelope) {
+            out.envelope(envelope.get());
+            next_envelope = Instant::now() + ENVELOPE_OSC_EVERY;
+        }
+        if !args.connect_out.is_empty() && ports_changed.swap(false, Ordering::Relaxed) {
+            reconnect_outputs(active.as_client(), &out_names, &args.connect_out);
+        }
+        if faulted.load(Ordering::Relaxed) && !fault_reported {
+            fault_reported = true;
+            lifecycle::log(Event::Faulted);
+            eprintln!("Audio processing panicked; the plugin stays silent for the rest of this run");
+        }
+        let counts = dropped.counts();
+        if counts != dropped_reported {
+            let new = std::array::from_fn(|i| counts[i] - dropped_reported[i]);
+            if let Some(what) = dropped::describe(&new) {
+                eprintln!("Dropped {what}: more than the queues hold");
+            }
+            dropped_reported = counts;
+        }
+        if instance.access_shared_handler(|host| host.callback.swap(false, Ordering::Relaxed)) {
+            instance.call_on_main_thread_callback();
+        }
+        timer::fire_due(&mut instance);
+        if let Some(step) = Step::from_code(preset_step.swap(0, Ordering::Relaxed)) {
+            repl.step_preset(&mut instance, step);
+        }
+        if let Some(index) = map_switched.swap(0, Ordering::Relaxed).checked_sub(1) {
+            repl.map_switched(&mut instance, index as usize);
+        }
+        if let Some(index) = macro_fired.swap(0, Ordering::Relaxed).checked_sub(1) {
+            repl.start_macro(index as usize);
+        }
+        for index in schedule.take_due() {
+            repl.start_macro(index);
+        }
+        if let Some(index) = slot_fired.swap(0, Ordering::Relaxed).checked_sub(1) {
+            repl.recall_slot(index as usize);
+        }
+        repl.poll_slots(&mut instance);
+        repl.run_macros(&mut instance);
+        if instance.access_handler_mut(|host| std::mem::take(&mut host.marked_dirty)) {
+            edits.mark();
+        }
+        if let Some(recovery) = &mut recovery {
+            recovery.poll(&mut instance, &edits, &identity);
+        }
+        // Restart when the plugin asks, or reactivate when JACK's sample rate
+        // changes (unless we can resample instead) or its period outgrows
+        // what the plugin was activated for
+        let requested = instance.access_shared_handler(|host| host.restart.swap(false, Ordering::Relaxed));
+        let (jack_rate, jack_period) = (rate.load(Ordering::Relaxed) as f64, period.load(Ordering::Relaxed));
+        // the guard times silence and stuck output in JACK's frames
+        guard.set_sample_rate(jack_rate);
+        let resample = args.sr_policy == SrPolicy::Resample
+            && jack_rate != audio_cfg.sample_rate
+            && resample::supports(audio_cfg.sample_rate, jack_rate, jack_period, MAX_PERIOD);
+        let from = if resample { jack_rate as u32 } else { 0 };
+        if rates.jack.swap(from, Ordering::Relaxed) != from {
+            match resample {
+                true => println!("JACK: sr={jack_rate}; resampling to the plugin's {}", audio_cfg.sample_rate),
+                false => println!("JACK: sr={jack_rate}; no longer resampling"),
+            }
+            instance.access_handler_mut(|host| host.latency_changed = true);
+        }
+        let reconfigure = (jack_rate != audio_cfg.sample_rate && !resample) || jack_period > audio_cfg.max_frames_count;
+        // and to turn the plugin's extra ports on or off as they're connected
+        let reconnected = connections_changed.swap(false, Ordering::Relaxed)
+            && activation.as_ref().is_some_and(|activation| activation.stale(active.as_client()));
+        let mut restarted = false;
+        if requested || reconfigure || reconnected {
+            if requested {
+                lifecycle::log(Event::RestartRequested);
+            }
+            if reconfigure {
+                println!("JACK: sr={jack_rate}, buffer={jack_period}; reactivating the plugin");
+                audio_cfg = PluginAudioConfiguration {
+                    sample_rate: if resample { audio_cfg.sample_rate } else { jack_rate },
+                    min_frames_count: 1,
+                    max_frames_count: audio_cfg.max_frames_count.max(jack_period),
+                };
+            }
+            let timeout = Duration::from_secs(args.shutdown_timeout);
+            let client = active.as_client();
+            let while_inactive = |instance: &mut PluginInstance<MyHost>| {
+                if let Some(activation) = &mut activation {
+                    activation.apply(instance, client);
+                }
+            };
+            match restarter.restart(&mut instance, audio_cfg, timeout, while_inactive) {
+                Ok(()) => restarted = true,
+                Err(e) => eprintln!("Plugin restart: {e}"),
+            }
+        }
+        // or one that timed out waiting for the audio thread may finish now
+        match restarter.check_started(&mut instance) {
+            Ok(finished) => restarted |= finished,
+            Err(e) => eprintln!("Plugin restart: {e}"),
+        }
+        if restarted {
+            rates.plugin.store(audio_cfg.sample_rate as u32, Ordering::Relaxed);
+            lifecycle::log(Event::Activated {
+                sample_rate: audio_cfg.sample_rate,
+                min_frames: 1,
+                max_frames: audio_cfg.max_frames_count,
+            });
+            // Restarts are usually for a new latency
+            instance.access_handler_mut(|host| host.latency_changed = true);
+        }
+        if instance.access_handler_mut(|host| std::mem::take(&mut host.latency_changed)) {
+            let mut frames = plugin_latency(&mut instance);
+            println!("Plugin latency is now {frames} frames");
+            // in JACK's frames, and the resampler's on top
+            let jack = rates.jack.load(Ordering::Relaxed);
+            if jack != 0 {
+                let (jack, plugin) = (jack as f64, audio_cfg.sample_rate);
+                frames = (frames as f64 * jack / plugin).round() as u32 + resample::latency(plugin, jack);
+                println!("{frames} frames with resampling");
+            }
+            latency.store(frames, Ordering::Relaxed);
+            if let Err(e) = active.as_client().recompute_total_latencies() {
+                eprintln!("Could not update JACK latencies: {e}");
+            }
+        }
+        repl.poll_gui(&mut instance);
+        if Instant::now() < next_check {
+            continue;
+        }
+        next_check += check_every;
+
+        for breach in guard.check() {
+            let reason = breach.to_string();
+            lifecycle::log(Event::GuardTripped(&reason));
+            if let Some(alerts) = &alerts {
+                alerts.alert(breach.kind(), &reason);
+            }
+            match args.guard_action {
+                GuardAction::Log => eprintln!("Guardrail: {reason}"),
+                GuardAction::Bypass => {
+                    eprintln!("Guardrail: {reason}; bypassing plugin");
+                    bypass.store(true, Ordering::Relaxed);
+                }
+                GuardAction::Exit => break 'run Some(reason),
+            }
+        }
+    };
+
+    match &tripped {
+        Some(reason) => eprintln!("Guardrail: {reason}; shutting down"),
+        None => {
+            lifecycle::log(Event::Interrupted);
+            eprintln!("Interrupted; shutting down (again to force)");
+        }
+    }
+    // the tail is in the plugin's frames, at whatever rate it was last activated for
+    shutdown::ring_out(&mut repl, &quit, &tail, audio_cfg.sample_rate, Duration::from_secs_f64(args.max_tail));
+    repl.stop_recording();
+    repl.finish_sinks();
+    repl.close_gui(&mut instance);
+    shutdown::shutdown(active, &mut instance, Duration::from_secs(args.shutdown_timeout))?;
+    let mut kept = false;
+    if let Some(path) = &save_state {
+        match state::save(&mut instance, path, &identity) {
+            Ok(()) => {
+                lifecycle::log(Event::StateSaved(path));
+                println!("Saved state to {}", path.display());
+                edits.saved();
+                kept = true;
+            }
+            Err(e) => eprintln!("--save-state: {e}"),
+        }
+    }
+    if edits.dirty() && save_state.is_none() {
+        eprintln!("Exiting with unsaved changes to the plugin's settings; --save-state FILE keeps them");
+    }
+    if let Some(recovery) = recovery {
+        recovery.finish(&mut instance, &edits, &identity, kept);
+    }
+    deadlines.print();
+    if let Some(ceiling) = args.true_peak_limit {
+        true_peaks.print(ceiling);
+    }
+    match tripped {
+        Some(reason) => Err(format!("guardrail tripped: {reason}").into()),
+        None => Ok(()),
+    }
+}
+
+// Our port name, under --port-prefix if given
+fn prefixed_port(prefix: Option<&str>, name: &str) -> String {
+    match prefix {
+        Some(prefix) => format!("{prefix}:{name}"),
+        None => name.to_string(),
+    }
+}
+
+// Connect our (left, right) outputs to the --connect-out targets, or say how
+// to do it by hand if there are none
+fn connect_outputs(client: &Client, out_names: &[String; 2], connect_out: &[String]) {
+    let targets = resolve_connect_targets(client, connect_out, true);
+    if targets.is_empty() {
+        println!("Running. Connect to playback, e.g.:");
+        println!("  jack_connect \"{}\" \"USB Audio Analog Stereo:playback_FL\"", out_names[0]);
+        println!("  jack_connect \"{}\" \"USB Audio Analog Stereo:playback_FR\"", out_names[1]);
+    } else {
+        for (ours, theirs) in out_names.iter().zip(&targets) {
+            match client.connect_ports_by_name(ours, theirs) {
+                Ok(()) => println!("Connected {ours} -> {theirs}"),
+                Err(e) => eprintln!("Could not connect {ours} -> {theirs}: {e}"),
+            }
+        }
+        println!("Running.");
+    }
+}
+
+// Connect whichever of the --connect-out pairs aren't connected yet and now
+// can be, e.g. once a USB interface has been plugged in
+fn reconnect_outputs(client: &Client, out_names: &[String; 2], connect_out: &[String]) {
+    for (ours, theirs) in out_names.iter().zip(&resolve_connect_targets(client, connect_out, false)) {
+        let Some(port) = client.port_by_name(ours) else { continue };
+        if client.port_by_name(theirs).is_none() || port.is_connected_to(theirs).unwrap_or(false) {
+            continue;
+        }
+        match client.connect_ports_by_name(ours, theirs) {
+            Ok(()) => println!("Connected {ours} -> {theirs}"),
+            Err(e) => eprintln!("Could not connect {ours} -> {theirs}: {e}"),
+        }
+    }
+}
+
+// Expand --connect-out targets into concrete JACK port names.
+// `@default-sink` resolves to the physical playback ports the server reports,
+// so the same command line works whatever the interface is called.
+fn resolve_connect_targets(client: &Client, targets: &[String], warn: bool) -> Vec<String> {
+    let mut resolved = Vec::new();
+    for target in targets {
+        if target == "@default-sink" {
+            let sinks = client.ports(None, Some("audio"), PortFlags::IS_INPUT | PortFlags::IS_PHYSICAL);
+            if sinks.is_empty() && warn {
+                eprintln!("No physical playback ports found for @default-sink");
+            }
+            resolved.extend(sinks);
+        } else {
+            resolved.push(target.clone());
+        }
+    }
+    resolved
+}
+
+// Load a bundle and find the given plugin ID in it
+fn load_plugin(path: &Path, id: &str) -> Result<(PluginBundle, CString), Box<dyn std::error::Error>> {
+    let bundle = unsafe { PluginBundle::load(path) }
+        .map_err(|e| format!("Failed to load bundle: {e:?}"))?;
+    let factory = bundle.get_plugin_factory().ok_or("Bundle has no plugin factory")?;
+    let found = factory
+        .plugin_descriptors()
+        .filter_map(|d| d.id())
+        .find(|d| d.to_bytes() == id.as_bytes())
+        .map(CStr::to_owned);
+    let plugin_id = found
+        .ok_or_else(|| unknown_plugin_id(id, factory.plugin_descriptors().filter_map(|d| d.id())))?;
+    Ok((bundle, plugin_id))
+}
+
+// Error for an ID that isn't in the bundle, listing the ones that are
+fn unknown_plugin_id<'a>(id: &str, available: impl Iterator<Item = &'a CStr>) -> String {
+    let available: Vec<_> = available.map(|a| format!("  {}", a.to_string_lossy())).collect();
+    if available.is_empty() {
+        format!("Could not find {id} in this bundle; it contains no plugins.")
+    } else {
+        format!("Could not find {id} in this bundle. Available plugin IDs:\n{}", available.join("\n"))
+    }
+}
+
+// --load-state, recovering from a state the plugin won't take: rather than
+// abort, keep the file as .bad for a look later and carry on from a `fresh`
+// instance, since a half-applied state is worse than none. False if the
+// file was left alone unapplied, so it mustn't be saved over on exit either.
+fn load_state_or_defaults(
+    instance: &mut PluginInstance<MyHost>,
+    path: &Path,
+    plugin: &state::Identity,
+    fresh: impl Fn() -> Result<PluginInstance<MyHost>, Box<dyn std::error::Error>>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    match state::load(instance, path, plugin) {
+        Ok(()) => {
+            lifecycle::log(Event::StateLoaded(path));
+            println!("Loaded state from {}", path.display());
+        }
+        Err(state::LoadError::Missing) => println!("No state in {} yet; starting from defaults", path.display()),
+        // Nothing reached the plugin, and the file may well be good
+        Err(e @ (state::LoadError::Unsupported | state::LoadError::Io(_) | state::LoadError::Foreign(_))) => {
+            eprintln!("--load-state: {e}; starting from the plugin's defaults");
+            return Ok(false);
+        }
+        Err(state::LoadError::Rejected(e)) => {
+            eprintln!("--load-state: {e}");
+            match state::set_aside(path) {
+                Ok(bad) => eprintln!("Moved it to {}", bad.display()),
+                Err(e) => eprintln!("Could not move it aside: {e}"),
+            }
+            eprintln!("Starting from the plugin's defaults");
+            *instance = fresh()?;
+        }
+    }
+    Ok(true)
+}
+
+// Host identity (name, vendor, url, version)
+fn host_info() -> Result<HostInfo, HostError> {
+    HostInfo::new(
+        "jack_minimal_clap",
+        "Giles",
+        "https://example.invalid",
+        "0.1.0",
+    )
+}
+
+// Create a fresh, inactive instance of the given plugin
+fn instantiate(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+) -> Result<PluginInstance<MyHost>, HostError> {
+    PluginInstance::<MyHost>::new(
+        |_| MyHostShared::default(),
+        |_| MyHostMainThread::default(),
+        bundle,
+        plugin_id,
+        host_info,
+    )
+}
+
+// The plugin's note inputs or outputs (clap.note-ports), by name. We send
+// CLAP note events wherever the plugin takes them, and raw MIDI otherwise.
+fn note_ports(instance: &mut PluginInstance<MyHost>, is_input: bool) -> Vec<(String, NotePort)> {
+    let mut handle = instance.plugin_handle();
+    let Some(ports) = handle.get_extension::<PluginNotePorts>() else { return Vec::new() };
+    let mut buffer = NotePortInfoBuffer::new();
+    (0..ports.count(&mut handle, is_input))
+        .filter_map(|i| {
+            let info = ports.get(&mut handle, i, is_input, &mut buffer)?;
+            let clap = info.supported_dialects.contains(NoteDialects::CLAP);
+            Some((String::from_utf8_lossy(info.name).into_owned(), NotePort { index: i as u16, clap }))
+        })
+        .collect()
+}
+
+// midi_in, then midi_in_<the plugin's name for it>, as for audio ports
+fn midi_port_name(prefix: &str, name: &str, index: usize) -> String {
+    match index {
+        0 => prefix.to_string(),
+        _ => format!("{prefix}_{}", layout::port_name(name, index)),
+    }
+}
+
+// The plugin's tail in frames, u32::MAX for "forever" (clap.tail)
+fn tail_frames(proc: &mut StartedPluginAudioProcessor<MyHost>) -> u64 {
+    let mut handle = proc.plugin_handle();
+    match handle.get_extension::<PluginTail>().map(|tail| tail.get(&mut handle)) {
+        Some(TailLength::Finite(frames)) => frames as u64,
+        Some(TailLength::Infinite) => u32::MAX as u64,
+        None => 0,
+    }
+}
+
+// Frames of latency the plugin reports, 0 if it doesn't say
+fn plugin_latency(instance: &mut PluginInstance<MyHost>) -> u32 {
+    let mut handle = instance.plugin_handle();
+    handle
+        .get_extension::<PluginLatency>()
+        .map_or(0, |latency| latency.get(&mut handle))
+}
+
+// Run one block through the plugin with every port in `layout`. `inputs` and
+// `outputs` hold a buffer per channel, port after port, of which the plugin
+// gets frames `range`. `ports` are made once, for the layout, and reused.
+// `steady_time` is the frame count since the plugin started processing.
+#[allow(clippy::too_many_arguments)]
+fn process_ports(
+    proc: &mut StartedPluginAudioProcessor<MyHost>,
+    steady_time: u64,
+    transport: Option<&TransportEvent>,
+    input_events: &InputEvents,
+    output_events: &mut EventBuffer,
+    layout: &Layout,
+    [input_ports, output_ports]: &mut [AudioPorts; 2],
+    inputs: &mut [Vec<f32>],
+    outputs: &mut [&mut [f32]],
+    range: Range<usize>,
+) -> Result<ProcessStatus, PluginInstanceError> {
+    let mut output_events = OutputEvents::from_buffer(output_events);
+
+    let Range { start, end } = range;
+    let in_audio = input_ports.with_input_buffers(layout::split(inputs, &layout.inputs).map(|port| AudioPortBuffer {
+        latency: 0,
+        channels: AudioPortBufferType::f32_input_only(
+            port.into_iter().map(move |ch| InputChannel::constant(&mut ch[start..end]))
+        )
+    }));
+    let mut out_audio = output_ports.with_output_buffers(layout::split(outputs, &layout.outputs).map(|port| AudioPortBuffer {
+        latency: 0,
+        channels: AudioPortBufferType::f32_output_only(
+            port.into_iter().map(move |ch| &mut ch[start..end])
+        )
+    }));
+
+    proc.process(
+        &in_audio,
+        &mut out_audio,
+        input_events,
+        &mut output_events,
+        Some(steady_time),
+        transport
+    )
+}
+
+// As process_ports, with 64-bit buffers for the plugin that asked for them
+#[allow(clippy::too_many_arguments)]
+fn process_ports_f64(
+    proc: &mut StartedPluginAudioProcessor<MyHost>,
+    steady_time: u64,
+    transport: Option<&TransportEvent>,
+    input_events: &InputEvents,
+    output_events: &mut EventBuffer,
+    layout: &Layout,
+    [input_ports, output_ports]: &mut [AudioPorts; 2],
+    inputs: &mut [Vec<f64>],
+    outputs: &mut [Vec<f64>],
+    range: Range<usize>,
+) -> Result<ProcessStatus, PluginInstanceError> {
+    let mut output_events = OutputEvents::from_buffer(output_events);
+
+    let Range { start, end } = range;
+    let in_audio = input_ports.with_input_buffers(layout::split(inputs, &layout.inputs).map(|port| AudioPortBuffer {
+        latency: 0,
+        channels: AudioPortBufferType::f64_input_only(
+            port.into_iter().map(move |ch| InputChannel::constant(&mut ch[start..end]))
+        )
+    }));
+    let mut out_audio = output_ports.with_output_buffers(layout::split(outputs, &layout.outputs).map(|port| AudioPortBuffer {
+        latency: 0,
+        channels: AudioPortBufferType::f64_output_only(
+            port.into_iter().map(move |ch| &mut ch[start..end])
+        )
+    }));
+
+    proc.process(
+        &in_audio,
+        &mut out_audio,
+        input_events,
+        &mut output_events,
+        Some(steady_time),
+        transport
+    )
+}
+
+// JACK server notifications we care about
+struct JackNotifications {
+    xruns: Arc<AtomicU64>,
+    // (full port name, frames) extra latency to report on our ports
+    latency_offsets: Vec<(String, u32)>,
+    // frames the plugin delays its output by (clap.latency)
+    plugin_latency: Arc<AtomicU32>,
+    in_names: Vec<String>,
+    out_names: Vec<String>,
+    // JACK's current sample rate, for the main loop
+    rate: Arc<AtomicU32>,
+    // set when ports come or go, so the main loop can retry --connect-out
+    ports_changed: Arc<AtomicBool>,
+    // set when connections change, for audio-ports-activation
+    connections_changed: Arc<AtomicBool>,
+}
+
+impl NotificationHandler for JackNotifications {
+    fn xrun(&mut self, _client: &Client) -> Control {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+        lifecycle::log(Event::Xrun);
+        Control::Continue
+    }
+
+    fn sample_rate(&mut self, _client: &Client, rate: jack::Frames) -> Control {
+        self.rate.store(rate, Ordering::Relaxed);
+        Control::Continue
+    }
+
+    // Connecting from a notification callback isn't allowed, so only flag it
+    fn ports_connected(&mut self, _client: &Client, _a: jack::PortId, _b: jack::PortId, _connected: bool) {
+        self.connections_changed.store(true, Ordering::Relaxed);
+    }
+
+    fn port_registration(&mut self, _client: &Client, _port_id: jack::PortId, is_registered: bool) {
+        if is_registered {
+            self.ports_changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Audio takes the plugin's latency to get from our inputs to our outputs.
+    // A --port-latency offset on an input adds to what reaches our outputs
+    // from upstream; one on an output adds to that output and to what lies
+    // downstream of our inputs. Without inputs, our outputs carry nothing
+    // from upstream.
+    fn latency(&mut self, client: &Client, mode: LatencyType) {
+        let plugin = self.plugin_latency.load(Ordering::Relaxed);
+        let offset = |name: &String| self.latency_offsets.iter().find(|(n, _)| n == name).map_or(0, |(_, f)| *f);
+        let range = |names: &[String], mode: LatencyType| {
+            names
+                .iter()
+                .filter_map(|name| client.port_by_name(name).map(|port| (port, offset(name))))
+                .map(|(port, offset)| {
+                    let (min, max) = port.get_latency_range(mode);
+                    (min + offset, max + offset)
+                })
+                .reduce(|(min, max), (lo, hi)| (min.min(lo), max.max(hi)))
+                .unwrap_or((0, 0))
+        };
+        match mode {
+            LatencyType::Capture => {
+                let (min, max) = range(&self.in_names, LatencyType::Capture);
+                for name in &self.out_names {
+                    if let Some(port) = client.port_by_name(name) {
+                        let extra = plugin + offset(name);
+                        port.set_latency_range(LatencyType::Capture, (min + extra, max + extra));
+                    }
+                }
+            }
+            LatencyType::Playback => {
+                let (min, max) = range(&self.out_names, LatencyType::Playback);
+                for name in &self.in_names {
+                    if let Some(port) = client.port_by_name(name) {
+                        port.set_latency_range(LatencyType::Playback, (min + plugin, max + plugin));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// MIDI that the host acts on instead of the plugin, left for the main
+// thread: --preset-trigger steps through the bank, --macro-trigger runs a
+// macro, --slot-notes recall a --slot
+struct Triggers {
+    presets: Vec<Trigger>,
+    // the step's code, 0 for none
+    preset_step: Arc<AtomicU8>,
+    macros: Vec<(MidiSource, usize)>,
+    // the macro's index + 1, 0 for none
+    macro_fired: Arc<AtomicU32>,
+    slot_notes: SlotNotes,
+    // how many --slot files there are; none, and the notes are the plugin's
+    slots: usize,
+    // the slot's index + 1, 0 for none
+    slot_fired: Arc<AtomicU32>,
+}
+
+impl Triggers {
+    // Whether the message is a trigger, which the plugin then doesn't get
+    fn take(&self, bytes: &[u8]) -> bool {
+        if let Some(trigger) = self.presets.iter().find(|t| t.matches(bytes)) {
+            self.preset_step.store(trigger.step.code(), Ordering::Relaxed);
+            return true;
+        }
+        if let Some(&(_, index)) = self.macros.iter().find(|(source, _)| source.matches(bytes)) {
+            self.macro_fired.store(index as u32 + 1, Ordering::Relaxed);
+            return true;
+        }
+        if let Some(slot) = self.slot_notes.take(bytes, self.slots).filter(|_| self.slots > 0) {
+            if let Some(index) = slot {
+                self.slot_fired.store(index as u32 + 1, Ordering::Relaxed);
+            }
+            return true;
+        }
+        false
+    }
+}
+
+// Queue one short MIDI message for this slice, in time order, unless it's
+// one of the triggers. A burst beyond what we allocated for is dropped, bar
+// the last NOTE_OFF_STAGED places, which are kept for note-offs.
+fn stage(
+    staged: &mut Vec<StagedMidi>,
+    triggers: &Triggers,
+    scale: Option<&Scale>,
+    dropped: &Dropped,
+    time: u32,
+    port: usize,
+    bytes: &[u8],
+) {
+    if triggers.take(bytes) {
+        return;
+    }
+    let kind = dropped::kind_of(bytes);
+    let limit = if kind == Kind::NoteOff { staged.capacity() } else { staged.capacity() - NOTE_OFF_STAGED };
+    if staged.len() >= limit {
+        dropped.count(kind);
+        return;
+    }
+    let mut buf = [0; 3];
+    let bytes = quantized(scale, bytes, &mut buf);
+    let mut message = StagedMidi { time, port, bytes: [0; 3], len: bytes.len() };
+    message.bytes[..bytes.len()].copy_from_slice(bytes);
+    let at = staged.partition_point(|s| s.time <= message.time);
+    staged.insert(at, message);
+}
+
+// MIDI as it came in, or moved onto the --scale
+fn quantized<'a>(scale: Option<&Scale>, bytes: &'a [u8], buf: &'a mut [u8; 3]) -> &'a [u8] {
+    match scale {
+        Some(scale) => scale.apply(bytes, buf),
+        None => bytes,
+    }
+}
+
+// An emptied Vec of buffer references, re-typed for another block's
+// lifetime, keeping its allocation so the audio thread doesn't allocate
+fn recycle<'a>(mut slices: Vec<&mut [f32]>) -> Vec<&'a mut [f32]> {
+    slices.clear();
+    let mut slices = std::mem::ManuallyDrop::new(slices);
+    // SAFETY: the Vec is empty, and a reference has the same layout whatever
+    // its lifetime, so this is the same allocation with no elements to outlive
+    unsafe { Vec::from_raw_parts(slices.as_mut_ptr().cast(), 0, slices.capacity()) }
+}
+
+// Whether a slice's events have room for one more MIDI message or change of
+// this kind, note-offs having the last NOTE_OFF_EVENTS to themselves; counts
+// it as dropped if not
+fn room(events: &EventBuffer, dropped: &Dropped, kind: Kind) -> bool {
+    let limit = if kind == Kind::NoteOff { MAX_EVENTS } else { MAX_EVENTS - NOTE_OFF_EVENTS };
+    let room = events.len() + EVENTS_PER_MESSAGE <= limit;
+    if !room {
+        dropped.count(kind);
+    }
+    room
+}
+
+fn arp_kind(note: &arp::ArpNote) -> Kind {
+    if note.on { Kind::Note } else { Kind::NoteOff }
+}
+
+fn push_arp_note(note: &arp::ArpNote, port: NotePort, tuning: &Tuning, events: &mut EventBuffer) {
+    if note.on {
+        midi::note_on(note.time, port, note.channel, note.key, note.velocity, events);
+        tuning.note(note.time, port, note.channel, note.key, events);
+    } else {
+        midi::note_off(note.time, port, note.channel, note.key, 0.0, events);
+    }
+}
+
+// One short MIDI message from any of our MIDI ins, for merging them in time
+// order before they go to the plugin
+struct StagedMidi {
+    time: u32,
+    // index into JackHandler::midi_ins
+    port: usize,
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl StagedMidi {
+    fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// 64-bit copies of the audio, for --prefer-f64: the plugin works on these
+// and we convert at the JACK ports. A buffer per channel, as for f32.
+struct Wide {
+    inputs: Vec<Vec<f64>>,
+    outputs: Vec<Vec<f64>>,
 }
 
 // JACK handler that calls the CLAP plugin each block
 struct JackHandler {
-    proc: clack_host::process::StartedPluginAudioProcessor<MyHost>,
+    // None while the main thread restarts the plugin
+    proc: Option<StartedPluginAudioProcessor<MyHost>>,
+    restart: restart::AudioSide,
+    // largest block the plugin was activated for
+    max_frames: u32,
+    // frames since the plugin started processing, silent ones included,
+    // for clap's steady_time
+    steady_time: u64,
+    // JACK's current period, for the main loop
+    period: Arc<AtomicU32>,
     out_l: Port<AudioOut>,
     out_r: Port<AudioOut>,
-    // silent input we'll hand to the plugin
-    in_l: Vec<f32>,
-    in_r: Vec<f32>,
-    // plugin output scratch (copied to JACK)
-    scratch_l: Vec<f32>,
-    scratch_r: Vec<f32>,
+    // the plugin's output channels after its main pair, straight out
+    aux_out: Vec<Port<AudioOut>>,
+    // channels in the plugin's main output; 1 plays on out_l and out_r
+    main_channels: usize,
+    // JACK inputs with the plugin input channel each feeds; none if it takes
+    // no audio
+    ins: Vec<(usize, Port<AudioIn>)>,
+    // input ports fed from --input-file instead
+    input_files: Vec<InputFile>,
+    // --envelope-follow, on the inputs as they come in
+    follower: Option<Follower>,
+    // notes in, one port per plugin note input, translated to the CLAP events
+    // handed to the plugin (or passed on as MIDI if that's all it takes)
+    midi_ins: Vec<(Port<MidiIn>, NotePort)>,
+    // console, OSC and arpeggiator notes go to the first note input
+    note_port: NotePort,
+    // the plugin's note outputs, by note port index
+    midi_outs: Vec<Port<MidiOut>>,
+    // this slice's MIDI from every port, in time order
+    staged: Vec<StagedMidi>,
+    // --midi-backend alsa-raw: messages read from the device, for the first port
+    raw_midi: Option<rtrb::Consumer<rawmidi::Message>>,
+    // the plugin's note output this block: (frame, note port, message)
+    midi_out_queue: Vec<(u32, u16, [u8; 3])>,
+    // the --map settings, and any --map-profile to switch to
+    maps: MidiMaps,
+    map_feedback: Option<Port<MidiOut>>,
+    feedback_queue: Vec<[u8; 3]>,
+    // --tuning and --master-transpose, and each channel's pitch bend
+    tuning: Tuning,
+    // MIDI for the host rather than the plugin
+    triggers: Triggers,
+    // counts parameter changes in and out, for warnings about unsaved ones
+    edits: Arc<Edits>,
+    scale: Option<Scale>,
+    // when set, held notes go to the arpeggiator rather than the plugin
+    arp: Option<Arp>,
+    events: EventBuffer,
+    out_events: EventBuffer,
+    // events left out because a queue or slice was full, by kind
+    dropped: Arc<Dropped>,
+    // --param values and live parameter/note changes, sent with the next block
+    pending: Vec<Change>,
+    changes: rtrb::Consumer<Change>,
+    // the plugin's audio ports, and a buffer per channel of them, port after
+    // port; the main output pair comes first and goes through the stages below
+    layout: Layout,
+    inputs: Vec<Vec<f32>>,
+    // room for the JACK output buffers the plugin renders into, one per
+    // channel; empty between blocks
+    out_slices: Vec<&'static mut [f32]>,
+    // under --sr-policy resample, for when JACK's rate isn't the plugin's
+    resampler: Option<Resampler>,
+    rates: Arc<Rates>,
+    // set when processing in 64-bit
+    wide: Option<Wide>,
+    // clack's per-port structs for the input and output buffers
+    ports: [AudioPorts; 2],
+    // width/balance applied to the plugin output
+    stereo: StereoStage,
+    // per-channel polarity flip applied on the way out
+    invert: [bool; 2],
+    // optional loudness gate
+    gate: Option<LoudnessGate>,
+    // optional levelling of preset switches
+    gain_match: Option<GainMatch>,
+    // optional true-peak ceiling
+    limiter: Option<TruePeakLimiter>,
+    // L/R correlation of the result, for the `correlation` command
+    correlation: CorrelationMeter,
+    // per-output mute and solo, then `fade`, last things before the click
+    mutes: OutputGains,
+    fade: Fade,
+    dip: Fade,
+    // set by the guardrails: skip the plugin and output silence
+    bypass: Arc<AtomicBool>,
+    // `sink jack off`: silence on the JACK ports, while the other sinks go on
+    jack_out: Arc<AtomicBool>,
+    // set for good if processing panicked; we output silence from then on
+    faulted: Arc<AtomicBool>,
+    // watches the plugin output for silence / a frozen buffer
+    health: HealthMonitor,
+    // time left in the period once the plugin has returned
+    deadlines: Arc<DeadlineStats>,
+    // the last --retro seconds of output, for `dump`, and what `record` takes from
+    retro: Arc<RetroBuffer>,
+    // --record-sync bar: mark where bars start in it
+    mark_bars: bool,
+    // the plugin's tail in frames once asked to ring out (shutdown::TAIL_UNKNOWN till then)
+    tail: Arc<AtomicU64>,
+    // optional metronome, on its own port or mixed into out_l/out_r
+    click: Option<Click>,
+    click_out: Option<Port<AudioOut>>,
+    click_buf: Vec<f32>,
+    transport: Transport,
+    // the JACK transport for the plugin, and whether to reset it on a jump
+    transport_sync: TransportSync,
+    reset_on_locate: bool,
+    // the rate the stages after the plugin were set up for: JACK's, whether
+    // or not the plugin is resampled to it
+    output_rate: f64,
+}
+
+impl JackHandler {
+    // The MIDI-side stages count in the plugin's frames
+    fn set_plugin_rate(&mut self, rate: f64) {
+        self.maps.set_sample_rate(rate);
+        if let Some(arp) = &mut self.arp {
+            arp.set_sample_rate(rate);
+        }
+        self.transport_sync.set_sample_rate(rate);
+    }
+
+    // Everything from the plugin's output on runs in JACK's
+    fn set_output_rate(&mut self, rate: f64) {
+        self.output_rate = rate;
+        if let Some(follower) = &mut self.follower {
+            follower.set_sample_rate(rate);
+        }
+        if let Some(gain_match) = &mut self.gain_match {
+            gain_match.set_sample_rate(rate);
+        }
+        if let Some(gate) = &mut self.gate {
+            gate.set_sample_rate(rate);
+        }
+        if let Some(limiter) = &mut self.limiter {
+            limiter.set_sample_rate(rate);
+        }
+        self.correlation.set_sample_rate(rate);
+        self.mutes.set_sample_rate(rate);
+        self.retro.set_sample_rate(rate);
+        if let Some(click) = &mut self.click {
+            click.set_sample_rate(rate);
+        }
+    }
 }
 
 impl ProcessHandler for JackHandler {
-    fn process(&mut self, _client: &Client, ps: &ProcessScope) -> Control {
+    fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
+        // A restarted plugin counts steady time from zero again, and may be
+        // at a new rate, as may JACK
+        if let Some(rate) = self.restart.service(&mut self.proc, &mut self.max_frames) {
+            self.steady_time = 0;
+            self.set_plugin_rate(rate);
+        }
+        let jack_rate = client.sample_rate() as f64;
+        if jack_rate != self.output_rate {
+            self.set_output_rate(jack_rate);
+        }
+
         let out_l = self.out_l.as_mut_slice(ps);
         let out_r = self.out_r.as_mut_slice(ps);
         let n = out_l.len();
+        // frames of the plugin's this block, n unless resampling
+        let mut frames = n;
+
+        if self.bypass.load(Ordering::Relaxed) || self.faulted.load(Ordering::Relaxed) || self.proc.is_none() {
+            out_l.fill(0.0);
+            out_r.fill(0.0);
+            self.aux_out.iter_mut().for_each(|port| port.as_mut_slice(ps).fill(0.0));
+            // a writer empties the port's buffer for this period
+            self.midi_outs.iter_mut().for_each(|port| drop(port.writer(ps)));
+        } else if let Some(proc) = &mut self.proc {
+            // A panic in here (our code or clack's glue; a crash in the plugin's
+            // own code can't be caught) leaves silence and the fault flag set,
+            // and the plugin isn't called again. Unwinding must stay enabled
+            // for this: under panic = "abort" the process still goes down.
+            let ran = panic::catch_unwind(AssertUnwindSafe(|| {
+                // Ensure buffers are the right size; within MAX_PERIOD this
+                // doesn't allocate
+                for buf in &mut self.inputs {
+                    if buf.len() != n { buf.resize(n, 0.0); }
+                }
+
+                // Live audio from JACK, and any --input-file; silence counts
+                // as expected for an effect whose input is silent too
+                for (channel, port) in &self.ins {
+                    self.inputs[*channel].copy_from_slice(port.as_slice(ps));
+                }
+                for file in &mut self.input_files {
+                    file.play(&mut self.inputs, n);
+                }
+                if let Some(follower) = &mut self.follower {
+                    follower.process(&self.inputs);
+                }
+                let input_live = self.inputs.is_empty() || self.inputs.iter().flatten().any(|&s| s != 0.0);
+
+                // Under --sr-policy resample the plugin may be at another rate
+                // than JACK: it gets `frames` of its own for our n, and MIDI
+                // times scaled to match
+                let resampling = self.resampler.as_mut().is_some_and(|r| r.update(&self.rates));
+                if let Some(resampler) = self.resampler.as_mut().filter(|_| resampling) {
+                    frames = resampler.into_plugin(&mut self.inputs, n);
+                }
+                let to_plugin = |time: usize| time * frames / n;
+                if let Some(wide) = &mut self.wide {
+                    for buf in wide.inputs.iter_mut().chain(&mut wide.outputs) {
+                        if buf.len() != frames { buf.resize(frames, 0.0); }
+                    }
+                }
+
+                // Live changes from the console and OSC
+                while let Ok(change) = self.changes.pop() {
+                    match change {
+                        Change::Width(width) => self.stereo.set_width(width),
+                        Change::Balance(balance) => self.stereo.set_balance(balance),
+                        Change::Fade { gain, seconds } => self.fade.start(gain, seconds, self.output_rate),
+                        Change::Dip { gain, seconds } => self.dip.start(gain, seconds, self.output_rate),
+                        Change::Feedback(bytes) => {
+                            if self.feedback_queue.len() < self.feedback_queue.capacity() {
+                                self.feedback_queue.push(bytes);
+                            }
+                        }
+                        change => self.pending.push(change),
+                    }
+                }
+
+                // Where the JACK transport is, for the plugin and the arpeggiator
+                let position = self.transport.query().ok();
+                let relocated = position.as_ref().is_some_and(|p| self.transport_sync.relocated(p, n));
+                if let (Some(arp), Some(position)) = (&mut self.arp, &position) {
+                    arp.sync(position);
+                }
+                if relocated && self.reset_on_locate {
+                    proc.reset();
+                }
+
+                // The plugin renders straight into our JACK ports: out_l and
+                // out_r (just out_l for a mono main), then the rest in order.
+                // Resampling, it renders at its rate for us to convert after.
+                let mut outputs = recycle(std::mem::take(&mut self.out_slices));
+                match self.resampler.as_mut().filter(|_| resampling) {
+                    Some(resampler) => outputs.extend(resampler.outputs.iter_mut().map(|buf| &mut buf[..frames])),
+                    None => {
+                        outputs.push(&mut *out_l);
+                        if self.main_channels > 1 {
+                            outputs.push(&mut *out_r);
+                        }
+                        outputs.extend(self.aux_out.iter_mut().map(|port| port.as_mut_slice(ps)));
+                    }
+                }
+
+                // Process one JACK block, in slices if it's bigger than the
+                // plugin was activated for
+                let max = self.max_frames as usize;
+                let mut pos = 0;
+                while pos < frames {
+                    let end = (pos + max).min(frames);
+
+                    // Pending changes go at the start of the first slice, then
+                    // any MIDI that falls in this one, timed relative to its start
+                    self.events.clear();
+                    let transport = position.as_ref().map(|p| self.transport_sync.event(p, pos));
+                    if let Some(transport) = transport.as_ref().filter(|_| relocated && pos == 0) {
+                        // ahead of everything else, so always room for it
+                        self.events.push(transport);
+                    }
+                    for change in self.pending.drain(..) {
+                        let dropped = &*self.dropped;
+                        match change {
+                            Change::Param(id, value) if room(&self.events, dropped, Kind::Param) => {
+                                self.events.push(&ParamValueEvent::new(0, id, Pckn::match_all(), value, Cookie::empty()));
+                            }
+                            Change::NoteOn { channel, key, velocity } if room(&self.events, dropped, Kind::Note) => {
+                                midi::note_on(0, self.note_port, channel, key, velocity, &mut self.events);
+                                self.tuning.note(0, self.note_port, channel, key, &mut self.events);
+                            }
+                            Change::NoteOff { channel, key } if room(&self.events, dropped, Kind::NoteOff) => {
+                                midi::note_off(0, self.note_port, channel, key, 0.0, &mut self.events);
+                            }
+                            Change::Map(to) if room(&self.events, dropped, Kind::Param) => {
+                                self.maps.switch(0, to, &mut self.events);
+                            }
+                            Change::Param(..) | Change::NoteOn { .. } | Change::NoteOff { .. } | Change::Map(_) => {}
+                            Change::RingOut => {
+                                for (_, port) in &self.midi_ins {
+                                    if room(&self.events, dropped, Kind::NoteOff) {
+                                        midi::all_notes_off(0, *port, &mut self.events);
+                                    }
+                                }
+                                if self.midi_ins.is_empty() && room(&self.events, dropped, Kind::NoteOff) {
+                                    midi::all_notes_off(0, self.note_port, &mut self.events);
+                                }
+                                if let Some(arp) = &mut self.arp {
+                                    arp.release();
+                                }
+                                self.tail.store(tail_frames(proc), Ordering::Relaxed);
+                            }
+                            // applied as they arrive
+                            Change::Width(_) | Change::Balance(_) | Change::Feedback(_) => {}
+                            Change::Fade { .. } | Change::Dip { .. } => {}
+                        }
+                    }
+                    // --envelope-param follows the input once a block
+                    let envelope = self.follower.as_mut().filter(|_| pos == 0).and_then(Follower::param_change);
+                    if let Some((id, value)) = envelope.filter(|_| room(&self.events, &self.dropped, Kind::Param)) {
+                        self.events.push(&ParamValueEvent::new(0, id, Pckn::match_all(), value, Cookie::empty()));
+                    }
+                    // This slice's MIDI from every port, merged in time order
+                    // (each port's own order kept at equal times); anything
+                    // longer than three bytes is sysex, which we drop
+                    let in_slice = |m: &jack::RawMidi| (pos..end).contains(&to_plugin(m.time as usize));
+                    self.staged.clear();
+                    let (triggers, scale, dropped) = (&self.triggers, self.scale.as_ref(), &*self.dropped);
+                    for (i, (midi_in, _)) in self.midi_ins.iter().enumerate() {
+                        for m in midi_in.iter(ps).filter(in_slice).filter(|m| m.bytes.len() <= 3) {
+                            let time = (to_plugin(m.time as usize) - pos) as u32;
+                            stage(&mut self.staged, triggers, scale, dropped, time, i, m.bytes);
+                        }
+                    }
+                    // --midi-backend alsa-raw: what the device sent since the
+                    // last block, at the start of this one, on the first port
+                    if let Some(raw_midi) = self.raw_midi.as_mut().filter(|_| pos == 0) {
+                        while let Ok(m) = raw_midi.pop() {
+                            stage(&mut self.staged, triggers, scale, dropped, 0, 0, m.bytes());
+                        }
+                    }
+
+                    // The arpeggiator takes the first port's notes up front; its
+                    // steps are then merged in time order with the rest of the MIDI
+                    if let Some(arp) = &mut self.arp {
+                        for m in self.staged.iter().filter(|m| m.port == 0) {
+                            arp.midi(m.bytes());
+                        }
+                        arp.process(end - pos);
+                    }
+                    let mut arp_notes = self.arp.as_ref().map_or(&[][..], Arp::events).iter().peekable();
+                    let mut reset = false;
+                    let dropped = &*self.dropped;
+                    // --cc-smoothing ramps step along in between, in time order
+                    // too, short of the room kept for note-offs
+                    let ramp_room = MAX_EVENTS - NOTE_OFF_EVENTS - EVENTS_PER_MESSAGE;
+                    for m in &self.staged {
+                        while let Some(note) = arp_notes.next_if(|note| note.time <= m.time) {
+                            self.maps.current().advance(note.time, ramp_room, &mut self.events);
+                            if room(&self.events, dropped, arp_kind(note)) {
+                                push_arp_note(note, self.note_port, &self.tuning, &mut self.events);
+                            }
+                        }
+                        if self.arp.is_some() && m.port == 0 && midi::is_note(m.bytes()) {
+                            continue;
+                        }
+                        self.maps.current().advance(m.time, ramp_room, &mut self.events);
+                        let full = !room(&self.events, dropped, dropped::kind_of(m.bytes()));
+                        if full || self.maps.switch_by_cc(m.time, m.bytes(), &mut self.events) {
+                            continue;
+                        }
+                        let port = self.midi_ins[m.port].1;
+                        let map = self.maps.current();
+                        reset |= midi::translate(m.time, m.bytes(), port, map, &mut self.tuning, &mut self.events);
+                    }
+                    for note in arp_notes {
+                        self.maps.current().advance(note.time, ramp_room, &mut self.events);
+                        if room(&self.events, dropped, arp_kind(note)) {
+                            push_arp_note(note, self.note_port, &self.tuning, &mut self.events);
+                        }
+                    }
+                    self.maps.current().advance((end - pos) as u32, ramp_room, &mut self.events);
+                    self.maps.current().end_slice((end - pos) as u32);
+                    // every push above checked room() for the most it could add
+                    debug_assert!(self.events.len() <= MAX_EVENTS);
+                    if reset {
+                        proc.reset();
+                    }
+                    let input_events = InputEvents::from_buffer(&self.events);
+
+                    self.out_events.clear();
+                    let steady_time = self.steady_time + pos as u64;
+                    let _status = match &mut self.wide {
+                        None => process_ports(
+                            proc,
+                            steady_time,
+                            transport.as_ref(),
+                            &input_events,
+                            &mut self.out_events,
+                            &self.layout,
+                            &mut self.ports,
+                            &mut self.inputs,
+                            &mut outputs,
+                            pos..end,
+                        ),
+                        Some(wide) => {
+                            for (wide, narrow) in wide.inputs.iter_mut().zip(&self.inputs) {
+                                wide[pos..end].iter_mut().zip(&narrow[pos..end]).for_each(|(w, s)| *w = *s as f64);
+                            }
+                            let status = process_ports_f64(
+                                proc,
+                                steady_time,
+                                transport.as_ref(),
+                                &input_events,
+                                &mut self.out_events,
+                                &self.layout,
+                                &mut self.ports,
+                                &mut wide.inputs,
+                                &mut wide.outputs,
+                                pos..end,
+                            );
+                            for (narrow, wide) in outputs.iter_mut().zip(&wide.outputs) {
+                                narrow[pos..end].iter_mut().zip(&wide[pos..end]).for_each(|(s, w)| *s = *w as f32);
+                            }
+                            status
+                        }
+                    }.unwrap_or(ProcessStatus::Continue);
+
+                    // Parameters moved either way are unsaved changes
+                    let mut moved = self.events.iter().chain(self.out_events.iter());
+                    if moved.any(|event| event.as_event::<ParamValueEvent>().is_some()) {
+                        self.edits.mark();
+                    }
+
+                    // Notes the plugin sent, for our MIDI outs at the end of the block
+                    for event in self.out_events.iter() {
+                        if let Some((time, port, bytes)) = midi::to_midi(event) {
+                            if (port as usize) < self.midi_outs.len() && self.midi_out_queue.len() < self.midi_out_queue.capacity() {
+                                // back in JACK frames
+                                let time = (pos + time as usize) * n / frames;
+                                self.midi_out_queue.push((time as u32, port, bytes));
+                            }
+                        }
+                    }
+                    pos = end;
+                }
+
+                // One writer per port and period, as making one clears the buffer
+                for (i, port) in self.midi_outs.iter_mut().enumerate() {
+                    let mut writer = port.writer(ps);
+                    for (time, _, bytes) in self.midi_out_queue.iter().filter(|e| e.1 as usize == i) {
+                        let _ = writer.write(&jack::RawMidi { time: *time, bytes });
+                    }
+                }
+                self.midi_out_queue.clear();
+                if let Some(port) = &mut self.map_feedback {
+                    let mut writer = port.writer(ps);
+                    for bytes in &self.feedback_queue {
+                        let _ = writer.write(&jack::RawMidi { time: 0, bytes });
+                    }
+                }
+                self.feedback_queue.clear();
 
-        // Ensure buffers are the right size
-        if self.in_l.len() != n { self.in_l.resize(n, 0.0); }
-        if self.in_r.len() != n { self.in_r.resize(n, 0.0); }
-        if self.scratch_l.len() != n { self.scratch_l.resize(n, 0.0); }
-        if self.scratch_r.len() != n { self.scratch_r.resize(n, 0.0); }
-
-        // Build clack audio ports: 1 input port (silent stereo), 1 output port (stereo)
-        let mut input_ports  = AudioPorts::with_capacity(2, 1);
-        let mut output_ports = AudioPorts::with_capacity(2, 1);
-
-        // Explicitly-typed EMPTY input event buffer — slice of references
-        let empty_in: [&UnknownEvent; 0] = [];
-        let input_events = InputEvents::from_buffer(&empty_in);
-        let mut output_events_buf = EventBuffer::new();
-        let mut output_events = OutputEvents::from_buffer(&mut output_events_buf);
-
-        // Attach input (silent stereo) and output (our scratch) buffers
-        let in_audio = input_ports.with_input_buffers([AudioPortBuffer {
-            latency: 0,
-            channels: AudioPortBufferType::f32_input_only(
-                // IMPORTANT: pass **mutable** slices to InputChannel::constant(...)
-                [&mut self.in_l[..], &mut self.in_r[..]]
-                    .into_iter()
-                    .map(InputChannel::constant)
-            )
-        }]);
-        let mut out_audio = output_ports.with_output_buffers([AudioPortBuffer {
-            latency: 0,
-            channels: AudioPortBufferType::f32_output_only(
-                [&mut self.scratch_l[..], &mut self.scratch_r[..]].into_iter()
-            )
-        }]);
-
-        // Process one JACK block
-        let _status = self.proc.process(
-            &in_audio,
-            &mut out_audio,
-            &input_events,
-            &mut output_events,
-            None,
-            None
-        ).unwrap_or(ProcessStatus::Continue);
-
-        // Copy to JACK
-        out_l.copy_from_slice(&self.scratch_l);
-        out_r.copy_from_slice(&self.scratch_l);
+                if let Ok(times) = ps.cycle_times() {
+                    let used = client.time().saturating_sub(times.current_usecs) as f32;
+                    self.deadlines.record(1.0 - used / times.period_usecs);
+                }
+
+                self.out_slices = recycle(outputs);
+                // resampling: back to JACK's rate, into our ports
+                if let Some(resampler) = self.resampler.as_mut().filter(|_| resampling) {
+                    resampler.from_plugin(frames);
+                    resampler.play(0, out_l);
+                    if self.main_channels > 1 {
+                        resampler.play(1, out_r);
+                    }
+                    for (i, port) in self.aux_out.iter_mut().enumerate() {
+                        resampler.play(self.main_channels.min(2) + i, port.as_mut_slice(ps));
+                    }
+                }
+                if self.main_channels == 1 {
+                    out_r.copy_from_slice(out_l);
+                }
+                self.health.observe(input_live, out_l, out_r);
+
+                if let Some(gain_match) = &mut self.gain_match {
+                    gain_match.process(out_l, out_r);
+                }
+                self.stereo.process(out_l, out_r);
+                for (out, invert) in [(&mut *out_l, self.invert[0]), (&mut *out_r, self.invert[1])] {
+                    if invert {
+                        out.iter_mut().for_each(|s| *s = -*s);
+                    }
+                }
+                if let Some(gate) = &mut self.gate {
+                    gate.process(out_l, out_r);
+                }
+                if let Some(limiter) = &mut self.limiter {
+                    limiter.process(out_l, out_r);
+                }
+                self.correlation.process(out_l, out_r);
+                self.mutes.update();
+                self.mutes.process(0, out_l);
+                self.mutes.process(1, out_r);
+                for (i, port) in self.aux_out.iter_mut().enumerate() {
+                    self.mutes.process(2 + i, port.as_mut_slice(ps));
+                }
+                for fade in [&mut self.fade, &mut self.dip] {
+                    fade.apply(out_l);
+                    fade.apply(out_r);
+                    for port in &mut self.aux_out {
+                        fade.apply(port.as_mut_slice(ps));
+                    }
+                    fade.advance(n);
+                }
+            }));
+            if ran.is_err() {
+                self.faulted.store(true, Ordering::Relaxed);
+                out_l.fill(0.0);
+                out_r.fill(0.0);
+                self.aux_out.iter_mut().for_each(|port| port.as_mut_slice(ps).fill(0.0));
+            }
+        }
+
+        self.steady_time += frames as u64;
+
+        // What the audience heard, without the click
+        if self.mark_bars {
+            let to_bar = self.transport.query().ok().and_then(|t| transport::frames_to_bar(&t, self.output_rate));
+            if let Some(to_bar) = to_bar.filter(|&frames| frames < n) {
+                self.retro.mark_bar(to_bar);
+            }
+        }
+        self.retro.write(out_l, out_r);
+        if !self.jack_out.load(Ordering::Relaxed) {
+            out_l.fill(0.0);
+            out_r.fill(0.0);
+            self.aux_out.iter_mut().for_each(|port| port.as_mut_slice(ps).fill(0.0));
+        }
+
+        // Metronome goes in last, so none of the output processing touches it
+        if let Some(click) = &mut self.click {
+            let Ok(transport) = self.transport.query() else { return Control::Continue };
+            match &mut self.click_out {
+                Some(port) => click.process(&transport, port.as_mut_slice(ps)),
+                None => {
+                    if self.click_buf.len() != n { self.click_buf.resize(n, 0.0); }
+                    click.process(&transport, &mut self.click_buf);
+                    for out in [&mut *out_l, &mut *out_r] {
+                        out.iter_mut().zip(&self.click_buf).for_each(|(s, c)| *s += c);
+                    }
+                }
+            }
+        }
+
+        Control::Continue
+    }
 
+    // Called on the process thread; the main loop reactivates the plugin if
+    // the new period is more than it was activated for
+    fn buffer_size(&mut self, _: &Client, frames: jack::Frames) -> Control {
+        self.period.store(frames, Ordering::Relaxed);
         Control::Continue
     }
 }
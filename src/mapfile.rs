@@ -0,0 +1,89 @@
+// --map-file: controller mappings kept in a file, so a controller template
+// made once (or exported from another host as CSV) needn't be re-entered as
+// --map flags knob by knob. One mapping a line, `#` starts a comment:
+//
+//     cc,parameter            <- a header line is skipped
+//     74,cutoff               <- CC number (or cc74) and a parameter name or ID
+//     cc71,param:resonance    <- or a target as --map takes it
+//     18,drive@s-curve        <- with its own --cc-curve
+//     20,mute:out_sidechain_l
+//     choke,42,44,46          <- a --choke group
+use std::path::Path;
+
+use crate::midi::MapTarget;
+use crate::{parse_choke, parse_map};
+
+#[derive(Default)]
+pub struct MapFile {
+    pub map: Vec<(u8, MapTarget)>,
+    pub chokes: Vec<Vec<u8>>,
+}
+
+pub fn load(path: &Path) -> Result<MapFile, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    parse(&text).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+fn parse(text: &str) -> Result<MapFile, String> {
+    let mut file = MapFile::default();
+    let lines = text.lines().map(|line| line.split('#').next().unwrap_or("").trim());
+    for (i, (n, line)) in lines.enumerate().filter(|(_, line)| !line.is_empty()).enumerate() {
+        let (first, rest) = line.split_once(',').map_or((line, ""), |(a, b)| (a.trim(), b.trim()));
+        let number = first.strip_prefix("cc").unwrap_or(first);
+        if first.eq_ignore_ascii_case("choke") {
+            file.chokes.push(parse_choke(rest).map_err(|e| format!("line {}: {e}", n + 1))?);
+        } else if number.parse::<u8>().is_ok() {
+            // a bare name is a parameter; columns after it (ranges, labels
+            // in other hosts' files) aren't ours
+            let target = rest.split(',').next().unwrap_or("").trim().trim_matches('"');
+            let target = match target.contains(':') {
+                true => target.to_string(),
+                false => format!("param:{target}"),
+            };
+            file.map.push(parse_map(&format!("cc{number}={target}")).map_err(|e| format!("line {}: {e}", n + 1))?);
+        } else if i > 0 {
+            return Err(format!("line {}: expected CC,TARGET or choke,KEYS, not {line:?}", n + 1));
+        }
+        // anything else on the first line is a header
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::Curve;
+    use crate::mute::Switch;
+
+    #[test]
+    fn parses_mappings_and_chokes() {
+        let file = parse(
+            "CC,Parameter,Min,Max\n\
+             \n\
+             74,cutoff,0,1  # ranges are another host's\n\
+             cc71, \"Res\" \n\
+             20,mute:out_sidechain_l\n\
+             18,drive@s-curve\n\
+             # 21,solo:out_l\n\
+             choke, 42, 44,46\n",
+        )
+        .unwrap();
+        assert_eq!(file.map.len(), 4);
+        assert!(matches!(&file.map[0], (74, MapTarget::Param(p, None)) if p == "cutoff"));
+        assert!(matches!(&file.map[1], (71, MapTarget::Param(p, None)) if p == "Res"));
+        assert!(matches!(&file.map[2], (20, MapTarget::Switch(Switch::Mute, o)) if o == "out_sidechain_l"));
+        assert!(matches!(&file.map[3], (18, MapTarget::Param(p, Some(Curve::SCurve))) if p == "drive"));
+        assert_eq!(file.chokes, [vec![42, 44, 46]]);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        // the header is only skipped on the first line
+        let err = parse("# template\n74,cutoff\nfoo,bar\n").err().unwrap();
+        assert!(err.starts_with("line 3:"), "{err}");
+        let err = parse("cc,param\n130,cutoff\n").err().unwrap();
+        assert!(err.starts_with("line 2:"), "{err}");
+        assert!(parse("choke,42\n").err().unwrap().starts_with("line 1:"));
+        assert!(parse("74,bogus:x\n").is_err());
+    }
+}
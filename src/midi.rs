@@ -0,0 +1,555 @@
+// JACK MIDI in -> CLAP events. Notes become CLAP note events and pitch bend a
+// per-channel tuning expression, so plugins that only speak the CLAP note
+// dialect can be played. Everything else (CCs, aftertouch, program changes)
+// is passed through as raw MIDI for the plugin to interpret, except for CCs
+// mapped to parameters with --map and the channel mode messages, which we act
+// on so panic buttons work everywhere (a CC can also mute or solo one of our
+// outputs). Keys put in a --choke group cut each other off, as open and
+// closed hi-hats do, for samplers that don't do it themselves. With
+// --cc-smoothing, a mapped controller's 7-bit steps become short ramps of
+// parameter events, against zipper noise in plugins that don't smooth, shaped
+// by --cc-curve or the mapping's own curve.
+// --tuning and --master-transpose shift every note by a tuning expression,
+// on top of pitch bend. With --map-profile there are several maps to switch
+// between while playing. Each JACK MIDI port feeds one of the plugin's note ports; a port
+// that only takes raw MIDI gets it untranslated.
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use clack_host::events::event_types::{
+    MidiEvent, NoteChokeEvent, NoteExpressionEvent, NoteExpressionType, NoteOffEvent, NoteOnEvent,
+    ParamValueEvent,
+};
+use clack_host::events::io::EventBuffer;
+use clack_host::events::{Match, Pckn, UnknownEvent};
+use clack_host::utils::{ClapId, Cookie};
+
+use crate::mute::{Switch, Switches};
+use crate::params::Param;
+
+// Pitch-bend range in semitones, the General MIDI default
+const BEND_RANGE: f64 = 2.0;
+
+// Controllers 0-119; the rest are channel mode messages
+const CONTROLLERS: usize = 120;
+
+// Most keys in one choke group, so a note-on turns into a bounded number of events
+pub const MAX_CHOKE_KEYS: usize = 16;
+
+// The most events one MIDI message, or a map switch, turns into: Reset All
+// Controllers recentres the bend, resets every mapped parameter and passes
+// the CC on. A choked note-on (MAX_CHOKE_KEYS - 1 note-offs, the note and
+// its tuning), a switch settling every ramp and All Notes Off on a MIDI port
+// (a CC per channel) all come to less.
+pub const MAX_EVENTS_PER_MESSAGE: usize = CONTROLLERS + 2;
+
+// Frames between the parameter events of a smoothing ramp
+const RAMP_STEP: u32 = 32;
+
+// How close a ramp gets to its goal, as a fraction of the parameter's range,
+// before it jumps there: well under one step of a 7-bit controller
+const RAMP_DONE: f64 = 0.001;
+
+// Stairs a stepped ramp climbs to its goal in
+const STAIRS: f64 = 4.0;
+
+// The shape of a smoothing ramp. Exponential glides ever slower towards the
+// goal, with --cc-smoothing as its time constant, and suits frequencies and
+// gains, which we hear on a log scale; the others take --cc-smoothing to get
+// all the way there.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    SCurve,
+    Stepped,
+}
+
+impl Curve {
+    // How far along a timed ramp is, 0 to 1, at `t` of its length
+    fn shape(self, t: f64) -> f64 {
+        match self {
+            Curve::Linear => t,
+            Curve::SCurve => t * t * (3.0 - 2.0 * t),
+            Curve::Stepped => (t * STAIRS).floor() / STAIRS,
+            // not timed: CcMap::advance glides it instead
+            Curve::Exponential => t,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CcTarget {
+    id: ClapId,
+    min: f64,
+    max: f64,
+    default: f64,
+    stepped: bool,
+    curve: Curve,
+}
+
+// Where a smoothed parameter is, and where its controller last sent it
+#[derive(Clone, Copy, Default)]
+struct Ramp {
+    current: f64,
+    goal: f64,
+    // for a timed curve: where it set off from, and the steps taken since
+    from: f64,
+    steps: u32,
+    // false until the controller first moves, as we don't know the value before
+    known: bool,
+}
+
+// What a --map'd controller drives: a parameter by name or ID, or the mute
+// or solo switch of an output
+#[derive(Clone, Debug)]
+pub enum MapTarget {
+    // with its own smoothing curve, if not --cc-curve's
+    Param(String, Option<Curve>),
+    Switch(Switch, String),
+}
+
+// --map: which CCs drive which parameters and output switches, on any
+// channel, and --choke: which keys cut each other off
+pub struct CcMap {
+    targets: [Option<CcTarget>; CONTROLLERS],
+    switch_targets: [Option<(Switch, usize)>; CONTROLLERS],
+    switches: Arc<Switches>,
+    // each key's choke group, from 1; 0 for none
+    choke_groups: [u8; 128],
+    // --cc-smoothing: the share of the way to its goal an exponential ramp
+    // goes each RAMP_STEP (0 for no smoothing), the RAMP_STEPs a timed one
+    // takes, each controller's ramp, a bit for each that's moving, and the
+    // frame in this slice of the next step
+    smoothing: f64,
+    ramp_steps: u32,
+    ramps: [Ramp; CONTROLLERS],
+    ramping: u128,
+    next_step: u32,
+}
+
+impl CcMap {
+    pub fn new(switches: Arc<Switches>) -> Self {
+        CcMap {
+            targets: [None; CONTROLLERS],
+            switch_targets: [None; CONTROLLERS],
+            switches,
+            choke_groups: [0; 128],
+            smoothing: 0.0,
+            ramp_steps: 1,
+            ramps: [Ramp::default(); CONTROLLERS],
+            ramping: 0,
+            next_step: 0,
+        }
+    }
+
+    // Glide mapped parameters to each new controller value with this time
+    // constant, rather than jumping
+    pub fn set_smoothing(&mut self, sample_rate: f64, seconds: f64) {
+        self.smoothing = match seconds > 0.0 {
+            true => 1.0 - (-(RAMP_STEP as f64) / (seconds * sample_rate)).exp(),
+            false => 0.0,
+        };
+        self.ramp_steps = ((seconds * sample_rate / RAMP_STEP as f64).round() as u32).max(1);
+    }
+
+    // A new group of keys that choke each other; a key already in a group
+    // moves to this one
+    pub fn insert_choke(&mut self, keys: &[u8]) {
+        let group = self.choke_groups.iter().copied().max().unwrap_or(0) + 1;
+        for &key in keys {
+            self.choke_groups[key as usize & 0x7f] = group;
+        }
+    }
+
+    // Cut off the other keys in `key`'s choke group, ahead of its note-on
+    fn choke(&self, time: u32, port: NotePort, channel: u16, key: u16, events: &mut EventBuffer) {
+        let group = self.choke_groups[key as usize & 0x7f];
+        if group == 0 {
+            return;
+        }
+        for other in (0..128u16).filter(|&k| k != key && self.choke_groups[k as usize] == group) {
+            if port.clap {
+                events.push(&NoteChokeEvent::new(time, Pckn::new(port.index, channel, other, Match::All)));
+            } else {
+                events.push(&MidiEvent::new(time, port.index, [0x80 | channel as u8 & 0x0f, other as u8, 0]));
+            }
+        }
+    }
+
+    pub fn insert(&mut self, cc: u8, param: &Param, curve: Curve) {
+        self.targets[cc as usize] = Some(CcTarget {
+            id: param.id,
+            min: param.min,
+            max: param.max,
+            default: param.default,
+            stepped: param.stepped,
+            curve,
+        });
+    }
+
+    pub fn insert_switch(&mut self, cc: u8, switch: Switch, output: usize) {
+        self.switch_targets[cc as usize] = Some((switch, output));
+    }
+
+    // Scale 0..127 onto the parameter's range; a switch is on from 64 up
+    fn push(&mut self, time: u32, cc: u8, value: u8, events: &mut EventBuffer) -> bool {
+        if let Some((switch, output)) = self.switch_targets.get(cc as usize).copied().flatten() {
+            self.switches.set(switch, output, value >= 64);
+            return true;
+        }
+        let Some(target) = self.targets.get(cc as usize).copied().flatten() else { return false };
+        let mut value = target.min + value as f64 / 127.0 * (target.max - target.min);
+        if target.stepped {
+            value = value.round();
+        }
+        let ramp = &mut self.ramps[cc as usize];
+        ramp.goal = value;
+        ramp.from = ramp.current;
+        ramp.steps = 0;
+        if self.smoothing == 0.0 || target.stepped || !ramp.known {
+            *ramp = Ramp { current: value, goal: value, from: value, steps: 0, known: true };
+            self.ramping &= !(1 << cc);
+            push_param(time, target.id, value, events);
+        } else {
+            self.ramping |= 1 << cc;
+        }
+        true
+    }
+
+    // Step the moving ramps up to `time` in this slice, leaving any step that
+    // would take the slice past `max_events` for later
+    pub fn advance(&mut self, time: u32, max_events: usize, events: &mut EventBuffer) {
+        while self.next_step < time {
+            if self.ramping == 0 {
+                // steps continue on the same grid once something moves again
+                self.next_step += (time - self.next_step).div_ceil(RAMP_STEP) * RAMP_STEP;
+                return;
+            }
+            for cc in 0..CONTROLLERS {
+                if self.ramping & 1 << cc == 0 || events.len() >= max_events {
+                    continue;
+                }
+                let Some(target) = self.targets[cc] else { continue };
+                let ramp = &mut self.ramps[cc];
+                ramp.steps += 1;
+                let done = match target.curve {
+                    Curve::Exponential => {
+                        ramp.current += (ramp.goal - ramp.current) * self.smoothing;
+                        (ramp.goal - ramp.current).abs() <= RAMP_DONE * (target.max - target.min)
+                    }
+                    curve => {
+                        let t = (ramp.steps as f64 / self.ramp_steps as f64).min(1.0);
+                        ramp.current = ramp.from + (ramp.goal - ramp.from) * curve.shape(t);
+                        ramp.steps >= self.ramp_steps
+                    }
+                };
+                if done {
+                    ramp.current = ramp.goal;
+                    self.ramping &= !(1 << cc);
+                }
+                push_param(self.next_step, target.id, ramp.current, events);
+            }
+            self.next_step += RAMP_STEP;
+        }
+    }
+
+    // Send every moving ramp straight to its goal, when switching away
+    fn settle(&mut self, time: u32, events: &mut EventBuffer) {
+        for cc in 0..CONTROLLERS {
+            if let (true, Some(target)) = (self.ramping & 1 << cc != 0, self.targets[cc]) {
+                self.ramps[cc].current = self.ramps[cc].goal;
+                push_param(time, target.id, self.ramps[cc].goal, events);
+            }
+        }
+        self.ramping = 0;
+    }
+
+    // Each controller mapped to a parameter, with the range it's scaled onto,
+    // for sending the parameters' values back to the controller
+    pub fn param_targets(&self) -> Vec<(u8, ClapId, f64, f64)> {
+        let targets = self.targets.iter().enumerate();
+        targets.filter_map(|(cc, t)| t.map(|t| (cc as u8, t.id, t.min, t.max))).collect()
+    }
+
+    // After the last advance() of a slice `frames` long
+    pub fn end_slice(&mut self, frames: u32) {
+        self.next_step = self.next_step.saturating_sub(frames);
+    }
+
+    // Reset All Controllers puts every mapped parameter back to its default
+    fn reset(&mut self, time: u32, events: &mut EventBuffer) {
+        self.ramping = 0;
+        for (cc, target) in self.targets.iter().enumerate() {
+            let Some(target) = target else { continue };
+            let default = target.default;
+            self.ramps[cc] = Ramp { current: default, goal: default, from: default, steps: 0, known: true };
+            push_param(time, target.id, target.default, events);
+        }
+    }
+}
+
+// --map-profile: the maps to switch between, by the console's `map` or the
+// --map-switch controller, whose value picks one. The first is the map from
+// --map, --choke and --map-file alone.
+pub struct MidiMaps {
+    maps: Vec<CcMap>,
+    current: usize,
+    switch_cc: Option<u8>,
+    // the map last switched to, plus one, for the main thread to announce
+    switched: Arc<AtomicU8>,
+    // --cc-smoothing, kept to retune it for a new sample rate
+    smoothing_seconds: f64,
+}
+
+impl MidiMaps {
+    pub fn new(maps: Vec<CcMap>, switch_cc: Option<u8>, switched: Arc<AtomicU8>) -> Self {
+        MidiMaps { maps, current: 0, switch_cc, switched, smoothing_seconds: 0.0 }
+    }
+
+    pub fn set_smoothing(&mut self, sample_rate: f64, seconds: f64) {
+        self.smoothing_seconds = seconds;
+        self.maps.iter_mut().for_each(|map| map.set_smoothing(sample_rate, seconds));
+    }
+
+    // For a plugin reactivated at another rate
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.set_smoothing(sample_rate, self.smoothing_seconds);
+    }
+
+    pub fn current(&mut self) -> &mut CcMap {
+        &mut self.maps[self.current]
+    }
+
+    pub fn switch(&mut self, time: u32, to: usize, events: &mut EventBuffer) {
+        if to >= self.maps.len() || to == self.current {
+            return;
+        }
+        self.maps[self.current].settle(time, events);
+        self.current = to;
+        self.switched.store(to as u8 + 1, Ordering::Relaxed);
+    }
+
+    // Switch if this is the --map-switch controller, on any channel
+    pub fn switch_by_cc(&mut self, time: u32, bytes: &[u8], events: &mut EventBuffer) -> bool {
+        match (bytes, self.switch_cc) {
+            (&[status, cc, value], Some(switch)) if status & 0xf0 == 0xb0 && cc == switch => {
+                let to = value as usize * self.maps.len() / 128;
+                self.switch(time, to, events);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// One of the plugin's note ports, and whether it takes CLAP note events or
+// only raw MIDI
+#[derive(Clone, Copy)]
+pub struct NotePort {
+    pub index: u16,
+    pub clap: bool,
+}
+
+impl Default for NotePort {
+    // what we assume without clap.note-ports: a single port taking anything
+    fn default() -> Self {
+        NotePort { index: 0, clap: true }
+    }
+}
+
+// --tuning and --master-transpose: semitones added to every note, sent as
+// note-expression tuning along with pitch bend. Only CLAP note ports can
+// take it; raw MIDI has no equivalent.
+pub struct Tuning {
+    offset: f64,
+    // pitch bend in semitones, per note port and channel
+    bend: Vec<[f64; 16]>,
+}
+
+impl Tuning {
+    pub fn new(a4: f64, transpose: f64, note_ports: usize) -> Self {
+        Tuning { offset: 12.0 * (a4 / 440.0).log2() + transpose, bend: vec![[0.0; 16]; note_ports.max(1)] }
+    }
+
+    fn set_bend(&mut self, time: u32, port: NotePort, channel: u16, semitones: f64, events: &mut EventBuffer) {
+        if let Some(bend) = self.bend.get_mut(port.index as usize) {
+            bend[channel as usize & 0x0f] = semitones;
+        }
+        let channel_notes = Pckn::new(port.index, channel, Match::All, Match::All);
+        events.push(&NoteExpressionEvent::new(time, channel_notes, NoteExpressionType::Tuning, self.offset + semitones));
+    }
+
+    // Right after a note-on: start the note at its channel's tuning
+    pub fn note(&self, time: u32, port: NotePort, channel: u16, key: u16, events: &mut EventBuffer) {
+        let bend = self.bend.get(port.index as usize).map_or(0.0, |bend| bend[channel as usize & 0x0f]);
+        if port.clap && self.offset + bend != 0.0 {
+            let note = Pckn::new(port.index, channel, key, Match::All);
+            events.push(&NoteExpressionEvent::new(time, note, NoteExpressionType::Tuning, self.offset + bend));
+        }
+    }
+}
+
+fn push_param(time: u32, id: ClapId, value: f64, events: &mut EventBuffer) {
+    events.push(&ParamValueEvent::new(time, id, Pckn::match_all(), value, Cookie::empty()));
+}
+
+// Append the CLAP equivalent of one MIDI message at `time` (in frames from
+// the start of the block being processed). Returns true if the plugin should
+// also be reset, to cut reverb and delay tails.
+pub fn translate(
+    time: u32,
+    bytes: &[u8],
+    port: NotePort,
+    cc_map: &mut CcMap,
+    tuning: &mut Tuning,
+    events: &mut EventBuffer,
+) -> bool {
+    let (status, data1, data2) = match *bytes {
+        [status, data1, data2] => (status, data1, data2),
+        [status, data1] => (status, data1, 0),
+        // realtime messages and sysex have nothing to say to a plugin here
+        _ => return false,
+    };
+    let channel = (status & 0x0f) as u16;
+    let (key, velocity) = (data1 as u16, data2 as f64 / 127.0);
+    if !port.clap {
+        // Only --map, --choke and All Sound Off are ours; the plugin reads the rest
+        match status & 0xf0 {
+            0x90 if data2 > 0 => {
+                cc_map.choke(time, port, channel, key, events);
+                events.push(&MidiEvent::new(time, port.index, [status, data1, data2]));
+            }
+            0xb0 if (data1 as usize) < CONTROLLERS && cc_map.push(time, data1, data2, events) => {}
+            0xf0 => {}
+            _ => events.push(&MidiEvent::new(time, port.index, [status, data1, data2])),
+        }
+        return status & 0xf0 == 0xb0 && data1 == 120;
+    }
+    match status & 0xf0 {
+        0x90 if data2 > 0 => {
+            cc_map.choke(time, port, channel, key, events);
+            note_on(time, port, channel, key, velocity, events);
+            tuning.note(time, port, channel, key, events);
+        }
+        // note-on with velocity 0 is a note-off
+        0x80 | 0x90 => note_off(time, port, channel, key, velocity, events),
+        0xe0 => {
+            let bend = ((data2 as i32) << 7 | data1 as i32) - 8192;
+            tuning.set_bend(time, port, channel, bend as f64 / 8192.0 * BEND_RANGE, events);
+        }
+        0xb0 if data1 as usize >= CONTROLLERS => return channel_mode(time, port, channel, data1, cc_map, tuning, events),
+        0xb0 if cc_map.push(time, data1, data2, events) => {}
+        0xf0 => {}
+        _ => events.push(&MidiEvent::new(time, port.index, [status, data1, data2])),
+    }
+    false
+}
+
+pub fn note_on(time: u32, port: NotePort, channel: u16, key: u16, velocity: f64, events: &mut EventBuffer) {
+    if port.clap {
+        events.push(&NoteOnEvent::new(time, Pckn::new(port.index, channel, key, Match::All), velocity));
+    } else {
+        let velocity = ((velocity * 127.0).round() as u8).clamp(1, 127);
+        events.push(&MidiEvent::new(time, port.index, [0x90 | channel as u8 & 0x0f, key as u8 & 0x7f, velocity]));
+    }
+}
+
+pub fn note_off(time: u32, port: NotePort, channel: u16, key: u16, velocity: f64, events: &mut EventBuffer) {
+    if port.clap {
+        events.push(&NoteOffEvent::new(time, Pckn::new(port.index, channel, key, Match::All), velocity));
+    } else {
+        let velocity = (velocity * 127.0).round().min(127.0) as u8;
+        events.push(&MidiEvent::new(time, port.index, [0x80 | channel as u8 & 0x0f, key as u8 & 0x7f, velocity]));
+    }
+}
+
+// Release every note on every channel of `port`
+pub fn all_notes_off(time: u32, port: NotePort, events: &mut EventBuffer) {
+    if port.clap {
+        events.push(&NoteOffEvent::new(time, Pckn::new(port.index, Match::All, Match::All, Match::All), 0.0));
+    } else {
+        for channel in 0..16 {
+            events.push(&MidiEvent::new(time, port.index, [0xb0 | channel, 123, 0]));
+        }
+    }
+}
+
+// The plugin's note output as MIDI: (time, note port, message). CLAP notes
+// addressed to a whole port or channel have no single MIDI equivalent.
+pub fn to_midi(event: &UnknownEvent) -> Option<(u32, u16, [u8; 3])> {
+    let time = event.header().time();
+    let specific = |pckn: (Match<u16>, Match<u16>, Match<u16>)| match pckn {
+        (Match::Specific(port), Match::Specific(channel), Match::Specific(key)) => {
+            Some((port, channel as u8 & 0x0f, key as u8 & 0x7f))
+        }
+        _ => None,
+    };
+    if let Some(e) = event.as_event::<NoteOnEvent>() {
+        let (port, channel, key) = specific((e.port_index(), e.channel(), e.key()))?;
+        let velocity = ((e.velocity() * 127.0).round() as u8).clamp(1, 127);
+        return Some((time, port, [0x90 | channel, key, velocity]));
+    }
+    if let Some(e) = event.as_event::<NoteOffEvent>() {
+        let (port, channel, key) = specific((e.port_index(), e.channel(), e.key()))?;
+        let velocity = (e.velocity() * 127.0).round().min(127.0) as u8;
+        return Some((time, port, [0x80 | channel, key, velocity]));
+    }
+    event.as_event::<MidiEvent>().map(|e| (time, e.port_index(), e.data()))
+}
+
+pub fn is_note(bytes: &[u8]) -> bool {
+    matches!(bytes, [status, _, _] if matches!(status & 0xf0, 0x80 | 0x90))
+}
+
+// CC 120-127. Returns true for All Sound Off, which also resets the plugin.
+fn channel_mode(
+    time: u32,
+    port: NotePort,
+    channel: u16,
+    controller: u8,
+    cc_map: &mut CcMap,
+    tuning: &mut Tuning,
+    events: &mut EventBuffer,
+) -> bool {
+    let channel_notes = Pckn::new(port.index, channel, Match::All, Match::All);
+    match controller {
+        // All Sound Off: end every voice now, without release
+        120 => {
+            events.push(&NoteChokeEvent::new(time, channel_notes));
+            return true;
+        }
+        // Reset All Controllers: recentre our pitch bend and reset --map'd
+        // parameters; the plugin gets the CC to reset its own mappings
+        121 => {
+            tuning.set_bend(time, port, channel, 0.0, events);
+            cc_map.reset(time, events);
+            events.push(&MidiEvent::new(time, port.index, [0xb0 | channel as u8, 121, 0]));
+        }
+        // Local Control only concerns a keyboard's own sound engine
+        122 => {}
+        // All Notes Off, and the omni/mono/poly switches that imply it
+        _ => events.push(&NoteOffEvent::new(time, channel_notes, 0.0)),
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timed_curves_start_and_end_on_the_values() {
+        for curve in [Curve::Linear, Curve::SCurve, Curve::Stepped] {
+            assert_eq!(curve.shape(0.0), 0.0, "{curve:?}");
+            assert_eq!(curve.shape(1.0), 1.0, "{curve:?}");
+        }
+        assert_eq!(Curve::Linear.shape(0.25), 0.25);
+        // the S eases in and out either side of the middle
+        assert!(Curve::SCurve.shape(0.1) < 0.1 && Curve::SCurve.shape(0.9) > 0.9);
+        assert_eq!(Curve::SCurve.shape(0.5), 0.5);
+        assert_eq!(Curve::Stepped.shape(0.3), 0.25);
+        assert_eq!(Curve::Stepped.shape(0.99), 0.75);
+    }
+}
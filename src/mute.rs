@@ -0,0 +1,123 @@
+// Mute and solo per JACK output, for checking the channels of multi-output
+// plugins one at a time. The switches are bitmasks shared by the console,
+// OSC and --map'd controllers; the audio thread ramps each output's gain
+// towards what they say, so switching doesn't click. While anything is
+// soloed, only soloed outputs are heard.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// One bit per output in each mask
+pub const MAX_OUTPUTS: usize = 64;
+
+// How long a switch takes to fade an output in or out
+const RAMP_SECONDS: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Switch {
+    Mute,
+    Solo,
+}
+
+impl Switch {
+    pub fn name(self) -> &'static str {
+        match self {
+            Switch::Mute => "mute",
+            Switch::Solo => "solo",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Switches {
+    muted: AtomicU64,
+    soloed: AtomicU64,
+}
+
+impl Switches {
+    fn mask(&self, switch: Switch) -> &AtomicU64 {
+        match switch {
+            Switch::Mute => &self.muted,
+            Switch::Solo => &self.soloed,
+        }
+    }
+
+    pub fn set(&self, switch: Switch, output: usize, on: bool) {
+        let bit = 1 << output;
+        match on {
+            true => self.mask(switch).fetch_or(bit, Ordering::Relaxed),
+            false => self.mask(switch).fetch_and(!bit, Ordering::Relaxed),
+        };
+    }
+
+    pub fn is_on(&self, switch: Switch, output: usize) -> bool {
+        self.mask(switch).load(Ordering::Relaxed) & 1 << output != 0
+    }
+
+    // A bit for each output that should be heard
+    fn audible(&self) -> u64 {
+        match self.soloed.load(Ordering::Relaxed) {
+            0 => !self.muted.load(Ordering::Relaxed),
+            soloed => soloed,
+        }
+    }
+}
+
+// An output by our port name (out_l, out_sidechain_r...) or its number from 1
+pub fn find(outputs: &[String], which: &str) -> Result<usize, String> {
+    let index = match which.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).filter(|i| *i < outputs.len()),
+        Err(_) => outputs.iter().position(|name| name == which),
+    };
+    match index {
+        Some(i) if i < MAX_OUTPUTS => Ok(i),
+        Some(_) => Err(format!("only the first {MAX_OUTPUTS} outputs can be muted or soloed")),
+        None => Err(format!("no output {which:?} (we have {}, or 1 to {})", outputs.join(", "), outputs.len())),
+    }
+}
+
+// The audio thread's side: each output's gain on its way to the switches
+pub struct OutputGains {
+    switches: Arc<Switches>,
+    gains: Vec<f32>,
+    // gain change per frame while ramping
+    step: f32,
+    audible: u64,
+}
+
+impl OutputGains {
+    pub fn new(switches: Arc<Switches>, outputs: usize, sample_rate: f64) -> Self {
+        OutputGains {
+            switches,
+            gains: vec![1.0; outputs.min(MAX_OUTPUTS)],
+            step: (1.0 / (RAMP_SECONDS * sample_rate)) as f32,
+            audible: u64::MAX,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.step = (1.0 / (RAMP_SECONDS * sample_rate)) as f32;
+    }
+
+    // Once per block, before process()
+    pub fn update(&mut self) {
+        self.audible = self.switches.audible();
+    }
+
+    pub fn process(&mut self, output: usize, buf: &mut [f32]) {
+        let Some(gain) = self.gains.get_mut(output) else { return };
+        let target = if self.audible & 1 << output != 0 { 1.0 } else { 0.0 };
+        if *gain == target {
+            if target == 0.0 {
+                buf.fill(0.0);
+            }
+            return;
+        }
+        for sample in buf.iter_mut() {
+            *gain = match target > *gain {
+                true => (*gain + self.step).min(target),
+                false => (*gain - self.step).max(target),
+            };
+            *sample *= *gain;
+        }
+    }
+}
@@ -0,0 +1,439 @@
+// Offline (JACK-free) rendering, used by the plugin test modes and `render`.
+use std::ffi::CStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use clack_host::events::event_types::ParamValueEvent;
+use clack_host::events::io::{EventBuffer, InputEvents};
+use clack_host::events::Pckn;
+use clack_host::prelude::*;
+use clack_host::utils::Cookie;
+
+use crate::layout::{self, Layout};
+use crate::sinks::{Sink, WavSink};
+use crate::{host_info, instantiate, load_plugin, params, presets, process_ports, state};
+
+// Largest per-sample difference we still treat as "the same output"
+const TOLERANCE: f32 = 1e-6;
+
+pub struct OfflineConfig {
+    pub sample_rate: f64,
+    pub max_block: u32,
+    pub frames: usize,
+}
+
+// Where a render starts from, other than the plugin's defaults
+#[derive(Clone, Copy, Default)]
+pub struct Setup<'a> {
+    pub load_state: Option<&'a Path>,
+    // a preset location, loaded over the state
+    pub preset: Option<&'a str>,
+    // NAME=VALUE or ID=VALUE, as for --param
+    pub params: &'a [(String, String)],
+}
+
+// Render `cfg.frames` from a fresh plugin instance, set up as `setup` says
+// and fed with silence on every input, with the buffers laid out for its
+// audio ports as the JACK path does. Returns that layout and every output
+// channel, port after port. `block_size` is given the block index and
+// returns the size of that block (clamped to 1..=max_block).
+fn render(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &OfflineConfig,
+    setup: &Setup,
+    mut block_size: impl FnMut(usize) -> usize,
+) -> Result<(Layout, Vec<Vec<f32>>), Box<dyn std::error::Error>> {
+    let mut instance = instantiate(bundle, plugin_id, host_info)?;
+    if let Some(path) = setup.load_state {
+        match state::load(&mut instance, path, &state::Identity::of(bundle, plugin_id)) {
+            Ok(()) => {}
+            Err(state::LoadError::Missing) => return Err(format!("no state in {}", path.display()).into()),
+            Err(e) => return Err(e.to_string().into()),
+        }
+    }
+    if let Some(location) = setup.preset {
+        presets::load(&mut instance, location)?;
+    }
+    // Parameters go with the first block
+    let mut events = EventBuffer::new();
+    for (id, value) in params::resolve(&mut instance, setup.params).map_err(|e| format!("--param: {e}"))? {
+        events.push(&ParamValueEvent::new(0, id, Pckn::match_all(), value, Cookie::empty()));
+    }
+    let layout = Layout::query(&mut instance);
+    if layout.output_channels() == 0 {
+        return Err("the plugin has no audio output".into());
+    }
+    let audio_cfg = PluginAudioConfiguration {
+        sample_rate: cfg.sample_rate,
+        min_frames_count: 1,
+        max_frames_count: cfg.max_block,
+    };
+    let mut proc = instance.activate(|_, _| (), audio_cfg)?.start_processing()?;
+
+    // Everything the block loop needs, allocated once
+    let max = cfg.max_block as usize;
+    let mut inputs = vec![vec![0.0; max]; layout.input_channels()];
+    let mut scratch = vec![vec![0.0; max]; layout.output_channels()];
+    let mut outputs: Vec<&mut [f32]> = scratch.iter_mut().map(Vec::as_mut_slice).collect();
+    let mut ports = [
+        AudioPorts::with_capacity(layout.input_channels(), layout.inputs.len()),
+        AudioPorts::with_capacity(layout.output_channels(), layout.outputs.len()),
+    ];
+    let mut out_events = EventBuffer::with_capacity(256);
+    let mut rendered: Vec<Vec<f32>> = (0..layout.output_channels()).map(|_| Vec::with_capacity(cfg.frames)).collect();
+
+    let mut pos = 0;
+    let mut block = 0;
+    while pos < cfg.frames {
+        let n = block_size(block).clamp(1, max).min(cfg.frames - pos);
+        out_events.clear();
+        process_ports(
+            &mut proc,
+            pos as u64,
+            None,
+            &InputEvents::from_buffer(&events),
+            &mut out_events,
+            &layout,
+            &mut ports,
+            &mut inputs,
+            &mut outputs,
+            0..n,
+        )?;
+        for (channel, out) in rendered.iter_mut().zip(&outputs) {
+            channel.extend_from_slice(&out[..n]);
+        }
+        events.clear();
+        pos += n;
+        block += 1;
+    }
+
+    instance.deactivate(proc.stop_processing());
+    Ok((layout, rendered))
+}
+
+// Block sizes for the sweep: 1, 2, ..., max, then round again
+fn sweep_block_size(block: usize, max: usize) -> usize {
+    block % max + 1
+}
+
+// Size of the sweep block that contains `frame`
+fn sweep_block_at(frame: usize, max: usize) -> usize {
+    let mut pos = 0;
+    let mut block = 0;
+    loop {
+        let n = sweep_block_size(block, max);
+        if frame < pos + n {
+            return n;
+        }
+        pos += n;
+        block += 1;
+    }
+}
+
+// First (channel, frame) where the renders differ, plus the largest difference overall
+fn compare(a: &[Vec<f32>], b: &[Vec<f32>]) -> Option<((usize, usize), f32)> {
+    let mut first = None;
+    let mut max_diff = 0.0f32;
+    for (ch, (a, b)) in a.iter().zip(b).enumerate() {
+        for (frame, (x, y)) in a.iter().zip(b).enumerate() {
+            let diff = (x - y).abs();
+            if diff > TOLERANCE {
+                max_diff = max_diff.max(diff);
+                match first {
+                    Some((_, f)) if f <= frame => {}
+                    _ => first = Some((ch, frame)),
+                }
+            }
+        }
+    }
+    first.map(|at| (at, max_diff))
+}
+
+// What the block-size test found, for main to pick an exit status by
+pub enum BlockSizeOutcome {
+    Independent,
+    DependsOnBlockSize,
+    // two renders alike differ, so there was nothing to compare
+    NotDeterministic,
+}
+
+// Render with a fixed block size and with a 1..=max sweep, and report
+// whether the plugin's output depends on how the host slices the audio.
+pub fn block_size_test(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &OfflineConfig,
+) -> Result<BlockSizeOutcome, Box<dyn std::error::Error>> {
+    let max = cfg.max_block as usize;
+    println!(
+        "Block-size test: {} frames at {} Hz, reference block {max}, sweep 1..={max}",
+        cfg.frames, cfg.sample_rate
+    );
+
+    // Two reference renders: if these differ the plugin isn't deterministic
+    // and comparing block sizes tells us nothing.
+    let (_, reference) = render(bundle, plugin_id, host_info, cfg, &Setup::default(), |_| max)?;
+    let (_, again) = render(bundle, plugin_id, host_info, cfg, &Setup::default(), |_| max)?;
+    if let Some(((ch, frame), diff)) = compare(&reference, &again) {
+        eprintln!(
+            "Plugin output is not deterministic: two identical renders differ \
+             (first at frame {frame}, channel {ch}; max diff {diff:e}). \
+             Block-size comparison is not meaningful for this plugin."
+        );
+        return Ok(BlockSizeOutcome::NotDeterministic);
+    }
+
+    let (_, swept) = render(bundle, plugin_id, host_info, cfg, &Setup::default(), |b| sweep_block_size(b, max))?;
+    match compare(&reference, &swept) {
+        None => {
+            println!("OK: output is independent of block size.");
+            Ok(BlockSizeOutcome::Independent)
+        }
+        Some(((ch, frame), diff)) => {
+            eprintln!(
+                "FAIL: output depends on block size. First difference at frame {frame} \
+                 (channel {ch}, inside a {}-frame block); max diff {diff:e}.",
+                sweep_block_at(frame, max)
+            );
+            Ok(BlockSizeOutcome::DependsOnBlockSize)
+        }
+    }
+}
+
+// `render`: bounce the plugin's main output to a 32-bit float WAV with a
+// channel for each of its channels, in blocks of cfg.max_block, as
+// regression references or test files. With `stems`, each output bus also
+// gets a WAV of its own, and `out` mixes them all down.
+pub fn bounce(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &OfflineConfig,
+    setup: &Setup,
+    out: &Path,
+    stems: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bounced = bounce_quietly(bundle, plugin_id, host_info, cfg, setup, out, stems)?;
+    println!(
+        "Rendered {:.1}s to {} ({} channel(s), peak {:.1} dBFS)",
+        cfg.frames as f64 / cfg.sample_rate,
+        out.display(),
+        bounced.channels,
+        20.0 * bounced.peak.max(1e-10).log10()
+    );
+    for (name, path) in &bounced.stems {
+        println!("  {name:?} to {}", path.display());
+    }
+    Ok(())
+}
+
+// What a bounce wrote, for the caller to report
+struct Bounced {
+    channels: usize,
+    peak: f32,
+    // each bus's port name and file, with `stems`
+    stems: Vec<(String, PathBuf)>,
+}
+
+fn bounce_quietly(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &OfflineConfig,
+    setup: &Setup,
+    out: &Path,
+    stems: bool,
+) -> Result<Bounced, Box<dyn std::error::Error>> {
+    let block = cfg.max_block as usize;
+    let (layout, rendered) = render(bundle, plugin_id, host_info, cfg, setup, |_| block)?;
+    let main_channels = layout.outputs[0].channels;
+    if main_channels == 0 {
+        return Err("the plugin's main output has no channels".into());
+    }
+    let main = match stems {
+        true => mixdown(&layout, &rendered),
+        false => rendered[..main_channels].to_vec(),
+    };
+    write_wav(out, cfg.sample_rate as u32, &main)?;
+    let peak = main.iter().flatten().fold(0.0f32, |peak, s| peak.max(s.abs()));
+
+    let mut written = Vec::new();
+    if stems {
+        let mut first = 0;
+        for (i, port) in layout.outputs.iter().enumerate() {
+            let bus = &rendered[first..first + port.channels];
+            first += port.channels;
+            if bus.is_empty() {
+                continue;
+            }
+            let path = stem_path(out, &layout::port_base(port, i, "out"));
+            write_wav(&path, cfg.sample_rate as u32, bus)?;
+            written.push((port.name.clone(), path));
+        }
+    }
+    Ok(Bounced { channels: main.len(), peak, stems: written })
+}
+
+// `render --batch`: bounce every preset, each from a fresh instance, into
+// `out_dir` as <preset>.wav, keeping the folders they sit in under `dir`.
+// `jobs` workers run at once, each with the bundle loaded for itself, so
+// plugins that keep per-thread or main-thread state still see one thread
+// per instance. A failed preset is reported and the rest carry on.
+#[allow(clippy::too_many_arguments)]
+pub fn batch(
+    bundle_path: &Path,
+    plugin_id: &str,
+    cfg: &OfflineConfig,
+    setup: &Setup,
+    dir: &Path,
+    presets: &[PathBuf],
+    out_dir: &Path,
+    stems: bool,
+    jobs: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = jobs.clamp(1, presets.len().max(1));
+    println!("Rendering {} preset(s) to {}, {jobs} at a time", presets.len(), out_dir.display());
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
+    let fail = |preset: &Path, e: String| {
+        let mut failed = failed.lock().unwrap();
+        failed.push((preset.to_path_buf(), e));
+        failed.len()
+    };
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                let loaded = load_plugin(bundle_path, plugin_id)
+                    .map_err(|e| e.to_string())
+                    .and_then(|(bundle, id)| host_info().map(|info| (bundle, id, info)).map_err(|e| e.to_string()));
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(preset) = presets.get(i) else { return };
+                    let result = loaded.as_ref().map_err(Clone::clone).and_then(|(bundle, id, info)| {
+                        let location = preset.to_str().ok_or("the path is not UTF-8")?;
+                        let out = out_dir.join(preset.strip_prefix(dir).unwrap_or(preset)).with_extension("wav");
+                        if let Some(parent) = out.parent() {
+                            std::fs::create_dir_all(parent).map_err(|e| format!("{}: {e}", parent.display()))?;
+                        }
+                        let setup = Setup { preset: Some(location), ..*setup };
+                        bounce_quietly(bundle, id, info, cfg, &setup, &out, stems).map(drop).map_err(|e| e.to_string())
+                    });
+                    let failures = match result {
+                        Ok(()) => failed.lock().unwrap().len(),
+                        Err(e) => fail(preset, e),
+                    };
+                    progress(done.fetch_add(1, Ordering::Relaxed) + 1, presets.len(), failures);
+                }
+            });
+        }
+    });
+    eprintln!();
+
+    let failed = failed.into_inner().unwrap();
+    for (preset, e) in &failed {
+        eprintln!("  {}: {e}", preset.display());
+    }
+    match failed.len() {
+        0 => Ok(()),
+        n => Err(format!("{n} of {} render(s) failed", presets.len()).into()),
+    }
+}
+
+// One line, redrawn in place as renders finish
+fn progress(done: usize, total: usize, failed: usize) {
+    const WIDTH: usize = 30;
+    let filled = done * WIDTH / total.max(1);
+    let failed = if failed > 0 { format!(", {failed} failed") } else { String::new() };
+    let mut err = std::io::stderr().lock();
+    let _ = write!(err, "\r[{}{}] {done}/{total}{failed}", "#".repeat(filled), " ".repeat(WIDTH - filled));
+    let _ = err.flush();
+}
+
+// Every bus added onto the main one's channels: a mono bus into each of
+// them, wider ones channel by channel, wrapping round if there are more
+fn mixdown(layout: &Layout, rendered: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let mut main = rendered[..layout.outputs[0].channels].to_vec();
+    let mut first = main.len();
+    for port in &layout.outputs[1..] {
+        for c in 0..port.channels {
+            let targets = match port.channels {
+                1 => 0..main.len(),
+                _ => c % main.len()..c % main.len() + 1,
+            };
+            for target in &mut main[targets] {
+                target.iter_mut().zip(&rendered[first + c]).for_each(|(sum, s)| *sum += s);
+            }
+        }
+        first += port.channels;
+    }
+    main
+}
+
+// song.wav and "out_kick" make song_out_kick.wav, beside it
+fn stem_path(out: &Path, name: &str) -> PathBuf {
+    let stem = out.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    out.with_file_name(format!("{stem}_{name}.wav"))
+}
+
+// The channels interleaved into one WAV
+fn write_wav(path: &Path, sample_rate: u32, channels: &[Vec<f32>]) -> Result<(), String> {
+    let mut wav = Box::new(WavSink::create(path, channels.len() as u16, sample_rate)?);
+    let mut frame = vec![0.0; channels.len()];
+    for i in 0..channels[0].len() {
+        frame.iter_mut().zip(channels).for_each(|(s, channel)| *s = channel[i]);
+        wav.write(&frame)?;
+    }
+    wav.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sweep_runs_through_every_size_and_round_again() {
+        let sizes: Vec<usize> = (0..7).map(|block| sweep_block_size(block, 3)).collect();
+        assert_eq!(sizes, [1, 2, 3, 1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn sweep_block_at_finds_the_block_holding_a_frame() {
+        // blocks of 1, 2, 3, then 1, 2, 3 again: frames 0 | 1 2 | 3 4 5 | 6 | 7 8
+        let at: Vec<usize> = (0..9).map(|frame| sweep_block_at(frame, 3)).collect();
+        assert_eq!(at, [1, 2, 2, 3, 3, 3, 1, 2, 2]);
+    }
+
+    #[test]
+    fn compare_ignores_differences_within_tolerance() {
+        let a = vec![vec![0.5; 4], vec![-0.5; 4]];
+        let mut b = a.clone();
+        b[1][2] += TOLERANCE / 2.0;
+        assert_eq!(compare(&a, &b), None);
+    }
+
+    #[test]
+    fn compare_reports_the_earliest_frame_on_any_channel() {
+        let a = vec![vec![0.0; 8], vec![0.0; 8]];
+        let mut b = a.clone();
+        b[0][6] = 0.25;
+        b[1][3] = 0.5;
+        // the later channel's difference comes first in time, and the
+        // largest difference is taken over both
+        assert_eq!(compare(&a, &b), Some(((1, 3), 0.5)));
+    }
+
+    #[test]
+    fn compare_prefers_the_first_channel_at_the_same_frame() {
+        let a = vec![vec![0.0; 4], vec![0.0; 4]];
+        let mut b = a.clone();
+        b[0][2] = 0.1;
+        b[1][2] = 0.2;
+        assert_eq!(compare(&a, &b), Some(((0, 2), 0.2)));
+    }
+}
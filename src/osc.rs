@@ -0,0 +1,180 @@
+// OSC control over UDP, for TouchOSC, Open Stage Control and friends. Each
+// message is turned into the equivalent console command, so OSC and stdin
+// share one parser and one path to the audio thread:
+//
+//     /param/<name or id> <value>   -> set <param> <value>
+//     /bypass [0|1]                 -> bypass
+//     /fade in|out <seconds>        -> fade
+//     /mute/<output> [0|1], /solo/<output> [0|1]
+//     /note_on <ch> <key> [vel]     -> note_on
+//     /note_off <ch> <key>          -> note_off
+//     /transport/start, /transport/stop, /transport/locate <frame>
+//     /dump [file.wav]              (a file name only, saved in --dump-dir)
+//     /record [file.wav|stop]       (the same)
+//     /sink/<name> [0|1]            -> sink, e.g. /sink/jack 0
+//     /preset next|prev|random|<name in the --preset-bank>
+//     /map <name>                   -> map, to switch --map-profile
+//     /slot <n>                     -> slot, to recall a --slot state
+//     /macro <name>                 -> macro, to run a --macro
+//     /gui show|hide, /gui resize <w> <h>, /gui scale <factor>
+//
+// There is no authentication, so we listen on localhost unless --osc-bind
+// says otherwise.
+//
+// Going the other way, --osc-alert gets each guardrail alert as
+// /alert <kind> <message>, e.g. /alert silence "plugin output silent for 60s",
+// and --envelope-osc the --envelope-follow level as /envelope <0..1>.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::time::Duration;
+
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::dropped::{Dropped, Kind};
+use crate::repl::Source;
+
+// Receive errors in a row before we give up on the socket
+const MAX_ERRORS: u32 = 10;
+
+// A full command queue drops what comes in, counted, except for note-offs,
+// which wait for room rather than leave a note hanging
+pub fn spawn(
+    addr: IpAddr,
+    port: u16,
+    commands: SyncSender<(Source, String)>,
+    dropped: Arc<Dropped>,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((addr, port))?;
+    std::thread::spawn(move || {
+        let mut buf = [0u8; rosc::decoder::MTU];
+        let mut errors = 0;
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    errors += 1;
+                    if errors == MAX_ERRORS {
+                        eprintln!("OSC: {e}; giving up after {MAX_ERRORS} errors in a row");
+                        return;
+                    }
+                    eprintln!("OSC: {e}");
+                    std::thread::sleep(Duration::from_millis(100) * errors);
+                    continue;
+                }
+            };
+            errors = 0;
+            let packet = match rosc::decoder::decode_udp(&buf[..len]) {
+                Ok((_, packet)) => packet,
+                Err(e) => {
+                    eprintln!("OSC: bad packet from {from}: {e:?}");
+                    continue;
+                }
+            };
+            let mut messages = Vec::new();
+            flatten(packet, &mut messages);
+            for msg in messages {
+                let Some(command) = to_command(&msg) else {
+                    eprintln!("OSC: don't know {}", msg.addr);
+                    continue;
+                };
+                let sent = match commands.try_send((Source::Remote, command)) {
+                    Err(TrySendError::Full(command)) if command.1.starts_with("note_off ") => {
+                        commands.send(command).is_ok()
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        dropped.count(Kind::Command);
+                        true
+                    }
+                    result => result.is_ok(),
+                };
+                if !sent {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Messages we send out, for --osc-alert and --envelope-osc
+pub struct Outgoing {
+    socket: UdpSocket,
+    to: SocketAddr,
+}
+
+impl Outgoing {
+    pub fn new(to: SocketAddr) -> std::io::Result<Self> {
+        let any: IpAddr = if to.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        Ok(Outgoing { socket: UdpSocket::bind((any, 0))?, to })
+    }
+
+    pub fn alert(&self, kind: &str, message: &str) {
+        self.send("/alert", vec![OscType::String(kind.into()), OscType::String(message.into())]);
+    }
+
+    pub fn envelope(&self, level: f32) {
+        self.send("/envelope", vec![OscType::Float(level)]);
+    }
+
+    // Fire and forget: nobody listening only costs a warning
+    fn send(&self, addr: &str, args: Vec<OscType>) {
+        let packet = OscPacket::Message(OscMessage { addr: addr.into(), args });
+        let sent = rosc::encoder::encode(&packet)
+            .map_err(|e| format!("{e:?}"))
+            .and_then(|bytes| self.socket.send_to(&bytes, self.to).map_err(|e| e.to_string()));
+        if let Err(e) = sent {
+            eprintln!("OSC: {addr} to {}: {e}", self.to);
+        }
+    }
+}
+
+// Bundles are applied straight away, in order; we don't schedule by timetag
+fn flatten(packet: OscPacket, out: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(msg) => out.push(msg),
+        OscPacket::Bundle(bundle) => bundle.content.into_iter().for_each(|p| flatten(p, out)),
+    }
+}
+
+fn to_command(msg: &OscMessage) -> Option<String> {
+    let args: Vec<String> = msg.args.iter().filter_map(arg_text).collect();
+    if let Some(param) = msg.addr.strip_prefix("/param/") {
+        return Some(format!("set {param} {}", args.first()?));
+    }
+    for switch in ["mute", "solo", "sink"] {
+        if let Some(output) = msg.addr.strip_prefix(&format!("/{switch}/")) {
+            return Some(std::iter::once(format!("{switch} {output}")).chain(args).collect::<Vec<_>>().join(" "));
+        }
+    }
+    let command = match msg.addr.as_str() {
+        "/bypass" => "bypass",
+        "/fade" => "fade",
+        "/note_on" => "note_on",
+        "/note_off" => "note_off",
+        "/transport/start" => "play",
+        "/transport/stop" => "stop",
+        "/transport/locate" => "locate",
+        "/dump" => "dump",
+        "/record" => "record",
+        "/preset" => "preset",
+        "/map" => "map",
+        "/slot" => "slot",
+        "/macro" => "macro",
+        "/gui" => "gui",
+        _ => return None,
+    };
+    Some(std::iter::once(command.to_string()).chain(args).collect::<Vec<_>>().join(" "))
+}
+
+fn arg_text(arg: &OscType) -> Option<String> {
+    match arg {
+        OscType::Float(v) => Some(v.to_string()),
+        OscType::Double(v) => Some(v.to_string()),
+        OscType::Int(v) => Some(v.to_string()),
+        OscType::Long(v) => Some(v.to_string()),
+        OscType::Bool(v) => Some(if *v { "1" } else { "0" }.into()),
+        OscType::String(s) => Some(s.clone()),
+        _ => None,
+    }
+}
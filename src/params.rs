@@ -0,0 +1,176 @@
+// `params`: dump every parameter the plugin exposes through clap.params, with
+// its range, default and current value. Also resolves parameters by name for
+// --param, --map and the stdin console.
+use std::ffi::{CStr, CString};
+
+use clack_extensions::params::{ParamInfoBuffer, ParamInfoFlags, PluginParams};
+use clack_host::prelude::*;
+use clack_host::utils::ClapId;
+
+use crate::{instantiate, MyHost};
+
+// What we keep of a ParamInfo once its buffer is reused
+#[derive(Clone)]
+pub struct Param {
+    pub id: ClapId,
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    pub default: f64,
+    pub stepped: bool,
+}
+
+pub fn run(bundle: &PluginBundle, plugin_id: &CStr, host_info: &HostInfo) -> Result<(), Box<dyn std::error::Error>> {
+    let mut instance = instantiate(bundle, plugin_id, host_info)?;
+    print_table(&mut instance)?;
+    Ok(())
+}
+
+pub fn print_table(instance: &mut PluginInstance<MyHost>) -> Result<(), String> {
+    let mut handle = instance.plugin_handle();
+    let params = handle
+        .get_extension::<PluginParams>()
+        .ok_or("Plugin does not support clap.params")?;
+
+    let mut buffer = ParamInfoBuffer::new();
+    let count = params.count(&mut handle);
+    println!("{:>10}  {:<32} {:>12} {:>12} {:>12} {:>12}  flags", "id", "name", "min", "max", "default", "value");
+    for i in 0..count {
+        let Some(info) = params.get_info(&mut handle, i, &mut buffer) else { continue };
+        let (id, flags) = (info.id, info.flags);
+        let (min, max, default) = (info.min_value, info.max_value, info.default_value);
+        let mut name = String::from_utf8_lossy(info.module).into_owned();
+        if !name.is_empty() {
+            name.push('/');
+        }
+        name.push_str(&String::from_utf8_lossy(info.name));
+
+        let value = params
+            .get_value(&mut handle, id)
+            .map_or("-".into(), |v| format!("{v:.4}"));
+        println!(
+            "{:>10}  {name:<32} {min:>12.4} {max:>12.4} {default:>12.4} {value:>12}  {}",
+            id.get(),
+            describe_flags(flags)
+        );
+    }
+    println!("{count} parameter(s)");
+    Ok(())
+}
+
+fn describe_flags(flags: ParamInfoFlags) -> String {
+    let names = [
+        (ParamInfoFlags::IS_STEPPED, "stepped"),
+        (ParamInfoFlags::IS_READONLY, "readonly"),
+        (ParamInfoFlags::IS_HIDDEN, "hidden"),
+        (ParamInfoFlags::IS_BYPASS, "bypass"),
+        (ParamInfoFlags::IS_AUTOMATABLE, "automatable"),
+        (ParamInfoFlags::IS_MODULATABLE, "modulatable"),
+    ];
+    let set: Vec<_> = names.iter().filter(|(f, _)| flags.contains(*f)).map(|(_, n)| *n).collect();
+    set.join(",")
+}
+
+// Every parameter the plugin has; none without clap.params
+pub fn list(instance: &mut PluginInstance<MyHost>) -> Vec<Param> {
+    let mut handle = instance.plugin_handle();
+    handle
+        .get_extension::<PluginParams>()
+        .map_or(Vec::new(), |params| all_params(params, &mut handle))
+}
+
+fn all_params(params: PluginParams, handle: &mut PluginMainThreadHandle<'_>) -> Vec<Param> {
+    let mut buffer = ParamInfoBuffer::new();
+    (0..params.count(handle))
+        .filter_map(|i| {
+            let info = params.get_info(handle, i, &mut buffer)?;
+            let name = String::from_utf8_lossy(info.name).into_owned();
+            Some(Param {
+                id: info.id,
+                name,
+                min: info.min_value,
+                max: info.max_value,
+                default: info.default_value,
+                stepped: info.flags.contains(ParamInfoFlags::IS_STEPPED),
+            })
+        })
+        .collect()
+}
+
+// Names match exactly first, then ignoring case, then as a numeric ID
+fn find<'a>(all: &'a [Param], param: &str) -> Result<&'a Param, String> {
+    all.iter()
+        .find(|p| p.name == param)
+        .or_else(|| all.iter().find(|p| p.name.eq_ignore_ascii_case(param)))
+        .or_else(|| {
+            let id: u32 = param.parse().ok()?;
+            all.iter().find(|p| p.id.get() == id)
+        })
+        .ok_or_else(|| format!("no parameter {param:?} (see the `params` subcommand)"))
+}
+
+// Turn NAME=VALUE / ID=VALUE pairs into (id, value) ready to send. Values
+// are numbers or whatever text the plugin's text_to_value understands
+// (e.g. "white").
+pub fn resolve(
+    instance: &mut PluginInstance<MyHost>,
+    settings: &[(String, String)],
+) -> Result<Vec<(ClapId, f64)>, String> {
+    if settings.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut handle = instance.plugin_handle();
+    let params = handle
+        .get_extension::<PluginParams>()
+        .ok_or("plugin does not support clap.params")?;
+    let all = all_params(params, &mut handle);
+
+    let mut resolved = Vec::with_capacity(settings.len());
+    for (param, text) in settings {
+        let Param { id, name, min, max, .. } = find(&all, param)?;
+        let value = match text.parse::<f64>() {
+            Ok(value) => value,
+            Err(_) => {
+                let c_text = CString::new(text.as_str()).map_err(|_| format!("bad value {text:?}"))?;
+                params
+                    .text_to_value(&mut handle, *id, &c_text)
+                    .ok_or_else(|| format!("{name} does not understand {text:?}"))?
+            }
+        };
+        if !(*min..=*max).contains(&value) {
+            return Err(format!("{name}={value} is outside {min}..={max}"));
+        }
+        resolved.push((*id, value));
+    }
+    Ok(resolved)
+}
+
+// One parameter by name or ID
+pub fn lookup(instance: &mut PluginInstance<MyHost>, param: &str) -> Result<Param, String> {
+    let mut handle = instance.plugin_handle();
+    let params = handle
+        .get_extension::<PluginParams>()
+        .ok_or("plugin does not support clap.params")?;
+    find(&all_params(params, &mut handle), param).cloned()
+}
+
+// Current value of a parameter we've already looked up
+pub fn current(instance: &mut PluginInstance<MyHost>, id: ClapId) -> Option<f64> {
+    let mut handle = instance.plugin_handle();
+    let params = handle.get_extension::<PluginParams>()?;
+    params.get_value(&mut handle, id)
+}
+
+// Current value of one parameter, with its full name
+pub fn value_of(instance: &mut PluginInstance<MyHost>, param: &str) -> Result<(String, f64), String> {
+    let mut handle = instance.plugin_handle();
+    let params = handle
+        .get_extension::<PluginParams>()
+        .ok_or("plugin does not support clap.params")?;
+    let all = all_params(params, &mut handle);
+    let found = find(&all, param)?;
+    let value = params
+        .get_value(&mut handle, found.id)
+        .ok_or_else(|| format!("{} has no value", found.name))?;
+    Ok((found.name.clone(), value))
+}
@@ -0,0 +1,312 @@
+// `presets` and --preset: list the bundle's preset discovery providers, and
+// start a plugin from a factory preset through clap.preset-load.
+//
+// A preset location is a file path, with an optional `#KEY` for files that
+// hold several presets, or `plugin:KEY` for presets built into the plugin.
+//
+// --preset-bank: a directory of preset files to step through while running,
+// with `preset next/prev/random` or MIDI notes and CCs bound to those. The
+// subdirectories a preset sits in are its tags, for --preset-tag.
+use std::ffi::{CStr, CString};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clack_extensions::preset_discovery::{Location, PresetDiscoveryFactory};
+use clack_extensions::preset_load::PluginPresetLoad;
+use clack_host::prelude::*;
+
+use crate::MyHost;
+
+pub fn run(bundle_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let bundle = unsafe { PluginBundle::load(bundle_path) }
+        .map_err(|e| format!("Failed to load bundle: {e:?}"))?;
+    let Some(factory) = bundle.get_factory::<PresetDiscoveryFactory>() else {
+        println!("{} has no preset discovery factory", bundle_path.display());
+        return Ok(());
+    };
+
+    let text = |s: Option<&CStr>| s.map_or("-".into(), |s| s.to_string_lossy().into_owned());
+    let mut count = 0;
+    for desc in factory.provider_descriptors() {
+        println!("{}", text(desc.id()));
+        println!("  name:     {}", text(desc.name()));
+        println!("  vendor:   {}", text(desc.vendor()));
+        count += 1;
+    }
+    println!("{count} preset provider(s) in {}", bundle_path.display());
+    Ok(())
+}
+
+// Ask the plugin to load a preset. The outcome arrives later through the
+// host's preset-load callbacks, which log it.
+pub fn load(instance: &mut PluginInstance<MyHost>, location: &str) -> Result<(), String> {
+    let c = |s: &str| CString::new(s).map_err(|_| format!("bad preset location {location:?}"));
+    let (path, key) = match location.strip_prefix("plugin:") {
+        Some(key) => (None, Some(c(key)?)),
+        None => match location.rsplit_once('#') {
+            Some((path, key)) => (Some(c(path)?), Some(c(key)?)),
+            None => (Some(c(location)?), None),
+        },
+    };
+    let location_ref = match &path {
+        Some(path) => Location::File { path },
+        None => Location::Plugin,
+    };
+
+    let mut handle = instance.plugin_handle();
+    let preset_load = handle
+        .get_extension::<PluginPresetLoad>()
+        .ok_or("plugin does not support clap.preset-load")?;
+    preset_load
+        .from_location(&mut handle, location_ref, key.as_deref())
+        .map_err(|e| format!("plugin could not load {location:?}: {e:?}"))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Next,
+    Prev,
+    Random,
+}
+
+impl Step {
+    pub fn parse(s: &str) -> Option<Step> {
+        match s {
+            "next" => Some(Step::Next),
+            "prev" => Some(Step::Prev),
+            "random" => Some(Step::Random),
+            _ => None,
+        }
+    }
+
+    // For handing over from the audio thread in an atomic; 0 is "none"
+    pub fn code(self) -> u8 {
+        self as u8 + 1
+    }
+
+    pub fn from_code(code: u8) -> Option<Step> {
+        [Step::Next, Step::Prev, Step::Random].get((code as usize).wrapping_sub(1)).copied()
+    }
+}
+
+// A MIDI note or controller that triggers something in the host
+#[derive(Clone, Copy, Debug)]
+pub enum MidiSource {
+    Note(u8),
+    Cc(u8),
+}
+
+impl MidiSource {
+    // noteN or ccN
+    pub fn parse(s: &str) -> Result<MidiSource, String> {
+        let number = |n: &str, limit: u8| n.parse().ok().filter(|n| *n < limit);
+        if let Some(key) = s.strip_prefix("note") {
+            Ok(MidiSource::Note(number(key, 128).ok_or_else(|| format!("bad note {s:?}: expected note0 to note127"))?))
+        } else if let Some(cc) = s.strip_prefix("cc") {
+            Ok(MidiSource::Cc(number(cc, 120).ok_or_else(|| format!("bad controller {s:?}: expected cc0 to cc119"))?))
+        } else {
+            Err(format!("expected noteN or ccN, not {s:?}"))
+        }
+    }
+
+    // A note-on of the key, on any channel, or the controller at 64 or above
+    pub fn matches(self, bytes: &[u8]) -> bool {
+        match (self, bytes) {
+            (MidiSource::Note(key), &[status, k, velocity]) => status & 0xf0 == 0x90 && k == key && velocity > 0,
+            (MidiSource::Cc(cc), &[status, c, value]) => status & 0xf0 == 0xb0 && c == cc && value >= 64,
+            _ => false,
+        }
+    }
+}
+
+// --preset-trigger: a MIDI note or controller bound to a step through the bank
+#[derive(Clone, Copy, Debug)]
+pub struct Trigger {
+    source: MidiSource,
+    pub step: Step,
+}
+
+impl Trigger {
+    pub fn matches(&self, bytes: &[u8]) -> bool {
+        self.source.matches(bytes)
+    }
+}
+
+// noteN=STEP or ccN=STEP, STEP being next, prev or random
+pub fn parse_trigger(s: &str) -> Result<Trigger, String> {
+    let usage = "expected noteN=next|prev|random or ccN=next|prev|random";
+    let (source, step) = s.split_once('=').ok_or(usage)?;
+    let step = Step::parse(step).ok_or(usage)?;
+    Ok(Trigger { source: MidiSource::parse(source)?, step })
+}
+
+pub struct Bank {
+    presets: Vec<PathBuf>,
+    // None until the first step, so `next` starts at the first preset
+    current: Option<usize>,
+}
+
+impl Bank {
+    // Every file under `dir`, in path order; with a tag, only those in a
+    // subdirectory of that name
+    pub fn scan(dir: &Path, tag: Option<&str>) -> Result<Bank, String> {
+        let mut presets = Vec::new();
+        walk(dir, tag.is_none(), tag, &mut presets).map_err(|e| format!("can't read {}: {e}", dir.display()))?;
+        presets.sort();
+        if presets.is_empty() {
+            let tagged = tag.map_or(String::new(), |tag| format!(" tagged {tag:?}"));
+            return Err(format!("no presets{tagged} in {}", dir.display()));
+        }
+        Ok(Bank { presets, current: None })
+    }
+
+    pub fn len(&self) -> usize {
+        self.presets.len()
+    }
+
+    pub fn presets(&self) -> &[PathBuf] {
+        &self.presets
+    }
+
+    // Go to the preset with this file name, with or without its extension
+    pub fn select(&mut self, name: &str) -> Option<&Path> {
+        let matches = |path: &Path| {
+            path.file_name().is_some_and(|n| n == name) || path.file_stem().is_some_and(|n| n == name)
+        };
+        let i = self.presets.iter().position(|path| matches(path))?;
+        self.current = Some(i);
+        Some(&self.presets[i])
+    }
+
+    // Move through the bank, wrapping at either end; random never picks the
+    // preset playing now if there's another
+    pub fn step(&mut self, step: Step) -> &Path {
+        let len = self.presets.len();
+        let next = match (step, self.current) {
+            (Step::Next, None) => 0,
+            (Step::Prev, None) => len - 1,
+            (Step::Next, Some(i)) => (i + 1) % len,
+            (Step::Prev, Some(i)) => (i + len - 1) % len,
+            (Step::Random, current) => {
+                let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos()) as usize;
+                match current {
+                    Some(i) if len > 1 => (i + 1 + nanos % (len - 1)) % len,
+                    _ => nanos % len,
+                }
+            }
+        };
+        self.current = Some(next);
+        &self.presets[next]
+    }
+}
+
+fn walk(dir: &Path, tagged: bool, tag: Option<&str>, presets: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map_or("".into(), |n| n.to_string_lossy());
+        if name.starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            let tagged = tagged || tag == Some(&*name);
+            walk(&path, tagged, tag, presets)?;
+        } else if tagged {
+            presets.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(names: &[&str]) -> Bank {
+        Bank { presets: names.iter().map(PathBuf::from).collect(), current: None }
+    }
+
+    #[test]
+    fn step_codes_round_trip() {
+        for step in [Step::Next, Step::Prev, Step::Random] {
+            assert_eq!(Step::from_code(step.code()), Some(step));
+        }
+        assert_eq!(Step::from_code(0), None);
+        assert_eq!(Step::from_code(4), None);
+        assert_eq!(Step::parse("random"), Some(Step::Random));
+        assert_eq!(Step::parse("Next"), None);
+    }
+
+    #[test]
+    fn triggers() {
+        let note = parse_trigger("note36=next").unwrap();
+        assert_eq!(note.step, Step::Next);
+        assert!(note.matches(&[0x99, 36, 1]));
+        assert!(!note.matches(&[0x99, 36, 0]));
+        assert!(!note.matches(&[0x89, 36, 64]));
+        assert!(!note.matches(&[0x90, 37, 100]));
+
+        let cc = parse_trigger("cc64=prev").unwrap();
+        assert!(cc.matches(&[0xb3, 64, 64]));
+        assert!(!cc.matches(&[0xb3, 64, 63]));
+
+        for bad in ["note128=next", "cc120=next", "cc1=forward", "pc1=next", "note36"] {
+            assert!(parse_trigger(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn step_wraps_at_either_end() {
+        let mut bank = bank(&["a.fxp", "b.fxp", "c.fxp"]);
+        assert_eq!(bank.step(Step::Prev), Path::new("c.fxp"));
+        assert_eq!(bank.step(Step::Next), Path::new("a.fxp"));
+        assert_eq!(bank.step(Step::Prev), Path::new("c.fxp"));
+
+        let mut bank = self::bank(&["a.fxp", "b.fxp"]);
+        assert_eq!(bank.step(Step::Next), Path::new("a.fxp"));
+        assert_eq!(bank.step(Step::Next), Path::new("b.fxp"));
+        assert_eq!(bank.step(Step::Next), Path::new("a.fxp"));
+    }
+
+    #[test]
+    fn random_moves_on() {
+        let mut bank = bank(&["a", "b", "c", "d"]);
+        let mut last = bank.step(Step::Random).to_path_buf();
+        for _ in 0..100 {
+            let next = bank.step(Step::Random).to_path_buf();
+            assert_ne!(next, last);
+            last = next;
+        }
+        // with one preset there's nowhere else to go
+        let mut bank = self::bank(&["a"]);
+        bank.step(Step::Random);
+        assert_eq!(bank.step(Step::Random), Path::new("a"));
+    }
+
+    #[test]
+    fn select_by_name_or_stem() {
+        let mut bank = bank(&["bank/pads/warm.fxp", "bank/leads/saw.fxp"]);
+        assert_eq!(bank.select("saw"), Some(Path::new("bank/leads/saw.fxp")));
+        assert_eq!(bank.step(Step::Prev), Path::new("bank/pads/warm.fxp"));
+        assert_eq!(bank.select("warm.fxp"), Some(Path::new("bank/pads/warm.fxp")));
+        assert_eq!(bank.select("pads"), None);
+    }
+
+    #[test]
+    fn scan_by_tag() {
+        let dir = std::env::temp_dir().join(format!("jack_minimal_clap_bank_{}", std::process::id()));
+        for path in ["pads/warm", "pads/.hidden", "leads/saw", "leads/pads/bright", "top"] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        let relative = |path: &PathBuf| path.strip_prefix(&dir).unwrap().to_path_buf();
+        let all = Bank::scan(&dir, None).unwrap();
+        let pads = Bank::scan(&dir, Some("pads")).unwrap();
+        let missing = Bank::scan(&dir, Some("bass"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(all.len(), 4);
+        assert_eq!(pads.presets.iter().map(relative).collect::<Vec<_>>(), [Path::new("leads/pads/bright"), Path::new("pads/warm")]);
+        assert!(missing.is_err());
+    }
+}
@@ -0,0 +1,155 @@
+// --midi-backend alsa-raw:hw:CARD,DEVICE: read MIDI straight from an ALSA raw
+// MIDI device, for systems without a2jmidid or setups that want the shortest
+// path from the keyboard. A thread of its own reads the device node and cuts
+// the byte stream into messages, which reach the audio thread through a
+// lock-free ring. They go to the plugin's first note input at the start of
+// the next block, merged with whatever comes in on the JACK MIDI port.
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use rtrb::{Consumer, RingBuffer};
+
+use crate::dropped::{self, Dropped};
+
+// Messages held between two blocks; more than any controller sends in one
+const QUEUE_LEN: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiBackend {
+    // just the JACK MIDI ports
+    Jack,
+    AlsaRaw { card: u32, device: u32 },
+}
+
+// `jack`, or `alsa-raw:hw:CARD,DEVICE[,SUBDEVICE]` as `amidi -l` lists them.
+// The device node hands out its first free subdevice, so that part is only
+// checked for being a number.
+pub fn parse(s: &str) -> Result<MidiBackend, String> {
+    if s == "jack" {
+        return Ok(MidiBackend::Jack);
+    }
+    let usage = || format!("expected jack or alsa-raw:hw:CARD,DEVICE (e.g. alsa-raw:hw:2,0,0), not {s:?}");
+    let hw = s.strip_prefix("alsa-raw:hw:").ok_or_else(usage)?;
+    let numbers: Vec<u32> = hw.split(',').map(|n| n.trim().parse()).collect::<Result<_, _>>().map_err(|_| usage())?;
+    match numbers[..] {
+        [card, device] | [card, device, _] => Ok(MidiBackend::AlsaRaw { card, device }),
+        _ => Err(usage()),
+    }
+}
+
+// One channel message of up to three bytes
+#[derive(Clone, Copy)]
+pub struct Message {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl Message {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+// Cuts a raw MIDI byte stream into channel messages, following running
+// status. Sysex, system common and realtime bytes are dropped, as
+// midi::translate() has nothing to do with them either.
+#[derive(Default)]
+pub struct Parser {
+    // the running status; 0 when there is none to follow
+    status: u8,
+    data: [u8; 2],
+    have: usize,
+}
+
+impl Parser {
+    pub fn byte(&mut self, byte: u8) -> Option<Message> {
+        match byte {
+            // realtime bytes may come between any two others
+            0xf8..=0xff => None,
+            // sysex and system common end running status until the next status byte
+            0xf0..=0xf7 => {
+                self.status = 0;
+                None
+            }
+            0x80..=0xef => {
+                self.status = byte;
+                self.have = 0;
+                None
+            }
+            _ if self.status == 0 => None,
+            _ => {
+                self.data[self.have] = byte;
+                self.have += 1;
+                let needed = if matches!(self.status & 0xf0, 0xc0 | 0xd0) { 1 } else { 2 };
+                if self.have < needed {
+                    return None;
+                }
+                self.have = 0;
+                Some(Message { bytes: [self.status, self.data[0], self.data[1]], len: 1 + needed })
+            }
+        }
+    }
+}
+
+// Open the device and start reading it. A message that finds the ring full
+// is dropped and counted, as a burst beyond what the audio thread has room
+// for is.
+pub fn spawn(card: u32, device: u32, dropped: Arc<Dropped>) -> io::Result<Consumer<Message>> {
+    let path = format!("/dev/snd/midiC{card}D{device}");
+    let mut file = File::open(&path).map_err(|e| io::Error::new(e.kind(), format!("{path}: {e}")))?;
+    let (mut messages, consumer) = RingBuffer::new(QUEUE_LEN);
+    std::thread::spawn(move || {
+        let mut parser = Parser::default();
+        let mut buf = [0u8; 256];
+        loop {
+            let n = match file.read(&mut buf) {
+                Ok(0) => {
+                    eprintln!("MIDI: {path} closed");
+                    return;
+                }
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("MIDI: {path}: {e}");
+                    return;
+                }
+            };
+            for message in buf[..n].iter().filter_map(|&byte| parser.byte(byte)) {
+                if messages.push(message).is_err() {
+                    dropped.count(dropped::kind_of(message.bytes()));
+                }
+            }
+        }
+    });
+    Ok(consumer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut parser = Parser::default();
+        stream.iter().filter_map(|&byte| parser.byte(byte)).map(|m| m.bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn follows_running_status_around_realtime_and_sysex() {
+        // a note-on, another by running status with a clock tick in the
+        // middle, a program change, then sysex ending running status
+        let stream = [0x90, 60, 100, 62, 0xf8, 90, 0xc1, 5, 0xf0, 0x7e, 0x01, 0xf7, 64, 0xb0, 7, 127];
+        assert_eq!(messages(&stream), [vec![0x90, 60, 100], vec![0x90, 62, 90], vec![0xc1, 5], vec![0xb0, 7, 127]]);
+        // data before any status byte is noise
+        assert_eq!(messages(&[1, 2, 0x80, 60, 0]), [vec![0x80, 60, 0]]);
+    }
+
+    #[test]
+    fn parses_backends() {
+        assert_eq!(parse("jack"), Ok(MidiBackend::Jack));
+        assert_eq!(parse("alsa-raw:hw:2,0,0"), Ok(MidiBackend::AlsaRaw { card: 2, device: 0 }));
+        assert_eq!(parse("alsa-raw:hw:1,3"), Ok(MidiBackend::AlsaRaw { card: 1, device: 3 }));
+        assert!(parse("alsa-raw:hw:1").is_err());
+        assert!(parse("alsa:hw:1,0").is_err());
+    }
+}
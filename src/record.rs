@@ -0,0 +1,98 @@
+// `record`: a live take of the output to WAV, started and stopped from the
+// console or OSC. The audio thread only ever writes the retro ring; the main
+// thread copies from it to the file as it goes, so no disk I/O happens in
+// the callback. With --record-sync bar an armed take starts on the next bar
+// line of the JACK transport, giving loop-ready files from tempo-synced
+// generators.
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::ValueEnum;
+
+use crate::retro::RetroBuffer;
+use crate::sinks::{Sink, WavSink};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum RecordSync {
+    /// Start on the next bar of the JACK transport
+    Bar,
+}
+
+struct Take {
+    wav: Box<WavSink>,
+    // the next ring frame to write, once started
+    next: u64,
+    // until it starts: the ring frame it was armed at
+    armed: Option<u64>,
+}
+
+pub struct Recorder {
+    retro: Arc<RetroBuffer>,
+    sync: Option<RecordSync>,
+    take: Option<Take>,
+}
+
+impl Recorder {
+    pub fn new(retro: Arc<RetroBuffer>, sync: Option<RecordSync>) -> Self {
+        Recorder { retro, sync, take: None }
+    }
+
+    pub fn sync(&self) -> Option<RecordSync> {
+        self.sync
+    }
+
+    pub fn start(&mut self, path: &Path) -> Result<(), String> {
+        if let Some(take) = &self.take {
+            return Err(format!("record: already recording to {}", take.wav.path().display()));
+        }
+        let wav = Box::new(WavSink::create(path, 2, self.retro.sample_rate()).map_err(|e| format!("record: {e}"))?);
+        let now = self.retro.written();
+        let armed = self.sync.map(|_| now);
+        self.take = Some(Take { wav, next: now, armed });
+        match armed {
+            Some(_) => println!("Recording to {} from the next bar", path.display()),
+            None => println!("Recording to {}", path.display()),
+        }
+        Ok(())
+    }
+
+    // From the main loop: start an armed take once its bar has come, and
+    // copy what's new in the ring to the file
+    pub fn poll(&mut self) {
+        let Some(take) = &mut self.take else { return };
+        if let Some(armed) = take.armed {
+            match self.retro.last_bar().filter(|&bar| bar >= armed) {
+                Some(bar) => {
+                    take.armed = None;
+                    take.next = bar;
+                    println!("Recording started on the bar");
+                }
+                None => return,
+            }
+        }
+        let end = self.retro.written();
+        let mut samples = Vec::new();
+        if !self.retro.read(take.next, end, |sample| samples.push(sample)) {
+            eprintln!("record: fell behind the audio and lost some of the take; carrying on from now");
+        } else if let Err(e) = take.wav.write(&samples) {
+            eprintln!("record: {e}; stopping");
+            self.take = None;
+            return;
+        }
+        take.next = end;
+    }
+
+    // Finish the take, returning where it went and how long it is
+    pub fn stop(&mut self) -> Result<(PathBuf, f64), String> {
+        self.poll();
+        let take = self.take.take().ok_or("record: not recording")?;
+        let seconds = take.wav.frames() as f64 / self.retro.sample_rate() as f64;
+        let started = take.armed.is_none();
+        let path = take.wav.path().to_path_buf();
+        take.wav.finish().map_err(|e| format!("record: {e}"))?;
+        if !started {
+            eprintln!("record: stopped before the next bar came; {} is empty", path.display());
+        }
+        Ok((path, seconds))
+    }
+}
@@ -0,0 +1,676 @@
+// Live control from stdin (and OSC, which is mapped onto the same commands)
+// while JACK is running. Lines are read on their own thread and handled on
+// the main thread, which is where the plugin's params extension may be
+// called; changes then reach the audio thread through a lock-free ring, so
+// it never waits on us.
+use std::io::BufRead;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use clack_extensions::gui::GuiSize;
+use clack_host::prelude::*;
+use clack_host::utils::ClapId;
+use jack::Transport;
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::dirty::Edits;
+use crate::dropped::{self, Dropped, Kind};
+use crate::gui::{self, Gui};
+use crate::macros::Macros;
+use crate::mute::{self, Switch, Switches};
+use crate::presets::{self, Bank, Step};
+use crate::record::{RecordSync, Recorder};
+use crate::retro::RetroBuffer;
+use crate::sinks::Sinks;
+use crate::slots::Slots;
+use crate::stereo::Correlation;
+use crate::{params, state, MyHost};
+
+// Changes queued between two process() calls; far more than anyone can type
+pub const QUEUE_LEN: usize = 256;
+// Of those, the last few are kept for note-offs
+const NOTE_OFF_SLOTS: usize = 16;
+
+const HELP: &str = "\
+Commands:
+  set <param> <value>   change a parameter (by name or ID; value as a number or text)
+  get <param>           print a parameter's current value
+  list                  print all parameters
+  width <0..2>          stereo width of the output
+  balance <-1..1>       output balance
+  fade in|out <seconds> ramp the whole output up from silence or down to it
+  correlation           L/R correlation of the output (+1 mono, -1 cancels in mono)
+  status                what has been dropped so far for want of room in the queues
+  bypass [on|off]       skip the plugin and output silence (no argument toggles)
+  mute <output> [on|off]
+  solo <output> [on|off]
+                        silence one output, or hear only the soloed ones (by
+                        port name, e.g. out_r, or number from 1; no argument
+                        toggles)
+  note_on <ch> <key> [velocity 0..1]
+  note_off <ch> <key>   play notes (channel 0..15, key 0..127)
+  play | stop           start or stop the JACK transport
+  locate <frame>        move the JACK transport
+  dump [file.wav]       save the last --retro seconds of output (a bare file
+                        name goes in --dump-dir)
+  record [file.wav]     record the output from now (or the next bar, with
+                        --record-sync bar) into a file, as for dump
+  record stop           finish the recording
+  sink [name] [on|off]  switch an output sink, jack or a --sink (no argument
+                        lists them; no on|off toggles)
+  preset next|prev|random
+                        step through the --preset-bank
+  preset <name>         go to the bank's preset of that file name
+  preset <location>     load a preset file, FILE#KEY or plugin:KEY
+  slot <n>              recall a --slot state, from 1
+  map [name]            switch to a --map-profile (no argument lists them)
+  macro [name]          run a --macro (no argument lists them)
+  gui show|hide         open or show the plugin's editor, or hide it
+  gui resize <W>x<H>    resize it, to the nearest size the plugin can do
+  gui scale <factor>    scale it, for HiDPI screens
+  help                  this text
+
+Over OSC, dump and record take only a bare file name and preset only a step or a
+name from the bank, so remote senders can't pick paths.";
+
+// Where a command came from. Remote ones may not name files.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source {
+    Console,
+    Remote,
+}
+
+// Something for the audio thread to apply at the start of its next block
+pub enum Change {
+    Param(ClapId, f64),
+    NoteOn { channel: u16, key: u16, velocity: f64 },
+    NoteOff { channel: u16, key: u16 },
+    Width(f32),
+    Balance(f32),
+    // to a gain from 0 to 1, over so many seconds
+    Fade { gain: f32, seconds: f32 },
+    // the same for the dip around a --slot recall, kept apart so the two
+    // don't undo each other
+    Dip { gain: f32, seconds: f32 },
+    // switch to one of the --map-profile maps
+    Map(usize),
+    // a CC for the --map-profile feedback port, so controllers show the
+    // values of what they now control
+    Feedback([u8; 3]),
+    // on the way out: release every note and report the plugin's tail
+    RingOut,
+}
+
+pub fn queue() -> (Producer<Change>, Consumer<Change>) {
+    RingBuffer::new(QUEUE_LEN)
+}
+
+// Send lines from stdin until it closes (e.g. when running as a service)
+pub fn spawn_reader(commands: SyncSender<(Source, String)>) {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else { break };
+            if commands.send((Source::Console, line)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+pub struct Repl {
+    changes: Producer<Change>,
+    bypass: Arc<AtomicBool>,
+    transport: Transport,
+    // retroactive recording, and where to dump it
+    retro: Option<(Arc<RetroBuffer>, PathBuf)>,
+    // live takes, and where they go by default
+    record: Option<(Recorder, PathBuf)>,
+    sinks: Option<Sinks>,
+    bank: Option<Bank>,
+    // per-output mute/solo, and the outputs' names to find them by
+    switches: Arc<Switches>,
+    outputs: Vec<String>,
+    // told after each preset load, for --preset-gain-match
+    preset_switched: Option<Arc<AtomicBool>>,
+    // so a preset load can warn about changes it replaces
+    edits: Arc<Edits>,
+    maps: Option<MapProfiles>,
+    correlation: Arc<Correlation>,
+    macros: Macros,
+    dropped: Arc<Dropped>,
+    // the plugin's editor while it's open, and the title for its window
+    gui: Option<Gui>,
+    gui_title: String,
+    slots: Option<Slots>,
+    // a recall has loaded, and the output still has to come back in
+    slot_dipped: bool,
+}
+
+// The --map-profile names, and for each the controllers mapped to
+// parameters, with the ranges they're scaled onto
+pub struct MapProfiles {
+    pub names: Vec<String>,
+    pub feedback: Vec<Vec<(u8, ClapId, f64, f64)>>,
+}
+
+impl Repl {
+    pub fn new(
+        changes: Producer<Change>,
+        bypass: Arc<AtomicBool>,
+        transport: Transport,
+        correlation: Arc<Correlation>,
+        dropped: Arc<Dropped>,
+    ) -> Self {
+        Repl {
+            changes,
+            bypass,
+            transport,
+            retro: None,
+            record: None,
+            sinks: None,
+            bank: None,
+            switches: Arc::default(),
+            outputs: Vec::new(),
+            preset_switched: None,
+            edits: Arc::default(),
+            maps: None,
+            correlation,
+            macros: Macros::default(),
+            dropped,
+            gui: None,
+            gui_title: String::new(),
+            slots: None,
+            slot_dipped: false,
+        }
+    }
+
+    pub fn enable_dump(&mut self, retro: Arc<RetroBuffer>, dir: PathBuf) {
+        self.retro = Some((retro, dir));
+    }
+
+    pub fn enable_record(&mut self, recorder: Recorder, dir: PathBuf) {
+        self.record = Some((recorder, dir));
+    }
+
+    // From the main loop, to keep a take going
+    pub fn poll_recording(&mut self) {
+        if let Some((recorder, _)) = &mut self.record {
+            recorder.poll();
+        }
+    }
+
+    // On the way out: finish a take still going
+    pub fn stop_recording(&mut self) {
+        if let Some(Ok((path, seconds))) = self.record.as_mut().map(|(recorder, _)| recorder.stop()) {
+            println!("Saved {seconds:.1}s to {}", path.display());
+        }
+    }
+
+    pub fn enable_sinks(&mut self, sinks: Sinks) {
+        self.sinks = Some(sinks);
+    }
+
+    // From the main loop, to feed the sinks
+    pub fn poll_sinks(&mut self) {
+        if let Some(sinks) = &mut self.sinks {
+            sinks.poll();
+        }
+    }
+
+    // On the way out: what's left, and close the files
+    pub fn finish_sinks(&mut self) {
+        if let Some(sinks) = self.sinks.take() {
+            sinks.finish();
+        }
+    }
+
+    pub fn enable_bank(&mut self, bank: Bank) {
+        self.bank = Some(bank);
+    }
+
+    pub fn enable_switches(&mut self, switches: Arc<Switches>, outputs: Vec<String>) {
+        self.switches = switches;
+        self.outputs = outputs;
+    }
+
+    pub fn track_edits(&mut self, edits: Arc<Edits>) {
+        self.edits = edits;
+    }
+
+    pub fn enable_gain_match(&mut self, switched: Arc<AtomicBool>) {
+        self.preset_switched = Some(switched);
+    }
+
+    pub fn enable_maps(&mut self, maps: MapProfiles) {
+        self.maps = Some(maps);
+    }
+
+    pub fn enable_macros(&mut self, macros: Macros) {
+        self.macros = macros;
+    }
+
+    // The editor opened with --gui, if any; `gui show` opens one otherwise
+    pub fn enable_gui(&mut self, title: &str, gui: Option<Gui>) {
+        self.gui_title = title.to_string();
+        self.gui = gui;
+    }
+
+    // From the main loop: window events, and the editor gone once closed
+    pub fn poll_gui(&mut self, instance: &mut PluginInstance<MyHost>) {
+        if self.gui.as_mut().is_some_and(|g| !g.poll(instance)) {
+            self.gui = None;
+        }
+    }
+
+    pub fn close_gui(&mut self, instance: &mut PluginInstance<MyHost>) {
+        if let Some(gui) = self.gui.take() {
+            gui.close(instance);
+        }
+    }
+
+    pub fn enable_slots(&mut self, slots: Slots) {
+        self.slots = Some(slots);
+    }
+
+    // From a --slot-notes note
+    pub fn recall_slot(&mut self, index: usize) {
+        if let Err(e) = self.slot(index) {
+            eprintln!("{e}");
+        }
+    }
+
+    fn slot(&mut self, index: usize) -> Result<(), String> {
+        let slots = self.slots.as_mut().ok_or("slot: start with --slot FILE to have some")?;
+        slots.recall(index, Instant::now())?;
+        // with the queue full it loads all the same, only without the dip
+        let _ = self.changes.push(Change::Dip { gain: 0.0, seconds: slots.fade().as_secs_f32() });
+        Ok(())
+    }
+
+    pub fn next_slot_due(&self) -> Option<Instant> {
+        self.slots.as_ref().and_then(Slots::next_due)
+    }
+
+    // From the main loop: load a recalled slot once the output has dipped
+    // out, then bring it back in
+    pub fn poll_slots(&mut self, instance: &mut PluginInstance<MyHost>) {
+        let Some(slots) = &mut self.slots else { return };
+        let fade = slots.fade().as_secs_f32();
+        if let Some((index, saved)) = slots.take_due(Instant::now()) {
+            if saved.is_ok() && self.edits.dirty() {
+                eprintln!("slot: replacing unsaved changes to the plugin's settings");
+            }
+            match saved.and_then(|saved| state::apply(instance, saved).map_err(|e| e.to_string())) {
+                Ok(()) => {
+                    println!("Slot {}", index + 1);
+                    if let Some(switched) = &self.preset_switched {
+                        switched.store(true, Ordering::Relaxed);
+                    }
+                    self.edits.saved();
+                }
+                // the sound stays as it was, so back in with that
+                Err(e) => eprintln!("slot {}: {e}", index + 1),
+            }
+            self.slot_dipped = true;
+        }
+        if self.slot_dipped && self.changes.push(Change::Dip { gain: 1.0, seconds: fade }).is_ok() {
+            self.slot_dipped = false;
+        }
+    }
+
+    // From a --macro-trigger
+    pub fn start_macro(&mut self, index: usize) {
+        self.macros.start(index, Instant::now());
+    }
+
+    pub fn next_macro_due(&self) -> Option<Instant> {
+        self.macros.next_due()
+    }
+
+    // From the main loop: whatever the running macros have come to
+    pub fn run_macros(&mut self, instance: &mut PluginInstance<MyHost>) {
+        for command in self.macros.take_due(Instant::now()) {
+            self.handle(instance, Source::Console, &command);
+        }
+    }
+
+    // Once the audio thread has switched maps: say so, and send the mapped
+    // parameters' values out for the controller to show
+    pub fn map_switched(&mut self, instance: &mut PluginInstance<MyHost>, index: usize) {
+        let Some(maps) = &self.maps else { return };
+        let Some(name) = maps.names.get(index) else { return };
+        println!("MIDI map {name}");
+        for &(cc, id, min, max) in &maps.feedback[index] {
+            let Some(value) = params::current(instance, id) else { continue };
+            let value = if max > min { ((value - min) / (max - min) * 127.0).round().clamp(0.0, 127.0) } else { 0.0 };
+            if self.changes.push(Change::Feedback([0xb0, cc, value as u8])).is_err() {
+                eprintln!("map: audio thread is not keeping up; controller feedback cut short");
+                return;
+            }
+        }
+    }
+
+    // Load the next, previous or a random preset from the bank
+    pub fn step_preset(&mut self, instance: &mut PluginInstance<MyHost>, step: Step) {
+        if let Err(e) = self.step(instance, step) {
+            eprintln!("{e}");
+        }
+    }
+
+    fn step(&mut self, instance: &mut PluginInstance<MyHost>, step: Step) -> Result<(), String> {
+        let bank = self.bank.as_mut().ok_or("preset: start with --preset-bank DIR to step through presets")?;
+        let path = bank.step(step).to_string_lossy().into_owned();
+        println!("Preset {path}");
+        self.load_preset(instance, &path)
+    }
+
+    fn load_preset(&mut self, instance: &mut PluginInstance<MyHost>, location: &str) -> Result<(), String> {
+        if self.edits.dirty() {
+            eprintln!("preset: replacing unsaved changes to the plugin's settings");
+        }
+        presets::load(instance, location).map_err(|e| format!("preset: {e}"))?;
+        // Only now: a failed load leaves the sound as it was, nothing to match
+        if let Some(switched) = &self.preset_switched {
+            switched.store(true, Ordering::Relaxed);
+        }
+        self.edits.saved();
+        Ok(())
+    }
+
+    fn gui(&mut self, instance: &mut PluginInstance<MyHost>, rest: &[&str]) -> Result<(), String> {
+        let usage = "usage: gui show|hide|resize <W>x<H>|scale <factor>";
+        let failed = |e: String| format!("gui: {e}");
+        if let ["show"] = rest {
+            match &mut self.gui {
+                Some(gui) => gui.set_visible(instance, true).map_err(failed)?,
+                None => self.gui = Some(gui::open(instance, &self.gui_title).map_err(failed)?),
+            }
+            return Ok(());
+        }
+        let gui = self.gui.as_mut().ok_or("gui: not open; `gui show` opens it")?;
+        let (width, height) = match rest {
+            ["hide"] => return gui.set_visible(instance, false).map_err(failed),
+            ["scale", factor] => {
+                let factor: f64 = factor.parse().ok().filter(|f: &f64| *f > 0.0).ok_or(usage)?;
+                return gui.scale(instance, factor).map_err(failed);
+            }
+            ["resize", size] => size.split_once('x').ok_or(usage)?,
+            // W H as well, for OSC senders with two numbers
+            ["resize", width, height] => (*width, *height),
+            _ => return Err(usage.into()),
+        };
+        let width: u32 = width.parse().ok().filter(|w| *w > 0).ok_or(usage)?;
+        let height: u32 = height.parse().ok().filter(|h| *h > 0).ok_or(usage)?;
+        let size = gui.resize(instance, GuiSize { width, height }).map_err(failed)?;
+        println!("GUI {}x{}", size.width, size.height);
+        Ok(())
+    }
+
+    // Start the ring-out before shutting down; false if the queue is full
+    pub fn ring_out(&mut self) -> bool {
+        self.changes.push(Change::RingOut).is_ok()
+    }
+
+    pub fn handle(&mut self, instance: &mut PluginInstance<MyHost>, source: Source, line: &str) {
+        if let Err(e) = self.run(instance, source, line) {
+            eprintln!("{e}");
+        }
+    }
+
+    fn run(&mut self, instance: &mut PluginInstance<MyHost>, source: Source, line: &str) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else { return Ok(()) };
+        // Parameter names may contain spaces, so the value is the last word
+        let rest: Vec<&str> = words.collect();
+        let split_value = || match rest.split_last() {
+            Some((value, name)) if !name.is_empty() => Ok((name.join(" "), value.to_string())),
+            _ => Err(format!("usage: {command} <param> <value>")),
+        };
+        let number = |what: &str| -> Result<f32, String> {
+            let [value] = rest[..] else { return Err(format!("usage: {command} <{what}>")) };
+            value.parse().map_err(|_| format!("{command}: not a number: {value:?}"))
+        };
+        let note = |velocity: bool| -> Result<(u16, u16, f64), String> {
+            let usage = || format!("usage: {command} <channel 0..15> <key 0..127>{}", if velocity { " [velocity]" } else { "" });
+            let (channel, key, vel) = match rest[..] {
+                [channel, key] => (channel, key, None),
+                [channel, key, vel] if velocity => (channel, key, Some(vel)),
+                _ => return Err(usage()),
+            };
+            let channel: u16 = channel.parse().ok().filter(|c| *c < 16).ok_or_else(usage)?;
+            let key: u16 = key.parse().ok().filter(|k| *k < 128).ok_or_else(usage)?;
+            let vel: f64 = vel.map_or(Some(1.0), |v| v.parse().ok()).ok_or_else(usage)?;
+            Ok((channel, key, vel.clamp(0.0, 1.0)))
+        };
+
+        let change = match command {
+            "set" => {
+                let (name, value) = split_value()?;
+                let resolved = params::resolve(instance, &[(name, value)])?;
+                let (id, value) = resolved[0];
+                Change::Param(id, value)
+            }
+            "get" => {
+                let (name, value) = params::value_of(instance, &rest.join(" "))?;
+                println!("{name} = {value}");
+                return Ok(());
+            }
+            "list" => return params::print_table(instance),
+            "width" => Change::Width(number("0..2")?),
+            "balance" => Change::Balance(number("-1..1")?),
+            "fade" => {
+                let usage = "usage: fade in|out <seconds>";
+                let [way, seconds] = rest[..] else { return Err(usage.into()) };
+                let gain = match way {
+                    "in" => 1.0,
+                    "out" => 0.0,
+                    _ => return Err(usage.into()),
+                };
+                let seconds = seconds.strip_suffix('s').unwrap_or(seconds);
+                let seconds: f32 = seconds.parse().ok().filter(|s: &f32| *s >= 0.0 && s.is_finite()).ok_or(usage)?;
+                println!("Fading {way} over {seconds}s");
+                Change::Fade { gain, seconds }
+            }
+            "note_on" => {
+                let (channel, key, velocity) = note(true)?;
+                Change::NoteOn { channel, key, velocity }
+            }
+            "note_off" => {
+                let (channel, key, _) = note(false)?;
+                Change::NoteOff { channel, key }
+            }
+            "bypass" => {
+                let on = match rest[..] {
+                    [] => !self.bypass.load(Ordering::Relaxed),
+                    ["on" | "true"] => true,
+                    ["off" | "false"] => false,
+                    // OSC toggles send 0.0 / 1.0
+                    [value] => value.parse::<f32>().map_err(|_| "usage: bypass [on|off]")? >= 0.5,
+                    _ => return Err("usage: bypass [on|off]".into()),
+                };
+                self.bypass.store(on, Ordering::Relaxed);
+                println!("bypass {}", if on { "on" } else { "off" });
+                return Ok(());
+            }
+            "mute" | "solo" => {
+                let switch = if command == "mute" { Switch::Mute } else { Switch::Solo };
+                let usage = || format!("usage: {command} <output> [on|off]");
+                let (output, on) = match rest[..] {
+                    [output] => (output, None),
+                    [output, on] => (output, Some(on)),
+                    _ => return Err(usage()),
+                };
+                let output = mute::find(&self.outputs, output).map_err(|e| format!("{command}: {e}"))?;
+                let on = match on {
+                    None => !self.switches.is_on(switch, output),
+                    Some("on" | "true") => true,
+                    Some("off" | "false") => false,
+                    Some(value) => value.parse::<f32>().map_err(|_| usage())? >= 0.5,
+                };
+                self.switches.set(switch, output, on);
+                println!("{} {} {}", self.outputs[output], switch.name(), if on { "on" } else { "off" });
+                return Ok(());
+            }
+            "dump" => {
+                let Some((retro, dir)) = &self.retro else {
+                    return Err("dump: start with --retro SECONDS to keep recent output".into());
+                };
+                let path = output_path(command, source, &rest, dir, "retro")?;
+                let seconds = retro.dump(&path).map_err(|e| format!("dump: {e}"))?;
+                println!("Saved the last {seconds:.1}s to {}", path.display());
+                return Ok(());
+            }
+            "record" => {
+                let Some((recorder, dir)) = &mut self.record else { return Err("record: not available".into()) };
+                if let ["stop"] = rest[..] {
+                    let (path, seconds) = recorder.stop()?;
+                    println!("Saved {seconds:.1}s to {}", path.display());
+                    return Ok(());
+                }
+                let path = output_path(command, source, &rest, dir, "take")?;
+                let bars = self.transport.query().is_ok_and(|t| t.pos.bbt().is_some());
+                if recorder.sync() == Some(RecordSync::Bar) && !bars {
+                    return Err("record: --record-sync bar needs a JACK timebase master to say where bars are".into());
+                }
+                return recorder.start(&path);
+            }
+            "sink" => {
+                let sinks = self.sinks.as_mut().ok_or("sink: not available")?;
+                let usage = "usage: sink [name] [on|off]";
+                let (name, on) = match rest[..] {
+                    [] => {
+                        println!("Sinks: {}", sinks.describe());
+                        return Ok(());
+                    }
+                    [name] => (name, None),
+                    [name, on] => (name, Some(on)),
+                    _ => return Err(usage.into()),
+                };
+                let current = sinks.is_on(name).ok_or_else(|| format!("sink: no {name:?} ({})", sinks.describe()))?;
+                let on = match on {
+                    None => !current,
+                    Some("on" | "true") => true,
+                    Some("off" | "false") => false,
+                    Some(value) => value.parse::<f32>().map_err(|_| usage)? >= 0.5,
+                };
+                sinks.set(name, on)?;
+                println!("sink {name} {}", if on { "on" } else { "off" });
+                return Ok(());
+            }
+            "preset" => {
+                let location = rest.join(" ");
+                if let Some(step) = Step::parse(&location) {
+                    return self.step(instance, step);
+                }
+                if location.is_empty() {
+                    return Err("usage: preset next|prev|random|<name>|<location>".into());
+                }
+                if let Some(path) = self.bank.as_mut().and_then(|bank| bank.select(&location)) {
+                    let path = path.to_string_lossy().into_owned();
+                    println!("Preset {path}");
+                    return self.load_preset(instance, &path);
+                }
+                if source == Source::Remote {
+                    return Err(format!("preset: over OSC only next, prev, random or a --preset-bank name, not {location:?}"));
+                }
+                return self.load_preset(instance, &location);
+            }
+            "map" => {
+                let maps = self.maps.as_ref().ok_or("map: start with --map-profile NAME=FILE to switch MIDI maps")?;
+                let name = rest.join(" ");
+                if name.is_empty() {
+                    println!("MIDI maps: {}", maps.names.join(", "));
+                    return Ok(());
+                }
+                let index = maps.names.iter().position(|n| *n == name);
+                Change::Map(index.ok_or_else(|| format!("map: no map {name:?} (we have {})", maps.names.join(", ")))?)
+            }
+            "macro" => {
+                if self.macros.is_empty() {
+                    return Err("macro: start with --macro NAME=COMMANDS to have some".into());
+                }
+                let name = rest.join(" ");
+                if name.is_empty() {
+                    println!("Macros: {}", self.macros.names().join(", "));
+                    return Ok(());
+                }
+                let names = self.macros.names().join(", ");
+                let index =
+                    self.macros.index(&name).ok_or_else(|| format!("macro: no macro {name:?} (we have {names})"))?;
+                self.macros.start(index, Instant::now());
+                return Ok(());
+            }
+            "gui" => return self.gui(instance, &rest),
+            "slot" => {
+                let usage = "usage: slot <number from 1>";
+                let [number] = rest[..] else { return Err(usage.into()) };
+                let number: usize = number.parse().ok().filter(|n| *n > 0).ok_or(usage)?;
+                return self.slot(number - 1);
+            }
+            "status" => {
+                match dropped::describe(&self.dropped.counts()) {
+                    Some(what) => println!("Dropped so far: {what}"),
+                    None => println!("Nothing dropped so far"),
+                }
+                return Ok(());
+            }
+            "correlation" => {
+                match self.correlation.get() {
+                    Some(c) if c < 0.0 => println!("Correlation {c:+.2}: out of phase, cancels when summed to mono"),
+                    Some(c) => println!("Correlation {c:+.2}"),
+                    None => println!("Correlation: no output to measure"),
+                }
+                return Ok(());
+            }
+            "play" => return self.transport.start().map_err(|e| format!("play: {e}")),
+            "stop" => return self.transport.stop().map_err(|e| format!("stop: {e}")),
+            "locate" => {
+                let [frame] = rest[..] else { return Err("usage: locate <frame>".into()) };
+                let frame = frame.parse().map_err(|_| format!("locate: not a frame number: {frame:?}"))?;
+                return self.transport.locate(frame).map_err(|e| format!("locate: {e}"));
+            }
+            "help" => {
+                println!("{HELP}");
+                return Ok(());
+            }
+            _ => return Err(format!("unknown command {command:?}; try `help`")),
+        };
+        let (kind, keep) = match change {
+            Change::NoteOff { .. } => (Kind::NoteOff, 0),
+            Change::NoteOn { .. } => (Kind::Note, NOTE_OFF_SLOTS),
+            _ => (Kind::Param, NOTE_OFF_SLOTS),
+        };
+        if self.changes.slots() <= keep {
+            self.dropped.count(kind);
+            return Err("audio thread is not keeping up; try again".into());
+        }
+        self.changes.push(change).map_err(|_| "audio thread is not keeping up; try again".to_string())
+    }
+}
+
+// Where `dump` or `record` saves: a file name (made up from the time if
+// there's none) in `dir`, or from the console any path
+fn output_path(command: &str, source: Source, rest: &[&str], dir: &Path, prefix: &str) -> Result<PathBuf, String> {
+    let name = match rest {
+        [] => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            format!("{prefix}-{now}.wav")
+        }
+        _ => rest.join(" "),
+    };
+    let bare = is_bare_name(&name);
+    if source == Source::Remote && !bare {
+        return Err(format!("{command}: over OSC only a file name is allowed, not {name:?}"));
+    }
+    Ok(if bare { dir.join(&name) } else { PathBuf::from(&name) })
+}
+
+// A file name with no directory in it, which can only land in the directory
+// it's resolved against
+fn is_bare_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
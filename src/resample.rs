@@ -0,0 +1,242 @@
+// --sr-policy resample: when JACK's sample rate changes while we run (PipeWire
+// follows whatever it's playing), the plugin stays at the rate it was
+// activated at rather than being deactivated and started again, which drops
+// out and resets it. Audio is converted to its rate on the way in and back on
+// the way out with a windowed-sinc interpolator, and MIDI times are scaled to
+// match. That costs latency: the output runs a fixed few frames behind, to
+// cover the frames per block not coming out even. Only the plugin's side is
+// converted; what we do to its output afterwards (limiter, gate, --retro...)
+// keeps the timings of the rate it started at.
+//
+// Nothing here allocates once made. Switching rates works out the filter
+// again on the audio thread, a fraction of a millisecond for a block that
+// glitches anyway.
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use clap::ValueEnum;
+
+// What to do when JACK's sample rate changes
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum SrPolicy {
+    /// Reactivate the plugin at the new rate
+    Reactivate,
+    /// Keep the plugin at its rate and resample around it
+    Resample,
+}
+
+// Rates further apart than this reactivate the plugin anyway
+pub const MAX_RATIO: f64 = 4.0;
+
+// Taps either side of the point being interpolated
+const HALF: usize = 16;
+const TAPS: usize = 2 * HALF;
+// Fractional positions the filter is worked out for; we interpolate between
+const PHASES: usize = 256;
+
+// What the main loop tells the audio thread: the plugin's rate, and JACK's
+// to convert from, or 0 to pass audio straight through
+#[derive(Default)]
+pub struct Rates {
+    pub plugin: AtomicU32,
+    pub jack: AtomicU32,
+}
+
+// Whether blocks of up to `period` JACK frames can be resampled into buffers
+// of `max_frames`
+pub fn supports(plugin_rate: f64, jack_rate: f64, period: u32, max_frames: usize) -> bool {
+    let ratio = plugin_rate / jack_rate;
+    (1.0 / MAX_RATIO..=MAX_RATIO).contains(&ratio) && (period as f64 * ratio).ceil() as usize + 1 < max_frames
+}
+
+// How far behind the output runs while resampling, in JACK frames
+pub fn latency(plugin_rate: f64, jack_rate: f64) -> u32 {
+    // each converter waits for HALF frames of what it's given
+    (HALF as f64 * (1.0 + jack_rate / plugin_rate)).ceil() as u32 + 4
+}
+
+// One direction of the conversion, for any number of channels in step
+struct Converter {
+    // input frames per output frame
+    step: f64,
+    // where the next output frame falls, in frames into `history`
+    pos: f64,
+    // PHASES + 1 rows of TAPS weights
+    kernel: Vec<f32>,
+    // per channel: the TAPS frames before this block's, then this block's
+    history: Vec<Vec<f32>>,
+}
+
+impl Converter {
+    fn new(channels: usize, max_frames: usize) -> Self {
+        Converter {
+            step: 1.0,
+            pos: TAPS as f64,
+            kernel: vec![0.0; (PHASES + 1) * TAPS],
+            // not vec![]: cloning a Vec doesn't keep its capacity
+            history: (0..channels).map(|_| Vec::with_capacity(TAPS + max_frames)).collect(),
+        }
+    }
+
+    fn set(&mut self, from: f64, to: f64) {
+        self.step = from / to;
+        // below the lower of the two Nyquists, with room for the window
+        let cutoff = 0.9 * (to / from).min(1.0);
+        for (phase, row) in self.kernel.chunks_exact_mut(TAPS).enumerate() {
+            let frac = phase as f64 / PHASES as f64;
+            for (j, weight) in row.iter_mut().enumerate() {
+                // from this tap to the point
+                let d = frac + (HALF - 1) as f64 - j as f64;
+                *weight = (cutoff * sinc(cutoff * d) * blackman(d / HALF as f64)) as f32;
+            }
+            let sum: f32 = row.iter().sum();
+            row.iter_mut().for_each(|weight| *weight /= sum);
+        }
+        self.pos = TAPS as f64;
+        for history in &mut self.history {
+            history.clear();
+            history.resize(TAPS, 0.0);
+        }
+    }
+
+    // This block's frames for one channel
+    fn write(&mut self, channel: usize, input: &[f32]) {
+        let history = &mut self.history[channel];
+        let room = history.capacity() - history.len();
+        history.extend_from_slice(&input[..input.len().min(room)]);
+    }
+
+    // Every output frame the frames written so far make, a sample at a time
+    // through emit(channel, sample); returns how many frames
+    fn read(&mut self, mut emit: impl FnMut(usize, f32)) -> usize {
+        let Some(len) = self.history.first().map(Vec::len) else { return 0 };
+        let mut frames = 0;
+        while (self.pos as usize) + HALF < len {
+            let first = self.pos as usize + 1 - HALF;
+            let at = self.pos.fract() * PHASES as f64;
+            let (row, frac) = (at as usize, at.fract() as f32);
+            let [a, b] = [row, row + 1].map(|row| &self.kernel[row * TAPS..][..TAPS]);
+            for (channel, history) in self.history.iter().enumerate() {
+                let taps = &history[first..][..TAPS];
+                let sample: f32 = taps.iter().zip(a.iter().zip(b)).map(|(s, (a, b))| s * (a + (b - a) * frac)).sum();
+                emit(channel, sample);
+            }
+            self.pos += self.step;
+            frames += 1;
+        }
+        // keep the last TAPS frames for the next block
+        let drop = len.saturating_sub(TAPS);
+        for history in &mut self.history {
+            history.drain(..drop);
+        }
+        self.pos -= drop as f64;
+        frames
+    }
+
+    // With no channels there's nothing to read frames from, so count them
+    fn count(&mut self, frames: usize) -> usize {
+        let len = TAPS + frames;
+        let mut count = 0;
+        while (self.pos as usize) + HALF < len {
+            self.pos += self.step;
+            count += 1;
+        }
+        self.pos -= frames as f64;
+        count
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// over -1..=1
+fn blackman(t: f64) -> f64 {
+    0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos()
+}
+
+// The audio thread's side
+pub struct Resampler {
+    // (plugin, JACK) rates we're converting between; None while they match
+    rates: Option<(u32, u32)>,
+    // our inputs into the plugin's rate, and its outputs back to JACK's
+    into: Converter,
+    back: Converter,
+    // the plugin renders into these at its rate, a buffer per channel
+    pub outputs: Vec<Vec<f32>>,
+    // its output at JACK's rate, ahead of the blocks that play it
+    queue: Vec<VecDeque<f32>>,
+}
+
+impl Resampler {
+    pub fn new(inputs: usize, outputs: usize, max_frames: usize) -> Self {
+        Resampler {
+            rates: None,
+            into: Converter::new(inputs, max_frames),
+            back: Converter::new(outputs, max_frames),
+            outputs: vec![vec![0.0; max_frames]; outputs],
+            queue: (0..outputs).map(|_| VecDeque::with_capacity(2 * max_frames)).collect(),
+        }
+    }
+
+    // Once per block, before anything else: whether we're resampling it
+    pub fn update(&mut self, rates: &Rates) -> bool {
+        let (plugin, jack) = (rates.plugin.load(Ordering::Relaxed), rates.jack.load(Ordering::Relaxed));
+        let wanted = (jack != 0 && jack != plugin).then_some((plugin, jack));
+        if wanted != self.rates {
+            self.rates = wanted;
+            if let Some((plugin, jack)) = wanted {
+                let (plugin, jack) = (plugin as f64, jack as f64);
+                self.into.set(jack, plugin);
+                self.back.set(plugin, jack);
+                for queue in &mut self.queue {
+                    queue.clear();
+                    queue.resize(latency(plugin, jack) as usize, 0.0);
+                }
+            }
+        }
+        self.rates.is_some()
+    }
+
+    // Converts this block's `frames` JACK frames in `inputs` to the plugin's
+    // rate, in place, and returns how many frames the plugin is to render
+    pub fn into_plugin(&mut self, inputs: &mut [Vec<f32>], frames: usize) -> usize {
+        if inputs.is_empty() {
+            return self.into.count(frames);
+        }
+        for (channel, buf) in inputs.iter_mut().enumerate() {
+            self.into.write(channel, buf);
+            buf.clear();
+        }
+        self.into.read(|channel, sample| {
+            let buf = &mut inputs[channel];
+            if buf.len() < buf.capacity() {
+                buf.push(sample);
+            }
+        })
+    }
+
+    // Converts the `frames` the plugin rendered into `outputs` back to JACK's rate
+    pub fn from_plugin(&mut self, frames: usize) {
+        for (channel, buf) in self.outputs.iter().enumerate() {
+            self.back.write(channel, &buf[..frames]);
+        }
+        let queue = &mut self.queue;
+        self.back.read(|channel, sample| {
+            let queue = &mut queue[channel];
+            if queue.len() < queue.capacity() {
+                queue.push_back(sample);
+            }
+        });
+    }
+
+    // One output channel's next block at JACK's rate
+    pub fn play(&mut self, channel: usize, out: &mut [f32]) {
+        let queue = &mut self.queue[channel];
+        for sample in out.iter_mut() {
+            // short only if the converters fell behind, which they shouldn't
+            *sample = queue.pop_front().unwrap_or(0.0);
+        }
+    }
+}
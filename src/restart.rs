@@ -0,0 +1,161 @@
+// Restarting the plugin (request_restart) while JACK keeps running, so our
+// ports and their connections survive. The audio thread gives up its
+// processor, stopping it as only the audio thread may; the main thread
+// deactivates and reactivates the plugin; the audio thread starts the new
+// processor on its next block. It plays silence in between.
+use std::time::{Duration, Instant};
+
+use clack_host::prelude::*;
+use clack_host::process::{StartedPluginAudioProcessor, StoppedPluginAudioProcessor};
+use rtrb::{Consumer, Producer, RingBuffer};
+
+use crate::MyHost;
+
+type Stopped = StoppedPluginAudioProcessor<MyHost>;
+
+enum ToAudio {
+    Surrender,
+    // with the largest block and the sample rate it was activated for
+    Start(Stopped, u32, f64),
+}
+
+enum FromAudio {
+    Stopped(Stopped),
+    // start_processing failed; handed back to be deactivated
+    NotStarted(Stopped),
+    // asked to surrender with no processor, as after a failed restart: the
+    // plugin is already inactive
+    Idle,
+}
+
+// The main thread's end
+pub struct Restarter {
+    to_audio: Producer<ToAudio>,
+    from_audio: Consumer<FromAudio>,
+    // what to reactivate with once a hand-over that timed out comes in
+    pending: Option<PluginAudioConfiguration>,
+}
+
+// The audio thread's end, serviced at the start of every block
+pub struct AudioSide {
+    from_main: Consumer<ToAudio>,
+    to_main: Producer<FromAudio>,
+}
+
+pub fn channel() -> (Restarter, AudioSide) {
+    let (to_audio, from_main) = RingBuffer::new(2);
+    let (to_main, from_audio) = RingBuffer::new(2);
+    (Restarter { to_audio, from_audio, pending: None }, AudioSide { from_main, to_main })
+}
+
+impl AudioSide {
+    // The sample rate of a new processor, if one was started
+    pub fn service(
+        &mut self,
+        proc: &mut Option<StartedPluginAudioProcessor<MyHost>>,
+        max_frames: &mut u32,
+    ) -> Option<f64> {
+        let mut started_new = None;
+        while let Ok(message) = self.from_main.pop() {
+            let reply = match message {
+                ToAudio::Surrender => match proc.take() {
+                    Some(started) => FromAudio::Stopped(started.stop_processing()),
+                    None => FromAudio::Idle,
+                },
+                ToAudio::Start(stopped, frames, rate) => match stopped.start_processing() {
+                    Ok(started) => {
+                        *proc = Some(started);
+                        *max_frames = frames;
+                        started_new = Some(rate);
+                        continue;
+                    }
+                    Err(e) => FromAudio::NotStarted(e.into_stopped_processor()),
+                },
+            };
+            // Room for both replies, and the main thread only asks once
+            let _ = self.to_main.push(reply);
+        }
+        started_new
+    }
+}
+
+impl Restarter {
+    // Deactivate and reactivate with `config`, calling `while_inactive` in
+    // between. The new processor starts on the audio thread's next block;
+    // see check_started for how that went. If the audio thread doesn't hand
+    // over the plugin in time, the restart is left pending: check_started
+    // finishes it when the plugin does come back, and a restart asked for
+    // meanwhile waits on that same hand-over.
+    pub fn restart(
+        &mut self,
+        instance: &mut PluginInstance<MyHost>,
+        config: PluginAudioConfiguration,
+        timeout: Duration,
+        while_inactive: impl FnOnce(&mut PluginInstance<MyHost>),
+    ) -> Result<(), String> {
+        if self.pending.is_none() {
+            self.to_audio
+                .push(ToAudio::Surrender)
+                .map_err(|_| "a restart is already in progress")?;
+        }
+        self.pending = Some(config);
+        // Replies come in order, so a failed start not yet picked up by
+        // check_started comes before the answer to this request
+        let asked = Instant::now();
+        let stopped = loop {
+            match self.from_audio.pop() {
+                Ok(FromAudio::NotStarted(stopped)) => instance.deactivate(stopped),
+                Ok(FromAudio::Stopped(stopped)) => break Some(stopped),
+                Ok(FromAudio::Idle) => break None,
+                Err(_) if asked.elapsed() < timeout => std::thread::sleep(Duration::from_millis(1)),
+                Err(_) => {
+                    return Err(format!(
+                        "the audio thread did not hand over the plugin within {timeout:?}; \
+                         it restarts once it does"
+                    ));
+                }
+            }
+        };
+        self.pending = None;
+        if let Some(stopped) = stopped {
+            instance.deactivate(stopped);
+        }
+        while_inactive(instance);
+        self.reactivate(instance, config)
+    }
+
+    fn reactivate(
+        &mut self,
+        instance: &mut PluginInstance<MyHost>,
+        config: PluginAudioConfiguration,
+    ) -> Result<(), String> {
+        let stopped = instance
+            .activate(|_, _| (), config)
+            .map_err(|e| format!("reactivation failed: {e:?}"))?;
+        self.to_audio
+            .push(ToAudio::Start(stopped, config.max_frames_count, config.sample_rate))
+            .map_err(|_| "the audio thread is not taking messages".into())
+    }
+
+    // Finish a restart whose hand-over came in late, returning true if it
+    // did. If the audio thread could not start the new processor, deactivate
+    // it and say so; the plugin then stays silent.
+    pub fn check_started(&mut self, instance: &mut PluginInstance<MyHost>) -> Result<bool, String> {
+        let Ok(reply) = self.from_audio.pop() else { return Ok(false) };
+        match (reply, self.pending.take()) {
+            (FromAudio::Stopped(stopped), Some(config)) => {
+                instance.deactivate(stopped);
+                self.reactivate(instance, config).map(|()| true)
+            }
+            // there was no processor to hand over, as after a failed restart
+            (FromAudio::Idle, Some(config)) => self.reactivate(instance, config).map(|()| true),
+            // a start that failed before the pending hand-over came in
+            (FromAudio::NotStarted(stopped) | FromAudio::Stopped(stopped), pending) => {
+                self.pending = pending;
+                instance.deactivate(stopped);
+                Err("the plugin would not start processing after its restart".into())
+            }
+            (FromAudio::Idle, None) => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Retroactive recording: the last N seconds of output are always kept, and
+// `dump` writes them to a WAV file after the fact, so a happy accident can be
+// saved even though nothing was armed. The same ring is what `record` takes
+// a live take from (see record.rs), with SLACK_SECONDS for it to keep up.
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::sinks::{Sink, WavSink};
+
+// Extra ring space beyond what a dump reads, so the audio thread can keep
+// writing during a dump without overwriting the frames being saved
+const SLACK_SECONDS: f64 = 2.0;
+
+pub struct RetroBuffer {
+    // interleaved stereo f32 bits; atomics so the audio thread never locks
+    samples: Box<[AtomicU32]>,
+    frames: usize,
+    seconds: f64,
+    // frames a dump saves, and the rate they're at; the audio thread
+    // changes both if JACK's rate does
+    keep: AtomicUsize,
+    sample_rate: AtomicU32,
+    // total frames ever written
+    written: AtomicU64,
+    // the frame (counted as `written` is) the latest bar started at, for
+    // --record-sync bar; NO_BAR until the transport has rolled into one
+    bar: AtomicU64,
+}
+
+const NO_BAR: u64 = u64::MAX;
+
+impl RetroBuffer {
+    pub fn new(sample_rate: f64, seconds: f64) -> Self {
+        let keep = (seconds * sample_rate) as usize;
+        let frames = keep + (SLACK_SECONDS * sample_rate) as usize;
+        RetroBuffer {
+            samples: (0..frames * 2).map(|_| AtomicU32::new(0)).collect(),
+            frames,
+            seconds,
+            keep: AtomicUsize::new(keep),
+            sample_rate: AtomicU32::new(sample_rate as u32),
+            written: AtomicU64::new(0),
+            bar: AtomicU64::new(NO_BAR),
+        }
+    }
+
+    // Audio thread: output now comes at `sample_rate`. The ring was sized
+    // at startup, so at a higher rate a dump holds less than --retro seconds.
+    // Frames from before the change play back at the new rate.
+    pub fn set_sample_rate(&self, sample_rate: f64) {
+        let slack = (SLACK_SECONDS * sample_rate) as usize;
+        let keep = ((self.seconds * sample_rate) as usize).min(self.frames.saturating_sub(slack));
+        self.keep.store(keep, Ordering::Relaxed);
+        self.sample_rate.store(sample_rate as u32, Ordering::Relaxed);
+    }
+
+    // Audio thread: append one block
+    pub fn write(&self, l: &[f32], r: &[f32]) {
+        let start = self.written.load(Ordering::Relaxed);
+        for (i, (l, r)) in l.iter().zip(r).enumerate() {
+            let at = ((start + i as u64) % self.frames as u64) as usize * 2;
+            self.samples[at].store(l.to_bits(), Ordering::Relaxed);
+            self.samples[at + 1].store(r.to_bits(), Ordering::Relaxed);
+        }
+        self.written.store(start + l.len() as u64, Ordering::Release);
+    }
+
+    // Audio thread: a bar starts `offset` frames into the block about to be written
+    pub fn mark_bar(&self, offset: usize) {
+        self.bar.store(self.written.load(Ordering::Relaxed) + offset as u64, Ordering::Relaxed);
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Acquire)
+    }
+
+    pub fn last_bar(&self) -> Option<u64> {
+        Some(self.bar.load(Ordering::Relaxed)).filter(|&bar| bar != NO_BAR)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Relaxed)
+    }
+
+    // Main thread: every sample of frames `from..to`, interleaved, or false
+    // (and nothing) if the ring no longer holds them all
+    pub fn read(&self, from: u64, to: u64, mut sample: impl FnMut(f32)) -> bool {
+        if self.written().saturating_sub(from) > self.frames as u64 {
+            return false;
+        }
+        for frame in from..to {
+            let at = (frame % self.frames as u64) as usize * 2;
+            for s in &self.samples[at..at + 2] {
+                sample(f32::from_bits(s.load(Ordering::Relaxed)));
+            }
+        }
+        true
+    }
+
+    // Main thread: save up to the last N seconds as 32-bit float WAV and
+    // return how many seconds that was
+    pub fn dump(&self, path: &Path) -> Result<f64, String> {
+        let sample_rate = self.sample_rate.load(Ordering::Relaxed);
+        let end = self.written.load(Ordering::Acquire);
+        let start = end.saturating_sub(self.keep.load(Ordering::Relaxed) as u64);
+        let mut wav = Box::new(WavSink::create(path, 2, sample_rate)?);
+        for frame in start..end {
+            let at = (frame % self.frames as u64) as usize * 2;
+            let samples = &self.samples[at..at + 2];
+            wav.write(&[0, 1].map(|i| f32::from_bits(samples[i].load(Ordering::Relaxed))))?;
+        }
+        wav.finish()?;
+        Ok((end - start) as f64 / sample_rate as f64)
+    }
+}
@@ -0,0 +1,110 @@
+// --scale: move incoming MIDI notes onto the nearest tone of a scale, so
+// nothing played on the hosted instrument can sound wrong.
+const SCALES: &[(&str, &[u8])] = &[
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("harmonic-minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("pentatonic", &[0, 2, 4, 7, 9]),
+    ("minor-pentatonic", &[0, 3, 5, 7, 10]),
+    ("blues", &[0, 3, 5, 6, 7, 10]),
+    ("chromatic", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+];
+
+const NOTES: &[(&str, u8)] = &[
+    ("c", 0), ("c#", 1), ("db", 1), ("d", 2), ("d#", 3), ("eb", 3), ("e", 4), ("f", 5),
+    ("f#", 6), ("gb", 6), ("g", 7), ("g#", 8), ("ab", 8), ("a", 9), ("a#", 10), ("bb", 10), ("b", 11),
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Scale {
+    root: u8,
+    // bit n set: n semitones above the root is in the scale
+    tones: u16,
+}
+
+// ROOT:NAME, e.g. C:minor or F#:pentatonic
+pub fn parse(s: &str) -> Result<Scale, String> {
+    let names = || SCALES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+    let (root, name) = s.split_once(':').ok_or_else(|| format!("expected ROOT:SCALE, e.g. C:minor ({})", names()))?;
+    let root = pitch_class(root).ok_or_else(|| format!("bad root note {root:?}: expected C, C#, Db ... B"))?;
+    let (_, steps) = SCALES
+        .iter()
+        .find(|(scale, _)| scale.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("unknown scale {name:?}: expected one of {}", names()))?;
+    Ok(Scale { root, tones: steps.iter().fold(0, |tones, step| tones | 1 << step) })
+}
+
+// C, C#, Db ... B, in any case, as semitones above C
+pub fn pitch_class(name: &str) -> Option<u8> {
+    NOTES.iter().find(|(note, _)| note.eq_ignore_ascii_case(name)).map(|(_, n)| *n)
+}
+
+impl Scale {
+    // Nearest scale tone, preferring the one below on a tie
+    pub fn quantize(&self, key: u8) -> u8 {
+        let in_scale = |key: i16| key >= 0 && self.tones & 1 << (key - self.root as i16).rem_euclid(12) != 0;
+        let key = key as i16;
+        (0..12)
+            .flat_map(|d| [key - d, key + d])
+            .find(|&k| in_scale(k) && k <= 127)
+            .unwrap_or(key) as u8
+    }
+
+    // Note and poly-pressure messages with their key moved onto the scale;
+    // anything else comes back as it was
+    pub fn apply<'a>(&self, bytes: &'a [u8], buf: &'a mut [u8; 3]) -> &'a [u8] {
+        match *bytes {
+            [status, key, value] if matches!(status & 0xf0, 0x80 | 0x90 | 0xa0) => {
+                *buf = [status, self.quantize(key), value];
+                buf
+            }
+            _ => bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantize_moves_to_the_nearest_tone() {
+        let c_major = parse("C:major").unwrap();
+        assert_eq!(c_major.quantize(60), 60);
+        // C# and F# are a semitone from two tones each: the lower wins
+        assert_eq!(c_major.quantize(61), 60);
+        assert_eq!(c_major.quantize(66), 65);
+        assert_eq!(parse("a:minor").unwrap().quantize(61), 60);
+        assert_eq!(parse("D:chromatic").unwrap().quantize(61), 61);
+    }
+
+    #[test]
+    fn quantize_stays_in_midi_range() {
+        assert_eq!(parse("C:major").unwrap().quantize(127), 127);
+        // F# 126: F isn't in the scale, so up to G
+        assert_eq!(parse("C:pentatonic").unwrap().quantize(126), 127);
+        // G 127: G# above would be 128, so down to F
+        assert_eq!(parse("G#:pentatonic").unwrap().quantize(127), 125);
+        assert_eq!(parse("E:major").unwrap().quantize(0), 1);
+    }
+
+    #[test]
+    fn parse_rejects_bad_scales() {
+        assert!(parse("Cmajor").is_err());
+        assert!(parse("H:major").is_err());
+        assert!(parse("C:lydian").is_err());
+        assert!(parse("Bb:blues").is_ok());
+    }
+
+    #[test]
+    fn apply_leaves_other_messages_alone() {
+        let scale = parse("C:major").unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(scale.apply(&[0x91, 61, 100], &mut buf), [0x91u8, 60, 100]);
+        assert_eq!(scale.apply(&[0xa0, 66, 10], &mut buf), [0xa0u8, 65, 10]);
+        assert_eq!(scale.apply(&[0xb0, 61, 100], &mut buf), [0xb0u8, 61, 100]);
+        assert_eq!(scale.apply(&[0xf8], &mut buf), [0xf8u8]);
+    }
+}
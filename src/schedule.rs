@@ -0,0 +1,158 @@
+// --at TIME=COMMANDS: live commands run at a time of day, every day, for
+// installations and background audio that keep opening hours:
+//
+//     --at "09:00=preset Morning; fade in 10" --at "22:00=fade out 30; wait 30; stop"
+//
+// or `at = ["09:00=preset Morning; fade in 10", ...]` in the config. Each is
+// a macro called @TIME, so it may `wait` between commands and `macro @22:00`
+// tries it out early; the main loop starts it when its time comes. Times are
+// local, going by what `date` says the offset from UTC is, checked now and
+// then so the clocks changing is picked up. An entry whose time we started
+// after waits for tomorrow; one the clock skipped over (a suspend, the clocks
+// going forward) runs as soon as we notice.
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::macros::{self, Macro, Macros};
+
+const DAY: i64 = 24 * 60 * 60;
+// How often to ask `date` for the UTC offset again
+const OFFSET_EVERY: Duration = Duration::from_secs(60);
+// A step back in the local time of up to this is the clocks going back, and
+// what already ran in the hour they repeat doesn't run again
+const CLOCKS_BACK: i64 = 60 * 60;
+
+#[derive(Clone, Debug)]
+pub struct At {
+    // seconds after local midnight
+    second: i64,
+    steps: Macro,
+}
+
+pub fn parse_at(s: &str) -> Result<At, String> {
+    let (time, commands) = s.split_once('=').ok_or("expected HH:MM=COMMAND; COMMAND; ...")?;
+    let time = time.trim();
+    let bad = || format!("bad time {time:?}: expected HH:MM or HH:MM:SS");
+    let parts: Vec<i64> = time.split(':').map(|part| part.parse().map_err(|_| bad())).collect::<Result<_, _>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [h, m] => (h, m, 0),
+        [h, m, s] => (h, m, s),
+        _ => return Err(bad()),
+    };
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) || !(0..60).contains(&seconds) {
+        return Err(bad());
+    }
+    Ok(At { second: hours * 3600 + minutes * 60 + seconds, steps: macros::scheduled(time, commands)? })
+}
+
+impl At {
+    pub fn into_macro(self) -> Macro {
+        self.steps
+    }
+}
+
+pub struct Schedule {
+    // each entry's time of day and its macro
+    entries: Vec<(i64, usize)>,
+    offset: i64,
+    offset_checked: Instant,
+    // the local time, in seconds since the epoch, we last looked
+    last: Option<i64>,
+}
+
+impl Schedule {
+    // After the entries' macros have gone into `macros`
+    pub fn new(ats: &[At], macros: &Macros) -> Self {
+        let entries = ats.iter().filter_map(|at| Some((at.second, macros.index(at.steps.name())?))).collect();
+        let offset = match ats.is_empty() {
+            true => 0,
+            false => utc_offset().unwrap_or_else(|| {
+                eprintln!("--at: `date` would not say the local time zone; going by UTC");
+                0
+            }),
+        };
+        Schedule { entries, offset, offset_checked: Instant::now(), last: None }
+    }
+
+    // From the main loop: the macros whose time has come since it last asked
+    pub fn take_due(&mut self) -> Vec<usize> {
+        if self.entries.is_empty() {
+            return Vec::new();
+        }
+        if self.offset_checked.elapsed() >= OFFSET_EVERY {
+            self.offset = utc_offset().unwrap_or(self.offset);
+            self.offset_checked = Instant::now();
+        }
+        let now = unix_now() + self.offset;
+        let last = match self.last {
+            Some(last) if now >= last - CLOCKS_BACK => last,
+            _ => now,
+        };
+        self.last = Some(now.max(last));
+        due(&self.entries, last, now)
+    }
+
+    // Near enough: the loop checks again often anyway
+    pub fn next_due(&self) -> Option<Instant> {
+        let now = unix_now() + self.offset;
+        // from 1s, not 0, once this second's entries have run
+        let wait = self.entries.iter().map(|&(second, _)| (second - now - 1).rem_euclid(DAY) + 1).min()?;
+        Some(Instant::now() + Duration::from_secs(wait as u64))
+    }
+}
+
+// The entries whose time of day comes after `last` and by `now`, both in
+// local seconds since the epoch
+fn due(entries: &[(i64, usize)], last: i64, now: i64) -> Vec<usize> {
+    let mut due = Vec::new();
+    for &(second, index) in entries {
+        let mut at = last.div_euclid(DAY) * DAY + second;
+        if at <= last {
+            at += DAY;
+        }
+        if at <= now {
+            due.push(index);
+        }
+    }
+    due
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+// Seconds east of UTC from `date +%z`, e.g. +0100 for 3600
+fn utc_offset() -> Option<i64> {
+    let out = Command::new("date").arg("+%z").output().ok()?;
+    let text = String::from_utf8(out.stdout).ok()?;
+    let text = text.trim();
+    let (sign, digits) = match text.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    let (hours, minutes) = digits.split_at_checked(2)?;
+    Some(sign * (hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_each_entry_once_a_day() {
+        let at = |s| parse_at(s).unwrap().second;
+        let entries = [(at("09:00=preset Morning"), 0), (at("22:00:30=fade out 30; wait 30; stop"), 1)];
+        let morning = 10 * DAY + 9 * 3600;
+        // started just after 09:00: nothing till tomorrow
+        assert!(due(&entries, morning + 1, morning + 2).is_empty());
+        assert_eq!(due(&entries, morning - 1, morning), [0]);
+        assert_eq!(due(&entries, morning, morning + 13 * 3600 + 30), [1]);
+        // asleep over both
+        assert_eq!(due(&entries, morning - 60, morning + DAY - 120), [0, 1]);
+
+        for bad in ["9=play", "24:00=play", "09:60=play", "09:00:00:00=play", "09:00", "09:00=macro @22:00"] {
+            assert!(parse_at(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// Teardown with deadlines: a plugin that hangs in stop_processing/deactivate
+// (or wedges the JACK callback) must not keep the process alive.
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clack_host::prelude::*;
+use jack::AsyncClient;
+
+use crate::lifecycle::{self, Event};
+use crate::repl::Repl;
+use crate::{JackHandler, JackNotifications, MyHost};
+
+// Exit status used when teardown had to be abandoned
+const FORCED_EXIT: i32 = 4;
+
+// What the audio thread's tail report holds until it has answered
+pub const TAIL_UNKNOWN: u64 = u64::MAX;
+
+// Run `f`, but exit the whole process if it hasn't returned within `timeout`.
+// Exiting closes our JACK connection, so the server drops our callback and
+// ports even if the plugin never comes back.
+pub fn with_deadline<R>(what: &'static str, timeout: Duration, f: impl FnOnce() -> R) -> R {
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    std::thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+            eprintln!("{what} did not finish within {timeout:?}; forcing exit");
+            std::process::exit(FORCED_EXIT);
+        }
+    });
+    let result = f();
+    let _ = done_tx.send(());
+    result
+}
+
+// Where a run is in stopping, shared with the signal handler
+const RUNNING: u8 = 0;
+const QUITTING: u8 = 1;
+const RINGING_OUT: u8 = 2;
+const CUT_SHORT: u8 = 3;
+
+// SIGINT/SIGTERM ask the run to stop, for the caller to shut down as it would
+// for any other exit. A second one cuts a ring-out short; at any other time
+// it exits without waiting for that.
+pub struct Quit(Arc<AtomicU8>);
+
+impl Quit {
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed) != RUNNING
+    }
+}
+
+pub fn on_signal() -> Result<Quit, ctrlc::Error> {
+    let quit = Quit(Arc::new(AtomicU8::new(RUNNING)));
+    let state = quit.0.clone();
+    ctrlc::set_handler(move || {
+        let next = state.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| match state {
+            RUNNING => Some(QUITTING),
+            RINGING_OUT => Some(CUT_SHORT),
+            _ => None,
+        });
+        if next.is_err() {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(quit)
+}
+
+// Release every note and keep running for the plugin's tail (at most
+// `max_tail`), so reverb and delay tails aren't cut off. The audio thread
+// answers with the tail on its next block; if it doesn't, we don't wait.
+// Another Ctrl+C meanwhile stops waiting.
+pub fn ring_out(repl: &mut Repl, quit: &Quit, tail: &AtomicU64, sample_rate: f64, max_tail: Duration) {
+    if !repl.ring_out() {
+        return;
+    }
+    quit.0.store(RINGING_OUT, Ordering::Relaxed);
+    let mut deadline = Instant::now() + Duration::from_secs(1);
+    let mut answered = false;
+    while quit.0.load(Ordering::Relaxed) == RINGING_OUT {
+        let frames = tail.load(Ordering::Relaxed);
+        if !answered && frames != TAIL_UNKNOWN {
+            if frames == 0 {
+                break;
+            }
+            answered = true;
+            let ring = Duration::from_secs_f64(frames as f64 / sample_rate).min(max_tail);
+            println!("Letting the plugin's tail ring out for {:.1}s (Ctrl+C to cut it short)", ring.as_secs_f64());
+            deadline = Instant::now() + ring;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        std::thread::sleep(left.min(Duration::from_millis(5)));
+        // a take still going gets the tail too
+        repl.poll_recording();
+    }
+    // From here on a signal exits straight away again
+    quit.0.store(QUITTING, Ordering::Relaxed);
+}
+
+// Detach from JACK (removing our callback and ports), then stop and deactivate
+// the plugin, each step bounded by `timeout`.
+pub fn shutdown(
+    active: AsyncClient<JackNotifications, JackHandler>,
+    instance: &mut PluginInstance<MyHost>,
+    timeout: Duration,
+) -> Result<(), jack::Error> {
+    let handler = with_deadline("JACK deactivation", timeout, || {
+        active.deactivate().map(|(client, _notifications, handler)| {
+            drop(client);
+            handler
+        })
+    })?;
+    // No processor if we stopped in the middle of a restart
+    if let Some(proc) = handler.proc {
+        let stopped = with_deadline("Plugin stop_processing", timeout, || proc.stop_processing());
+        with_deadline("Plugin deactivation", timeout, || instance.deactivate(stopped));
+    }
+    lifecycle::log(Event::Shutdown);
+    Ok(())
+}
@@ -0,0 +1,293 @@
+// Where the output goes. JACK always, and with --sink as many more as wanted
+// at once, each of them switched on and off with `sink NAME on|off` from the
+// console or OSC while the rest carry on:
+//
+//     --sink file:set.wav           a WAV file of the whole run
+//     --sink rtp:239.0.0.1:5004     an RTP stream, 16-bit PCM (L16) stereo on
+//                                   payload type 96, for ffplay or VLC with
+//                                   an SDP saying so
+//     --sink backup=file:b.wav      NAME= to tell two of a kind apart
+//
+// The sinks take what the JACK ports get, without the click. Like `record`
+// they're fed on the main thread from the retro ring, so no file or network
+// I/O happens in the callback; the JACK one is only a switch the audio thread
+// looks at, to play silence while it's off. The same Sink trait writes the
+// WAV files of `dump`, `record` and `render`.
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::retro::RetroBuffer;
+
+// Interleaved samples in, for as long as it's open
+pub trait Sink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String>;
+    // Anything buffered out, and files closed with their headers right
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+// 32-bit float WAV
+pub struct WavSink {
+    path: PathBuf,
+    wav: WavWriter<BufWriter<File>>,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> Result<Self, String> {
+        let spec = WavSpec { channels, sample_rate, bits_per_sample: 32, sample_format: SampleFormat::Float };
+        let wav = WavWriter::create(path, spec).map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(WavSink { path: path.to_path_buf(), wav })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Frames written so far
+    pub fn frames(&self) -> u32 {
+        self.wav.duration()
+    }
+}
+
+impl Sink for WavSink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        samples.iter().try_for_each(|&s| self.wav.write_sample(s)).map_err(|e| format!("{}: {e}", self.path.display()))
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        self.wav.finalize().map_err(|e| format!("{}: {e}", self.path.display()))
+    }
+}
+
+// RTP payload type for the stream: the first dynamic one, as L16 at any rate
+// needs. Frames per packet keep it well under an Ethernet MTU.
+const RTP_PAYLOAD_TYPE: u8 = 96;
+const RTP_FRAMES: usize = 240;
+
+pub struct RtpSink {
+    socket: UdpSocket,
+    seq: u16,
+    timestamp: u32,
+    ssrc: u32,
+    // samples waiting to fill a packet
+    pending: Vec<f32>,
+    packet: Vec<u8>,
+}
+
+impl RtpSink {
+    pub fn connect(target: &str) -> Result<Self, String> {
+        let addr = target.to_socket_addrs().ok().and_then(|mut a| a.next()).ok_or(format!("bad address {target:?}"))?;
+        let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).and_then(|s| s.connect(addr).map(|_| s));
+        let socket = socket.map_err(|e| format!("{target}: {e}"))?;
+        // any value will do, as long as two streams are unlikely to share it
+        let ssrc = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let packet = Vec::with_capacity(12 + RTP_FRAMES * 4);
+        Ok(RtpSink { socket, seq: 0, timestamp: 0, ssrc, pending: Vec::new(), packet })
+    }
+}
+
+impl Sink for RtpSink {
+    fn write(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.pending.extend_from_slice(samples);
+        let full = self.pending.len() / (RTP_FRAMES * 2) * RTP_FRAMES * 2;
+        for chunk in self.pending[..full].chunks_exact(RTP_FRAMES * 2) {
+            self.packet.clear();
+            self.packet.extend_from_slice(&[0x80, RTP_PAYLOAD_TYPE]);
+            self.packet.extend_from_slice(&self.seq.to_be_bytes());
+            self.packet.extend_from_slice(&self.timestamp.to_be_bytes());
+            self.packet.extend_from_slice(&self.ssrc.to_be_bytes());
+            for &s in chunk {
+                self.packet.extend_from_slice(&((s.clamp(-1.0, 1.0) * 32767.0) as i16).to_be_bytes());
+            }
+            self.seq = self.seq.wrapping_add(1);
+            self.timestamp = self.timestamp.wrapping_add(RTP_FRAMES as u32);
+            // a listener not there yet is no reason to stop
+            let _ = self.socket.send(&self.packet);
+        }
+        self.pending.drain(..full);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// --sink [NAME=]file:PATH or [NAME=]rtp:HOST:PORT
+#[derive(Clone, Debug)]
+pub struct SinkSpec {
+    name: String,
+    kind: SinkKind,
+}
+
+#[derive(Clone, Debug)]
+enum SinkKind {
+    File(PathBuf),
+    Rtp(String),
+}
+
+pub fn parse_sink(s: &str) -> Result<SinkSpec, String> {
+    let usage = "expected [NAME=]file:PATH or [NAME=]rtp:HOST:PORT";
+    let (name, target) = match s.split_once('=').filter(|(name, _)| !name.contains(':')) {
+        Some((name, target)) => (Some(name.trim()), target),
+        None => (None, s),
+    };
+    let (kind, rest) = target.split_once(':').ok_or(usage)?;
+    let kind = match kind {
+        "file" if !rest.is_empty() => SinkKind::File(PathBuf::from(rest)),
+        "rtp" if rest.contains(':') => SinkKind::Rtp(rest.to_string()),
+        _ => return Err(usage.into()),
+    };
+    let name = name.unwrap_or(match kind {
+        SinkKind::File(_) => "file",
+        SinkKind::Rtp(_) => "rtp",
+    });
+    if name.is_empty() || name.contains(char::is_whitespace) || name == JACK {
+        return Err(format!("bad sink name {name:?}: expected one word other than {JACK}"));
+    }
+    Ok(SinkSpec { name: name.to_string(), kind })
+}
+
+const JACK: &str = "jack";
+
+struct Open {
+    name: String,
+    sink: Box<dyn Sink>,
+    on: bool,
+}
+
+pub struct Sinks {
+    retro: Arc<RetroBuffer>,
+    // the JACK ports' switch, for the audio thread
+    jack: Arc<AtomicBool>,
+    sinks: Vec<Open>,
+    // the next ring frame for the sinks, and room to read it into
+    next: u64,
+    samples: Vec<f32>,
+}
+
+impl Sinks {
+    pub fn open(specs: &[SinkSpec], retro: Arc<RetroBuffer>, jack: Arc<AtomicBool>) -> Result<Self, String> {
+        let mut sinks = Vec::new();
+        for (i, spec) in specs.iter().enumerate() {
+            if specs[..i].iter().any(|other| other.name == spec.name) {
+                return Err(format!("--sink {}: two sinks of that name; tell them apart with NAME=", spec.name));
+            }
+            let sink: Box<dyn Sink> = match &spec.kind {
+                SinkKind::File(path) => Box::new(WavSink::create(path, 2, retro.sample_rate())?),
+                SinkKind::Rtp(target) => Box::new(RtpSink::connect(target)?),
+            };
+            sinks.push(Open { name: spec.name.clone(), sink, on: true });
+        }
+        let next = retro.written();
+        Ok(Sinks { retro, jack, sinks, next, samples: Vec::new() })
+    }
+
+    // `sink` with no argument
+    pub fn describe(&self) -> String {
+        let jack = format!("{JACK} {}", on_off(self.jack.load(Ordering::Relaxed)));
+        let rest = self.sinks.iter().map(|open| format!("{} {}", open.name, on_off(open.on)));
+        std::iter::once(jack).chain(rest).collect::<Vec<_>>().join(", ")
+    }
+
+    pub fn set(&mut self, name: &str, on: bool) -> Result<(), String> {
+        if name == JACK {
+            self.jack.store(on, Ordering::Relaxed);
+            return Ok(());
+        }
+        let open = self.sinks.iter_mut().find(|open| open.name == name).ok_or_else(|| format!("no sink {name:?}"))?;
+        open.on = on;
+        Ok(())
+    }
+
+    pub fn is_on(&self, name: &str) -> Option<bool> {
+        match name {
+            JACK => Some(self.jack.load(Ordering::Relaxed)),
+            _ => self.sinks.iter().find(|open| open.name == name).map(|open| open.on),
+        }
+    }
+
+    // From the main loop: what's new in the ring, to every sink that's on.
+    // One that fails is closed and dropped; the rest carry on.
+    pub fn poll(&mut self) {
+        let end = self.retro.written();
+        let from = std::mem::replace(&mut self.next, end);
+        if !self.sinks.iter().any(|open| open.on) {
+            return;
+        }
+        let samples = &mut self.samples;
+        samples.clear();
+        if !self.retro.read(from, end, |sample| samples.push(sample)) {
+            eprintln!("sink: fell behind the audio and lost some of it; carrying on from now");
+        }
+        let mut i = 0;
+        while i < self.sinks.len() {
+            let open = &mut self.sinks[i];
+            match open.on.then(|| open.sink.write(&self.samples)) {
+                Some(Err(e)) => {
+                    eprintln!("sink {}: {e}; closing it", open.name);
+                    let _ = self.sinks.remove(i).sink.finish();
+                }
+                _ => i += 1,
+            }
+        }
+    }
+
+    // On the way out
+    pub fn finish(mut self) {
+        self.poll();
+        for open in self.sinks {
+            if let Err(e) = open.sink.finish() {
+                eprintln!("sink {}: {e}", open.name);
+            }
+        }
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sinks_with_and_without_names() {
+        let file = parse_sink("file:set.wav").unwrap();
+        assert_eq!(file.name, "file");
+        assert!(matches!(file.kind, SinkKind::File(ref path) if path == Path::new("set.wav")));
+        let rtp = parse_sink("out=rtp:239.0.0.1:5004").unwrap();
+        assert_eq!(rtp.name, "out");
+        assert!(matches!(rtp.kind, SinkKind::Rtp(ref target) if target == "239.0.0.1:5004"));
+        for bad in ["set.wav", "file:", "rtp:239.0.0.1", "icecast:host:8000", "jack=file:a.wav", "two words=file:a"] {
+            assert!(parse_sink(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rtp_sends_full_packets_in_sequence() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut rtp = RtpSink::connect(&listener.local_addr().unwrap().to_string()).unwrap();
+        rtp.write(&vec![0.5; RTP_FRAMES * 2 + 10]).unwrap();
+        rtp.write(&vec![-1.0; RTP_FRAMES * 2 - 10]).unwrap();
+        let mut buf = [0; 2048];
+        for seq in 0..2u16 {
+            let len = listener.recv(&mut buf).unwrap();
+            assert_eq!(len, 12 + RTP_FRAMES * 4);
+            assert_eq!(&buf[..4], &[0x80, RTP_PAYLOAD_TYPE, 0, seq as u8]);
+            assert_eq!(buf[4..8], (seq as u32 * RTP_FRAMES as u32).to_be_bytes());
+        }
+        // the second packet: the 10 left over from the first write, then the second's
+        let end = 12 + RTP_FRAMES * 4;
+        assert_eq!(i16::from_be_bytes([buf[12], buf[13]]), 16383);
+        assert_eq!(i16::from_be_bytes([buf[end - 2], buf[end - 1]]), -32767);
+    }
+}
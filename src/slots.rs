@@ -0,0 +1,169 @@
+// --slot FILE, repeated: states saved with --save-state, recalled by playing
+// a note in the --slot-notes range (the first slot on its lowest note) or
+// with `slot N` from the console or OSC, for changing sounds without taking
+// a hand off the keys. The files are read and checked on a thread of their
+// own as we start, so a recall only hands the plugin a state already in
+// memory. One plugin can't play two states at once to crossfade them, so the
+// output dips instead: out over --slot-fade, the state loads, and back in.
+// Notes anywhere in the range are the host's; the plugin doesn't get them.
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+use crate::scale;
+use crate::state::{self, Identity, Saved};
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlotNotes {
+    low: u8,
+    high: u8,
+}
+
+// LOW..HIGH, each a MIDI note number or a name like C-1 (0) or F#4 (66)
+pub fn parse_notes(s: &str) -> Result<SlotNotes, String> {
+    let (low, high) = s.split_once("..").ok_or("expected LOW..HIGH, e.g. C-1..B-1")?;
+    let (low, high) = (parse_note(low)?, parse_note(high)?);
+    if low > high {
+        return Err(format!("{s:?} runs downwards"));
+    }
+    Ok(SlotNotes { low, high })
+}
+
+fn parse_note(s: &str) -> Result<u8, String> {
+    let bad = || format!("bad note {s:?}: expected 0 to 127 or a name like C-1, F#3 or Bb4");
+    if let Ok(key) = s.parse::<u8>() {
+        return Some(key).filter(|k| *k < 128).ok_or_else(bad);
+    }
+    let split = s.char_indices().skip(1).find(|(_, c)| *c == '-' || c.is_ascii_digit()).ok_or_else(bad)?.0;
+    let (name, octave) = s.split_at(split);
+    let pitch = scale::pitch_class(name).ok_or_else(bad)? as i32;
+    let octave: i32 = octave.parse().map_err(|_| bad())?;
+    u8::try_from((octave + 1) * 12 + pitch).ok().filter(|k| *k < 128).ok_or_else(bad)
+}
+
+impl SlotNotes {
+    pub fn len(&self) -> usize {
+        (self.high - self.low) as usize + 1
+    }
+
+    // For a note-on or note-off in the range, Some: of the slot to recall
+    // for a note-on of one of the first `slots` keys, else of None
+    pub fn take(&self, bytes: &[u8], slots: usize) -> Option<Option<usize>> {
+        let &[status, key, velocity] = bytes else { return None };
+        if !matches!(status & 0xf0, 0x80 | 0x90) || !(self.low..=self.high).contains(&key) {
+            return None;
+        }
+        let slot = (key - self.low) as usize;
+        Some(Some(slot).filter(|&slot| status & 0xf0 == 0x90 && velocity > 0 && slot < slots))
+    }
+}
+
+enum Slot {
+    Reading,
+    Read(Saved),
+    Failed(String),
+}
+
+pub struct Slots {
+    paths: Vec<PathBuf>,
+    slots: Vec<Slot>,
+    read: Receiver<(usize, Result<Saved, String>)>,
+    fade: Duration,
+    // the slot to load once the output has dipped out, and when that is
+    pending: Option<(usize, Instant)>,
+}
+
+impl Slots {
+    pub fn spawn(paths: Vec<PathBuf>, plugin: Identity, fade: Duration) -> Self {
+        let (tx, read) = mpsc::channel();
+        let files = paths.clone();
+        std::thread::spawn(move || {
+            for (i, path) in files.iter().enumerate() {
+                let saved = state::read(path, &plugin).map_err(|e| e.to_string());
+                if tx.send((i, saved)).is_err() {
+                    break;
+                }
+            }
+        });
+        let slots = paths.iter().map(|_| Slot::Reading).collect();
+        Slots { paths, slots, read, fade, pending: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn fade(&self) -> Duration {
+        self.fade
+    }
+
+    // Dip out for slot `index` (from 0); a second recall before the first
+    // has loaded takes its place
+    pub fn recall(&mut self, index: usize, now: Instant) -> Result<(), String> {
+        match self.slots.get(index) {
+            None => Err(format!("slot: no slot {} (we have 1 to {})", index + 1, self.slots.len())),
+            Some(Slot::Failed(e)) => Err(format!("slot {}: {e}", index + 1)),
+            Some(_) => {
+                self.pending = Some((index, now + self.fade));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.pending.map(|(_, due)| due)
+    }
+
+    // From the main loop: the files read so far, then the slot to load now
+    // that the output is silent, if it has been read. Its number and state.
+    pub fn take_due(&mut self, now: Instant) -> Option<(usize, Result<&Saved, String>)> {
+        for (i, saved) in self.read.try_iter() {
+            self.slots[i] = match saved {
+                Ok(saved) => Slot::Read(saved),
+                Err(e) => {
+                    eprintln!("--slot {}: {e}", self.paths[i].display());
+                    Slot::Failed(e)
+                }
+            };
+        }
+        let (index, due) = self.pending?;
+        let saved = match &self.slots[index] {
+            _ if due > now => return None,
+            Slot::Reading => return None,
+            Slot::Read(saved) => Ok(saved),
+            Slot::Failed(e) => Err(e.clone()),
+        };
+        self.pending = None;
+        Some((index, saved))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_in_the_range_pick_slots() {
+        let notes = parse_notes("C-1..B-1").unwrap();
+        assert_eq!(notes.len(), 12);
+        assert_eq!(notes.take(&[0x90, 0, 100], 3), Some(Some(0)));
+        assert_eq!(notes.take(&[0x93, 2, 1], 3), Some(Some(2)));
+        // in the range but past the slots, or a note-off: swallowed
+        assert_eq!(notes.take(&[0x90, 5, 100], 3), Some(None));
+        assert_eq!(notes.take(&[0x80, 0, 0], 3), Some(None));
+        assert_eq!(notes.take(&[0x90, 0, 0], 3), Some(None));
+        assert_eq!(notes.take(&[0x90, 12, 100], 3), None);
+        assert_eq!(notes.take(&[0xb0, 0, 100], 3), None);
+
+        assert_eq!(parse_note("F#4"), Ok(66));
+        assert_eq!(parse_note("bb-1"), Ok(10));
+        assert_eq!(parse_note("60"), Ok(60));
+        assert_eq!(parse_note("G9"), Ok(127));
+        for bad in ["C-2", "G#9", "H3", "128", "C", "-1"] {
+            assert!(parse_note(bad).is_err(), "{bad}");
+        }
+        for bad in ["C4", "C4..", "D4..C4"] {
+            assert!(parse_notes(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// `soak`: run a plugin offline for hours with randomised parameter changes,
+// periodic state save/load round-trips and start/stop_processing toggles,
+// reporting anything that goes wrong. Meant for overnight stability runs.
+use std::ffi::CStr;
+use std::time::{Duration, Instant};
+
+use clack_extensions::params::{ParamInfoBuffer, ParamInfoFlags, PluginParams};
+use clack_extensions::state::PluginState;
+use clack_host::events::event_types::ParamValueEvent;
+use clack_host::events::io::{EventBuffer, InputEvents};
+use clack_host::events::Pckn;
+use clack_host::prelude::*;
+use clack_host::utils::{ClapId, Cookie};
+
+use crate::layout::Layout;
+use crate::{instantiate, process_ports, MyHost};
+
+// Only the first few errors are printed in full; the rest are just counted
+const MAX_REPORTED_ERRORS: usize = 20;
+
+pub struct SoakConfig {
+    pub sample_rate: f64,
+    pub block: u32,
+    pub duration: Duration,
+    pub state_every: Duration,
+    pub toggle_every: Duration,
+    pub seed: u64,
+}
+
+// Small xorshift PRNG: reproducible from --seed, no dependency needed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Uniform in [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// A parameter we are allowed to automate
+struct Param {
+    id: ClapId,
+    min: f64,
+    max: f64,
+    stepped: bool,
+}
+
+fn automatable_params(instance: &mut PluginInstance<MyHost>) -> Vec<Param> {
+    let mut handle = instance.plugin_handle();
+    let Some(params) = handle.get_extension::<PluginParams>() else { return Vec::new() };
+    let mut buffer = ParamInfoBuffer::new();
+    (0..params.count(&mut handle))
+        .filter_map(|i| params.get_info(&mut handle, i, &mut buffer).map(|info| (info.id, info.flags, info.min_value, info.max_value)))
+        .filter(|(_, flags, _, _)| flags.contains(ParamInfoFlags::IS_AUTOMATABLE) && !flags.contains(ParamInfoFlags::IS_READONLY))
+        .map(|(id, flags, min, max)| Param { id, min, max, stepped: flags.contains(ParamInfoFlags::IS_STEPPED) })
+        .collect()
+}
+
+#[derive(Default)]
+struct Report {
+    blocks: u64,
+    param_events: u64,
+    state_round_trips: u64,
+    toggles: u64,
+    errors: u64,
+}
+
+impl Report {
+    // Print the summary; any error makes the process exit non-zero
+    fn finish(&self, block: usize, sample_rate: f64) -> Result<(), Box<dyn std::error::Error>> {
+        println!(
+            "Soak finished: {} blocks ({:.1}h of audio), {} param events, {} state round-trips, \
+             {} start/stop toggles, {} errors",
+            self.blocks,
+            self.blocks as f64 * block as f64 / sample_rate / 3600.0,
+            self.param_events,
+            self.state_round_trips,
+            self.toggles,
+            self.errors,
+        );
+        if self.errors > 0 {
+            std::process::exit(1);
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, elapsed: Duration, what: String) {
+        self.errors += 1;
+        if self.errors as usize <= MAX_REPORTED_ERRORS {
+            eprintln!("[{:>8.1}s] ERROR: {what}", elapsed.as_secs_f64());
+        } else if self.errors as usize == MAX_REPORTED_ERRORS + 1 {
+            eprintln!("(further errors are only counted)");
+        }
+    }
+}
+
+pub fn run(
+    bundle: &PluginBundle,
+    plugin_id: &CStr,
+    host_info: &HostInfo,
+    cfg: &SoakConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut instance = instantiate(bundle, plugin_id, host_info)?;
+    let params = automatable_params(&mut instance);
+    let layout = Layout::query(&mut instance);
+    let state = instance.plugin_handle().get_extension::<PluginState>();
+    println!(
+        "Soak: {:.1}h, {} automatable params, state {}, seed {}",
+        cfg.duration.as_secs_f64() / 3600.0,
+        params.len(),
+        if state.is_some() { "supported" } else { "not supported" },
+        cfg.seed,
+    );
+
+    let audio_cfg = PluginAudioConfiguration {
+        sample_rate: cfg.sample_rate,
+        min_frames_count: 1,
+        max_frames_count: cfg.block,
+    };
+    let mut proc = instance.activate(|_, _| (), audio_cfg)?.start_processing()?;
+
+    let mut rng = Rng(cfg.seed.max(1));
+    let n = cfg.block as usize;
+    // silence into every input, and a buffer for every output channel
+    let mut inputs = vec![vec![0.0f32; n]; layout.input_channels()];
+    let mut scratch = vec![vec![0.0f32; n]; layout.output_channels()];
+    let mut outputs: Vec<&mut [f32]> = scratch.iter_mut().map(Vec::as_mut_slice).collect();
+    let mut ports = [
+        AudioPorts::with_capacity(layout.input_channels(), layout.inputs.len()),
+        AudioPorts::with_capacity(layout.output_channels(), layout.outputs.len()),
+    ];
+    let mut events = EventBuffer::with_capacity(4);
+    let mut out_events = EventBuffer::with_capacity(256);
+    let mut report = Report::default();
+    // frames since the last start_processing
+    let mut steady_time = 0u64;
+
+    let started = Instant::now();
+    let mut last_state = started;
+    let mut last_toggle = started;
+    let mut last_progress = started;
+    while started.elapsed() < cfg.duration {
+        let elapsed = started.elapsed();
+
+        // Roughly one random parameter change every 8 blocks
+        events.clear();
+        if !params.is_empty() && rng.below(8) == 0 {
+            let p = &params[rng.below(params.len())];
+            let mut value = p.min + rng.unit() * (p.max - p.min);
+            if p.stepped {
+                value = value.round();
+            }
+            let time = rng.below(n) as u32;
+            events.push(&ParamValueEvent::new(time, p.id, Pckn::match_all(), value, Cookie::empty()));
+            report.param_events += 1;
+        }
+
+        out_events.clear();
+        let result = process_ports(
+            &mut proc,
+            steady_time,
+            None,
+            &InputEvents::from_buffer(&events),
+            &mut out_events,
+            &layout,
+            &mut ports,
+            &mut inputs,
+            &mut outputs,
+            0..n,
+        );
+        report.blocks += 1;
+        steady_time += n as u64;
+        if let Err(e) = result {
+            report.error(elapsed, format!("process() failed in block {}: {e}", report.blocks));
+        }
+        if outputs.iter().any(|out| out.iter().any(|s| !s.is_finite())) {
+            report.error(elapsed, format!("non-finite output in block {}", report.blocks));
+        }
+
+        if let Some(state) = state {
+            if last_state.elapsed() >= cfg.state_every {
+                last_state = Instant::now();
+                let mut blob = Vec::new();
+                let result = state
+                    .save(&mut instance.plugin_handle(), &mut blob)
+                    .map_err(|e| format!("state save failed: {e:?}"))
+                    .and_then(|()| {
+                        state
+                            .load(&mut instance.plugin_handle(), &mut blob.as_slice())
+                            .map_err(|e| format!("state load of {} bytes failed: {e:?}", blob.len()))
+                    });
+                match result {
+                    Ok(()) => report.state_round_trips += 1,
+                    Err(e) => report.error(elapsed, e),
+                }
+            }
+        }
+
+        if last_toggle.elapsed() >= cfg.toggle_every {
+            last_toggle = Instant::now();
+            proc = match proc.stop_processing().start_processing() {
+                Ok(restarted) => restarted,
+                Err(e) => {
+                    // Can't carry on without a started processor
+                    report.error(elapsed, format!("start_processing after stop failed: {e}"));
+                    instance.deactivate(e.into_stopped_processor());
+                    return report.finish(n, cfg.sample_rate);
+                }
+            };
+            report.toggles += 1;
+            steady_time = 0;
+        }
+
+        if last_progress.elapsed() >= Duration::from_secs(60) {
+            last_progress = Instant::now();
+            println!(
+                "[{:>8.1}s] {} blocks, {} errors",
+                elapsed.as_secs_f64(),
+                report.blocks,
+                report.errors
+            );
+        }
+    }
+
+    instance.deactivate(proc.stop_processing());
+    report.finish(n, cfg.sample_rate)
+}
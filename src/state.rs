@@ -0,0 +1,520 @@
+// --load-state / --save-state: the plugin's own clap.state blob in a file, so
+// settings dialled in by hand survive a restart.
+//
+// The file wraps the blob with what it belongs to, so loading one saved for
+// another plugin fails with a message saying so rather than the plugin
+// choking on it. After an 8-byte magic and a format number come chunks, each
+// a 4-byte tag, a length and that many bytes, little-endian throughout:
+//
+//     HOST  the jack_minimal_clap version that saved it
+//     PLUG  the plugin ID, a NUL, and the plugin's version
+//     TIME  Unix seconds when it was saved
+//     PRMS  every parameter as (u32 ID, f64 value), checked after loading
+//     BLOB  a codec byte, then the plugin's state
+//
+// Chunks we don't know are skipped, so later additions still load here. The
+// state is compressed (codec 1, after its length as a u32) unless that
+// doesn't make it smaller, when it's stored as is (codec 0). A file without
+// the magic is taken to be a bare blob as saved before, and loads as it
+// always did.
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clack_extensions::state::PluginState;
+use clack_host::prelude::*;
+use clack_host::utils::ClapId;
+
+use crate::{params, MyHost};
+
+const MAGIC: &[u8; 8] = b"JMCSTATE";
+const FORMAT: u16 = 1;
+// BLOB codecs: the state as the plugin wrote it, or LZ-compressed
+const STORED: u8 = 0;
+const LZ: u8 = 1;
+
+// LZ matches are at least this long and at most this far back
+const MIN_MATCH: usize = 4;
+const WINDOW: usize = u16::MAX as usize;
+// bits of the hash the compressor finds earlier matches by
+const HASH_BITS: u32 = 12;
+
+// The plugin a state file is for
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub id: String,
+    pub version: String,
+}
+
+impl Identity {
+    pub fn of(bundle: &PluginBundle, id: &CStr) -> Identity {
+        let version = bundle
+            .get_plugin_factory()
+            .and_then(|factory| factory.plugin_descriptors().find(|d| d.id() == Some(id)))
+            .and_then(|desc| desc.version())
+            .map_or(String::new(), |v| v.to_string_lossy().into_owned());
+        Identity { id: id.to_string_lossy().into_owned(), version }
+    }
+}
+
+pub enum LoadError {
+    // nothing saved yet, e.g. the first run with the same file for both flags
+    Missing,
+    // the plugin has no clap.state, so the file can't be for it
+    Unsupported,
+    // the file couldn't be read; it may be fine once it can
+    Io(String),
+    // the file is another plugin's state
+    Foreign(String),
+    // the file is damaged, or the plugin read it and refused it
+    Rejected(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Missing => write!(f, "no state saved there yet"),
+            LoadError::Unsupported => write!(f, "plugin does not support clap.state"),
+            LoadError::Io(e) | LoadError::Foreign(e) | LoadError::Rejected(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+pub fn load(instance: &mut PluginInstance<MyHost>, path: &Path, plugin: &Identity) -> Result<(), LoadError> {
+    apply(instance, &read(path, plugin)?)
+}
+
+// A state file read and checked, for the plugin to load
+pub struct Saved {
+    path: PathBuf,
+    params: Vec<(ClapId, f64)>,
+    blob: Vec<u8>,
+}
+
+// The file side of a load, which needs no plugin, so may run anywhere
+pub fn read(path: &Path, plugin: &Identity) -> Result<Saved, LoadError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(LoadError::Missing),
+        Err(e) => return Err(LoadError::Io(format!("can't read {}: {e}", path.display()))),
+    };
+    let file = parse(&bytes).map_err(|e| LoadError::Rejected(format!("{} is damaged: {e}", path.display())))?;
+    if let Some(saved) = &file.plugin {
+        if saved.id != plugin.id {
+            return Err(LoadError::Foreign(format!(
+                "{} holds state for {}, not {}",
+                path.display(),
+                saved.id,
+                plugin.id
+            )));
+        }
+        if saved.version != plugin.version {
+            eprintln!(
+                "{} was saved by version {:?} of the plugin, this is {:?}",
+                path.display(),
+                saved.version,
+                plugin.version
+            );
+        }
+    }
+    Ok(Saved { path: path.to_path_buf(), params: file.params, blob: file.blob.into_owned() })
+}
+
+pub fn apply(instance: &mut PluginInstance<MyHost>, saved: &Saved) -> Result<(), LoadError> {
+    let path = &saved.path;
+    {
+        let mut handle = instance.plugin_handle();
+        let state = handle.get_extension::<PluginState>().ok_or(LoadError::Unsupported)?;
+        state
+            .load(&mut handle, &mut &saved.blob[..])
+            .map_err(|e| LoadError::Rejected(format!("plugin rejected {}: {e:?}", path.display())))?;
+    }
+    // what the file says the parameters were, against what they now are
+    let differ = saved
+        .params
+        .iter()
+        .filter(|(id, value)| params::current(instance, *id).is_some_and(|now| now != *value))
+        .count();
+    if differ > 0 {
+        eprintln!("{differ} parameter(s) came back from {} other than they were saved", path.display());
+    }
+    Ok(())
+}
+
+// What a state file holds
+struct Contents<'a> {
+    // None for a bare blob, which doesn't say
+    plugin: Option<Identity>,
+    params: Vec<(ClapId, f64)>,
+    blob: Cow<'a, [u8]>,
+}
+
+fn parse(bytes: &[u8]) -> Result<Contents<'_>, String> {
+    let Some(mut rest) = bytes.strip_prefix(MAGIC) else {
+        return Ok(Contents { plugin: None, params: Vec::new(), blob: Cow::Borrowed(bytes) });
+    };
+    let format = take(&mut rest, 2).map(|b| u16::from_le_bytes([b[0], b[1]]))?;
+    if format > FORMAT {
+        return Err(format!("format {format} is newer than this build reads ({FORMAT})"));
+    }
+    let (mut plugin, mut params, mut blob) = (None, Vec::new(), None);
+    while !rest.is_empty() {
+        let tag = take(&mut rest, 4)?;
+        let len = take(&mut rest, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))?;
+        let chunk = take(&mut rest, len as usize)?;
+        match tag {
+            b"PLUG" => {
+                let text = String::from_utf8_lossy(chunk);
+                let (id, version) = text.split_once('\0').unwrap_or((&text, ""));
+                plugin = Some(Identity { id: id.to_string(), version: version.to_string() });
+            }
+            b"PRMS" => {
+                if chunk.len() % 12 != 0 {
+                    return Err("PRMS chunk ends partway through a parameter".into());
+                }
+                params = chunk
+                    .chunks_exact(12)
+                    .map(|p| {
+                        let id = u32::from_le_bytes(p[..4].try_into().unwrap());
+                        (ClapId::new(id), f64::from_le_bytes(p[4..].try_into().unwrap()))
+                    })
+                    .collect();
+            }
+            b"BLOB" => blob = Some(decode(chunk)?),
+            // HOST and TIME are for people reading the file
+            _ => {}
+        }
+    }
+    Ok(Contents { plugin: Some(plugin.ok_or("no PLUG chunk")?), params, blob: blob.ok_or("no BLOB chunk")? })
+}
+
+// The BLOB chunk for a state: compressed if that makes it smaller
+fn encode(state: &[u8]) -> Vec<u8> {
+    let packed = compress(state);
+    if packed.len() + 4 >= state.len() {
+        return [&[STORED][..], state].concat();
+    }
+    [&[LZ][..], &(state.len() as u32).to_le_bytes(), &packed].concat()
+}
+
+fn decode(chunk: &[u8]) -> Result<Cow<'_, [u8]>, String> {
+    match chunk.split_first() {
+        Some((&STORED, state)) => Ok(Cow::Borrowed(state)),
+        Some((&LZ, mut rest)) => {
+            let len = take(&mut rest, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))?;
+            decompress(rest, len as usize).map(Cow::Owned)
+        }
+        Some((codec, _)) => Err(format!("unknown codec {codec}")),
+        None => Err("empty BLOB chunk".into()),
+    }
+}
+
+// LZ77 laid out as LZ4 blocks are. Each sequence is a token, holding a count
+// of literals in its high nibble and a match length less MIN_MATCH in its low
+// (15 in either meaning more of it follows, in bytes added up to the first
+// under 255), the literal count's extra bytes, the literals, then the
+// match's distance back as a u16 and its length's extra bytes. The last
+// sequence is literals alone.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2 + 16);
+    // where each hash of four bytes was last seen
+    let mut seen = vec![usize::MAX; 1 << HASH_BITS];
+    let (mut pos, mut literals) = (0, 0);
+    while pos + MIN_MATCH <= data.len() {
+        let word = u32::from_le_bytes(data[pos..pos + MIN_MATCH].try_into().unwrap());
+        let hash = (word.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let earlier = std::mem::replace(&mut seen[hash], pos);
+        let found = earlier != usize::MAX && pos - earlier <= WINDOW;
+        if !found || data[earlier..earlier + MIN_MATCH] != data[pos..pos + MIN_MATCH] {
+            pos += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while pos + len < data.len() && data[earlier + len] == data[pos + len] {
+            len += 1;
+        }
+        sequence(&mut out, &data[literals..pos], Some((pos - earlier, len)));
+        pos += len;
+        literals = pos;
+    }
+    sequence(&mut out, &data[literals..], None);
+    out
+}
+
+fn sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let extra = found.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) as u8) << 4 | extra.min(15) as u8);
+    push_length(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((distance, _)) = found {
+        out.extend_from_slice(&(distance as u16).to_le_bytes());
+        push_length(out, extra);
+    }
+}
+
+// What's left of a length past the 15 its nibble holds
+fn push_length(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn take_length(rest: &mut &[u8], nibble: u8) -> Result<usize, String> {
+    let mut len = nibble as usize;
+    if nibble == 15 {
+        loop {
+            let byte = take(rest, 1)?[0];
+            len += byte as usize;
+            if byte < 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+// `len` bytes back out of compress(), or why they can't be
+fn decompress(mut rest: &[u8], len: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let token = take(&mut rest, 1)?[0];
+        let literals = take_length(&mut rest, token >> 4)?;
+        out.extend_from_slice(take(&mut rest, literals)?);
+        if rest.is_empty() {
+            break;
+        }
+        let distance = take(&mut rest, 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize)?;
+        let matched = take_length(&mut rest, token & 0x0f)? + MIN_MATCH;
+        if distance == 0 || distance > out.len() {
+            return Err("compressed state refers back past its start".into());
+        }
+        if out.len() + matched > len {
+            return Err("compressed state is longer than it says".into());
+        }
+        // byte by byte, as a match may overlap what it copies
+        let from = out.len() - distance;
+        for i in from..from + matched {
+            out.push(out[i]);
+        }
+    }
+    if out.len() != len {
+        return Err(format!("compressed state came to {} bytes, not {len}", out.len()));
+    }
+    Ok(out)
+}
+
+// The next `len` bytes
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if rest.len() < len {
+        return Err("truncated".into());
+    }
+    let (this, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(this)
+}
+
+fn chunk(out: &mut Vec<u8>, tag: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+// Written next to the target and renamed over it, so a crash mid-save never
+// leaves a truncated state behind
+pub fn save(instance: &mut PluginInstance<MyHost>, path: &Path, plugin: &Identity) -> Result<(), String> {
+    let mut blob = Vec::new();
+    {
+        let mut handle = instance.plugin_handle();
+        let state = handle
+            .get_extension::<PluginState>()
+            .ok_or("plugin does not support clap.state")?;
+        state
+            .save(&mut handle, &mut blob)
+            .map_err(|e| format!("plugin failed to save its state: {e:?}"))?;
+    }
+    let mut params = Vec::new();
+    for param in params::list(instance) {
+        if let Some(value) = params::current(instance, param.id) {
+            params.extend_from_slice(&param.id.get().to_le_bytes());
+            params.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    let out = contents(plugin, &params, &blob);
+
+    // On disk before the rename, and the rename on disk after, so a power
+    // cut leaves either the old state or the new one
+    let tmp = with_suffix(path, "tmp");
+    let file = File::create(&tmp).map_err(|e| format!("can't write {}: {e}", tmp.display()))?;
+    let mut writer = BufWriter::new(file);
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let saved = writer
+        .write_all(&out)
+        .and_then(|()| writer.flush())
+        .and_then(|()| writer.get_ref().sync_all())
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .and_then(|()| File::open(dir)?.sync_all())
+        .map_err(|e| format!("can't write {}: {e}", path.display()));
+    // Whatever went wrong, the old state stays and the half-written one goes
+    if saved.is_err() {
+        drop(writer);
+        let _ = std::fs::remove_file(&tmp);
+    }
+    saved
+}
+
+// A whole state file, for `params` (as PRMS holds them) and the plugin's `state`
+fn contents(plugin: &Identity, params: &[u8], state: &[u8]) -> Vec<u8> {
+    let saved_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs());
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&FORMAT.to_le_bytes());
+    chunk(&mut out, b"HOST", env!("CARGO_PKG_VERSION").as_bytes());
+    chunk(&mut out, b"PLUG", format!("{}\0{}", plugin.id, plugin.version).as_bytes());
+    chunk(&mut out, b"TIME", &saved_at.to_le_bytes());
+    chunk(&mut out, b"PRMS", params);
+    chunk(&mut out, b"BLOB", &encode(state));
+    out
+}
+
+// Move a state file the plugin wouldn't take out of the way, keeping it for
+// inspection; returns where it went
+pub fn set_aside(path: &Path) -> std::io::Result<PathBuf> {
+    let bad = with_suffix(path, "bad");
+    std::fs::rename(path, &bad)?;
+    Ok(bad)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    name.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the PLUG chunk for plugin()
+    const PLUG: &[u8] = b"com.example.synth\x001.2";
+
+    fn plugin() -> Identity {
+        Identity { id: "com.example.synth".into(), version: "1.2".into() }
+    }
+
+    // A state file with these chunks after the magic and format
+    fn file(format: u16, chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&format.to_le_bytes());
+        for (tag, data) in chunks {
+            chunk(&mut out, tag, data);
+        }
+        out
+    }
+
+    fn param(id: u32, value: f64) -> Vec<u8> {
+        [&id.to_le_bytes()[..], &value.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn round_trips_what_save_writes() {
+        let state: Vec<u8> = (0..2000).map(|i| (i % 7) as u8).collect();
+        let bytes = contents(&plugin(), &[param(3, 0.5), param(9, -1.0)].concat(), &state);
+        let file = parse(&bytes).unwrap();
+        assert_eq!(file.plugin, Some(plugin()));
+        assert_eq!(file.params, [(ClapId::new(3), 0.5), (ClapId::new(9), -1.0)]);
+        assert_eq!(&file.blob[..], &state[..]);
+        // and a state that repetitive went in compressed
+        assert!(bytes.len() < state.len() / 4);
+    }
+
+    #[test]
+    fn a_file_without_the_magic_is_a_bare_blob() {
+        let file = parse(b"some plugin's own state").unwrap();
+        assert_eq!(file.plugin, None);
+        assert!(file.params.is_empty());
+        assert_eq!(&file.blob[..], b"some plugin's own state");
+    }
+
+    #[test]
+    fn unknown_chunks_are_skipped() {
+        let bytes = file(FORMAT, &[(b"PLUG", PLUG), (b"NEWS", b"later"), (b"BLOB", b"\0ab")]);
+        let file = parse(&bytes).unwrap();
+        assert_eq!(file.plugin, Some(plugin()));
+        assert_eq!(&file.blob[..], b"ab");
+    }
+
+    #[test]
+    fn a_newer_format_is_refused() {
+        let bytes = file(FORMAT + 1, &[(b"PLUG", PLUG), (b"BLOB", b"\0ab")]);
+        assert!(parse(&bytes).err().unwrap().contains("newer"));
+    }
+
+    #[test]
+    fn plug_and_blob_are_required() {
+        let no_plug = file(FORMAT, &[(b"BLOB", b"\0ab")]);
+        assert_eq!(parse(&no_plug).err().unwrap(), "no PLUG chunk");
+        let no_blob = file(FORMAT, &[(b"PLUG", PLUG)]);
+        assert_eq!(parse(&no_blob).err().unwrap(), "no BLOB chunk");
+    }
+
+    #[test]
+    fn truncation_anywhere_is_refused() {
+        let bytes = contents(&plugin(), &param(3, 0.5), b"state state state state state");
+        for len in MAGIC.len()..bytes.len() {
+            assert!(parse(&bytes[..len]).is_err(), "cut to {len} of {} bytes", bytes.len());
+        }
+    }
+
+    #[test]
+    fn a_partial_parameter_is_refused() {
+        let prms = [param(3, 0.5), vec![1, 2, 3]].concat();
+        let bytes = file(FORMAT, &[(b"PLUG", PLUG), (b"PRMS", &prms), (b"BLOB", b"\0ab")]);
+        assert!(parse(&bytes).err().unwrap().contains("PRMS"));
+    }
+
+    #[test]
+    fn an_unknown_codec_is_refused() {
+        let bytes = file(FORMAT, &[(b"PLUG", PLUG), (b"BLOB", b"\x07ab")]);
+        assert_eq!(parse(&bytes).err().unwrap(), "unknown codec 7");
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let mut noise = 0x2545_f491u32;
+        let random: Vec<u8> = (0..5000)
+            .map(|_| {
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                noise as u8
+            })
+            .collect();
+        let runs: Vec<u8> = (0..70000).map(|i| (i / 300) as u8).collect();
+        let text = b"<preset><osc wave=\"saw\"/><osc wave=\"saw\"/><filter cutoff=\"0.5\"/></preset>".repeat(40);
+        for data in [&[][..], b"abc", &random, &runs, &text] {
+            assert_eq!(decompress(&compress(data), data.len()).unwrap(), data);
+        }
+        // incompressible state is stored rather than grown
+        assert_eq!(encode(&random)[0], STORED);
+        assert_eq!(encode(&runs)[0], LZ);
+    }
+
+    #[test]
+    fn damaged_compression_is_refused() {
+        let data = b"abcdabcdabcdabcdabcdabcd".repeat(10);
+        let packed = compress(&data);
+        assert!(decompress(&packed, data.len() + 1).is_err());
+        assert!(decompress(&packed[..packed.len() - 1], data.len()).is_err());
+        // a match before anything has been decoded
+        assert!(decompress(&[0x00, 0x01, 0x00], 4).is_err());
+    }
+}
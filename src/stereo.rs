@@ -0,0 +1,134 @@
+// Host-side stereo utility applied to the plugin's output: mid/side width
+// and balance, so stereo generators can be tailored without another plugin,
+// and a correlation readout to check how well they'd survive a mono sum.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// How far back the correlation readout averages
+const CORRELATION_SECONDS: f64 = 0.3;
+
+// Mean square (about -100 dBFS) below which there's nothing to correlate
+const CORRELATION_FLOOR: f64 = 1e-10;
+
+pub struct StereoStage {
+    // 0 = mono, 1 = unchanged, 2 = side doubled
+    width: f32,
+    // -1 = hard left, 0 = centre, 1 = hard right
+    balance: f32,
+}
+
+impl StereoStage {
+    pub fn new(width: f32, balance: f32) -> Self {
+        StereoStage {
+            width: width.clamp(0.0, 2.0),
+            balance: balance.clamp(-1.0, 1.0),
+        }
+    }
+
+    pub fn set_width(&mut self, width: f32) {
+        self.width = width.clamp(0.0, 2.0);
+    }
+
+    pub fn set_balance(&mut self, balance: f32) {
+        self.balance = balance.clamp(-1.0, 1.0);
+    }
+
+    pub fn is_neutral(&self) -> bool {
+        self.width == 1.0 && self.balance == 0.0
+    }
+
+    pub fn process(&self, l: &mut [f32], r: &mut [f32]) {
+        if self.is_neutral() {
+            return;
+        }
+        // Balance only ever attenuates the far side, so centre stays at unity
+        let gain_l = (1.0 - self.balance).min(1.0);
+        let gain_r = (1.0 + self.balance).min(1.0);
+        for (l, r) in l.iter_mut().zip(r.iter_mut()) {
+            let mid = (*l + *r) * 0.5;
+            let side = (*l - *r) * 0.5 * self.width;
+            *l = (mid + side) * gain_l;
+            *r = (mid - side) * gain_r;
+        }
+    }
+}
+
+// The L/R correlation of what we send out, as f32 bits: +1 for mono, 0 for
+// unrelated channels, -1 for one the inverse of the other (which cancels
+// when summed to mono). NaN while there's nothing to measure.
+pub struct Correlation(AtomicU32);
+
+impl Default for Correlation {
+    fn default() -> Self {
+        Correlation(AtomicU32::new(f32::NAN.to_bits()))
+    }
+}
+
+impl Correlation {
+    pub fn get(&self) -> Option<f32> {
+        Some(f32::from_bits(self.0.load(Ordering::Relaxed))).filter(|c| !c.is_nan())
+    }
+}
+
+// Audio-thread side: running means of L*R, L² and R²
+pub struct CorrelationMeter {
+    lr: f64,
+    ll: f64,
+    rr: f64,
+    coeff: f64,
+    shared: Arc<Correlation>,
+}
+
+impl CorrelationMeter {
+    pub fn new(sample_rate: f64, shared: Arc<Correlation>) -> Self {
+        let mut meter = CorrelationMeter { lr: 0.0, ll: 0.0, rr: 0.0, coeff: 0.0, shared };
+        meter.set_sample_rate(sample_rate);
+        meter
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.coeff = 1.0 - (-1.0 / (CORRELATION_SECONDS * sample_rate)).exp();
+    }
+
+    pub fn process(&mut self, l: &[f32], r: &[f32]) {
+        for (&l, &r) in l.iter().zip(r) {
+            let (l, r) = (l as f64, r as f64);
+            self.lr += (l * r - self.lr) * self.coeff;
+            self.ll += (l * l - self.ll) * self.coeff;
+            self.rr += (r * r - self.rr) * self.coeff;
+        }
+        let power = (self.ll * self.rr).sqrt();
+        let correlation = if power > CORRELATION_FLOOR { (self.lr / power).clamp(-1.0, 1.0) as f32 } else { f32::NAN };
+        self.shared.0.store(correlation.to_bits(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    const RATE: f64 = 48000.0;
+
+    fn sine(phase: f64) -> Vec<f32> {
+        (0..RATE as usize).map(|n| (2.0 * PI * 440.0 * n as f64 / RATE + phase).sin() as f32 * 0.5).collect()
+    }
+
+    fn measure(l: &[f32], r: &[f32]) -> Option<f32> {
+        let shared = Arc::new(Correlation::default());
+        CorrelationMeter::new(RATE, shared.clone()).process(l, r);
+        shared.get()
+    }
+
+    #[test]
+    fn correlation_tells_mono_from_unrelated_from_inverted() {
+        let l = sine(0.0);
+        let inverted: Vec<f32> = l.iter().map(|s| -s).collect();
+        assert!(measure(&l, &l).unwrap() > 0.99);
+        assert!(measure(&l, &inverted).unwrap() < -0.99);
+        // a quarter cycle apart: no correlation either way
+        assert!(measure(&l, &sine(PI / 2.0)).unwrap().abs() < 0.05);
+        assert_eq!(measure(&l, &vec![0.0; l.len()]), None);
+    }
+}
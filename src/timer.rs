@@ -0,0 +1,65 @@
+// clap.timer-support: plugins (their GUIs especially) ask for periodic
+// callbacks on the main thread, which the main loop runs between commands.
+use std::time::{Duration, Instant};
+
+use clack_extensions::timer::{PluginTimer, TimerId};
+use clack_host::prelude::*;
+
+use crate::MyHost;
+
+#[derive(Default)]
+pub struct Timers {
+    next_id: u32,
+    timers: Vec<Timer>,
+}
+
+struct Timer {
+    id: TimerId,
+    period: Duration,
+    due: Instant,
+}
+
+impl Timers {
+    pub fn register(&mut self, period_ms: u32) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        // 0 means "as often as you can"; every millisecond is plenty
+        let period = Duration::from_millis(period_ms.max(1) as u64);
+        self.timers.push(Timer { id, period, due: Instant::now() + period });
+        id
+    }
+
+    pub fn unregister(&mut self, id: TimerId) -> bool {
+        let before = self.timers.len();
+        self.timers.retain(|t| t.id != id);
+        self.timers.len() != before
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.timers.iter().map(|t| t.due).min()
+    }
+
+    // Timers that are due, rescheduled a period from now; ticks we were too
+    // busy for are skipped rather than delivered in a burst
+    fn take_due(&mut self, now: Instant) -> Vec<TimerId> {
+        let mut due = Vec::new();
+        for timer in self.timers.iter_mut().filter(|t| t.due <= now) {
+            timer.due = now + timer.period;
+            due.push(timer.id);
+        }
+        due
+    }
+}
+
+pub fn fire_due(instance: &mut PluginInstance<MyHost>) {
+    let now = Instant::now();
+    let due = instance.access_handler_mut(|host| host.timers.take_due(now));
+    if due.is_empty() {
+        return;
+    }
+    let mut handle = instance.plugin_handle();
+    let Some(timer) = handle.get_extension::<PluginTimer>() else { return };
+    for id in due {
+        timer.on_timer(&mut handle, id);
+    }
+}
@@ -0,0 +1,93 @@
+// The JACK transport as CLAP's, passed with every process() call so
+// tempo-synced plugins follow it. JACK doesn't announce a relocate, so we
+// spot one by the transport not being where the last block left it. The
+// plugin then also gets a transport event at the start of the block, CLAP's
+// way of saying the timeline moved, and with --reset-on-locate a reset(), so
+// delay lines and sequencers don't smear the old position into the new one.
+use clack_host::events::event_types::{TransportEvent, TransportFlags};
+use clack_host::events::{EventFlags, EventHeader};
+use clack_host::utils::{BeatTime, SecondsTime};
+use jack::{TransportState, TransportStatePosition};
+
+pub struct TransportSync {
+    sample_rate: f64,
+    // the transport frame the next block should start at
+    expected: Option<u64>,
+}
+
+impl TransportSync {
+    pub fn new(sample_rate: f64) -> Self {
+        TransportSync { sample_rate, expected: None }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+
+    // Once per JACK block of `frames`: whether the transport jumped since the
+    // last block
+    pub fn relocated(&mut self, transport: &TransportStatePosition, frames: usize) -> bool {
+        let frame = transport.pos.frame() as u64;
+        let jumped = self.expected.is_some_and(|expected| expected != frame);
+        self.expected = Some(if rolling(transport) { frame + frames as u64 } else { frame });
+        jumped
+    }
+
+    // The transport `offset` frames into the block, for a slice starting there.
+    // Beats and tempo only when a timebase master publishes them.
+    pub fn event(&self, transport: &TransportStatePosition, offset: usize) -> TransportEvent {
+        let rolling = rolling(transport);
+        let advance = if rolling { offset as f64 } else { 0.0 };
+        let mut flags = TransportFlags::HAS_SECONDS_TIMELINE;
+        if rolling {
+            flags |= TransportFlags::IS_PLAYING;
+        }
+        let zero = BeatTime::from_float(0.0);
+        let mut event = TransportEvent {
+            header: EventHeader::new_core(0, EventFlags::empty()),
+            flags,
+            song_pos_beats: zero,
+            song_pos_seconds: SecondsTime::from_float((transport.pos.frame() as f64 + advance) / self.sample_rate),
+            tempo: 0.0,
+            tempo_inc: 0.0,
+            loop_start_beats: zero,
+            loop_end_beats: zero,
+            loop_start_seconds: SecondsTime::from_float(0.0),
+            loop_end_seconds: SecondsTime::from_float(0.0),
+            bar_start: zero,
+            bar_number: 0,
+            time_signature_numerator: 4,
+            time_signature_denominator: 4,
+        };
+        if let Some(bbt) = transport.pos.bbt().filter(|bbt| bbt.bpm > 0.0) {
+            let bar = bbt.bar.saturating_sub(1);
+            let bar_start = bar as f64 * bbt.sig_num as f64;
+            let beats = bar_start
+                + bbt.beat.saturating_sub(1) as f64
+                + bbt.tick as f64 / bbt.ticks_per_beat
+                + advance * bbt.bpm / (60.0 * self.sample_rate);
+            event.flags |= TransportFlags::HAS_TEMPO | TransportFlags::HAS_BEATS_TIMELINE | TransportFlags::HAS_TIME_SIGNATURE;
+            event.tempo = bbt.bpm;
+            event.song_pos_beats = BeatTime::from_float(beats);
+            event.bar_start = BeatTime::from_float(bar_start);
+            event.bar_number = bar as i32;
+            event.time_signature_numerator = bbt.sig_num as u16;
+            event.time_signature_denominator = bbt.sig_denom as u16;
+        }
+        event
+    }
+}
+
+// Frames from the start of this block to the next bar line (0 when one starts
+// right here), while the transport rolls and a timebase master says where
+// the bars are
+pub fn frames_to_bar(transport: &TransportStatePosition, sample_rate: f64) -> Option<usize> {
+    let bbt = transport.pos.bbt().filter(|bbt| bbt.bpm > 0.0 && (bbt.sig_num as f64) > 0.0 && rolling(transport))?;
+    let beats = bbt.beat.saturating_sub(1) as f64 + bbt.tick as f64 / bbt.ticks_per_beat;
+    let to_bar = (bbt.sig_num as f64 - beats).rem_euclid(bbt.sig_num as f64);
+    Some((to_bar * 60.0 * sample_rate / bbt.bpm).round() as usize)
+}
+
+fn rolling(transport: &TransportStatePosition) -> bool {
+    matches!(transport.state, TransportState::Rolling)
+}